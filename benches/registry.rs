@@ -0,0 +1,174 @@
+//! Drives 100k registered clients through search, request and connect
+//! Run with `cargo bench --bench registry`
+
+use std::{
+    mem::ManuallyDrop,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bytes_kman::TBytes;
+use relay_man::{
+    common::{frame::FrameDecoder, packets::*, tls::Tls, FromRawSock, IntoRawSock},
+    server::{Client, ClientStage, RelayServer},
+};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+const CLIENTS: usize = 100_000;
+const SEARCHES: usize = 100;
+
+fn report(name: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{name:<10} {count:>7} ops in {elapsed:>12?}, {:>10?}/op",
+        elapsed / count as u32
+    );
+}
+
+fn adress(i: usize) -> Vec<u8> {
+    i.to_be_bytes().to_vec()
+}
+
+fn udp_port(server: &mut RelayServer, session: usize, peer: usize, from: SocketAddr) {
+    let token = server.port_token(session, peer);
+    let mut bytes = Packets::Register(Register::Port { session, token }).to_bytes();
+    bytes.reverse();
+    server.on_udp_packet(bytes, &SockAddr::from(from));
+}
+
+fn main() {
+    // Every client writes in the same connected udp socket, so the benchmark
+    // is not limited by the open file limit, the sink never reads
+    let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let conn = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+    conn.set_nonblocking(true).unwrap();
+    conn.connect(&sink.local_addr().unwrap().into()).unwrap();
+    let fd = conn.into_raw();
+
+    // `ManuallyDrop` because all the clients share `fd`
+    let mut server =
+        ManuallyDrop::new(RelayServer::bind("127.0.0.1:0", Duration::from_secs(3600)).unwrap());
+
+    let mut sessions = Vec::with_capacity(CLIENTS);
+
+    let start = Instant::now();
+    for i in 0..CLIENTS {
+        let session = server.create_session();
+        server.clients.push(Client {
+            session,
+            conn: Socket::from_raw(fd),
+            fd,
+            from: SockAddr::from(SocketAddr::from((
+                [127, 0, 0, 1],
+                (i % 60000) as u16 + 1024,
+            ))),
+            stage: ClientStage::NotRegistered,
+            last_message: std::time::SystemTime::now(),
+            connected: std::time::SystemTime::now(),
+            buffer: FrameDecoder::default(),
+            version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            tls: Tls::default(),
+            #[cfg(feature = "tokio")]
+            writer: None,
+        });
+
+        let index = server.clients.index_of(session).unwrap();
+        server.handle_packets(
+            index,
+            vec![Packets::Register(Register::Client {
+                client: String::from("bench"),
+                public: adress(i),
+                name: format!("peer{i}"),
+                other: Vec::new(),
+                privacy: false,
+                private_adress: String::from("127.0.0.1"),
+                auth: Auth::None,
+                identity: false,
+            })],
+        );
+        sessions.push(session);
+    }
+    report("register", CLIENTS, start.elapsed());
+
+    let start = Instant::now();
+    for (i, &session) in sessions.iter().enumerate().take(SEARCHES) {
+        let index = server.clients.index_of(session).unwrap();
+        server.handle_packets(
+            index,
+            vec![Packets::Search(Search {
+                session,
+                id: i + 1,
+                name: SearchType::Exact(format!("peer{}", CLIENTS - i - 1)),
+                ..Default::default()
+            })],
+        );
+    }
+    report("search", SEARCHES, start.elapsed());
+
+    let start = Instant::now();
+    for (i, &session) in sessions.iter().enumerate() {
+        let index = server.clients.index_of(session).unwrap();
+        server.handle_packets(
+            index,
+            vec![Packets::InfoRequest(InfoRequest {
+                adress: adress(CLIENTS - i - 1),
+                session,
+                id: 1,
+            })],
+        );
+    }
+    report("info", CLIENTS, start.elapsed());
+
+    let start = Instant::now();
+    for pair in sessions.chunks_exact(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let adress_a = match &server.clients.by_session(a).unwrap().stage {
+            ClientStage::Registered(rclient) => rclient.adress.clone(),
+            _ => unreachable!(),
+        };
+        let adress_b = match &server.clients.by_session(b).unwrap().stage {
+            ClientStage::Registered(rclient) => rclient.adress.clone(),
+            _ => unreachable!(),
+        };
+
+        let index_a = server.clients.index_of(a).unwrap();
+        let index_b = server.clients.index_of(b).unwrap();
+
+        server.handle_packets(
+            index_a,
+            vec![Packets::Request(Request {
+                session: a,
+                id: 1,
+                to: adress_b.clone(),
+                secret: String::new(),
+            })],
+        );
+        server.handle_packets(
+            index_b,
+            vec![Packets::RequestResponse(RequestResponse {
+                session: b,
+                id: 1,
+                to: adress_a,
+                accepted: true,
+                secret: String::new(),
+            })],
+        );
+        server.handle_packets(
+            index_a,
+            vec![Packets::RequestFinal(RequestFinal {
+                session: a,
+                id: 2,
+                to: adress_b,
+                accepted: true,
+                time_offset: 0,
+            })],
+        );
+
+        udp_port(&mut server, a, b, SocketAddr::from(([127, 0, 0, 1], 40000)));
+        udp_port(&mut server, b, a, SocketAddr::from(([127, 0, 0, 1], 40001)));
+        server.connect();
+    }
+    report("connect", CLIENTS / 2, start.elapsed());
+
+    assert!(server.clients.connecting.is_empty());
+}
@@ -0,0 +1,12 @@
+use std::time::Duration;
+
+use relay_man::server::async_server::AsyncRelayServer;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::init();
+    let server = AsyncRelayServer::new("0.0.0.0", Duration::from_secs(5)).unwrap();
+    println!("Server Started");
+
+    server.run().await.unwrap();
+}
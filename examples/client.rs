@@ -1,14 +1,13 @@
-use std::{mem::MaybeUninit, net::ToSocketAddrs, thread::JoinHandle, time::Duration};
+use std::{mem::MaybeUninit, thread::JoinHandle, time::Duration};
 
-use rand::{random, Rng};
+use rand::random;
 use relay_man::{
-    client::{response::Conn, ConnectionInfo, RelayClient},
+    client::{response::Conn, udp_socket, ConnectionInfo, RelayClient, TConnection},
     common::{
         adress::Adress,
-        packets::{Search, SearchType},
+        packets::{Auth, Search, SearchType},
     },
 };
-use socket2::{Domain, SockAddr, Socket};
 
 fn main() {
     println!("Starting client");
@@ -18,6 +17,9 @@ fn main() {
         public: vec![random(), random(), random(), random()],
         other: vec![],
         privacy: false,
+        auth: Auth::None,
+        identity: None,
+        tls: None,
     };
     println!("Info: {:?}", info);
     let mut client = RelayClient::new(
@@ -28,15 +30,20 @@ fn main() {
 
     println!("Create connection");
 
+    for conn in client.connections.iter() {
+        println!("NAT: {:?}", conn.detect_nat());
+    }
+
     client.step();
     let search = client.search(Search {
         session: 0,
+        id: 0,
         client: SearchType::None,
         name: SearchType::None,
         other: SearchType::None,
     });
 
-    let search = search.get();
+    let search = search.get().unwrap();
 
     println!("Search: {:?}", search);
 
@@ -45,7 +52,7 @@ fn main() {
         let Some(where_is) = where_is.pop() else{continue};
         let info = client.get(where_is).unwrap().info(&adress);
         let client_info = info.get();
-        if let Some(info) = client_info {
+        if let Ok(Ok(info)) = client_info {
             println!("Client: {:?}", info);
         }
     }
@@ -53,13 +60,11 @@ fn main() {
     let mut connections = Vec::new();
     let mut thread: Option<JoinHandle<(Adress, Conn)>> = None;
 
-    let socket = Socket::new(Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
-    socket.bind(&SockAddr::from(
-        "0.0.0.0:0".to_socket_addrs().unwrap().next().unwrap(),
-    ));
+    let relay = client.get(0).unwrap().read().unwrap().adress;
+    let socket = udp_socket(relay).unwrap();
     let mut connecting_to = Vec::new();
 
-    let search = client.search(Search::default()).get();
+    let search = client.search(Search::default()).get().unwrap();
     for adress in search {
         if adress != client.get(0).unwrap().adress() && !connecting_to.contains(&adress) {
             connecting_to.push(adress.clone());
@@ -96,11 +101,14 @@ fn main() {
                 }
                 relay_man::client::response::RequestStage::NewRequestResponse(new) => {
                     println!("Res from: {:?}", new.from);
-                    let res = new.add_socket(&socket);
+                    let res = new.add_socket_with_candidates(&socket);
                     match res {
                         relay_man::client::response::RegisterResponse::Success { port } => {
                             println!("Connection on port: {port}")
                         }
+                        relay_man::client::response::RegisterResponse::Refused(error) => {
+                            panic!("Relay refused: {error}")
+                        }
                         relay_man::client::response::RegisterResponse::Error => {
                             panic!("Some thing went rong!")
                         }
@@ -109,11 +117,14 @@ fn main() {
                 }
                 relay_man::client::response::RequestStage::NewRequestFinal(new) => {
                     println!("Final from: {:?}", new.from);
-                    let res = new.add_socket(&socket);
+                    let res = new.add_socket_with_candidates(&socket);
                     match res {
                         relay_man::client::response::RegisterResponse::Success { port } => {
                             println!("Connection on port: {port}")
                         }
+                        relay_man::client::response::RegisterResponse::Refused(error) => {
+                            panic!("Relay refused: {error}")
+                        }
                         relay_man::client::response::RegisterResponse::Error => {
                             panic!("Some thing went rong!")
                         }
@@ -125,8 +136,12 @@ fn main() {
                     thread = Some(std::thread::spawn(|| {
                         (
                             new.adress.clone(),
-                            new.connect(Duration::from_secs(5), Duration::from_millis(100), socket)
-                                .unwrap(),
+                            new.connect_ice(
+                                Duration::from_secs(5),
+                                Duration::from_millis(100),
+                                socket,
+                            )
+                            .unwrap(),
                         )
                     }));
                 }
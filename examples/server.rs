@@ -1,10 +1,16 @@
 use std::time::Duration;
 
-use relay_man::server::RelayServer;
+use relay_man::server::{RelayQuota, RelayServer};
 
 fn main() {
     env_logger::init();
     let mut server = RelayServer::new("0.0.0.0", Duration::from_secs(5)).unwrap();
+    if let Ok(key) = std::env::var("RELAY_AUTH_KEY") {
+        server.set_auth_key(key);
+    }
+    if std::env::var("RELAY_FORWARD").is_ok() {
+        server.set_relay_quota(RelayQuota::default());
+    }
     println!("Server Started");
 
     loop {
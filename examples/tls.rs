@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use relay_man::{
+    client::{Connection, ConnectionInfo},
+    common::{
+        identity::Identity,
+        packets::Auth,
+        tls::{SelfSigned, TlsOptions},
+    },
+    server::RelayServer,
+};
+
+/// Starts a relay with a self signed certificate on localhost and registers a client over TLS
+fn main() {
+    env_logger::init();
+
+    let certificate = SelfSigned::generate(&["localhost", "127.0.0.1"]).unwrap();
+
+    let mut server = RelayServer::bind("127.0.0.1:0", Duration::from_secs(5)).unwrap();
+    server
+        .set_tls(vec![certificate.cert.clone()], certificate.key.clone())
+        .unwrap();
+    let adress = server.local_addrs()[0];
+    println!("Server Started on {adress}");
+
+    std::thread::spawn(move || loop {
+        server.step();
+    });
+
+    for pin in [certificate.pin(), certificate.pin_sha256()] {
+        let info = ConnectionInfo {
+            client: "Test".into(),
+            name: "tls".into(),
+            public: Vec::new(),
+            other: vec![],
+            privacy: false,
+            auth: Auth::None,
+            identity: None,
+            tls: Some(TlsOptions::new(pin)),
+        }
+        .with_identity(Identity::generate());
+
+        let connection = Connection::new(adress.to_string(), info).unwrap();
+        println!(
+            "Registered over TLS, session: {}, version: {}",
+            connection.session, connection.version
+        );
+    }
+}
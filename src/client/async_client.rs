@@ -0,0 +1,1032 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use socket2::Socket;
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::common::{
+    adress::Adress,
+    frame::{read_packet_async, write_packet_async, FrameDecoder},
+    packets::{
+        Auth, Candidate, Error, Hello, HelloResponse, InfoRequest, NatType, Packets, Register,
+        RegisterResponse, RelayRequest, Request, RequestFinal, RequestResponse, Search,
+        MIN_PROTOCOL_VERSION,
+    },
+    tls::{AsyncReader, AsyncWriter, Tls},
+};
+
+use super::{
+    connection::{
+        register_candidates, register_probes, register_socket, relay_host, resolve_relay,
+    },
+    detect_nat,
+    ice::{gather_candidates, remote_candidates, run_ice, Ice},
+    response::{self, hole_punch, relay_bind, Conn, ConnectOnError},
+    ConnectionError, ConnectionInfo, RelayClientError,
+};
+
+/// How often a `Tick` is sent to keep the session alive
+pub const TICK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum AsyncError {
+    Relay(Error),
+    InvalidResponse,
+    Closed,
+    /// A blocking task panicked
+    TaskFailed,
+}
+
+/// Aborts the task when dropped
+#[derive(Debug)]
+pub struct Driver(pub JoinHandle<()>);
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub type Pending = Arc<Mutex<HashMap<usize, oneshot::Sender<Packets>>>>;
+
+/// Removes a request from `Pending` when its caller stops waiting,
+/// like when the future of `call` is dropped or timed out
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: usize,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A connection to a relay that is driven by a background task
+#[derive(Clone)]
+pub struct AsyncConnection {
+    pub session: usize,
+    pub adress: SocketAddr,
+    pub info: ConnectionInfo,
+    pub version: u16,
+    pub capabilities: Vec<String>,
+    pub adresses: Arc<Mutex<Vec<Adress>>>,
+    pub writer: Arc<tokio::sync::Mutex<AsyncWriter>>,
+    pub pending: Pending,
+    pub last_id: Arc<AtomicUsize>,
+    pub events: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Packets>>>,
+    pub driver: Arc<Driver>,
+}
+
+impl AsyncConnection {
+    pub async fn new(ip: impl Into<String>, info: ConnectionInfo) -> Result<Self, ConnectionError> {
+        let ip = ip.into();
+        let host = relay_host(&ip);
+        let Ok(Some(adress)) = tokio::task::spawn_blocking(move || resolve_relay(&ip)).await else {
+            return Err(ConnectionError::InvalidIp);
+        };
+        let Ok(stream) = TcpStream::connect(adress).await else {
+            return Err(ConnectionError::HostIsNotAlive);
+        };
+        let Ok(local_addr) = stream.local_addr() else {
+            return Err(ConnectionError::HostIsNotAlive);
+        };
+
+        let (mut reader, mut writer) =
+            match Tls::connect_async(info.tls.as_ref(), &host, stream).await {
+                Ok(halves) => halves,
+                Err(error) => return Err(ConnectionError::Tls(error)),
+            };
+        let mut buffer = FrameDecoder::default();
+
+        let Ok(_) = write_packet_async(&mut writer, &Packets::Hello(Hello::new())).await else {
+            return Err(ConnectionError::HostIsNotAlive);
+        };
+        let Ok(packet) = read_packet_async(&mut reader, &mut buffer).await else {
+            return Err(ConnectionError::InvalidInfo);
+        };
+        let (version, capabilities) = match packet {
+            Packets::HelloResponse(HelloResponse::Accepted {
+                version,
+                capabilities,
+            }) if version >= MIN_PROTOCOL_VERSION => (version, capabilities),
+            // a older relay accepts the newest version that it speaks
+            Packets::HelloResponse(HelloResponse::Accepted { version, .. }) => {
+                return Err(ConnectionError::IncompatibleVersion {
+                    min_version: version,
+                    max_version: version,
+                })
+            }
+            Packets::HelloResponse(HelloResponse::Rejected {
+                min_version,
+                max_version,
+            }) => {
+                return Err(ConnectionError::IncompatibleVersion {
+                    min_version,
+                    max_version,
+                })
+            }
+            _ => return Err(ConnectionError::InvalidInfo),
+        };
+
+        let pak = Packets::Register(Register::Client {
+            client: info.client.clone(),
+            public: info.public.clone(),
+            name: info.name.clone(),
+            other: info.other.clone(),
+            privacy: info.privacy,
+            private_adress: local_addr.ip().to_string(),
+            auth: info.auth.clone(),
+            identity: info.identity.is_some(),
+        });
+        let Ok(_) = write_packet_async(&mut writer, &pak).await else {
+            return Err(ConnectionError::InvalidInfo);
+        };
+        let Ok(mut packet) = read_packet_async(&mut reader, &mut buffer).await else {
+            return Err(ConnectionError::InvalidInfo);
+        };
+        if let Packets::RegisterResponse(RegisterResponse::Challenge { nonce }) = &packet {
+            let Some(identity) = &info.identity else {
+                return Err(ConnectionError::InvalidInfo);
+            };
+            let pak = Packets::Register(Register::Proof {
+                signature: identity.sign_challenge(nonce),
+            });
+            let Ok(_) = write_packet_async(&mut writer, &pak).await else {
+                return Err(ConnectionError::InvalidInfo);
+            };
+            let Ok(response) = read_packet_async(&mut reader, &mut buffer).await else {
+                return Err(ConnectionError::InvalidInfo);
+            };
+            packet = response;
+        }
+        let session = match packet {
+            Packets::RegisterResponse(RegisterResponse::Client {
+                accepted: true,
+                session,
+            }) => session,
+            Packets::Error(error) => return Err(ConnectionError::Refused(error)),
+            _ => return Err(ConnectionError::InvalidInfo),
+        };
+
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        let pending = Pending::default();
+        let (events_sender, events) = mpsc::unbounded_channel();
+
+        let driver = tokio::spawn(drive(
+            reader,
+            buffer,
+            writer.clone(),
+            pending.clone(),
+            events_sender,
+            session,
+        ));
+
+        Ok(Self {
+            session,
+            adress,
+            info,
+            version,
+            capabilities,
+            adresses: Arc::default(),
+            writer,
+            pending,
+            last_id: Arc::new(AtomicUsize::new(0)),
+            events: Arc::new(tokio::sync::Mutex::new(events)),
+            driver: Arc::new(Driver(driver)),
+        })
+    }
+
+    pub fn adress(&self) -> Adress {
+        self.info.public.clone()
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Returns a new request id, never 0
+    pub fn next_id(&self) -> usize {
+        loop {
+            let id = self.last_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    /// Sends a packet that has no response
+    pub async fn send(&self, packet: Packets) -> Result<(), AsyncError> {
+        let mut packet = packet;
+        packet.set_session(self.session);
+
+        let mut writer = self.writer.lock().await;
+        write_packet_async(&mut *writer, &packet)
+            .await
+            .map_err(|_| AsyncError::Closed)
+    }
+
+    /// Sends a request and waits for the response with the same id
+    pub async fn call(&self, packet: Packets) -> Result<Packets, AsyncError> {
+        let mut packet = packet;
+        let id = self.next_id();
+        if let Some(request_id) = packet.request_id_mut() {
+            *request_id = id;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            id,
+        };
+        // the driver clears `pending` before it ends, so nobody would answer
+        if self.driver.0.is_finished() {
+            return Err(AsyncError::Closed);
+        }
+
+        self.send(packet).await?;
+
+        match receiver.await {
+            Ok(Packets::Error(error)) => Err(AsyncError::Relay(error)),
+            Ok(packet) => Ok(packet),
+            Err(_) => Err(AsyncError::Closed),
+        }
+    }
+
+    pub async fn search(&self, search: Search) -> Result<response::SearchResponse, AsyncError> {
+        let Packets::SearchResponse(pak) = self.call(Packets::Search(search)).await? else {
+            return Err(AsyncError::InvalidResponse);
+        };
+
+        *self.adresses.lock().unwrap() = pak.adresses.clone();
+        Ok(response::SearchResponse {
+            adresses: pak.adresses,
+        })
+    }
+
+    pub async fn info(&self, adress: &Adress) -> Result<ConnectionInfo, AsyncError> {
+        let pak = Packets::InfoRequest(InfoRequest {
+            adress: adress.clone(),
+            session: 0,
+            id: 0,
+        });
+        let Packets::Info(pak) = self.call(pak).await? else {
+            return Err(AsyncError::InvalidResponse);
+        };
+
+        Ok(ConnectionInfo {
+            client: pak.client,
+            name: pak.name,
+            public: adress.clone(),
+            other: pak.other,
+            privacy: false,
+            auth: Auth::None,
+            identity: None,
+            tls: None,
+        })
+    }
+
+    pub async fn request(
+        &self,
+        adress: &Adress,
+        secret: String,
+    ) -> Result<NewRequestResponse, AsyncError> {
+        let pak = Packets::Request(Request {
+            session: 0,
+            id: 0,
+            to: adress.clone(),
+            secret,
+        });
+        let Packets::NewRequestResponse(pak) = self.call(pak).await? else {
+            return Err(AsyncError::InvalidResponse);
+        };
+
+        Ok(NewRequestResponse {
+            connection: self.clone(),
+            from: pak.from,
+            accept: pak.accepted,
+            secret: pak.secret,
+            port_token: pak.port_token,
+        })
+    }
+
+    pub async fn request_response(
+        &self,
+        adress: &Adress,
+        accept: bool,
+    ) -> Result<NewRequestFinal, AsyncError> {
+        let pak = Packets::RequestResponse(RequestResponse {
+            session: 0,
+            id: 0,
+            to: adress.clone(),
+            accepted: accept,
+            secret: String::new(),
+        });
+        let Packets::NewRequestFinal(pak) = self.call(pak).await? else {
+            return Err(AsyncError::InvalidResponse);
+        };
+
+        Ok(NewRequestFinal {
+            connection: self.clone(),
+            from: pak.from,
+            accept: pak.accepted,
+            port_token: pak.port_token,
+        })
+    }
+
+    /// `time_offset` should be in nanosecconds
+    pub async fn request_final(
+        &self,
+        adress: &Adress,
+        accept: bool,
+        time_offset: Option<u128>,
+    ) -> Result<ConnectOn, AsyncError> {
+        let time_offset = match time_offset {
+            Some(s) => s,
+            None => Duration::from_secs(1).as_nanos(),
+        };
+
+        let pak = Packets::RequestFinal(RequestFinal {
+            session: 0,
+            id: 0,
+            to: adress.clone(),
+            accepted: accept,
+            time_offset,
+        });
+        let Packets::ConnectOn(pak) = self.call(pak).await? else {
+            return Err(AsyncError::InvalidResponse);
+        };
+
+        Ok(ConnectOn {
+            connection: self.clone(),
+            adress: pak.adress,
+            to: pak.to,
+            port: pak.port,
+            time: pak.time,
+            relay: pak.relay,
+            nat: pak.nat,
+            punch: pak.punch,
+            predicted: pak.predicted,
+            candidates: pak.candidates,
+        })
+    }
+
+    /// `token` is the `port_token` of the `NewRequestResponse` or `NewRequestFinal`
+    pub async fn add_socket(&self, socket: &Socket, token: &[u8]) -> response::RegisterResponse {
+        let Ok(socket) = socket.try_clone() else {
+            return response::RegisterResponse::Error;
+        };
+        let session = self.session;
+        let adress = self.adress;
+        let token = token.to_vec();
+
+        tokio::task::spawn_blocking(move || register_socket(session, &token, adress, &socket))
+            .await
+            .unwrap_or(response::RegisterResponse::Error)
+    }
+
+    /// Same as `TConnection::add_socket_with_probes`
+    pub async fn add_socket_with_probes(
+        &self,
+        socket: &Socket,
+        token: &[u8],
+        probes: usize,
+    ) -> response::RegisterResponse {
+        let Ok(socket) = socket.try_clone() else {
+            return response::RegisterResponse::Error;
+        };
+        let session = self.session;
+        let adress = self.adress;
+        let token = token.to_vec();
+
+        tokio::task::spawn_blocking(move || {
+            let _probes = register_probes(session, &token, adress, probes);
+            register_socket(session, &token, adress, &socket)
+        })
+        .await
+        .unwrap_or(response::RegisterResponse::Error)
+    }
+
+    /// Same as `TConnection::add_socket_with_candidates`
+    pub async fn add_socket_with_candidates(
+        &self,
+        socket: &Socket,
+        token: &[u8],
+    ) -> response::RegisterResponse {
+        let Ok(socket) = socket.try_clone() else {
+            return response::RegisterResponse::Error;
+        };
+        let session = self.session;
+        let adress = self.adress;
+        let token = token.to_vec();
+
+        tokio::task::spawn_blocking(move || {
+            let candidates = gather_candidates(&socket);
+            register_candidates(session, &token, adress, &socket, &candidates)
+        })
+        .await
+        .unwrap_or(response::RegisterResponse::Error)
+    }
+
+    /// Asks for the relayed port, `token` is the `relay` of the `ConnectOn`
+    pub async fn relay(&self, token: &[u8]) -> Result<u16, AsyncError> {
+        let pak = Packets::RelayRequest(RelayRequest {
+            session: 0,
+            id: 0,
+            token: token.to_vec(),
+        });
+        let Packets::RelayResponse(pak) = self.call(pak).await? else {
+            return Err(AsyncError::InvalidResponse);
+        };
+
+        Ok(pak.port)
+    }
+
+    /// Same as `response::relay_connect`
+    pub async fn relay_connect(
+        &self,
+        token: &[u8],
+        timeout: Duration,
+        resend: Duration,
+    ) -> Result<Conn, ConnectOnError> {
+        if token.is_empty() {
+            return Err(ConnectOnError::NoRelay);
+        }
+
+        let port = match tokio::time::timeout(timeout, self.relay(token)).await {
+            Ok(Ok(port)) => port,
+            Ok(Err(AsyncError::Relay(error))) => return Err(ConnectOnError::RelayRefused(error)),
+            _ => return Err(ConnectOnError::RelayTimeout),
+        };
+        let mut relay = self.adress;
+        relay.set_port(port);
+
+        let token = token.to_vec();
+        tokio::task::spawn_blocking(move || relay_bind(relay, &token, timeout, resend))
+            .await
+            .unwrap_or(Err(ConnectOnError::TaskFailed))
+    }
+
+    /// Same as `Connection::detect_nat`, the detection runs on a blocking task
+    pub async fn detect_nat(&self) -> Result<NatType, AsyncError> {
+        let relay = self.adress;
+        let Ok(nat) = tokio::task::spawn_blocking(move || detect_nat(relay)).await else {
+            return Err(AsyncError::TaskFailed);
+        };
+        self.send(Packets::Register(Register::Nat { nat })).await?;
+        Ok(nat)
+    }
+
+    /// Waits for a packet that was not the answer to a request
+    /// Returns `None` if the relay closed the connection
+    pub async fn next_event(&self) -> Option<RequestStage> {
+        let mut events = self.events.lock().await;
+
+        loop {
+            let packet = events.recv().await?;
+            if let Some(stage) = RequestStage::from_packet(self, packet) {
+                return Some(stage);
+            }
+        }
+    }
+}
+
+async fn drive(
+    mut reader: AsyncReader,
+    mut buffer: FrameDecoder,
+    writer: Arc<tokio::sync::Mutex<AsyncWriter>>,
+    pending: Pending,
+    events: mpsc::UnboundedSender<Packets>,
+    session: usize,
+) {
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            packet = read_packet_async(&mut reader, &mut buffer) => {
+                let Ok(packet) = packet else { break };
+                log::trace!("From relay: {packet:?}");
+
+                let mut packet = Some(packet);
+                if let Some(id) = packet.as_ref().and_then(Packets::response_id) {
+                    let sender = pending.lock().unwrap().remove(&id);
+                    if let Some(sender) = sender {
+                        // if the receiver was dropped the packet becomes an event
+                        packet = sender.send(packet.take().unwrap()).err();
+                    }
+                }
+
+                if let Some(packet) = packet {
+                    let _ = events.send(packet);
+                }
+            }
+            _ = tick.tick() => {
+                let mut writer = writer.lock().await;
+                if write_packet_async(&mut *writer, &Packets::Tick { session }).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Wakes up every waiting request with `AsyncError::Closed`
+    pending.lock().unwrap().clear();
+}
+
+pub enum RequestStage {
+    NewRequest(NewRequest),
+    NewRequestResponse(NewRequestResponse),
+    NewRequestFinal(NewRequestFinal),
+    ConnectOn(ConnectOn),
+}
+
+impl RequestStage {
+    fn from_packet(connection: &AsyncConnection, packet: Packets) -> Option<Self> {
+        let connection = connection.clone();
+        Some(match packet {
+            Packets::NewRequest(pak) => Self::NewRequest(NewRequest {
+                connection,
+                from: pak.from,
+                secret: pak.secret,
+                verified: pak.verified,
+            }),
+            Packets::NewRequestResponse(pak) => Self::NewRequestResponse(NewRequestResponse {
+                connection,
+                from: pak.from,
+                accept: pak.accepted,
+                secret: pak.secret,
+                port_token: pak.port_token,
+            }),
+            Packets::NewRequestFinal(pak) => Self::NewRequestFinal(NewRequestFinal {
+                connection,
+                from: pak.from,
+                accept: pak.accepted,
+                port_token: pak.port_token,
+            }),
+            Packets::ConnectOn(pak) => Self::ConnectOn(ConnectOn {
+                connection,
+                adress: pak.adress,
+                to: pak.to,
+                port: pak.port,
+                time: pak.time,
+                relay: pak.relay,
+                nat: pak.nat,
+                punch: pak.punch,
+                predicted: pak.predicted,
+                candidates: pak.candidates,
+            }),
+            _ => return None,
+        })
+    }
+}
+
+pub struct NewRequest {
+    pub connection: AsyncConnection,
+    pub from: Adress,
+    pub secret: String,
+    /// The relay checked that `from` is held by its `Identity`
+    pub verified: bool,
+}
+
+impl NewRequest {
+    pub async fn accept(self, accept: bool) -> Result<NewRequestFinal, AsyncError> {
+        self.connection.request_response(&self.from, accept).await
+    }
+}
+
+pub struct NewRequestResponse {
+    pub connection: AsyncConnection,
+    pub from: Adress,
+    pub accept: bool,
+    pub secret: String,
+    /// Allows `add_socket` for this connection
+    pub port_token: Vec<u8>,
+}
+
+impl NewRequestResponse {
+    pub async fn add_socket(&self, socket: &Socket) -> response::RegisterResponse {
+        self.connection.add_socket(socket, &self.port_token).await
+    }
+
+    /// Use if the NAT is symmetric, see `TConnection::add_socket_with_probes`
+    pub async fn add_socket_with_probes(
+        &self,
+        socket: &Socket,
+        probes: usize,
+    ) -> response::RegisterResponse {
+        self.connection
+            .add_socket_with_probes(socket, &self.port_token, probes)
+            .await
+    }
+
+    /// Use for `ConnectOn::connect_ice`, see `TConnection::add_socket_with_candidates`
+    pub async fn add_socket_with_candidates(&self, socket: &Socket) -> response::RegisterResponse {
+        self.connection
+            .add_socket_with_candidates(socket, &self.port_token)
+            .await
+    }
+
+    /// `time_offset` should be in nanosecconds
+    pub async fn accept(
+        self,
+        accept: bool,
+        time_offset: Option<u128>,
+    ) -> Result<ConnectOn, AsyncError> {
+        self.connection
+            .request_final(&self.from, accept, time_offset)
+            .await
+    }
+}
+
+pub struct NewRequestFinal {
+    pub connection: AsyncConnection,
+    pub from: Adress,
+    pub accept: bool,
+    /// Allows `add_socket` for this connection
+    pub port_token: Vec<u8>,
+}
+
+impl NewRequestFinal {
+    pub async fn add_socket(&self, socket: &Socket) -> response::RegisterResponse {
+        self.connection.add_socket(socket, &self.port_token).await
+    }
+
+    /// Use if the NAT is symmetric, see `TConnection::add_socket_with_probes`
+    pub async fn add_socket_with_probes(
+        &self,
+        socket: &Socket,
+        probes: usize,
+    ) -> response::RegisterResponse {
+        self.connection
+            .add_socket_with_probes(socket, &self.port_token, probes)
+            .await
+    }
+
+    /// Use for `ConnectOn::connect_ice`, see `TConnection::add_socket_with_candidates`
+    pub async fn add_socket_with_candidates(&self, socket: &Socket) -> response::RegisterResponse {
+        self.connection
+            .add_socket_with_candidates(socket, &self.port_token)
+            .await
+    }
+}
+
+pub struct ConnectOn {
+    pub connection: AsyncConnection,
+    pub adress: Adress,
+    pub to: String,
+    pub port: u16,
+    pub time: u128,
+    /// Token for `connect_or_relay`, empty if the relay does not forward
+    pub relay: Vec<u8>,
+    /// The advertised `NatType` of the peer
+    pub nat: NatType,
+    /// False if the relay expects hole punching to fail, `connect_or_relay` then relays directly
+    pub punch: bool,
+    /// Ports of the symmetric NAT of the peer that `connect` also sends to
+    pub predicted: Vec<u16>,
+    /// Adresses of the peer for `connect_ice`, empty if the peer registered none
+    pub candidates: Vec<Candidate>,
+}
+
+impl std::fmt::Debug for ConnectOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectOn")
+            .field("adress", &self.adress)
+            .field("to", &self.to)
+            .field("port", &self.port)
+            .field("time", &self.time)
+            .field("nat", &self.nat)
+            .field("punch", &self.punch)
+            .field("predicted", &self.predicted)
+            .field("candidates", &self.candidates)
+            .finish()
+    }
+}
+
+impl ConnectOn {
+    /// timeout need to be bigger then resend
+    pub async fn connect(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<Conn, ConnectOnError> {
+        tokio::task::spawn_blocking(move || {
+            hole_punch(
+                &self.to,
+                &self.predicted,
+                self.port,
+                self.time,
+                timeout,
+                resend,
+                socket,
+            )
+        })
+        .await
+        .unwrap_or(Err(ConnectOnError::TaskFailed))
+    }
+
+    /// Same as `connect` but if hole punching fails the datagrams go through the relay
+    /// and `Conn::relayed` is true, the relay needs a `RelayQuota` for it
+    /// Both peers should call this, until both are bound the relay drops the datagrams
+    pub async fn connect_or_relay(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<Conn, ConnectOnError> {
+        let connection = self.connection.clone();
+        let token = self.relay.clone();
+
+        if self.punch || token.is_empty() {
+            match self.connect(timeout, resend, socket).await {
+                Err(ConnectOnError::StageOneFailed | ConnectOnError::StageTwoFailed) => {}
+                res => return res,
+            }
+        }
+        connection.relay_connect(&token, timeout, resend).await
+    }
+
+    /// Same as `response::ConnectOn::connect_ice`, the checks run on a blocking task
+    pub async fn connect_ice(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<Conn, ConnectOnError> {
+        if self.candidates.is_empty() {
+            return self.connect_or_relay(timeout, resend, socket).await;
+        }
+
+        let candidates = remote_candidates(&self.candidates, &self.to, &self.predicted);
+        let controlling = self.connection.info.public > self.adress;
+        let ice = Ice::new(controlling, &candidates, self.time, timeout, resend)?;
+        // the relay allocates the relayed port on request
+        let relay = self
+            .connection
+            .relay_connect(&self.relay, timeout, resend)
+            .await;
+        let (port, punch) = (self.port, self.punch);
+        tokio::task::spawn_blocking(move || run_ice(ice, port, socket, relay, punch))
+            .await
+            .unwrap_or(Err(ConnectOnError::TaskFailed))
+    }
+}
+
+/// Async version of `RelayClient`
+pub struct AsyncRelayClient {
+    pub connections: Vec<AsyncConnection>,
+    pub connection_errors: Vec<ConnectionError>,
+    pub info: ConnectionInfo,
+    pub events: tokio::sync::Mutex<mpsc::UnboundedReceiver<(usize, RequestStage)>>,
+    pub forwarders: Vec<Driver>,
+}
+
+impl AsyncRelayClient {
+    pub async fn new(info: ConnectionInfo, relays: Vec<String>) -> Result<Self, RelayClientError> {
+        let mut connection_errors = Vec::new();
+        use RelayClientError::*;
+        if relays.is_empty() {
+            return Err(NoRelays);
+        }
+
+        let mut connections = Vec::new();
+        for relay in relays {
+            match AsyncConnection::new(relay, info.clone()).await {
+                Ok(conn) => connections.push(conn),
+                Err(error) => connection_errors.push(error),
+            }
+        }
+
+        if connections.is_empty() {
+            log::error!("Errors: {:?}", connection_errors);
+            return Err(NoConnections);
+        }
+
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut forwarders = Vec::new();
+        for (index, conn) in connections.iter().enumerate() {
+            let conn = conn.clone();
+            let sender = sender.clone();
+            forwarders.push(Driver(tokio::spawn(async move {
+                while let Some(stage) = conn.next_event().await {
+                    if sender.send((index, stage)).is_err() {
+                        break;
+                    }
+                }
+            })));
+        }
+
+        Ok(Self {
+            connections,
+            connection_errors,
+            info,
+            events: tokio::sync::Mutex::new(events),
+            forwarders,
+        })
+    }
+
+    pub fn where_is_adress(&self, adress: &Adress) -> Vec<usize> {
+        let mut indexs = Vec::new();
+        for (index, conn) in self.connections.iter().enumerate() {
+            if conn.adresses.lock().unwrap().contains(adress) {
+                indexs.push(index);
+            }
+        }
+        indexs
+    }
+
+    /// Searches on every relay at the same time
+    pub async fn search(&self, search: Search) -> Vec<Adress> {
+        let mut tasks = Vec::new();
+        for conn in self.connections.iter() {
+            let conn = conn.clone();
+            let search = search.clone();
+            tasks.push(tokio::spawn(async move { conn.search(search).await }));
+        }
+
+        let mut res = Vec::new();
+        for task in tasks {
+            let Ok(Ok(v)) = task.await else { continue };
+            for adress in v.adresses {
+                if !res.contains(&adress) {
+                    res.push(adress)
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Waits for the next new request stage from any relay
+    pub async fn next_event(&self) -> Option<(usize, RequestStage)> {
+        self.events.lock().await.recv().await
+    }
+
+    pub fn get(&self, index: usize) -> Option<&AsyncConnection> {
+        self.connections.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::common::{
+        frame::write_packet,
+        packets::{SearchType, PROTOCOL_VERSION},
+    };
+
+    use super::*;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn info(public: Vec<u8>) -> ConnectionInfo {
+        ConnectionInfo {
+            client: String::from("test"),
+            name: String::from("test"),
+            public,
+            other: vec![],
+            privacy: false,
+            auth: Auth::None,
+            identity: None,
+            tls: None,
+        }
+    }
+
+    /// A relay that registers one client and then gives its stream to `then`
+    fn fake_relay(then: impl FnOnce(std::net::TcpStream, FrameDecoder) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let adress = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut buffer = FrameDecoder::default();
+
+            let Ok(Packets::Hello(_)) = buffer.recv_packet(&stream) else {
+                panic!("No hello");
+            };
+            let hello = HelloResponse::Accepted {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![],
+            };
+            write_packet(&stream, &Packets::HelloResponse(hello)).unwrap();
+
+            let Ok(Packets::Register(_)) = buffer.recv_packet(&stream) else {
+                panic!("No register");
+            };
+            let registered = RegisterResponse::Client {
+                accepted: true,
+                session: 1,
+            };
+            write_packet(&stream, &Packets::RegisterResponse(registered)).unwrap();
+
+            then(stream, buffer);
+        });
+        adress
+    }
+
+    fn search(name: &str) -> Search {
+        Search {
+            name: SearchType::Exact(String::from(name)),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn concurrent_calls_get_their_own_response() {
+        let relay = crate::client::testing::relay();
+        runtime().block_on(async {
+            let mut b = info(vec![2]);
+            b.name = String::from("b");
+            let _b = AsyncConnection::new(relay.adress.clone(), b).await.unwrap();
+            let mut a = info(vec![1]);
+            a.name = String::from("a");
+            let a = AsyncConnection::new(relay.adress.clone(), a).await.unwrap();
+
+            let (adress_a, adress_b) = (vec![1], vec![2]);
+            let (search_a, search_b, info_a, info_b) = tokio::join!(
+                a.search(search("a")),
+                a.search(search("b")),
+                a.info(&adress_a),
+                a.info(&adress_b),
+            );
+            assert_eq!(search_a.unwrap().adresses, vec![vec![1]]);
+            assert_eq!(search_b.unwrap().adresses, vec![vec![2]]);
+            assert_eq!(info_a.unwrap().name, "a");
+            assert_eq!(info_b.unwrap().name, "b");
+            assert!(a.pending.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn disconnect_ends_pending_calls() {
+        let relay = fake_relay(|stream, mut buffer| {
+            // closes the connection without answering
+            let _ = buffer.recv_packet(&stream);
+        });
+        runtime().block_on(async {
+            let connection = AsyncConnection::new(relay, info(vec![1])).await.unwrap();
+            let result = connection.search(search("a")).await;
+            assert!(matches!(result, Err(AsyncError::Closed)));
+            assert!(connection.pending.lock().unwrap().is_empty());
+
+            // and the calls after it
+            let result = connection.info(&vec![2]).await;
+            assert!(matches!(result, Err(AsyncError::Closed)));
+        });
+    }
+
+    #[test]
+    fn cancelled_call_is_forgotten() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let relay = fake_relay(move |stream, mut buffer| {
+            // answers only the second search
+            let mut searches = std::iter::from_fn(|| match buffer.recv_packet(&stream) {
+                Ok(Packets::Search(search)) => Some(Some(search)),
+                Ok(_) => Some(None),
+                Err(_) => None,
+            })
+            .flatten();
+            let _ = searches.next();
+            let search = searches.next().unwrap();
+            let response = Packets::SearchResponse(crate::common::packets::SearchResponse {
+                session: 1,
+                id: search.id,
+                adresses: vec![vec![2]],
+            });
+            write_packet(&stream, &response).unwrap();
+            let _ = receiver.recv();
+        });
+        runtime().block_on(async {
+            let connection = AsyncConnection::new(relay, info(vec![1])).await.unwrap();
+
+            let timeout = Duration::from_millis(50);
+            let result = tokio::time::timeout(timeout, connection.search(search("a"))).await;
+            assert!(result.is_err());
+            assert!(connection.pending.lock().unwrap().is_empty());
+
+            let found = connection.search(search("b")).await.unwrap();
+            assert_eq!(found.adresses, vec![vec![2]]);
+            assert!(connection.pending.lock().unwrap().is_empty());
+        });
+        let _ = sender.send(());
+    }
+}
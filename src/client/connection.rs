@@ -1,8 +1,9 @@
 use std::{
+    io,
     mem::MaybeUninit,
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
     sync::{Arc, LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use bytes_kman::TBytes;
@@ -10,20 +11,87 @@ use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 use crate::common::{
     adress::Adress,
+    frame::FrameDecoder,
+    identity::Identity,
     packets::{
-        InfoRequest, Packets, Register, RegisterResponse, Request, RequestFinal, RequestResponse,
-        Search,
+        Auth, Candidate, Error, ErrorRequest, Hello, HelloResponse, InfoRequest, NatType, Packets,
+        Register, RegisterResponse, RelayRequest, Request, RequestFinal, RequestResponse, Search,
+        MIN_PROTOCOL_VERSION,
     },
+    stun,
+    tls::{Tls, TlsError, TlsOptions},
 };
 
-use super::response::{self, NewRequestFinal, RequestStage, Response};
+use super::{
+    detect_nat,
+    ice::gather_candidates,
+    response::{self, CancelHandle, NewRequestFinal, RequestStage, Response, Signal},
+};
+
+/// The port of a relay when the relay string has none
+pub const DEFAULT_PORT: u16 = 2120;
+
+/// Resolves a relay string like `host`, `host:port`, `ip`, `[ip]` or `ip:port`
+/// `DEFAULT_PORT` is used when there is no port
+pub fn resolve_relay(relay: &str) -> Option<SocketAddr> {
+    if let Ok(adress) = relay.parse::<SocketAddr>() {
+        return Some(adress);
+    }
+
+    let ip = relay
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(relay);
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+
+    if let Some(adress) = relay.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+        return Some(adress);
+    }
+
+    (relay, DEFAULT_PORT)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut a| a.next())
+}
+
+/// The host part of a relay string, used as the TLS server name
+pub fn relay_host(relay: &str) -> String {
+    if let Ok(adress) = relay.parse::<SocketAddr>() {
+        return adress.ip().to_string();
+    }
+    if relay.parse::<IpAddr>().is_ok() {
+        return relay.to_string();
+    }
+
+    let host = match relay.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => relay,
+    };
+    host.trim_start_matches('[').trim_end_matches(']').to_string()
+}
+
+/// Creates a UDP socket with the same family as `relay`
+/// that can be used with `add_socket`
+pub fn udp_socket(relay: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(relay), Type::DGRAM, None)?;
+    let any = match relay {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    socket.bind(&SockAddr::from(any))?;
+    Ok(socket)
+}
 
 #[derive(Debug)]
 pub enum ConnectionError {
     InvalidIp,
     HostIsNotAlive,
     InvalidInfo,
-    InvalidAdress,
+    Refused(Error),
+    IncompatibleVersion { min_version: u16, max_version: u16 },
+    Tls(TlsError),
 }
 
 pub struct Connection {
@@ -34,6 +102,17 @@ pub struct Connection {
     pub last_packet: SystemTime,
     pub packets: Vec<Packets>,
     pub adresses: Vec<Adress>,
+    pub buffer: FrameDecoder,
+    pub last_id: usize,
+    /// Notified every time new packets are received
+    pub signal: Arc<Signal>,
+    /// Protocol version negotiated with the relay
+    pub version: u16,
+    /// Capabilities supported by both sides
+    pub capabilities: Vec<String>,
+    pub tls: Tls,
+    /// Set by `detect_nat` or `advertise_nat`
+    pub nat: NatType,
 }
 
 #[derive(Clone, Debug)]
@@ -43,13 +122,29 @@ pub struct ConnectionInfo {
     pub public: Vec<u8>,
     pub other: Vec<u8>,
     pub privacy: bool,
+    /// Token minted by the backend of the relay, `Auth::None` if the relay has no auth
+    pub auth: Auth,
+    /// If set `public` has to be `Identity::adress` and is proven to the relay
+    pub identity: Option<Identity>,
+    /// If set the control channel uses TLS, needs the `tls` feature
+    pub tls: Option<TlsOptions>,
+}
+
+impl ConnectionInfo {
+    /// Uses the adress of `identity` as `public`
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.public = identity.adress();
+        self.identity = Some(identity);
+        self
+    }
 }
 
 impl Connection {
     pub fn new(ip: impl Into<String>, info: ConnectionInfo) -> Result<Self, ConnectionError> {
-        let Ok(mut adress) = format!("{}:2120", ip.into())
-            .to_socket_addrs() else {return Err(ConnectionError::InvalidIp)};
-        let Some(adress) = adress.next() else{return Err(ConnectionError::InvalidIp)};
+        let ip = ip.into();
+        let Some(adress) = resolve_relay(&ip) else {
+            return Err(ConnectionError::InvalidIp);
+        };
         let address_sock = SockAddr::from(adress);
         let conn = Socket::new(
             Domain::for_address(adress),
@@ -63,6 +158,44 @@ impl Connection {
 
         let local_addr = conn.local_addr().unwrap().as_socket().unwrap().ip();
 
+        let tls = match Tls::connect(info.tls.as_ref(), &relay_host(&ip)) {
+            Ok(tls) => tls,
+            Err(error) => return Err(ConnectionError::Tls(error)),
+        };
+
+        let mut buffer = FrameDecoder::default();
+
+        let Ok(_) = tls.write_packet(&conn, &Packets::Hello(Hello::new())) else {
+            return Err(ConnectionError::HostIsNotAlive);
+        };
+
+        let Ok(packet) = tls.recv_packet(&conn, &mut buffer) else {
+            return Err(ConnectionError::InvalidInfo);
+        };
+        let (version, capabilities) = match packet {
+            Packets::HelloResponse(HelloResponse::Accepted {
+                version,
+                capabilities,
+            }) if version >= MIN_PROTOCOL_VERSION => (version, capabilities),
+            // a older relay accepts the newest version that it speaks
+            Packets::HelloResponse(HelloResponse::Accepted { version, .. }) => {
+                return Err(ConnectionError::IncompatibleVersion {
+                    min_version: version,
+                    max_version: version,
+                })
+            }
+            Packets::HelloResponse(HelloResponse::Rejected {
+                min_version,
+                max_version,
+            }) => {
+                return Err(ConnectionError::IncompatibleVersion {
+                    min_version,
+                    max_version,
+                })
+            }
+            _ => return Err(ConnectionError::InvalidInfo),
+        };
+
         let pak = Packets::Register(Register::Client {
             client: info.client.clone(),
             public: info.public.clone(),
@@ -70,36 +203,44 @@ impl Connection {
             other: info.other.clone(),
             privacy: info.privacy,
             private_adress: local_addr.to_string(),
+            auth: info.auth.clone(),
+            identity: info.identity.is_some(),
         });
 
-        let mut bytes = pak.to_bytes();
-        bytes.reverse();
-
-        let Ok(_) = conn.send(&bytes) else {
+        let Ok(_) = tls.write_packet(&conn, &pak) else {
             return Err(ConnectionError::InvalidInfo);
         };
 
-        let mut buffer = [MaybeUninit::new(0); 1024];
-
-        let Ok(len) = conn.recv(&mut buffer)else{
+        let Ok(mut packet) = tls.recv_packet(&conn, &mut buffer) else{
             return Err(ConnectionError::InvalidInfo);
         };
-
-        let buffer: &[u8] = unsafe { std::mem::transmute(&buffer[0..len]) };
-        let mut buffer = buffer.to_owned();
-        let Some(packet) = Packets::from_bytes(&mut buffer) else{return Err(ConnectionError::InvalidInfo)};
-        let Packets::RegisterResponse(res) = packet else {return Err(ConnectionError::InvalidInfo)};
-
-        let RegisterResponse::Client{ accepted, session } = res else {
-            return Err(ConnectionError::InvalidAdress);
-        };
-        if !accepted {
-            return Err(ConnectionError::InvalidAdress);
+        if let Packets::RegisterResponse(RegisterResponse::Challenge { nonce }) = &packet {
+            let Some(identity) = &info.identity else {
+                return Err(ConnectionError::InvalidInfo);
+            };
+            let pak = Packets::Register(Register::Proof {
+                signature: identity.sign_challenge(nonce),
+            });
+            let Ok(_) = tls.write_packet(&conn, &pak) else {
+                return Err(ConnectionError::InvalidInfo);
+            };
+            let Ok(response) = tls.recv_packet(&conn, &mut buffer) else {
+                return Err(ConnectionError::InvalidInfo);
+            };
+            packet = response;
         }
+        let session = match packet {
+            Packets::RegisterResponse(RegisterResponse::Client {
+                accepted: true,
+                session,
+            }) => session,
+            Packets::Error(error) => return Err(ConnectionError::Refused(error)),
+            _ => return Err(ConnectionError::InvalidInfo),
+        };
 
         conn.set_nonblocking(true).unwrap();
-        let _ = conn.set_recv_buffer_size(1024);
-        let _ = conn.set_send_buffer_size(1024);
+        // without a poller the waiters poll the connection
+        let signal = Signal::with_socket(&conn).unwrap_or_default();
 
         Ok(Self {
             session,
@@ -109,15 +250,31 @@ impl Connection {
             packets: Vec::new(),
             adresses: Vec::new(),
             adress,
+            buffer,
+            last_id: 0,
+            signal: Arc::new(signal),
+            version,
+            capabilities,
+            tls,
+            nat: NatType::Unknown,
         })
     }
 
     pub fn step(&mut self) {
-        if let Some(packet) = self.recv() {
+        // what the socket did not take when it was sent
+        let _ = self.tls.flush(&self.conn);
+
+        let mut received = false;
+        while let Some(packet) = self.recv() {
             if let Packets::SearchResponse(pak) = &packet {
                 self.adresses = pak.adresses.clone()
             };
-            self.packets.push(packet)
+            self.packets.push(packet);
+            received = true;
+        }
+
+        if received {
+            self.signal.notify();
         }
 
         if self.last_packet.elapsed().unwrap() < Duration::from_secs(2) {
@@ -127,64 +284,79 @@ impl Connection {
         let pak = Packets::Tick {
             session: self.session,
         };
-        let mut bytes = pak.to_bytes();
-        bytes.reverse();
-        let _ = self.conn.send(&bytes);
+        let _ = self.tls.write_packet(&self.conn, &pak);
         self.last_packet = SystemTime::now();
     }
 
-    pub fn send(&mut self, packet: Packets) {
+    /// Returns a new request id, never 0
+    pub fn next_id(&mut self) -> usize {
+        self.last_id = self.last_id.wrapping_add(1);
+        if self.last_id == 0 {
+            self.last_id = 1;
+        }
+        self.last_id
+    }
+
+    /// Returns the sent packet with the session and request id set
+    pub fn send(&mut self, packet: Packets) -> Packets {
         let mut packet = packet;
 
-        // Set session for packages
-        match &mut packet {
-            Packets::UnRegister(pak) => pak.session = self.session,
-            Packets::Search(pak) => pak.session = self.session,
-            Packets::InfoRequest(pak) => pak.session = self.session,
-            Packets::Request(pak) => pak.session = self.session,
-            Packets::RequestResponse(pak) => pak.session = self.session,
-            Packets::RequestFinal(pak) => pak.session = self.session,
-            _ => {}
+        packet.set_session(self.session);
+
+        // Set request id for packages that will have a response
+        if let Some(id) = packet.request_id_mut() {
+            if *id == 0 {
+                *id = self.next_id();
+            }
         }
 
-        let mut bytes = packet.to_bytes();
-        bytes.reverse();
-        let _ = self.conn.send(&bytes);
-        self.last_packet = SystemTime::now()
+        let _ = self.tls.write_packet(&self.conn, &packet);
+        self.last_packet = SystemTime::now();
+        packet
     }
 
-    pub fn recv(&self) -> Option<Packets> {
-        let mut buffer = [MaybeUninit::new(0); 1024];
-        if let Ok(len) = self.conn.recv(&mut buffer) {
-            let buffer: &[u8] = unsafe { std::mem::transmute(&buffer[0..len]) };
-            let mut buffer = buffer.to_owned();
-            if let Some(packet) = Packets::from_bytes(&mut buffer) {
-                return Some(packet);
-            }
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Returns the next packet from the relay if a hole one was received
+    pub fn recv(&mut self) -> Option<Packets> {
+        if let Ok(Some(packet)) = self.buffer.next_packet() {
+            return Some(packet);
+        }
+
+        let Ok(len) = self.tls.fill(&self.conn, &mut self.buffer) else {return None};
+        if len == 0 {
+            return None;
         }
-        None
+
+        self.buffer.next_packet().ok().flatten()
     }
 }
 
 pub trait TConnection {
     fn step(&self);
 
-    fn read(&self) -> LockResult<RwLockReadGuard<Connection>>;
-    fn write(&self) -> LockResult<RwLockWriteGuard<Connection>>;
+    fn read(&self) -> LockResult<RwLockReadGuard<'_, Connection>>;
+    fn write(&self) -> LockResult<RwLockWriteGuard<'_, Connection>>;
 
-    fn search(&self, search: Search) -> Response<Box<dyn TConnection>, response::SearchResponse>;
-    fn info(&self, adress: &Adress) -> Response<Box<dyn TConnection>, Option<ConnectionInfo>>;
+    fn search(
+        &self,
+        search: Search,
+    ) -> Response<Box<dyn TConnection>, Result<response::SearchResponse, Error>>;
+    fn info(&self, adress: &Adress)
+        -> Response<Box<dyn TConnection>, Result<ConnectionInfo, Error>>;
 
     fn request(
         &self,
         adress: &Adress,
         secret: String,
-    ) -> Response<Box<dyn TConnection>, response::NewRequestResponse>;
+    ) -> Response<Box<dyn TConnection>, Result<response::NewRequestResponse, Error>>;
     fn request_response(
         &self,
         adress: &Adress,
         accept: bool,
-    ) -> Response<Box<dyn TConnection>, response::NewRequestFinal>;
+    ) -> Response<Box<dyn TConnection>, Result<response::NewRequestFinal, Error>>;
 
     /// `time_offset` should be in nanosecconds
     fn request_final(
@@ -192,8 +364,28 @@ pub trait TConnection {
         adress: &Adress,
         accept: bool,
         time_offset: Option<u128>,
-    ) -> Response<Box<dyn TConnection>, response::ConnectOn>;
-    fn add_socket(&self, socket: &Socket) -> response::RegisterResponse;
+    ) -> Response<Box<dyn TConnection>, Result<response::ConnectOn, Error>>;
+    /// `token` is the `port_token` of the `NewRequestResponse` or `NewRequestFinal`
+    fn add_socket(&self, socket: &Socket, token: &[u8]) -> response::RegisterResponse;
+    /// Same as `add_socket` but first registers `probes` new sockets
+    /// so the relay can predict the ports if the NAT is symmetric
+    fn add_socket_with_probes(
+        &self,
+        socket: &Socket,
+        token: &[u8],
+        probes: usize,
+    ) -> response::RegisterResponse;
+    /// Same as `add_socket` but also sends the host candidates of `socket`
+    /// so the peer can use `ConnectOn::connect_ice`
+    fn add_socket_with_candidates(
+        &self,
+        socket: &Socket,
+        token: &[u8],
+    ) -> response::RegisterResponse;
+    /// Asks for the relayed port, `token` is the `relay` of the `ConnectOn`
+    fn relay(&self, token: &[u8]) -> Response<Box<dyn TConnection>, Result<u16, Error>>;
+    /// Same as `Connection::detect_nat` without locking the connection while detecting
+    fn detect_nat(&self) -> NatType;
 
     fn adress(&self) -> Adress;
 
@@ -206,38 +398,45 @@ impl TConnection for Arc<RwLock<Connection>> {
         self.write().unwrap().step();
     }
 
-    fn read(&self) -> LockResult<RwLockReadGuard<Connection>> {
+    fn read(&self) -> LockResult<RwLockReadGuard<'_, Connection>> {
         RwLock::read(self)
     }
 
-    fn write(&self) -> LockResult<RwLockWriteGuard<Connection>> {
+    fn write(&self) -> LockResult<RwLockWriteGuard<'_, Connection>> {
         RwLock::write(self)
     }
 
-    fn search(&self, search: Search) -> Response<Box<dyn TConnection>, response::SearchResponse> {
-        let pak = Packets::Search(search);
-        self.write().unwrap().send(pak.clone());
+    fn search(
+        &self,
+        search: Search,
+    ) -> Response<Box<dyn TConnection>, Result<response::SearchResponse, Error>> {
+        let pak = self.write().unwrap().send(Packets::Search(search));
 
         Response {
             connection: Box::new(self.clone()),
             packets: pak,
             fn_has: search_fn_has,
             fn_get: search_fn_get,
+            cancel: CancelHandle::new(self.read().unwrap().signal.clone()),
         }
     }
 
-    fn info(&self, adress: &Adress) -> Response<Box<dyn TConnection>, Option<ConnectionInfo>> {
-        let pak = Packets::InfoRequest(InfoRequest {
+    fn info(
+        &self,
+        adress: &Adress,
+    ) -> Response<Box<dyn TConnection>, Result<ConnectionInfo, Error>> {
+        let pak = self.write().unwrap().send(Packets::InfoRequest(InfoRequest {
             adress: adress.clone(),
             session: 0,
-        });
-        self.write().unwrap().send(pak.clone());
+            id: 0,
+        }));
 
         Response {
             connection: Box::new(self.clone()),
             packets: pak,
             fn_has: info_fn_has,
             fn_get: info_fn_get,
+            cancel: CancelHandle::new(self.read().unwrap().signal.clone()),
         }
     }
 
@@ -245,19 +444,20 @@ impl TConnection for Arc<RwLock<Connection>> {
         &self,
         adress: &Adress,
         secret: String,
-    ) -> Response<Box<dyn TConnection>, response::NewRequestResponse> {
-        let pak = Packets::Request(Request {
+    ) -> Response<Box<dyn TConnection>, Result<response::NewRequestResponse, Error>> {
+        let pak = self.write().unwrap().send(Packets::Request(Request {
             session: 0,
+            id: 0,
             to: adress.clone(),
             secret,
-        });
-        self.write().unwrap().send(pak.clone());
+        }));
 
         Response {
             connection: Box::new(self.clone()),
             packets: pak,
             fn_has: request_fn_has,
             fn_get: request_fn_get,
+            cancel: CancelHandle::new(self.read().unwrap().signal.clone()),
         }
     }
 
@@ -265,20 +465,24 @@ impl TConnection for Arc<RwLock<Connection>> {
         &self,
         adress: &Adress,
         accept: bool,
-    ) -> Response<Box<dyn TConnection>, NewRequestFinal> {
-        let pak = Packets::RequestResponse(RequestResponse {
-            session: 0,
-            to: adress.clone(),
-            accepted: accept,
-            secret: String::new(),
-        });
-        self.write().unwrap().send(pak.clone());
+    ) -> Response<Box<dyn TConnection>, Result<NewRequestFinal, Error>> {
+        let pak = self
+            .write()
+            .unwrap()
+            .send(Packets::RequestResponse(RequestResponse {
+                session: 0,
+                id: 0,
+                to: adress.clone(),
+                accepted: accept,
+                secret: String::new(),
+            }));
 
         Response {
             connection: Box::new(self.clone()),
             packets: pak,
             fn_has: request_response_fn_has,
             fn_get: request_response_fn_get,
+            cancel: CancelHandle::new(self.read().unwrap().signal.clone()),
         }
     }
 
@@ -287,53 +491,81 @@ impl TConnection for Arc<RwLock<Connection>> {
         adress: &Adress,
         accept: bool,
         time_offset: Option<u128>,
-    ) -> Response<Box<dyn TConnection>, response::ConnectOn> {
+    ) -> Response<Box<dyn TConnection>, Result<response::ConnectOn, Error>> {
         let time_offset = match time_offset {
             Some(s) => s,
             None => Duration::from_secs(1).as_nanos(),
         };
 
-        let pak = Packets::RequestFinal(RequestFinal {
+        let pak = self.write().unwrap().send(Packets::RequestFinal(RequestFinal {
             session: 0,
+            id: 0,
             to: adress.clone(),
             accepted: accept,
             time_offset,
-        });
-        self.write().unwrap().send(pak.clone());
+        }));
         Response {
             connection: self.c(),
             packets: pak,
             fn_has: request_final_fn_has,
             fn_get: request_final_fn_get,
+            cancel: CancelHandle::new(self.read().unwrap().signal.clone()),
         }
     }
 
-    fn add_socket(&self, socket: &Socket) -> response::RegisterResponse {
+    fn add_socket(&self, socket: &Socket, token: &[u8]) -> response::RegisterResponse {
         let session = self.read().unwrap().session;
-        let pak = Packets::Register(Register::Port { session });
-        let mut bytes = pak.to_bytes();
-        bytes.reverse();
-        let addr = self.read().unwrap().adress.clone();
-        socket.send_to(&bytes, &addr.into());
-
-        socket.set_nonblocking(false);
-        let mut buffer = [MaybeUninit::uninit(); 4096];
-        if let Ok(len) = socket.recv(&mut buffer) {
-            let buffer = buffer[0..len].to_vec();
-            let mut buffer = unsafe { std::mem::transmute(buffer) };
-            let Some(packet) = Packets::from_bytes(&mut buffer)else{return response::RegisterResponse::Error};
-            if let Packets::RegisterResponse(res) = packet {
-                match res {
-                    RegisterResponse::Client { accepted, session } => {
-                        return response::RegisterResponse::Error
-                    }
-                    RegisterResponse::Port { port } => {
-                        return response::RegisterResponse::Success { port }
-                    }
-                }
-            }
+        let addr = self.read().unwrap().adress;
+        register_socket(session, token, addr, socket)
+    }
+
+    fn add_socket_with_probes(
+        &self,
+        socket: &Socket,
+        token: &[u8],
+        probes: usize,
+    ) -> response::RegisterResponse {
+        let session = self.read().unwrap().session;
+        let addr = self.read().unwrap().adress;
+        let _probes = register_probes(session, token, addr, probes);
+        register_socket(session, token, addr, socket)
+    }
+
+    fn add_socket_with_candidates(
+        &self,
+        socket: &Socket,
+        token: &[u8],
+    ) -> response::RegisterResponse {
+        let session = self.read().unwrap().session;
+        let addr = self.read().unwrap().adress;
+        let candidates = gather_candidates(socket);
+        register_candidates(session, token, addr, socket, &candidates)
+    }
+
+    fn relay(&self, token: &[u8]) -> Response<Box<dyn TConnection>, Result<u16, Error>> {
+        let pak = self
+            .write()
+            .unwrap()
+            .send(Packets::RelayRequest(RelayRequest {
+                session: 0,
+                id: 0,
+                token: token.to_vec(),
+            }));
+
+        Response {
+            connection: Box::new(self.clone()),
+            packets: pak,
+            fn_has: relay_fn_has,
+            fn_get: relay_fn_get,
+            cancel: CancelHandle::new(self.read().unwrap().signal.clone()),
         }
-        response::RegisterResponse::Error
+    }
+
+    fn detect_nat(&self) -> NatType {
+        let relay = self.read().unwrap().adress;
+        let nat = detect_nat(relay);
+        self.write().unwrap().advertise_nat(nat);
+        nat
     }
 
     fn adress(&self) -> Adress {
@@ -351,6 +583,7 @@ impl TConnection for Arc<RwLock<Connection>> {
                             connection: Box::new(self.clone()),
                             from: pak.from.clone(),
                             secret: pak.secret.clone(),
+                            verified: pak.verified,
                         }));
                         false
                     }
@@ -361,6 +594,7 @@ impl TConnection for Arc<RwLock<Connection>> {
                                 from: pak.from.clone(),
                                 accept: pak.accepted,
                                 secret: pak.secret.clone(),
+                                port_token: pak.port_token.clone(),
                             },
                         ));
                         false
@@ -370,6 +604,7 @@ impl TConnection for Arc<RwLock<Connection>> {
                             connection: Box::new(self.clone()),
                             from: pak.from.clone(),
                             accept: pak.accepted,
+                            port_token: pak.port_token.clone(),
                         }));
                         false
                     }
@@ -380,6 +615,11 @@ impl TConnection for Arc<RwLock<Connection>> {
                             to: pak.to.clone(),
                             port: pak.port,
                             time: pak.time,
+                            relay: pak.relay.clone(),
+                            nat: pak.nat,
+                            punch: pak.punch,
+                            predicted: pak.predicted.clone(),
+                            candidates: pak.candidates.clone(),
                         }));
                         false
                     }
@@ -400,24 +640,245 @@ impl TConnection for Arc<RwLock<Connection>> {
 
 unsafe impl Send for Connection {}
 
+/// Registers the udp `socket` on the relay at `adress`, blocks until the relay answers
+pub fn register_socket(
+    session: usize,
+    token: &[u8],
+    adress: SocketAddr,
+    socket: &Socket,
+) -> response::RegisterResponse {
+    let pak = Packets::Register(Register::Port {
+        session,
+        token: token.to_vec(),
+    });
+    register_udp(pak, adress, socket)
+}
+
+/// Registers `count` new udp sockets with `Register::Probe` so the relay can predict
+/// the ports of a symmetric NAT, should be called right before `register_socket`
+/// The returned sockets should live until the real socket is registered
+/// so the os does not give their ports to it
+pub fn register_probes(
+    session: usize,
+    token: &[u8],
+    adress: SocketAddr,
+    count: usize,
+) -> Vec<Socket> {
+    let mut probes = Vec::new();
+    for _ in 0..count {
+        let Ok(socket) = udp_socket(adress) else {
+            continue;
+        };
+        // a lost probe should not block the connection
+        let _ = socket.set_read_timeout(Some(Duration::from_secs(1)));
+        let pak = Packets::Register(Register::Probe {
+            session,
+            token: token.to_vec(),
+        });
+        if let response::RegisterResponse::Success { .. } = register_udp(pak, adress, &socket) {
+            probes.push(socket);
+        }
+    }
+    probes
+}
+
+/// Same as `register_socket` but sends the `candidates` of `socket` with it,
+/// the relay adds the adress that it sees as a server reflexive candidate
+pub fn register_candidates(
+    session: usize,
+    token: &[u8],
+    adress: SocketAddr,
+    socket: &Socket,
+    candidates: &[Candidate],
+) -> response::RegisterResponse {
+    let pak = Packets::Register(Register::Candidates {
+        session,
+        token: token.to_vec(),
+        candidates: candidates.to_vec(),
+    });
+    register_udp(pak, adress, socket)
+}
+
+fn register_udp(pak: Packets, adress: SocketAddr, socket: &Socket) -> response::RegisterResponse {
+    let mut bytes = pak.to_bytes();
+    bytes.reverse();
+    let _ = socket.send_to(&bytes, &adress.into());
+
+    let _ = socket.set_nonblocking(false);
+    let mut buffer = [MaybeUninit::uninit(); 4096];
+    if let Ok(len) = socket.recv(&mut buffer) {
+        let buffer = buffer[0..len].to_vec();
+        let mut buffer = unsafe { std::mem::transmute::<Vec<MaybeUninit<u8>>, Vec<u8>>(buffer) };
+        let Some(packet) = Packets::from_bytes(&mut buffer)else{return response::RegisterResponse::Error};
+        match packet {
+            Packets::RegisterResponse(RegisterResponse::Port { port }) => {
+                return response::RegisterResponse::Success { port }
+            }
+            Packets::Error(error) => return response::RegisterResponse::Refused(error),
+            _ => {}
+        }
+    }
+    response::RegisterResponse::Error
+}
+
+/// Learns the reflexive adress of the udp `socket` from the STUN server at `server`
+/// works with relays and with any RFC 5389 server, blocks until `timeout`
+pub fn stun_probe(
+    socket: &Socket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> io::Result<SocketAddr> {
+    stun_binding(socket, server, 0, timeout).map(|(mapped, _)| mapped)
+}
+
+/// Sends a Binding request with the `CHANGE-REQUEST` flags `change`
+/// Returns the reflexive adress and the `OTHER-ADDRESS` of the server
+pub(crate) fn stun_binding(
+    socket: &Socket,
+    server: SocketAddr,
+    change: u8,
+    timeout: Duration,
+) -> io::Result<(SocketAddr, Option<SocketAddr>)> {
+    socket.set_nonblocking(false)?;
+    let result = stun_transaction(socket, server, change, timeout);
+    socket.set_read_timeout(None)?;
+    result
+}
+
+fn stun_transaction(
+    socket: &Socket,
+    server: SocketAddr,
+    change: u8,
+    timeout: Duration,
+) -> io::Result<(SocketAddr, Option<SocketAddr>)> {
+    let transaction = stun::new_transaction();
+    let request = if change == 0 {
+        stun::binding_request(&transaction)
+    } else {
+        stun::change_request(&transaction, change)
+    };
+    let deadline = Instant::now() + timeout;
+
+    // the retransmission timeout of RFC 5389, doubled on every resend
+    let mut resend = Duration::from_millis(500);
+    let mut buffer = [MaybeUninit::uninit(); 1024];
+    while Instant::now() < deadline {
+        socket.send_to(&request, &server.into())?;
+        let resend_at = (Instant::now() + resend).min(deadline);
+        resend *= 2;
+
+        while let Some(wait) = resend_at.checked_duration_since(Instant::now()) {
+            if wait.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(wait))?;
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                            | io::ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue
+                }
+                Err(error) => return Err(error),
+            };
+            let datagram: &[u8] = unsafe { std::mem::transmute(&buffer[0..len]) };
+
+            let Some(response) = stun::Message::parse(datagram) else {
+                continue;
+            };
+            if response.transaction != transaction {
+                continue;
+            }
+            if response.kind != stun::BINDING_SUCCESS {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "STUN Binding was refused",
+                ));
+            }
+            let Some(mapped) = response.mapped_adress() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "STUN response has no adress",
+                ));
+            };
+            return Ok((mapped, response.other_adress()));
+        }
+    }
+
+    Err(io::Error::from(io::ErrorKind::TimedOut))
+}
+
+// Errors
+
+fn error_matches(error: &Error, request: ErrorRequest, id: usize) -> bool {
+    error.request == request && error.id == id
+}
+
+fn has_error(conn: &Box<dyn TConnection>, request: ErrorRequest, id: usize) -> bool {
+    conn.read().unwrap().packets.iter().any(|pak| {
+        if let Packets::Error(error) = pak {
+            error_matches(error, request, id)
+        } else {
+            false
+        }
+    })
+}
+
+fn take_error(
+    conn: &Box<dyn TConnection>,
+    request: ErrorRequest,
+    id: usize,
+) -> Option<Error> {
+    let mut res = None;
+
+    conn.write().unwrap().packets.retain(|pak| {
+        if let Packets::Error(error) = pak {
+            if res.is_none() && error_matches(error, request, id) {
+                res = Some(error.clone());
+                return false;
+            }
+        }
+        true
+    });
+
+    res
+}
+
+// End Errors
+//
 // Search
 
-fn search_fn_has(conn: &Box<dyn TConnection>, _: &Packets) -> bool {
+fn search_fn_has(conn: &Box<dyn TConnection>, packet: &Packets) -> bool {
     conn.step();
-    for pak in conn.read().unwrap().packets.iter() {
-        if let Packets::SearchResponse(_) = pak {
-            return true;
+    if let Packets::Search(packet) = packet {
+        for pak in conn.read().unwrap().packets.iter() {
+            if let Packets::SearchResponse(pak) = pak {
+                if pak.id == packet.id {
+                    return true;
+                }
+            }
         }
+        return has_error(conn, ErrorRequest::Search, packet.id);
     }
     false
 }
 
-fn search_fn_get(conn: Box<dyn TConnection>, _: Packets) -> response::SearchResponse {
+fn search_fn_get(
+    conn: &Box<dyn TConnection>,
+    packet: &Packets,
+) -> Option<Result<response::SearchResponse, Error>> {
     let mut res = None;
+    let Packets::Search(packet) = packet else { return None };
 
     conn.write().unwrap().packets.retain(|pak| {
         if let Packets::SearchResponse(pak) = pak {
-            if res.is_none() {
+            if pak.id == packet.id && res.is_none() {
                 res = Some(response::SearchResponse {
                     adresses: pak.adresses.clone(),
                 });
@@ -428,9 +889,9 @@ fn search_fn_get(conn: Box<dyn TConnection>, _: Packets) -> response::SearchResp
     });
 
     if let Some(res) = res {
-        res
+        Some(Ok(res))
     } else {
-        panic!()
+        take_error(conn, ErrorRequest::Search, packet.id).map(Err)
     }
 }
 
@@ -443,43 +904,46 @@ fn info_fn_has(conn: &Box<dyn TConnection>, packet: &Packets) -> bool {
     if let Packets::InfoRequest(packet) = packet {
         for pak in conn.read().unwrap().packets.iter() {
             if let Packets::Info(pak) = pak {
-                if pak.adress == packet.adress {
+                if pak.id == packet.id {
                     return true;
                 }
             }
         }
+        return has_error(conn, ErrorRequest::Info, packet.id);
     }
     false
 }
 
-fn info_fn_get(conn: Box<dyn TConnection>, packet: Packets) -> Option<ConnectionInfo> {
+fn info_fn_get(
+    conn: &Box<dyn TConnection>,
+    packet: &Packets,
+) -> Option<Result<ConnectionInfo, Error>> {
     let mut res = None;
-    if let Packets::InfoRequest(packet) = packet {
-        conn.write().unwrap().packets.retain(|pak| {
-            if let Packets::Info(pak) = pak {
-                if pak.adress == packet.adress && res.is_none() {
-                    if pak.has {
-                        res = Some(Some(ConnectionInfo {
-                            client: pak.client.clone(),
-                            name: pak.client.clone(),
-                            public: packet.adress.clone(),
-                            other: pak.other.clone(),
-                            privacy: false,
-                        }));
-                    } else {
-                        res = Some(None)
-                    }
-                    return false;
-                }
+    let Packets::InfoRequest(packet) = packet else { return None };
+
+    conn.write().unwrap().packets.retain(|pak| {
+        if let Packets::Info(pak) = pak {
+            if pak.id == packet.id && res.is_none() {
+                res = Some(ConnectionInfo {
+                    client: pak.client.clone(),
+                    name: pak.name.clone(),
+                    public: packet.adress.clone(),
+                    other: pak.other.clone(),
+                    privacy: false,
+                    auth: Auth::None,
+                    identity: None,
+                    tls: None,
+                });
+                return false;
             }
-            true
-        })
-    }
+        }
+        true
+    });
 
     if let Some(res) = res {
-        res
+        Some(Ok(res))
     } else {
-        panic!()
+        take_error(conn, ErrorRequest::Info, packet.id).map(Err)
     }
 }
 
@@ -492,38 +956,43 @@ fn request_fn_has(conn: &Box<dyn TConnection>, packet: &Packets) -> bool {
     if let Packets::Request(packet) = packet {
         for pak in conn.read().unwrap().packets.iter() {
             if let Packets::NewRequestResponse(pak) = pak {
-                if pak.from == packet.to {
+                if pak.id == packet.id {
                     return true;
                 }
             }
         }
+        return has_error(conn, ErrorRequest::Request, packet.id);
     }
     false
 }
 
-fn request_fn_get(conn: Box<dyn TConnection>, packet: Packets) -> response::NewRequestResponse {
+fn request_fn_get(
+    conn: &Box<dyn TConnection>,
+    packet: &Packets,
+) -> Option<Result<response::NewRequestResponse, Error>> {
     let mut res = None;
-    if let Packets::Request(packet) = packet {
-        conn.write().unwrap().packets.retain(|pak| {
-            if let Packets::NewRequestResponse(pak) = pak {
-                if pak.from == packet.to && res.is_none() {
-                    res = Some(response::NewRequestResponse {
-                        connection: conn.c(),
-                        from: pak.from.clone(),
-                        accept: pak.accepted,
-                        secret: pak.secret.clone(),
-                    });
-                    return false;
-                }
+    let Packets::Request(packet) = packet else { return None };
+
+    conn.write().unwrap().packets.retain(|pak| {
+        if let Packets::NewRequestResponse(pak) = pak {
+            if pak.id == packet.id && res.is_none() {
+                res = Some(response::NewRequestResponse {
+                    connection: conn.c(),
+                    from: pak.from.clone(),
+                    accept: pak.accepted,
+                    secret: pak.secret.clone(),
+                    port_token: pak.port_token.clone(),
+                });
+                return false;
             }
-            true
-        })
-    }
+        }
+        true
+    });
 
     if let Some(res) = res {
-        res
+        Some(Ok(res))
     } else {
-        panic!()
+        take_error(conn, ErrorRequest::Request, packet.id).map(Err)
     }
 }
 
@@ -533,43 +1002,45 @@ fn request_fn_get(conn: Box<dyn TConnection>, packet: Packets) -> response::NewR
 
 fn request_response_fn_has(conn: &Box<dyn TConnection>, packet: &Packets) -> bool {
     conn.step();
-    if let Packets::Request(packet) = packet {
+    if let Packets::RequestResponse(packet) = packet {
         for pak in conn.read().unwrap().packets.iter() {
             if let Packets::NewRequestFinal(pak) = pak {
-                if pak.from == packet.to {
+                if pak.id == packet.id {
                     return true;
                 }
             }
         }
+        return has_error(conn, ErrorRequest::RequestResponse, packet.id);
     }
     false
 }
 
 fn request_response_fn_get(
-    conn: Box<dyn TConnection>,
-    packet: Packets,
-) -> response::NewRequestFinal {
+    conn: &Box<dyn TConnection>,
+    packet: &Packets,
+) -> Option<Result<response::NewRequestFinal, Error>> {
     let mut res = None;
-    if let Packets::Request(packet) = packet {
-        conn.write().unwrap().packets.retain(|pak| {
-            if let Packets::NewRequestFinal(pak) = pak {
-                if pak.from == packet.to && res.is_none() {
-                    res = Some(response::NewRequestFinal {
-                        connection: conn.c(),
-                        from: pak.from.clone(),
-                        accept: pak.accepted,
-                    });
-                    return false;
-                }
+    let Packets::RequestResponse(packet) = packet else { return None };
+
+    conn.write().unwrap().packets.retain(|pak| {
+        if let Packets::NewRequestFinal(pak) = pak {
+            if pak.id == packet.id && res.is_none() {
+                res = Some(response::NewRequestFinal {
+                    connection: conn.c(),
+                    from: pak.from.clone(),
+                    accept: pak.accepted,
+                    port_token: pak.port_token.clone(),
+                });
+                return false;
             }
-            true
-        })
-    }
+        }
+        true
+    });
 
     if let Some(res) = res {
-        res
+        Some(Ok(res))
     } else {
-        panic!()
+        take_error(conn, ErrorRequest::RequestResponse, packet.id).map(Err)
     }
 }
 
@@ -582,38 +1053,196 @@ fn request_final_fn_has(conn: &Box<dyn TConnection>, packet: &Packets) -> bool {
     if let Packets::RequestFinal(packet) = packet {
         for pak in conn.read().unwrap().packets.iter() {
             if let Packets::ConnectOn(pak) = pak {
-                if pak.adress == packet.to {
+                if pak.id == packet.id {
                     return true;
                 }
             }
         }
+        return has_error(conn, ErrorRequest::RequestFinal, packet.id);
     }
     false
 }
 
-fn request_final_fn_get(conn: Box<dyn TConnection>, packet: Packets) -> response::ConnectOn {
+fn request_final_fn_get(
+    conn: &Box<dyn TConnection>,
+    packet: &Packets,
+) -> Option<Result<response::ConnectOn, Error>> {
     let mut res = None;
-    if let Packets::RequestFinal(packet) = packet {
-        conn.write().unwrap().packets.retain(|pak| {
-            if let Packets::ConnectOn(pak) = pak {
-                if pak.adress == packet.to && res.is_none() {
-                    res = Some(response::ConnectOn {
-                        connection: conn.c(),
-                        adress: pak.adress.clone(),
-                        to: pak.to.clone(),
-                        port: pak.port,
-                        time: pak.time,
-                    });
-                    return false;
+    let Packets::RequestFinal(packet) = packet else { return None };
+
+    conn.write().unwrap().packets.retain(|pak| {
+        if let Packets::ConnectOn(pak) = pak {
+            if pak.id == packet.id && res.is_none() {
+                res = Some(response::ConnectOn {
+                    connection: conn.c(),
+                    adress: pak.adress.clone(),
+                    to: pak.to.clone(),
+                    port: pak.port,
+                    time: pak.time,
+                    relay: pak.relay.clone(),
+                    nat: pak.nat,
+                    punch: pak.punch,
+                    predicted: pak.predicted.clone(),
+                    candidates: pak.candidates.clone(),
+                });
+                return false;
+            }
+        }
+        true
+    });
+
+    if let Some(res) = res {
+        Some(Ok(res))
+    } else {
+        take_error(conn, ErrorRequest::RequestFinal, packet.id).map(Err)
+    }
+}
+
+// End RequestFinal
+//
+// Relay
+
+fn relay_fn_has(conn: &Box<dyn TConnection>, packet: &Packets) -> bool {
+    conn.step();
+    if let Packets::RelayRequest(packet) = packet {
+        for pak in conn.read().unwrap().packets.iter() {
+            if let Packets::RelayResponse(pak) = pak {
+                if pak.id == packet.id {
+                    return true;
                 }
             }
-            true
-        })
+        }
+        return has_error(conn, ErrorRequest::Relay, packet.id);
     }
+    false
+}
+
+fn relay_fn_get(conn: &Box<dyn TConnection>, packet: &Packets) -> Option<Result<u16, Error>> {
+    let mut res = None;
+    let Packets::RelayRequest(packet) = packet else {
+        return None;
+    };
+
+    conn.write().unwrap().packets.retain(|pak| {
+        if let Packets::RelayResponse(pak) = pak {
+            if pak.id == packet.id && res.is_none() {
+                res = Some(pak.port);
+                return false;
+            }
+        }
+        true
+    });
 
     if let Some(res) = res {
-        res
+        Some(Ok(res))
     } else {
-        panic!()
+        take_error(conn, ErrorRequest::Relay, packet.id).map(Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "server")]
+    use crate::{client::testing, common::packets::SearchType};
+
+    use super::*;
+
+    #[test]
+    fn relay_strings_are_resolved() {
+        let resolve = |relay: &str| resolve_relay(relay).map(|adress| adress.to_string());
+
+        assert_eq!(resolve("127.0.0.1:9000").unwrap(), "127.0.0.1:9000");
+        assert_eq!(resolve("127.0.0.1").unwrap(), "127.0.0.1:2120");
+        assert_eq!(resolve("[::1]:9000").unwrap(), "[::1]:9000");
+        assert_eq!(resolve("::1").unwrap(), "[::1]:2120");
+        assert_eq!(resolve("[::1]").unwrap(), "[::1]:2120");
+        assert_eq!(resolve("[fe80::1%1]:9000").unwrap(), "[fe80::1%1]:9000");
+
+        let localhost = resolve_relay("localhost").unwrap();
+        assert!(localhost.ip().is_loopback());
+        assert_eq!(localhost.port(), DEFAULT_PORT);
+        let localhost = resolve_relay("localhost:9000").unwrap();
+        assert!(localhost.ip().is_loopback());
+        assert_eq!(localhost.port(), 9000);
+
+        for bad in ["", "127.0.0.1:", "127.0.0.1:70000", "[::1", "::1]:9000"] {
+            assert_eq!(resolve(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn relay_host_is_the_server_name() {
+        assert_eq!(relay_host("127.0.0.1:9000"), "127.0.0.1");
+        assert_eq!(relay_host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(relay_host("[::1]:9000"), "::1");
+        assert_eq!(relay_host("[::1]"), "::1");
+        assert_eq!(relay_host("::1"), "::1");
+        assert_eq!(relay_host("relay.example.com:9000"), "relay.example.com");
+        assert_eq!(relay_host("relay.example.com"), "relay.example.com");
+        assert_eq!(relay_host("localhost"), "localhost");
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn overlapping_requests_get_their_own_response() {
+        let relay = testing::relay();
+        let _b = Connection::new(relay.adress.clone(), testing::info(vec![2], "b")).unwrap();
+        let a = Connection::new(relay.adress.clone(), testing::info(vec![1], "a")).unwrap();
+        let a = Arc::new(RwLock::new(a));
+
+        let search = |name: &str| Search {
+            name: SearchType::Exact(String::from(name)),
+            ..Default::default()
+        };
+        let search_a = a.search(search("a"));
+        let search_b = a.search(search("b"));
+        let info_a = a.info(&vec![1]);
+        let info_b = a.info(&vec![2]);
+
+        // the answers are taken in a other order then the requests were sent
+        let timeout = Duration::from_secs(5);
+        assert_eq!(info_b.get_timeout(timeout).unwrap().unwrap().name, "b");
+        let found = search_b.get_timeout(timeout).unwrap().unwrap();
+        assert_eq!(found.adresses, vec![vec![2]]);
+        assert_eq!(info_a.get_timeout(timeout).unwrap().unwrap().name, "a");
+        let found = search_a.get_timeout(timeout).unwrap().unwrap();
+        assert_eq!(found.adresses, vec![vec![1]]);
+        assert!(a.read().unwrap().packets.is_empty());
+    }
+
+    #[cfg(all(feature = "server", feature = "tls"))]
+    #[test]
+    fn tls_round_trip() {
+        use crate::common::tls::{SelfSigned, TlsOptions};
+
+        let certificate = SelfSigned::generate(&["localhost", "127.0.0.1"]).unwrap();
+        let mut server =
+            crate::server::RelayServer::bind("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+        server
+            .set_tls(vec![certificate.cert.clone()], certificate.key.clone())
+            .unwrap();
+        let relay = testing::Relay::start(server);
+
+        let mut info = testing::info(vec![1], "a");
+        info.tls = Some(TlsOptions::new(certificate.pin()));
+        let a = Connection::new(relay.adress.clone(), info.clone()).unwrap();
+        assert!(a.tls.is_enabled());
+        let a = Arc::new(RwLock::new(a));
+
+        let timeout = Duration::from_secs(5);
+        let found = a.info(&vec![1]).get_timeout(timeout).unwrap().unwrap();
+        assert_eq!(found.name, "a");
+        let search = Search {
+            name: SearchType::Exact(String::from("a")),
+            ..Default::default()
+        };
+        let found = a.search(search).get_timeout(timeout).unwrap().unwrap();
+        assert_eq!(found.adresses, vec![vec![1]]);
+
+        // a other certificate is refused
+        let other = SelfSigned::generate(&["localhost", "127.0.0.1"]).unwrap();
+        info.public = vec![2];
+        info.tls = Some(TlsOptions::new(other.pin()));
+        assert!(Connection::new(relay.adress.clone(), info).is_err());
     }
 }
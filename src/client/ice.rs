@@ -0,0 +1,779 @@
+//! Connectivity checks between the candidates of two peers like ICE (RFC 8445),
+//! the checks are sent from the registered socket so no STUN server is needed
+
+use std::{
+    collections::VecDeque,
+    mem::MaybeUninit,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use socket2::{SockAddr, Socket};
+
+use crate::common::{
+    packets::{Candidate, CandidateKind},
+    FromRawSock, IntoRawSock,
+};
+
+use super::response::{relay_connect, Conn, ConnectOn, ConnectOnError};
+
+/// `[CHECK_REQUEST, nominate, time (8 bytes), transaction (8 bytes)]`
+pub const CHECK_REQUEST: u8 = 0x60;
+/// `[CHECK_RESPONSE, time (8 bytes), transaction (8 bytes)]`
+pub const CHECK_RESPONSE: u8 = 0x61;
+/// `[CHECK_CONFIRM, time (8 bytes), transaction of the nominate (8 bytes)]`
+/// Sent by the controlling peer when the nominated pair answered
+pub const CHECK_CONFIRM: u8 = 0x62;
+
+/// The relay keeps not more for a socket
+const MAX_CANDIDATES: usize = 16;
+/// How many `resend` the controlled peer answers the nominate if the confirm does not come
+pub const LINGER_RESENDS: u32 = 4;
+
+/// The host candidates of `socket`, one for every interface with the family of `socket`
+/// The relay adds the server reflexive candidate when the socket is registered
+pub fn gather_candidates(socket: &Socket) -> Vec<Candidate> {
+    let Some(local) = socket
+        .local_addr()
+        .ok()
+        .and_then(|adress| adress.as_socket())
+    else {
+        return Vec::new();
+    };
+    let Ok(interfaces) = local_ip_address::list_afinet_netifas() else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (_, ip) in interfaces {
+        if ip.is_ipv4() != local.is_ipv4()
+            || ip.is_loopback()
+            || ip.is_unspecified()
+            || is_link_local(ip)
+        {
+            continue;
+        }
+        // a socket that is bound to one interface has only that
+        if !local.ip().is_unspecified() && ip != local.ip() {
+            continue;
+        }
+
+        let adress = SocketAddr::new(ip, local.port());
+        if candidates
+            .iter()
+            .any(|candidate| candidate.adress() == Some(adress))
+        {
+            continue;
+        }
+        // the first interfaces are preferred
+        let local = u16::MAX - candidates.len() as u16;
+        candidates.push(Candidate::new(CandidateKind::Host, adress, local));
+        if candidates.len() == MAX_CANDIDATES {
+            break;
+        }
+    }
+    candidates
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// The relayed candidate of the port that `relay_connect` bound,
+/// it has the lowest priority so it is only nominated if no direct pair works
+pub fn relayed_candidate(relay: &Conn) -> Candidate {
+    Candidate::new(CandidateKind::Relayed, relay.addr, 0)
+}
+
+/// The `candidates` of the peer with `to` and the `predicted` ports,
+/// those are only checked after the candidates of the peer
+/// Relayed candidates of the peer are dropped, a peer can only check its own relayed port
+pub fn remote_candidates(candidates: &[Candidate], to: &str, predicted: &[u16]) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.kind != CandidateKind::Relayed)
+        .cloned()
+        .collect();
+    let Some(to) = to
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut adresses| adresses.next())
+    else {
+        return candidates;
+    };
+
+    let ports = std::iter::once(to.port()).chain(predicted.iter().copied());
+    for (index, port) in ports.enumerate() {
+        let adress = SocketAddr::new(to.ip(), port);
+        if candidates
+            .iter()
+            .any(|candidate| candidate.adress() == Some(adress))
+        {
+            continue;
+        }
+        let local = u16::MAX - 1 - index as u16;
+        candidates.push(Candidate::new(
+            CandidateKind::ServerReflexive,
+            adress,
+            local,
+        ));
+    }
+    candidates
+}
+
+struct Check {
+    adress: SocketAddr,
+    priority: u32,
+    /// Sent from the socket bound to the relayed port
+    relayed: bool,
+    transaction: [u8; 8],
+    /// Where the response came from, can differ from `adress` behind a symmetric NAT
+    valid: Option<SocketAddr>,
+}
+
+/// The controlled peer answered the nominate
+struct Linger {
+    relayed: bool,
+    adress: SocketAddr,
+    transaction: [u8; 8],
+    /// Answers until then if the confirm does not come
+    until: Instant,
+}
+
+/// What `Ice` needs next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IcePoll {
+    /// Send `datagram` to `to`, from the relayed socket if `relayed`
+    Send {
+        relayed: bool,
+        to: SocketAddr,
+        datagram: Vec<u8>,
+    },
+    /// Nothing to send until a datagram is received or some time passed
+    Wait,
+    /// The nominated pair, `adress` is the relayed port if `relayed`
+    Connected { relayed: bool, adress: SocketAddr },
+    /// No pair answered in the timeout
+    Failed,
+}
+
+/// The connectivity checks of one peer without any socket,
+/// received datagrams go to `handle` and `poll` says what to do
+///
+/// The `controlling` peer nominates the working pair with the biggest priority
+/// after all pairs answered or `resend` after the first one answered,
+/// the other peer uses the pair that was nominated
+/// The controlled peer answers the nominate until it is confirmed
+/// or `LINGER_RESENDS` `resend` passed, so a lost response does not break the pair
+pub struct Ice {
+    controlling: bool,
+    /// When both peers start, from `ConnectOn::time`
+    time: u128,
+    session: [u8; 8],
+    checks: Vec<Check>,
+    timeout: Duration,
+    resend: Duration,
+    start: Option<Instant>,
+    time_send: Option<Instant>,
+    first_success: Option<Instant>,
+    nominated: Option<usize>,
+    linger: Option<Linger>,
+    confirmed: bool,
+    connected: Option<(bool, SocketAddr)>,
+    send: VecDeque<(bool, SocketAddr, Vec<u8>)>,
+}
+
+impl Ice {
+    /// Checks all `candidates` of the peer in parallel, starting at `time`
+    /// timeout need to be bigger then resend
+    pub fn new(
+        controlling: bool,
+        candidates: &[Candidate],
+        time: u128,
+        timeout: Duration,
+        resend: Duration,
+    ) -> Result<Self, ConnectOnError> {
+        if timeout < resend {
+            return Err(ConnectOnError::TimoutIsLesTheResend);
+        }
+
+        let mut ice = Self {
+            controlling,
+            time,
+            session: (time as u64).to_be_bytes(),
+            checks: Vec::new(),
+            timeout,
+            resend,
+            start: None,
+            time_send: None,
+            first_success: None,
+            nominated: None,
+            linger: None,
+            confirmed: false,
+            connected: None,
+            send: VecDeque::new(),
+        };
+        for candidate in candidates {
+            ice.add_candidate(candidate);
+        }
+        Ok(ice)
+    }
+
+    /// Adds a candidate while checking, like the relayed one
+    pub fn add_candidate(&mut self, candidate: &Candidate) {
+        let Some(adress) = candidate.adress() else {
+            return;
+        };
+        self.insert(Check {
+            adress,
+            priority: candidate.priority,
+            relayed: candidate.kind == CandidateKind::Relayed,
+            transaction: rand::random(),
+            valid: None,
+        });
+    }
+
+    /// Keeps `checks` sorted by priority, the biggest first
+    fn insert(&mut self, check: Check) -> usize {
+        let index = self
+            .checks
+            .iter()
+            .position(|other| other.priority < check.priority)
+            .unwrap_or(self.checks.len());
+        self.checks.insert(index, check);
+        if let Some(nominated) = self.nominated.as_mut() {
+            if *nominated >= index {
+                *nominated += 1;
+            }
+        }
+        index
+    }
+
+    fn message(&self, kind: u8, transaction: &[u8]) -> Vec<u8> {
+        let mut message = vec![kind];
+        message.extend(self.session);
+        message.extend(transaction);
+        message
+    }
+
+    fn request(&self, check: &Check, nominate: bool) -> Vec<u8> {
+        let mut request = vec![CHECK_REQUEST, nominate as u8];
+        request.extend(self.session);
+        request.extend(check.transaction);
+        request
+    }
+
+    /// Handles a datagram that `from` sent to the relayed socket if `relayed`
+    pub fn handle(&mut self, relayed: bool, from: SocketAddr, datagram: &[u8], now: Instant) {
+        match datagram {
+            [CHECK_REQUEST, nominate, rest @ ..] if rest.len() == 16 => {
+                if rest[0..8] != self.session {
+                    return;
+                }
+                let response = self.message(CHECK_RESPONSE, &rest[8..16]);
+                self.send.push_back((relayed, from, response));
+
+                if *nominate == 1 && !self.controlling {
+                    // a resent nominate means that the response was lost
+                    let mut transaction = [0; 8];
+                    transaction.copy_from_slice(&rest[8..16]);
+                    self.linger = Some(Linger {
+                        relayed,
+                        adress: from,
+                        transaction,
+                        until: now + self.resend * LINGER_RESENDS,
+                    });
+                    return;
+                }
+
+                // a adress that was not a candidate, like a unpredicted port
+                // datagrams of the relayed port allways come from the relay
+                let known = self
+                    .checks
+                    .iter()
+                    .any(|check| check.adress == from || check.valid == Some(from));
+                if !relayed && !known && self.checks.len() < MAX_CANDIDATES * 2 {
+                    let candidate = Candidate::new(CandidateKind::PeerReflexive, from, 0);
+                    let index = self.insert(Check {
+                        adress: from,
+                        priority: candidate.priority,
+                        relayed: false,
+                        transaction: rand::random(),
+                        valid: None,
+                    });
+                    // triggered check
+                    let request = self.request(&self.checks[index], false);
+                    self.send.push_back((false, from, request));
+                }
+            }
+            [CHECK_RESPONSE, rest @ ..] if rest.len() == 16 => {
+                if rest[0..8] != self.session {
+                    return;
+                }
+                let Some(index) = self
+                    .checks
+                    .iter()
+                    .position(|check| check.transaction[..] == rest[8..16])
+                else {
+                    return;
+                };
+
+                if self.nominated == Some(index) {
+                    if self.connected.is_none() {
+                        let confirm = self.message(CHECK_CONFIRM, &rest[8..16]);
+                        self.send.push_back((relayed, from, confirm));
+                        self.connected = Some((relayed, from));
+                    }
+                    return;
+                }
+                let check = &mut self.checks[index];
+                if check.valid.is_none() {
+                    check.valid = Some(from);
+                    self.first_success.get_or_insert(now);
+                }
+            }
+            [CHECK_CONFIRM, rest @ ..] if rest.len() == 16 => {
+                if rest[0..8] != self.session {
+                    return;
+                }
+                if let Some(linger) = &self.linger {
+                    if linger.transaction[..] == rest[8..16] {
+                        self.confirmed = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// What has to be done at `now`, called until it returns `Wait`
+    pub fn poll(&mut self, now: Instant) -> IcePoll {
+        if let Some((relayed, to, datagram)) = self.send.pop_front() {
+            return IcePoll::Send {
+                relayed,
+                to,
+                datagram,
+            };
+        }
+        if let Some((relayed, adress)) = self.connected {
+            return IcePoll::Connected { relayed, adress };
+        }
+
+        if let Some(linger) = &self.linger {
+            if self.confirmed || now >= linger.until {
+                self.connected = Some((linger.relayed, linger.adress));
+                return self.poll(now);
+            }
+            return IcePoll::Wait;
+        }
+
+        let start = *self.start.get_or_insert(now);
+        if now.duration_since(start) > self.timeout {
+            return IcePoll::Failed;
+        }
+
+        if self.controlling && self.nominated.is_none() {
+            let all = self.checks.iter().all(|check| check.valid.is_some());
+            let waited = self
+                .first_success
+                .is_some_and(|time| now.duration_since(time) > self.resend);
+            if self.first_success.is_some() && (all || waited) {
+                // `checks` is sorted, so the first is the best
+                self.nominated = self.checks.iter().position(|check| check.valid.is_some());
+                if let Some(index) = self.nominated {
+                    // so a late response to a check is not taken as the answer
+                    self.checks[index].transaction = rand::random();
+                }
+                self.time_send = None;
+            }
+        }
+
+        if self
+            .time_send
+            .is_none_or(|time| now.duration_since(time) > self.resend)
+        {
+            self.time_send = Some(now);
+            if let Some(index) = self.nominated {
+                let check = &self.checks[index];
+                let to = check.valid.unwrap_or(check.adress);
+                let request = self.request(check, true);
+                self.send.push_back((check.relayed, to, request));
+            } else {
+                for check in self.checks.iter().filter(|check| check.valid.is_none()) {
+                    let request = self.request(check, false);
+                    self.send.push_back((check.relayed, check.adress, request));
+                }
+            }
+            if !self.send.is_empty() {
+                return self.poll(now);
+            }
+        }
+        IcePoll::Wait
+    }
+}
+
+/// Runs `ice` from `socket` and from `relay`, the result of `relay_connect`,
+/// the relayed port is checked as the relayed candidate
+/// Without `punch` the relay expects direct pairs to fail and `relay` is used directly
+pub fn run_ice(
+    mut ice: Ice,
+    port: u16,
+    socket: Socket,
+    relay: Result<Conn, ConnectOnError>,
+    punch: bool,
+) -> Result<Conn, ConnectOnError> {
+    let mut relay = match relay {
+        Err(ConnectOnError::NoRelay) => None,
+        relay if !punch => return relay,
+        Ok(relay) => {
+            ice.add_candidate(&relayed_candidate(&relay));
+            Some(relay)
+        }
+        Err(_) => None,
+    };
+
+    let Ok(_) = socket.set_nonblocking(true) else {
+        return Err(ConnectOnError::CannotSetNonBlocking);
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    if ice.time > now {
+        std::thread::sleep(Duration::from_nanos((ice.time - now) as u64));
+    }
+
+    let mut buffer = [MaybeUninit::new(0); 64];
+    let addr = loop {
+        match ice.poll(Instant::now()) {
+            IcePoll::Send {
+                relayed,
+                to,
+                datagram,
+            } => {
+                let socket = if relayed {
+                    relay.as_deref()
+                } else {
+                    Some(&socket)
+                };
+                if let Some(socket) = socket {
+                    let _ = socket.send_to(&datagram, &SockAddr::from(to));
+                }
+            }
+            IcePoll::Wait => {
+                let sockets = [(false, Some(&socket)), (true, relay.as_deref())];
+                for (relayed, socket) in sockets {
+                    let Some(socket) = socket else {
+                        continue;
+                    };
+                    while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                        let Some(from) = from.as_socket() else {
+                            continue;
+                        };
+                        let datagram = unsafe {
+                            std::mem::transmute::<&[MaybeUninit<u8>], &[u8]>(&buffer[0..len])
+                        };
+                        ice.handle(relayed, from, datagram, Instant::now());
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            IcePoll::Connected { relayed: true, .. } => {
+                return relay.take().ok_or(ConnectOnError::ChecksFailed)
+            }
+            IcePoll::Connected { adress, .. } => break adress,
+            IcePoll::Failed => return Err(ConnectOnError::ChecksFailed),
+        }
+    };
+
+    let fd = socket.into_raw();
+    let conn = Conn {
+        port,
+        fd,
+        socket: Socket::from_raw(fd),
+        addr,
+        relayed: false,
+    };
+    let Ok(_) = conn.connect(&SockAddr::from(addr)) else {
+        return Err(ConnectOnError::CannotBind);
+    };
+    Ok(conn)
+}
+
+impl ConnectOn {
+    /// Runs `Ice` with the candidates of the peer and the relayed port,
+    /// that is only used if no direct pair works, like with `connect_or_relay`
+    /// Without candidates this is `connect_or_relay`, both peers should call this
+    /// and register the socket with `add_socket_with_candidates`
+    pub fn connect_ice(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<Conn, ConnectOnError> {
+        if self.candidates.is_empty() {
+            return self.connect_or_relay(timeout, resend, socket);
+        }
+
+        let connection = self.connection.c();
+        let candidates = remote_candidates(&self.candidates, &self.to, &self.predicted);
+        let controlling = connection.adress() > self.adress;
+        let ice = Ice::new(controlling, &candidates, self.time, timeout, resend)?;
+        // the relay allocates the relayed port on request
+        let relay = relay_connect(&*connection, &self.relay, timeout, resend);
+        run_ice(ice, self.port, socket, relay, self.punch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::testing;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const RESEND: Duration = Duration::from_millis(100);
+
+    struct Peer {
+        ice: Ice,
+        adress: SocketAddr,
+        relay: SocketAddr,
+        result: Option<IcePoll>,
+    }
+
+    fn adress(adress: &str) -> SocketAddr {
+        adress.parse().unwrap()
+    }
+
+    /// The controlling peer at 1.1.1.1, the other at 2.2.2.2, both with a unreachable
+    /// host candidate and the relayed port if `relayed`
+    fn peers(relayed: bool) -> [Peer; 2] {
+        let peer = |controlling: bool, ip: &str, other: &str| {
+            let candidates = [
+                Candidate::new(CandidateKind::Host, adress(&format!("10.0.0.{ip}:1000")), 1),
+                Candidate::new(CandidateKind::ServerReflexive, adress(other), 1),
+            ];
+            let mut ice = Ice::new(controlling, &candidates, 0, TIMEOUT, RESEND).unwrap();
+            let relay = adress(&format!("9.9.9.9:{ip}000"));
+            if relayed {
+                ice.add_candidate(&Candidate::new(CandidateKind::Relayed, relay, 0));
+            }
+            Peer {
+                ice,
+                adress: adress(&format!("{ip}.{ip}.{ip}.{ip}:1000")),
+                relay,
+                result: None,
+            }
+        };
+        [
+            peer(true, "1", "2.2.2.2:1000"),
+            peer(false, "2", "1.1.1.1:1000"),
+        ]
+    }
+
+    /// Runs both peers until both are done, `deliver` can drop a datagram
+    /// that peer `from` sends, relayed if the first is true
+    fn run(
+        mut peers: [Peer; 2],
+        mut deliver: impl FnMut(usize, bool, &[u8]) -> bool,
+    ) -> [Option<IcePoll>; 2] {
+        let start = Instant::now();
+        let mut now = start;
+        while now - start < TIMEOUT * 2 {
+            let mut sent = Vec::new();
+            for (index, peer) in peers.iter_mut().enumerate() {
+                while peer.result.is_none() {
+                    match peer.ice.poll(now) {
+                        IcePoll::Send {
+                            relayed,
+                            to,
+                            datagram,
+                        } => sent.push((index, relayed, to, datagram)),
+                        IcePoll::Wait => break,
+                        result => peer.result = Some(result),
+                    }
+                }
+            }
+
+            for (index, relayed, to, datagram) in sent {
+                let (from, other) = (&peers[index], &peers[1 - index]);
+                let from = match relayed {
+                    // the relay forwards from the relayed port of the peer
+                    true if to == from.relay => other.relay,
+                    false if to == other.adress => from.adress,
+                    _ => continue,
+                };
+                if other.result.is_none() && deliver(index, relayed, &datagram) {
+                    peers[1 - index].ice.handle(relayed, from, &datagram, now);
+                }
+            }
+
+            if peers.iter().all(|peer| peer.result.is_some()) {
+                break;
+            }
+            now += Duration::from_millis(1);
+        }
+        peers.map(|peer| peer.result)
+    }
+
+    fn connected(relayed: bool, to: &str) -> Option<IcePoll> {
+        Some(IcePoll::Connected {
+            relayed,
+            adress: adress(to),
+        })
+    }
+
+    #[test]
+    fn direct_pair_is_nominated() {
+        let [controlling, controlled] = run(peers(true), |_, _, _| true);
+        assert_eq!(controlling, connected(false, "2.2.2.2:1000"));
+        assert_eq!(controlled, connected(false, "1.1.1.1:1000"));
+    }
+
+    #[test]
+    fn relayed_pair_if_no_direct_works() {
+        let [controlling, controlled] = run(peers(true), |_, relayed, _| relayed);
+        assert_eq!(controlling, connected(true, "9.9.9.9:1000"));
+        assert_eq!(controlled, connected(true, "9.9.9.9:2000"));
+    }
+
+    #[test]
+    fn no_pair_fails() {
+        let [controlling, controlled] = run(peers(true), |_, _, _| false);
+        assert_eq!(controlling, Some(IcePoll::Failed));
+        assert_eq!(controlled, Some(IcePoll::Failed));
+    }
+
+    #[test]
+    fn lost_nominate_response_is_resent() {
+        let mut nominate = None;
+        let mut lost = false;
+        let [controlling, controlled] = run(peers(false), |from, _, datagram| {
+            match (from, datagram) {
+                (0, [CHECK_REQUEST, 1, rest @ ..]) => nominate = Some(rest[8..16].to_vec()),
+                (1, [CHECK_RESPONSE, rest @ ..])
+                    if !lost && nominate.as_deref() == Some(&rest[8..16]) =>
+                {
+                    lost = true;
+                    return false;
+                }
+                _ => {}
+            }
+            true
+        });
+        assert!(lost);
+        assert_eq!(controlling, connected(false, "2.2.2.2:1000"));
+        assert_eq!(controlled, connected(false, "1.1.1.1:1000"));
+    }
+
+    /// Polls until nothing is sent, returns how many datagrams were sent
+    fn drain(ice: &mut Ice, now: Instant) -> (usize, IcePoll) {
+        let mut sent = 0;
+        loop {
+            match ice.poll(now) {
+                IcePoll::Send { .. } => sent += 1,
+                poll => return (sent, poll),
+            }
+        }
+    }
+
+    fn message(ice: &Ice, message: &[u8]) -> Vec<u8> {
+        let mut message = message.to_vec();
+        message.splice(message.len() - 8..message.len() - 8, ice.session);
+        message
+    }
+
+    #[test]
+    fn controlled_waits_for_the_confirm() {
+        let [_, Peer {
+            ice: mut controlled,
+            ..
+        }] = peers(false);
+        let from = adress("1.1.1.1:1000");
+        let now = Instant::now();
+        drain(&mut controlled, now);
+
+        let nominate = message(&controlled, &[CHECK_REQUEST, 1, 7, 7, 7, 7, 7, 7, 7, 7]);
+        controlled.handle(false, from, &nominate, now);
+        assert_eq!(drain(&mut controlled, now), (1, IcePoll::Wait));
+
+        // a resent nominate extends the linger
+        let later = now + RESEND * LINGER_RESENDS - Duration::from_millis(1);
+        controlled.handle(false, from, &nominate, later);
+        assert_eq!(drain(&mut controlled, later), (1, IcePoll::Wait));
+        assert_eq!(drain(&mut controlled, later + RESEND), (0, IcePoll::Wait));
+
+        // without the confirm it gives up waiting
+        let linger = later + RESEND * LINGER_RESENDS;
+        assert_eq!(
+            drain(&mut controlled, linger),
+            (0, connected(false, "1.1.1.1:1000").unwrap())
+        );
+
+        // with the confirm right away
+        let [_, Peer {
+            ice: mut controlled,
+            ..
+        }] = peers(false);
+        controlled.handle(false, from, &nominate, now);
+        let confirm = message(&controlled, &[CHECK_CONFIRM, 7, 7, 7, 7, 7, 7, 7, 7]);
+        controlled.handle(false, from, &confirm, now);
+        assert_eq!(
+            drain(&mut controlled, now),
+            (1, connected(false, "1.1.1.1:1000").unwrap())
+        );
+    }
+
+    #[test]
+    fn checks_on_sockets() {
+        let (a, b) = (testing::socket(), testing::socket());
+        let local = |socket: &Socket| socket.local_addr().unwrap().as_socket().unwrap();
+        let (adress_a, adress_b) = (local(&a), local(&b));
+
+        let peer = move |controlling: bool, socket: Socket, to: SocketAddr| {
+            std::thread::spawn(move || {
+                let candidates = [Candidate::new(CandidateKind::Host, to, 1)];
+                let ice = Ice::new(controlling, &candidates, 0, TIMEOUT, RESEND).unwrap();
+                run_ice(ice, 0, socket, Err(ConnectOnError::NoRelay), true)
+            })
+        };
+        let a = peer(true, a, adress_b);
+        let b = peer(false, b, adress_a);
+
+        let (a, b) = (a.join().unwrap().unwrap(), b.join().unwrap().unwrap());
+        assert_eq!((a.addr, a.relayed), (adress_b, false));
+        assert_eq!((b.addr, b.relayed), (adress_a, false));
+    }
+
+    #[test]
+    fn relay_is_used_without_punching() {
+        let (relay, _) = testing::pair();
+        let relay = Conn {
+            relayed: true,
+            ..relay
+        };
+        let addr = relay.addr;
+        let ice = Ice::new(true, &[], 0, TIMEOUT, RESEND).unwrap();
+
+        let conn = run_ice(ice, 0, testing::socket(), Ok(relay), false).unwrap();
+        assert_eq!((conn.addr, conn.relayed), (addr, true));
+    }
+
+    #[test]
+    fn relayed_candidates_are_checked_last() {
+        let relayed = Candidate::new(CandidateKind::Relayed, adress("9.9.9.9:1000"), u16::MAX);
+        let peer_reflexive = Candidate::new(CandidateKind::PeerReflexive, adress("2.2.2.2:1"), 0);
+        assert!(relayed.priority < peer_reflexive.priority);
+
+        // the relayed port of the peer cannot be used
+        let candidates = remote_candidates(&[relayed], "2.2.2.2:1000", &[1001]);
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.kind == CandidateKind::ServerReflexive));
+        assert_eq!(candidates.len(), 2);
+    }
+}
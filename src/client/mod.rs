@@ -2,14 +2,25 @@ use std::sync::{Arc, RwLock};
 
 use crate::common::{
     adress::Adress,
-    packets::{Packets, Search},
+    packets::{Error, Packets, Search},
 };
 
+#[cfg(feature = "tokio")]
+pub mod async_client;
 mod connection;
+pub mod ice;
+pub mod mux;
+mod nat;
 pub mod response;
+#[cfg(feature = "noise")]
+pub mod secure;
+pub mod stream;
+#[cfg(test)]
+mod testing;
 pub use connection::*;
+pub use nat::*;
 
-use self::response::{RequestStage, Response};
+use self::response::{CancelHandle, RequestStage, Response, Signal};
 
 pub struct RelayClient {
     pub connections: Vec<Arc<RwLock<Connection>>>,
@@ -24,7 +35,8 @@ pub enum RelayClientError {
     NoConnections,
 }
 
-pub type SearchResponse = Vec<Response<Box<dyn TConnection>, response::SearchResponse>>;
+pub type SearchResponse =
+    Vec<Response<Box<dyn TConnection>, Result<response::SearchResponse, Error>>>;
 
 impl RelayClient {
     pub fn new(info: ConnectionInfo, relays: Vec<String>) -> Result<Self, RelayClientError> {
@@ -85,6 +97,7 @@ impl RelayClient {
             packets: Packets::Search(search),
             fn_has: search_fn_has,
             fn_get: search_fn_get,
+            cancel: CancelHandle::new(Arc::new(Signal::default())),
         }
     }
 
@@ -109,10 +122,7 @@ impl RelayClient {
 
 // Search
 
-fn search_fn_has(
-    connections: &Vec<Response<Box<dyn TConnection>, response::SearchResponse>>,
-    _: &Packets,
-) -> bool {
+fn search_fn_has(connections: &SearchResponse, _: &Packets) -> bool {
     let mut count = 0;
 
     for conn in connections.iter() {
@@ -122,14 +132,11 @@ fn search_fn_has(
     count == connections.len()
 }
 
-fn search_fn_get(
-    connections: Vec<Response<Box<dyn TConnection>, response::SearchResponse>>,
-    _: Packets,
-) -> Vec<Adress> {
+fn search_fn_get(connections: &SearchResponse, _: &Packets) -> Option<Vec<Adress>> {
     let mut res = Vec::new();
 
     for conn in connections {
-        let v = conn.get();
+        let Some(Ok(v)) = conn.take() else { continue };
         for adress in v.adresses {
             if !res.contains(&adress) {
                 res.push(adress)
@@ -137,5 +144,5 @@ fn search_fn_get(
         }
     }
 
-    res
+    Some(res)
 }
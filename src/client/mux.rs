@@ -0,0 +1,639 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use socket2::Socket;
+
+use super::{
+    response::{Conn, ConnectOn, ConnectOnError},
+    stream::{recv, send, Reliable, STREAM_TIMEOUT},
+};
+
+/// How often `OPEN` and `CLOSE` are sent again until the peer acks them
+pub const CONTROL_RESEND: Duration = Duration::from_millis(250);
+/// Biggest message of a unreliable channel
+/// Messages bigger then `stream::MSS` can be fragmented and are lost more often
+pub const MAX_DATAGRAM: usize = 65507 - HEADER_LEN;
+
+/// Messages that a unreliable channel keeps until they are received, newer ones are dropped
+pub const MAX_QUEUED: usize = 1024;
+
+/// kind and channel
+const HEADER_LEN: usize = 3;
+
+const OPEN: u8 = 0x50;
+const OPEN_ACK: u8 = 0x51;
+const CLOSE: u8 = 0x52;
+const CLOSE_ACK: u8 = 0x53;
+const DATAGRAM: u8 = 0x54;
+/// A datagram of the `Reliable` of the channel
+const STREAM: u8 = 0x55;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// Ordered bytes, like a `PeerStream`
+    Reliable = 0,
+    /// Messages that can be lost or reordered, like UDP
+    Unreliable = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxEvent {
+    /// The peer opened a channel
+    Opened(u16, ChannelKind),
+    /// The peer closed a channel, what was sent before can still be received
+    /// and it can still be sent on until it is closed here too
+    Closed(u16),
+}
+
+#[derive(Debug)]
+struct Channel {
+    kind: ChannelKind,
+    reliable: Reliable,
+    datagrams: VecDeque<Vec<u8>>,
+    /// If the peer did not ack the open yet
+    opening: bool,
+    /// `close` was called
+    closing: bool,
+    /// The peer acked our `CLOSE`
+    close_acked: bool,
+    peer_closed: bool,
+    /// `recv` returned that everything was received
+    finished: bool,
+    /// Last time `OPEN` or `CLOSE` was sent
+    control_sent: Option<Instant>,
+}
+
+impl Channel {
+    fn new(kind: ChannelKind, opening: bool) -> Self {
+        Self {
+            kind,
+            reliable: Reliable::default(),
+            datagrams: VecDeque::new(),
+            opening,
+            closing: false,
+            close_acked: false,
+            peer_closed: false,
+            finished: false,
+            control_sent: None,
+        }
+    }
+
+    /// Both peers closed it and everything was received, it can be removed
+    fn is_done(&self) -> bool {
+        self.close_acked && self.peer_closed && self.finished
+    }
+}
+
+/// Numbered channels between two peers over one punched `Conn`
+/// Channels can be opened and closed by both peers and use the same NAT mapping
+/// Both peers have to use a `Mux` on the same `Conn`
+#[derive(Debug)]
+pub struct Mux {
+    pub conn: Conn,
+    /// How long blocking calls wait without hearing from the peer
+    pub timeout: Duration,
+
+    channels: BTreeMap<u16, Channel>,
+    events: VecDeque<MuxEvent>,
+    last_recv: Instant,
+    /// Frames that still have to be sent
+    out: Vec<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ConnectOn {
+    /// Same as `connect` but returns a `Mux`
+    pub fn connect_mux(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<Mux, ConnectOnError> {
+        self.connect(timeout, resend, socket).map(Mux::new)
+    }
+}
+
+impl Mux {
+    pub fn new(conn: Conn) -> Self {
+        let _ = conn.set_nonblocking(true);
+        Self {
+            conn,
+            timeout: STREAM_TIMEOUT,
+            channels: BTreeMap::new(),
+            events: VecDeque::new(),
+            last_recv: Instant::now(),
+            out: Vec::new(),
+            buffer: vec![0; 65536],
+        }
+    }
+
+    /// Opens `channel`, it can be used right away
+    /// A channel that both peers closed can be opened again
+    /// The peer gets a `MuxEvent::Opened`
+    pub fn open(&mut self, channel: u16, kind: ChannelKind) -> io::Result<()> {
+        match self.channels.entry(channel) {
+            Entry::Occupied(mut entry) => {
+                let state = entry.get();
+                if !state.closing || !state.peer_closed {
+                    return Err(io::ErrorKind::AlreadyExists.into());
+                }
+                entry.insert(Channel::new(kind, true));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Channel::new(kind, true));
+            }
+        }
+        self.poll()?;
+        Ok(())
+    }
+
+    /// Closes the sending side of `channel` after everything that was sent on it was acked
+    /// The peer gets a `MuxEvent::Closed`, what it sends can still be received
+    /// The channel is removed when both peers closed it and `recv` returned `Ok(Some(0))`
+    pub fn close(&mut self, channel: u16) -> io::Result<()> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        state.closing = true;
+        state.reliable.shutdown();
+        self.poll()?;
+        Ok(())
+    }
+
+    pub fn kind(&self, channel: u16) -> Option<ChannelKind> {
+        self.channels.get(&channel).map(|state| state.kind)
+    }
+
+    /// Channels that are open or still closing
+    pub fn channels(&self) -> impl Iterator<Item = u16> + '_ {
+        self.channels.keys().copied()
+    }
+
+    /// Channels opened or closed by the peer
+    pub fn next_event(&mut self) -> Option<MuxEvent> {
+        self.events.pop_front()
+    }
+
+    /// Sends without blocking
+    /// On a reliable channel it buffers as much of `data` as fits
+    /// and returns `WouldBlock` if the buffer is full
+    /// On a unreliable channel `data` is sent as one message
+    pub fn send(&mut self, channel: u16, data: &[u8]) -> io::Result<usize> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        if state.closing {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        match state.kind {
+            ChannelKind::Reliable => {
+                let len = state.reliable.write(data);
+                if len == 0 {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                self.poll()?;
+                Ok(len)
+            }
+            ChannelKind::Unreliable => {
+                if data.len() > MAX_DATAGRAM {
+                    return Err(io::ErrorKind::InvalidInput.into());
+                }
+                send(&self.conn, &frame(DATAGRAM, channel, data))?;
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// Receives without blocking, `Ok(None)` if there is nothing
+    /// On a unreliable channel it is one message, cut to the size of `buf`
+    /// `Ok(Some(0))` if the peer closed the channel and everything was received
+    pub fn recv(&mut self, channel: u16, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.poll()?;
+
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+
+        let len = match state.kind {
+            ChannelKind::Reliable => {
+                let mut out = Vec::new();
+                let len = state.reliable.read(buf, &mut out);
+                for datagram in out {
+                    self.out.push(frame(STREAM, channel, &datagram));
+                }
+                len
+            }
+            ChannelKind::Unreliable => match state.datagrams.pop_front() {
+                Some(datagram) => {
+                    let len = buf.len().min(datagram.len());
+                    buf[0..len].copy_from_slice(&datagram[0..len]);
+                    len
+                }
+                None => 0,
+            },
+        };
+
+        if len > 0 {
+            self.send_out()?;
+            return Ok(Some(len));
+        }
+
+        state.finished = match state.kind {
+            ChannelKind::Reliable => state.reliable.is_finished(),
+            ChannelKind::Unreliable => state.peer_closed,
+        };
+        Ok(state.finished.then_some(0))
+    }
+
+    /// Blocks until everything sent on the reliable `channel` was acked
+    pub fn flush(&mut self, channel: u16) -> io::Result<()> {
+        let start = Instant::now();
+        loop {
+            self.poll()?;
+            let Some(state) = self.channels.get(&channel) else {
+                return Err(io::ErrorKind::NotFound.into());
+            };
+            if state.reliable.is_flushed() {
+                return Ok(());
+            }
+            self.wait(start)?;
+        }
+    }
+
+    /// A blocking `Read` and `Write` for a reliable `channel`
+    pub fn stream(&mut self, channel: u16) -> MuxStream<'_> {
+        MuxStream { mux: self, channel }
+    }
+
+    /// Sends, receives and retransmits on all channels without blocking
+    /// Returns true if something was received
+    /// Needs to be called regularly so the peer gets its acks
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let mut received = false;
+        while let Some(len) = recv(&self.conn, &mut self.buffer)? {
+            if len < HEADER_LEN {
+                continue;
+            }
+            let kind = self.buffer[0];
+            let channel = u16::from_be_bytes([self.buffer[1], self.buffer[2]]);
+            let body = self.buffer[HEADER_LEN..len].to_vec();
+            if self.on_frame(kind, channel, &body) {
+                received = true;
+                self.last_recv = Instant::now();
+            }
+        }
+
+        let now = Instant::now();
+        for (&channel, state) in self.channels.iter_mut() {
+            let mut out = Vec::new();
+            state.reliable.tick(&mut out);
+            for datagram in out {
+                self.out.push(frame(STREAM, channel, &datagram));
+            }
+
+            let control = if state.opening {
+                Some(frame(OPEN, channel, &[state.kind as u8]))
+            } else if state.closing && !state.close_acked && state.reliable.is_flushed() {
+                Some(frame(CLOSE, channel, &[]))
+            } else {
+                None
+            };
+
+            if let Some(control) = control {
+                if state
+                    .control_sent
+                    .is_none_or(|time| now.duration_since(time) > CONTROL_RESEND)
+                {
+                    self.out.push(control);
+                    state.control_sent = Some(now);
+                }
+            }
+        }
+        self.channels.retain(|_, state| !state.is_done());
+
+        self.send_out()?;
+        Ok(received)
+    }
+
+    /// Returns false if it was not a frame of the mux
+    fn on_frame(&mut self, kind: u8, channel: u16, body: &[u8]) -> bool {
+        match kind {
+            OPEN => {
+                let kind = if body.first() == Some(&(ChannelKind::Unreliable as u8)) {
+                    ChannelKind::Unreliable
+                } else {
+                    ChannelKind::Reliable
+                };
+                match self.channels.entry(channel) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(Channel::new(kind, false));
+                        self.events.push_back(MuxEvent::Opened(channel, kind));
+                    }
+                    // the peer closed it before, so this is a new channel
+                    Entry::Occupied(mut entry) if entry.get().peer_closed => {
+                        entry.insert(Channel::new(kind, false));
+                        self.events.push_back(MuxEvent::Opened(channel, kind));
+                    }
+                    // both peers opened it at the same time or the ack was lost
+                    Entry::Occupied(mut entry) => entry.get_mut().opening = false,
+                }
+                self.out.push(frame(OPEN_ACK, channel, &[]));
+            }
+            OPEN_ACK => {
+                if let Some(state) = self.channels.get_mut(&channel) {
+                    if state.opening {
+                        state.opening = false;
+                        state.control_sent = None;
+                    }
+                }
+            }
+            CLOSE => {
+                if let Some(state) = self.channels.get_mut(&channel) {
+                    if !state.peer_closed {
+                        state.peer_closed = true;
+                        self.events.push_back(MuxEvent::Closed(channel));
+                    }
+                }
+                // also if the channel is gone, the last ack could have been lost
+                self.out.push(frame(CLOSE_ACK, channel, &[]));
+            }
+            CLOSE_ACK => {
+                if let Some(state) = self.channels.get_mut(&channel) {
+                    state.close_acked |= state.closing;
+                }
+            }
+            DATAGRAM => {
+                let Some(state) = self.channels.get_mut(&channel) else {
+                    return true;
+                };
+                state.opening = false;
+                if !state.peer_closed && state.datagrams.len() < MAX_QUEUED {
+                    state.datagrams.push_back(body.to_vec());
+                }
+            }
+            STREAM => {
+                let Some(state) = self.channels.get_mut(&channel) else {
+                    return true;
+                };
+                state.opening = false;
+                let mut out = Vec::new();
+                state.reliable.on_datagram(body, &mut out);
+                for datagram in out {
+                    self.out.push(frame(STREAM, channel, &datagram));
+                }
+            }
+            // left overs from the hole punching
+            _ => return false,
+        }
+        true
+    }
+
+    fn send_out(&mut self) -> io::Result<()> {
+        for datagram in self.out.drain(..) {
+            send(&self.conn, &datagram)?;
+        }
+        Ok(())
+    }
+
+    /// Polls once and sleeps a bit if nothing happened
+    /// Fails if nothing was heard from the peer for `timeout` since `start`,
+    /// the start of the blocking call
+    fn wait(&mut self, start: Instant) -> io::Result<()> {
+        let received = self.poll()?;
+        if self.last_recv.max(start).elapsed() > self.timeout {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if !received {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
+
+/// A reliable channel of a `Mux` as blocking `Read` and `Write`
+#[derive(Debug)]
+pub struct MuxStream<'a> {
+    pub mux: &'a mut Mux,
+    pub channel: u16,
+}
+
+impl Read for MuxStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let start = Instant::now();
+        loop {
+            if let Some(len) = self.mux.recv(self.channel, buf)? {
+                return Ok(len);
+            }
+            self.mux.wait(start)?;
+        }
+    }
+}
+
+impl Write for MuxStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        loop {
+            match self.mux.send(self.channel, buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.mux.wait(start)?,
+                res => return res,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.mux.flush(self.channel)
+    }
+}
+
+fn frame(kind: u8, channel: u16, body: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_LEN + body.len());
+    datagram.push(kind);
+    datagram.extend(channel.to_be_bytes());
+    datagram.extend(body);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{stream::MSS, testing::pair};
+
+    use super::*;
+
+    /// Segments sent on every channel by `interleaved_channels`
+    const CHUNKS: usize = 8;
+
+    fn muxes() -> (Mux, Mux) {
+        let (a, b) = pair();
+        (Mux::new(a), Mux::new(b))
+    }
+
+    /// Polls both until `done` or panics after a while
+    fn until(a: &mut Mux, b: &mut Mux, mut done: impl FnMut(&mut Mux, &mut Mux) -> bool) {
+        let start = Instant::now();
+        while !done(a, b) {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            a.poll().unwrap();
+            b.poll().unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn recv_all(a: &mut Mux, b: &mut Mux, channel: u16) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        until(a, b, |_, b| match b.recv(channel, &mut buffer).unwrap() {
+            Some(0) => true,
+            Some(len) => {
+                received.extend(&buffer[..len]);
+                false
+            }
+            None => false,
+        });
+        received
+    }
+
+    #[test]
+    fn open_close_and_reopen() {
+        let (mut a, mut b) = muxes();
+
+        for round in 0..2u8 {
+            a.open(1, ChannelKind::Reliable).unwrap();
+            until(&mut a, &mut b, |_, b| b.next_event().is_some());
+            a.send(1, &[round; 10]).unwrap();
+            a.close(1).unwrap();
+            until(&mut a, &mut b, |_, b| {
+                b.next_event() == Some(MuxEvent::Closed(1))
+            });
+
+            // half closed, the peer can still send
+            b.send(1, b"answer").unwrap();
+            b.close(1).unwrap();
+            assert_eq!(recv_all(&mut a, &mut b, 1), [round; 10]);
+            assert_eq!(recv_all(&mut b, &mut a, 1), b"answer");
+
+            until(&mut a, &mut b, |a, b| {
+                a.channels().next().is_none() && b.channels().next().is_none()
+            });
+        }
+    }
+
+    #[test]
+    fn open_of_a_closed_channel_is_a_new_channel() {
+        let (mut a, mut b) = muxes();
+        a.open(1, ChannelKind::Reliable).unwrap();
+        a.send(1, b"old").unwrap();
+        a.close(1).unwrap();
+        until(&mut a, &mut b, |_, b| {
+            b.events.contains(&MuxEvent::Closed(1))
+        });
+        b.events.clear();
+        b.close(1).unwrap();
+
+        // `a` reopens before `b` read what was left
+        until(&mut a, &mut b, |a, _| a.channels[&1].peer_closed);
+        a.open(1, ChannelKind::Unreliable).unwrap();
+        until(&mut a, &mut b, |_, b| {
+            b.next_event() == Some(MuxEvent::Opened(1, ChannelKind::Unreliable))
+        });
+        assert_eq!(b.kind(1), Some(ChannelKind::Unreliable));
+
+        a.send(1, b"new").unwrap();
+        let mut buffer = [0; 16];
+        let mut len = None;
+        until(&mut a, &mut b, |_, b| {
+            len = b.recv(1, &mut buffer).unwrap();
+            len.is_some()
+        });
+        assert_eq!(&buffer[..len.unwrap()], b"new");
+    }
+
+    #[test]
+    fn unreliable_channel() {
+        let (mut a, mut b) = muxes();
+        a.open(2, ChannelKind::Unreliable).unwrap();
+        until(&mut a, &mut b, |_, b| {
+            b.next_event() == Some(MuxEvent::Opened(2, ChannelKind::Unreliable))
+        });
+
+        a.send(2, b"one").unwrap();
+        a.send(2, b"two").unwrap();
+        let mut messages = Vec::new();
+        let mut buffer = [0; 16];
+        until(&mut a, &mut b, |_, b| {
+            if let Some(len) = b.recv(2, &mut buffer).unwrap() {
+                messages.push(buffer[..len].to_vec());
+            }
+            messages.len() == 2
+        });
+        assert_eq!(messages, [b"one".to_vec(), b"two".to_vec()]);
+
+        assert_eq!(
+            a.send(2, &vec![0; MAX_DATAGRAM + 1]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        a.close(2).unwrap();
+        assert_eq!(
+            a.send(2, b"three").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        until(&mut a, &mut b, |_, b| {
+            b.recv(2, &mut buffer).unwrap() == Some(0)
+        });
+    }
+
+    #[test]
+    fn interleaved_channels() {
+        let (mut a, mut b) = muxes();
+        let data = |channel: u16| -> Vec<u8> {
+            (0..CHUNKS * MSS)
+                .map(|i| (i as u16 ^ channel) as u8)
+                .collect()
+        };
+        for channel in 0..3 {
+            a.open(channel, ChannelKind::Reliable).unwrap();
+        }
+        for chunk in 0..CHUNKS {
+            for channel in 0..3 {
+                let data = data(channel);
+                a.send(channel, &data[chunk * MSS..(chunk + 1) * MSS])
+                    .unwrap();
+            }
+        }
+        for channel in 0..3 {
+            a.close(channel).unwrap();
+        }
+
+        for channel in 0..3 {
+            assert_eq!(recv_all(&mut a, &mut b, channel), data(channel));
+        }
+    }
+
+    #[test]
+    fn idle_mux_can_flush() {
+        let (mut a, mut b) = muxes();
+        a.timeout = Duration::from_millis(200);
+        a.open(1, ChannelKind::Reliable).unwrap();
+        until(&mut a, &mut b, |_, b| b.next_event().is_some());
+        std::thread::sleep(Duration::from_millis(300));
+
+        a.send(1, b"late").unwrap();
+        let peer = std::thread::spawn(move || {
+            let mut buffer = [0; 4];
+            b.stream(1).read_exact(&mut buffer).unwrap();
+            buffer
+        });
+        a.flush(1).unwrap();
+        assert_eq!(&peer.join().unwrap(), b"late");
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use socket2::Socket;
+
+use crate::common::{
+    packets::{NatType, Packets, Register},
+    stun::{CHANGE_IP, CHANGE_PORT},
+};
+
+use super::{stun_binding, udp_socket, Connection};
+
+/// How long every test of `detect_nat` waits for the relay
+pub const NAT_TEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a Binding request of the RFC 5780 tests got back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// The reflexive adress and `OTHER-ADDRESS` if the relay knows it
+    Mapped(SocketAddr, Option<SocketAddr>),
+    /// No response in `NAT_TEST_TIMEOUT`
+    TimedOut,
+    Failed,
+}
+
+/// The results of the RFC 5780 tests, `None` if the test was not run yet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NatTests {
+    /// The adress of the socket that sends the tests
+    pub local: Option<SocketAddr>,
+    /// A Binding request to the relay
+    pub binding: Option<Binding>,
+    /// A Binding request to the other ip or port of the relay from the same socket
+    pub mapping: Option<Binding>,
+    /// `CHANGE_IP | CHANGE_PORT` from a new socket
+    pub change_ip: Option<Binding>,
+    /// `CHANGE_PORT` from the socket of `change_ip`
+    pub change_port: Option<Binding>,
+}
+
+/// The test that `classify_nat` needs next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatTest {
+    Binding,
+    /// To the adress
+    Mapping(SocketAddr),
+    ChangeIp,
+    ChangePort,
+}
+
+/// Finds the `NatType` with the behaviour discovery of RFC 5780
+/// `relay` is the UDP adress of the relay, blocks for up to a few `NAT_TEST_TIMEOUT`
+pub fn detect_nat(relay: SocketAddr) -> NatType {
+    let (Ok(socket), Ok(filter), Some(local_ip)) =
+        (udp_socket(relay), udp_socket(relay), local_ip(relay))
+    else {
+        return NatType::Unknown;
+    };
+
+    let mut tests = NatTests {
+        local: socket
+            .local_addr()
+            .ok()
+            .and_then(|adress| adress.as_socket())
+            .map(|adress| SocketAddr::new(local_ip, adress.port())),
+        ..Default::default()
+    };
+    let binding =
+        |socket: &Socket, to, change| match stun_binding(socket, to, change, NAT_TEST_TIMEOUT) {
+            Ok((mapped, other)) => Binding::Mapped(mapped, other),
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => Binding::TimedOut,
+            Err(_) => Binding::Failed,
+        };
+
+    loop {
+        match classify_nat(relay, &tests) {
+            Ok(nat) => return nat,
+            Err(NatTest::Binding) => tests.binding = Some(binding(&socket, relay, 0)),
+            Err(NatTest::Mapping(to)) => tests.mapping = Some(binding(&socket, to, 0)),
+            // filtering, with a new socket so no destination was already allowed
+            Err(NatTest::ChangeIp) => {
+                tests.change_ip = Some(binding(&filter, relay, CHANGE_IP | CHANGE_PORT))
+            }
+            Err(NatTest::ChangePort) => {
+                tests.change_port = Some(binding(&filter, relay, CHANGE_PORT))
+            }
+        }
+    }
+}
+
+/// The `NatType` from the results of the tests, or the test that has to be run next
+pub fn classify_nat(relay: SocketAddr, tests: &NatTests) -> Result<NatType, NatTest> {
+    let (mapped, other) = match tests.binding {
+        None => return Err(NatTest::Binding),
+        Some(Binding::Mapped(mapped, other)) => (mapped, other),
+        Some(Binding::TimedOut) => return Ok(NatType::Blocked),
+        Some(Binding::Failed) => return Ok(NatType::Unknown),
+    };
+    if tests.local == Some(mapped) {
+        return Ok(NatType::Open);
+    }
+
+    // the relay does not support RFC 5780
+    let Some(mut other) = other else {
+        return Ok(NatType::Unknown);
+    };
+    if other.ip().is_unspecified() {
+        other.set_ip(relay.ip());
+    }
+    let has_other_ip = other.ip() != relay.ip();
+
+    // mapping, a other destination from the same socket has to get the same port
+    match tests.mapping {
+        None if has_other_ip => {
+            return Err(NatTest::Mapping(SocketAddr::new(other.ip(), relay.port())))
+        }
+        None => return Err(NatTest::Mapping(SocketAddr::new(relay.ip(), other.port()))),
+        Some(Binding::Mapped(mapped2, _)) if mapped2 != mapped => return Ok(NatType::Symmetric),
+        Some(Binding::Mapped(..)) => {}
+        Some(_) => return Ok(NatType::Unknown),
+    }
+
+    if has_other_ip {
+        match tests.change_ip {
+            None => return Err(NatTest::ChangeIp),
+            Some(Binding::Mapped(..)) => return Ok(NatType::FullCone),
+            Some(_) => {}
+        }
+    }
+    // without a other ip a full cone cannot be told apart from a restricted cone
+    match tests.change_port {
+        None => Err(NatTest::ChangePort),
+        Some(Binding::Mapped(..)) => Ok(NatType::RestrictedCone),
+        Some(_) => Ok(NatType::PortRestrictedCone),
+    }
+}
+
+impl Connection {
+    /// Detects the `NatType` with the relay and advertises it with `Register::Nat`
+    /// Blocks, `TConnection::detect_nat` does not lock the connection while waiting
+    pub fn detect_nat(&mut self) -> NatType {
+        let nat = detect_nat(self.adress);
+        self.advertise_nat(nat);
+        nat
+    }
+
+    /// Sends `nat` to the relay, used when peers connect
+    pub fn advertise_nat(&mut self, nat: NatType) {
+        self.nat = nat;
+        self.send(Packets::Register(Register::Nat { nat }));
+    }
+}
+
+/// The ip that the os uses to reach `relay`, nothing is sent
+fn local_ip(relay: SocketAddr) -> Option<IpAddr> {
+    let socket = udp_socket(relay).ok()?;
+    socket.connect(&relay.into()).ok()?;
+    Some(socket.local_addr().ok()?.as_socket()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adress(adress: &str) -> SocketAddr {
+        adress.parse().unwrap()
+    }
+
+    const RELAY: &str = "1.1.1.1:3478";
+    const OTHER: &str = "2.2.2.2:3479";
+    const MAPPED: &str = "3.3.3.3:40000";
+
+    /// Runs `classify_nat` with `run` as the relay until it has a result
+    fn classify(other: Option<&str>, mut run: impl FnMut(NatTest) -> Binding) -> NatType {
+        let relay = adress(RELAY);
+        let mut tests = NatTests {
+            local: Some(adress("192.168.1.2:50000")),
+            ..Default::default()
+        };
+        loop {
+            match classify_nat(relay, &tests) {
+                Ok(nat) => return nat,
+                Err(NatTest::Binding) => {
+                    tests.binding = Some(Binding::Mapped(adress(MAPPED), other.map(adress)))
+                }
+                Err(test @ NatTest::Mapping(_)) => tests.mapping = Some(run(test)),
+                Err(NatTest::ChangeIp) => tests.change_ip = Some(run(NatTest::ChangeIp)),
+                Err(NatTest::ChangePort) => tests.change_port = Some(run(NatTest::ChangePort)),
+            }
+        }
+    }
+
+    fn same_port(_: NatTest) -> Binding {
+        Binding::Mapped(adress(MAPPED), None)
+    }
+
+    #[test]
+    fn binding_results() {
+        let relay = adress(RELAY);
+        let mut tests = NatTests {
+            local: Some(adress(MAPPED)),
+            ..Default::default()
+        };
+        assert_eq!(classify_nat(relay, &tests), Err(NatTest::Binding));
+
+        tests.binding = Some(Binding::TimedOut);
+        assert_eq!(classify_nat(relay, &tests), Ok(NatType::Blocked));
+        tests.binding = Some(Binding::Failed);
+        assert_eq!(classify_nat(relay, &tests), Ok(NatType::Unknown));
+        tests.binding = Some(Binding::Mapped(adress(MAPPED), None));
+        assert_eq!(classify_nat(relay, &tests), Ok(NatType::Open));
+
+        // without OTHER-ADDRESS nothing more can be tested
+        tests.local = Some(adress("192.168.1.2:50000"));
+        assert_eq!(classify_nat(relay, &tests), Ok(NatType::Unknown));
+    }
+
+    #[test]
+    fn mapping_destination() {
+        let relay = adress(RELAY);
+        let mut tests = NatTests {
+            binding: Some(Binding::Mapped(adress(MAPPED), Some(adress(OTHER)))),
+            ..Default::default()
+        };
+        assert_eq!(
+            classify_nat(relay, &tests),
+            Err(NatTest::Mapping(adress("2.2.2.2:3478")))
+        );
+
+        // a unspecified ip is the ip of the relay
+        tests.binding = Some(Binding::Mapped(
+            adress(MAPPED),
+            Some(adress("0.0.0.0:3479")),
+        ));
+        assert_eq!(
+            classify_nat(relay, &tests),
+            Err(NatTest::Mapping(adress("1.1.1.1:3479")))
+        );
+    }
+
+    #[test]
+    fn symmetric() {
+        let nat = classify(Some(OTHER), |_| {
+            Binding::Mapped(adress("3.3.3.3:40001"), None)
+        });
+        assert_eq!(nat, NatType::Symmetric);
+
+        let nat = classify(Some(OTHER), |_| Binding::TimedOut);
+        assert_eq!(nat, NatType::Unknown);
+    }
+
+    #[test]
+    fn filtering() {
+        assert_eq!(classify(Some(OTHER), same_port), NatType::FullCone);
+
+        let nat = classify(Some(OTHER), |test| match test {
+            NatTest::ChangeIp => Binding::TimedOut,
+            test => same_port(test),
+        });
+        assert_eq!(nat, NatType::RestrictedCone);
+
+        let nat = classify(Some(OTHER), |test| match test {
+            NatTest::Mapping(_) => same_port(test),
+            _ => Binding::TimedOut,
+        });
+        assert_eq!(nat, NatType::PortRestrictedCone);
+    }
+
+    #[test]
+    fn relay_without_other_ip() {
+        let mut change_ip = false;
+        let nat = classify(Some("1.1.1.1:3479"), |test| {
+            change_ip |= test == NatTest::ChangeIp;
+            same_port(test)
+        });
+        assert_eq!(nat, NatType::RestrictedCone);
+        assert!(!change_ip);
+
+        let nat = classify(Some("1.1.1.1:3479"), |test| match test {
+            NatTest::Mapping(_) => same_port(test),
+            _ => Binding::Failed,
+        });
+        assert_eq!(nat, NatType::PortRestrictedCone);
+    }
+}
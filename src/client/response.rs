@@ -1,20 +1,140 @@
 use std::{
+    io::Read,
     mem::MaybeUninit,
     net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use polling::{Event, Poller};
+use socket2::{SockAddr, Socket};
 
-use crate::common::{adress::Adress, packets::Packets, FromRawSock, IntoRawSock, RawSock};
+use crate::common::{
+    adress::Adress,
+    packets::{Candidate, Error, NatType, Packets, RELAY_BIND},
+    AsRawSock, FromRawSock, IntoRawSock, RawSock,
+};
+
+use super::{udp_socket, TConnection};
+
+/// How often a waiting `Response` polls the connection if the signal has no socket
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a waiting `Response` sleeps on the socket of the relay,
+/// the connection is stepped after that so the `Tick`s are sent
+pub const STEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wakes up the threads that wait for a `Response`
+#[derive(Debug, Default)]
+pub struct Signal {
+    generation: Mutex<usize>,
+    condvar: Condvar,
+    /// Wakes up when the socket of the relay is readable
+    poller: Option<(Poller, RawSock)>,
+    /// Only one thread can wait on `poller`, the others wait on `condvar`
+    polling: AtomicBool,
+}
+
+impl Signal {
+    /// A signal that also wakes up when `socket` has something to read
+    pub fn with_socket(socket: &Socket) -> std::io::Result<Self> {
+        let poller = Poller::new()?;
+        let raw = socket.as_raw();
+        poller.add(raw, Event::readable(0))?;
+        Ok(Self {
+            poller: Some((poller, raw)),
+            ..Default::default()
+        })
+    }
+
+    pub fn generation(&self) -> usize {
+        *self.generation.lock().unwrap()
+    }
+
+    pub fn has_socket(&self) -> bool {
+        self.poller.is_some()
+    }
+
+    pub fn notify(&self) {
+        self.wake();
+        if let Some((poller, _)) = &self.poller {
+            let _ = poller.notify();
+        }
+    }
+
+    fn wake(&self) {
+        let mut generation = self.generation.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+        self.condvar.notify_all();
+    }
+
+    /// Parks the thread until `notify` is called after `generation`, the socket is readable
+    /// or `timeout` elapsed
+    pub fn wait(&self, generation: usize, timeout: Duration) {
+        if let Some((poller, raw)) = &self.poller {
+            if !self.polling.swap(true, Ordering::SeqCst) {
+                let mut events = Vec::new();
+                let _ = poller.wait(&mut events, Some(timeout));
+                self.polling.store(false, Ordering::SeqCst);
+
+                if !events.is_empty() {
+                    // the poller is oneshot
+                    let _ = poller.modify(*raw, Event::readable(0));
+                    // the others should also look at the connection
+                    self.wake();
+                }
+                return;
+            }
+        }
+
+        let guard = self.generation.lock().unwrap();
+        let _ = self
+            .condvar
+            .wait_timeout_while(guard, timeout, |current| *current == generation);
+    }
+}
+
+/// Can cancel a `Response` from another thread
+#[derive(Clone, Debug)]
+pub struct CancelHandle {
+    pub cancelled: Arc<AtomicBool>,
+    pub signal: Arc<Signal>,
+}
+
+impl CancelHandle {
+    pub fn new(signal: Arc<Signal>) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            signal,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.signal.notify();
+    }
 
-use super::TConnection;
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResponseError {
+    Timeout,
+    Cancelled,
+    /// The answer was taken by someone else, like `has_new`
+    Vanished,
+}
 
 pub struct Response<T, R> {
     pub connection: T,
     pub packets: Packets,
     pub fn_has: fn(&T, &Packets) -> bool,
-    pub fn_get: fn(T, Packets) -> R,
+    pub fn_get: fn(&T, &Packets) -> Option<R>,
+    pub cancel: CancelHandle,
 }
 
 impl<T, R> Response<T, R> {
@@ -22,11 +142,65 @@ impl<T, R> Response<T, R> {
         (self.fn_has)(&self.connection, &self.packets)
     }
 
-    pub fn get(self) -> R {
-        while !self.has() {
-            std::thread::sleep(Duration::from_millis(0));
+    /// Takes the answer if it was received
+    pub fn take(&self) -> Option<R> {
+        (self.fn_get)(&self.connection, &self.packets)
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Blocks until the answer is received
+    /// Fails if the response was cancelled or the answer was taken by someone else
+    pub fn get(self) -> Result<R, ResponseError> {
+        self.wait(None)
+    }
+
+    pub fn get_timeout(self, timeout: Duration) -> Result<R, ResponseError> {
+        self.wait(Some(timeout))
+    }
+
+    /// Does not block, returns the response back if the answer is not here yet
+    #[allow(clippy::result_large_err)]
+    pub fn try_get(self) -> Result<R, Self> {
+        if self.has() {
+            if let Some(res) = self.take() {
+                return Ok(res);
+            }
+        }
+        Err(self)
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> Result<R, ResponseError> {
+        let start = SystemTime::now();
+
+        loop {
+            let generation = self.cancel.signal.generation();
+
+            if self.cancel.is_cancelled() {
+                return Err(ResponseError::Cancelled);
+            }
+
+            if self.has() {
+                return self.take().ok_or(ResponseError::Vanished);
+            }
+
+            let mut wait = if self.cancel.signal.has_socket() {
+                STEP_INTERVAL
+            } else {
+                POLL_INTERVAL
+            };
+            if let Some(timeout) = timeout {
+                let elapsed = start.elapsed().unwrap_or_default();
+                if elapsed >= timeout {
+                    return Err(ResponseError::Timeout);
+                }
+                wait = wait.min(timeout - elapsed);
+            }
+
+            self.cancel.signal.wait(generation, wait);
         }
-        (self.fn_get)(self.connection, self.packets)
     }
 }
 
@@ -41,10 +215,15 @@ pub struct NewRequest {
     pub connection: Box<dyn TConnection>,
     pub from: Adress,
     pub secret: String,
+    /// The relay checked that `from` is held by its `Identity`
+    pub verified: bool,
 }
 
 impl NewRequest {
-    pub fn accept(self, accept: bool) -> Response<Box<dyn TConnection>, NewRequestFinal> {
+    pub fn accept(
+        self,
+        accept: bool,
+    ) -> Response<Box<dyn TConnection>, Result<NewRequestFinal, Error>> {
         self.connection.request_response(&self.from, accept)
     }
 }
@@ -54,11 +233,25 @@ pub struct NewRequestResponse {
     pub from: Adress,
     pub accept: bool,
     pub secret: String,
+    /// Allows `add_socket` for this connection
+    pub port_token: Vec<u8>,
 }
 
 impl NewRequestResponse {
     pub fn add_socket(&self, socket: &Socket) -> RegisterResponse {
-        self.connection.add_socket(socket)
+        self.connection.add_socket(socket, &self.port_token)
+    }
+
+    /// Use if the NAT is symmetric, see `TConnection::add_socket_with_probes`
+    pub fn add_socket_with_probes(&self, socket: &Socket, probes: usize) -> RegisterResponse {
+        self.connection
+            .add_socket_with_probes(socket, &self.port_token, probes)
+    }
+
+    /// Use for `ConnectOn::connect_ice`, see `TConnection::add_socket_with_candidates`
+    pub fn add_socket_with_candidates(&self, socket: &Socket) -> RegisterResponse {
+        self.connection
+            .add_socket_with_candidates(socket, &self.port_token)
     }
 
     /// `time_offset` should be in nanosecconds
@@ -66,7 +259,7 @@ impl NewRequestResponse {
         self,
         accept: bool,
         time_offset: Option<u128>,
-    ) -> Response<Box<dyn TConnection>, ConnectOn> {
+    ) -> Response<Box<dyn TConnection>, Result<ConnectOn, Error>> {
         self.connection
             .request_final(&self.from, accept, time_offset)
     }
@@ -76,11 +269,25 @@ pub struct NewRequestFinal {
     pub connection: Box<dyn TConnection>,
     pub from: Adress,
     pub accept: bool,
+    /// Allows `add_socket` for this connection
+    pub port_token: Vec<u8>,
 }
 
 impl NewRequestFinal {
     pub fn add_socket(&self, socket: &Socket) -> RegisterResponse {
-        self.connection.add_socket(socket)
+        self.connection.add_socket(socket, &self.port_token)
+    }
+
+    /// Use if the NAT is symmetric, see `TConnection::add_socket_with_probes`
+    pub fn add_socket_with_probes(&self, socket: &Socket, probes: usize) -> RegisterResponse {
+        self.connection
+            .add_socket_with_probes(socket, &self.port_token, probes)
+    }
+
+    /// Use for `ConnectOn::connect_ice`, see `TConnection::add_socket_with_candidates`
+    pub fn add_socket_with_candidates(&self, socket: &Socket) -> RegisterResponse {
+        self.connection
+            .add_socket_with_candidates(socket, &self.port_token)
     }
 }
 
@@ -90,6 +297,16 @@ pub struct ConnectOn {
     pub to: String,
     pub port: u16,
     pub time: u128,
+    /// Token for `connect_or_relay`, empty if the relay does not forward
+    pub relay: Vec<u8>,
+    /// The advertised `NatType` of the peer
+    pub nat: NatType,
+    /// False if the relay expects hole punching to fail, `connect_or_relay` then relays directly
+    pub punch: bool,
+    /// Ports of the symmetric NAT of the peer that `connect` also sends to
+    pub predicted: Vec<u16>,
+    /// Adresses of the peer for `connect_ice`, empty if the peer registered none
+    pub candidates: Vec<Candidate>,
 }
 
 impl std::fmt::Debug for ConnectOn {
@@ -99,6 +316,10 @@ impl std::fmt::Debug for ConnectOn {
             .field("to", &self.to)
             .field("port", &self.port)
             .field("time", &self.time)
+            .field("nat", &self.nat)
+            .field("punch", &self.punch)
+            .field("predicted", &self.predicted)
+            .field("candidates", &self.candidates)
             .finish()
     }
 }
@@ -106,6 +327,7 @@ impl std::fmt::Debug for ConnectOn {
 #[derive(Debug)]
 pub enum RegisterResponse {
     Success { port: u16 },
+    Refused(Error),
     Error,
 }
 
@@ -116,6 +338,14 @@ pub enum ConnectOnError {
     TimoutIsLesTheResend,
     StageOneFailed,
     StageTwoFailed,
+    /// Hole punching failed and the relay does not forward
+    NoRelay,
+    RelayRefused(Error),
+    RelayTimeout,
+    /// No pair of candidates answered the connectivity checks
+    ChecksFailed,
+    /// The blocking task of a async connect panicked
+    TaskFailed,
 }
 
 #[derive(Debug)]
@@ -124,6 +354,8 @@ pub struct Conn {
     pub fd: RawSock,
     pub socket: Socket,
     pub addr: SocketAddr,
+    /// `addr` is a port on the relay that forwards to the peer
+    pub relayed: bool,
 }
 
 impl Conn {
@@ -170,99 +402,297 @@ impl ConnectOn {
         resend: Duration,
         socket: Socket,
     ) -> Result<Conn, ConnectOnError> {
-        if timeout < resend {
-            return Err(ConnectOnError::TimoutIsLesTheResend);
+        hole_punch(
+            &self.to,
+            &self.predicted,
+            self.port,
+            self.time,
+            timeout,
+            resend,
+            socket,
+        )
+    }
+
+    /// Same as `connect` but if hole punching fails the datagrams go through the relay
+    /// and `Conn::relayed` is true, the relay needs a `RelayQuota` for it
+    /// Both peers should call this, until both are bound the relay drops the datagrams
+    pub fn connect_or_relay(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<Conn, ConnectOnError> {
+        let connection = self.connection.c();
+        let token = self.relay.clone();
+
+        if self.punch || token.is_empty() {
+            match self.connect(timeout, resend, socket) {
+                Err(ConnectOnError::StageOneFailed | ConnectOnError::StageTwoFailed) => {}
+                res => return res,
+            }
         }
+        relay_connect(&*connection, &token, timeout, resend)
+    }
+}
 
-        let addr = self.to.to_socket_addrs().unwrap().next().unwrap();
-        let sock_addr = SockAddr::from(addr);
+/// Asks the relay of `connection` for the relayed port of `token`, the `relay` of a `ConnectOn`
+/// and binds a new UDP socket to it
+pub fn relay_connect(
+    connection: &dyn TConnection,
+    token: &[u8],
+    timeout: Duration,
+    resend: Duration,
+) -> Result<Conn, ConnectOnError> {
+    if token.is_empty() {
+        return Err(ConnectOnError::NoRelay);
+    }
 
-        let fd = socket.into_raw();
-        let mut conn = Conn {
-            fd,
-            port: self.port,
-            socket: Socket::from_raw(fd),
-            addr,
-        };
+    let port = match connection.relay(token).get_timeout(timeout) {
+        Ok(Ok(port)) => port,
+        Ok(Err(error)) => return Err(ConnectOnError::RelayRefused(error)),
+        Err(_) => return Err(ConnectOnError::RelayTimeout),
+    };
+    let mut relay = connection.read().unwrap().adress;
+    relay.set_port(port);
 
-        let Ok(_) = conn.set_nonblocking(true) else {return Err(ConnectOnError::CannotSetNonBlocking)};
-        let _ = conn.set_read_timeout(Some(resend));
-        let _ = conn.set_write_timeout(Some(resend));
-        conn.set_ttl(3600);
+    relay_bind(relay, token, timeout, resend)
+}
 
-        while SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-            < self.time
-        {}
+/// Binds a new UDP socket to the relayed port `relay` with the `token` of the `ConnectOn`
+/// timeout need to be bigger then resend
+pub fn relay_bind(
+    relay: SocketAddr,
+    token: &[u8],
+    timeout: Duration,
+    resend: Duration,
+) -> Result<Conn, ConnectOnError> {
+    if timeout < resend {
+        return Err(ConnectOnError::TimoutIsLesTheResend);
+    }
 
-        println!("Start");
+    let Ok(socket) = udp_socket(relay) else {
+        return Err(ConnectOnError::CannotBind);
+    };
+    let Ok(_) = socket.connect(&SockAddr::from(relay)) else {
+        return Err(ConnectOnError::CannotBind);
+    };
+    let Ok(_) = socket.set_nonblocking(true) else {
+        return Err(ConnectOnError::CannotSetNonBlocking);
+    };
+
+    let mut bind = vec![RELAY_BIND];
+    bind.extend_from_slice(token);
+
+    let time = SystemTime::now();
+    let mut time_send: Option<SystemTime> = None;
+    let mut buffer = [0; 256];
+    loop {
+        if time.elapsed().unwrap_or_default() > timeout {
+            return Err(ConnectOnError::RelayTimeout);
+        }
 
-        let time = SystemTime::now();
-        let mut time_send = time;
+        if time_send.is_none_or(|time| time.elapsed().unwrap_or_default() > resend) {
+            time_send = Some(SystemTime::now());
+            let _ = socket.send(&bind);
+        }
 
-        let mut buffer = [MaybeUninit::new(0); 4];
-        let message = [1, 4, 21, 6];
+        // the relay echoes the token back
+        if let Ok(len) = (&socket).read(&mut buffer) {
+            if buffer[0..len] == bind[..] {
+                break;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
 
-        let _ = conn.send_to(&message, &sock_addr);
+    let fd = socket.into_raw();
+    Ok(Conn {
+        port: relay.port(),
+        fd,
+        socket: Socket::from_raw(fd),
+        addr: relay,
+        relayed: true,
+    })
+}
 
-        println!("sock: {addr:?}");
+/// Connects `socket` to `to` by sending to each other at the same `time`
+/// timeout need to be bigger then resend
+/// `predicted` are other ports of the peer that are tried with `to`,
+/// the first port that answers is used
+pub fn hole_punch(
+    to: &str,
+    predicted: &[u16],
+    port: u16,
+    time: u128,
+    timeout: Duration,
+    resend: Duration,
+    socket: Socket,
+) -> Result<Conn, ConnectOnError> {
+    if timeout < resend {
+        return Err(ConnectOnError::TimoutIsLesTheResend);
+    }
 
-        loop {
-            if time.elapsed().unwrap() > timeout {
-                return Err(ConnectOnError::StageOneFailed);
-            }
+    let addr = to.to_socket_addrs().unwrap().next().unwrap();
+    let sock_addr = SockAddr::from(addr);
+    let predicted: Vec<SockAddr> = predicted
+        .iter()
+        .filter(|port| **port != addr.port())
+        .map(|port| SockAddr::from(SocketAddr::new(addr.ip(), *port)))
+        .collect();
+
+    let fd = socket.into_raw();
+    let mut conn = Conn {
+        fd,
+        port,
+        socket: Socket::from_raw(fd),
+        addr,
+        relayed: false,
+    };
+
+    let Ok(_) = conn.set_nonblocking(true) else {return Err(ConnectOnError::CannotSetNonBlocking)};
+    let _ = conn.set_read_timeout(Some(resend));
+    let _ = conn.set_write_timeout(Some(resend));
+    let _ = conn.set_ttl(3600);
+
+    while SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+        < time
+    {}
+
+    println!("Start");
+
+    let time = SystemTime::now();
+    let mut time_send = time;
+
+    let mut buffer = [MaybeUninit::new(0); 4];
+    let message = [1, 4, 21, 6];
+
+    let _ = conn.send_to(&message, &sock_addr);
+    for predicted in predicted.iter() {
+        let _ = conn.send_to(&message, predicted);
+    }
+
+    println!("sock: {addr:?}");
 
-            if let Ok((len, from)) = conn.recv_from(&mut buffer) {
-                if let Some(from) = from.as_socket() {
-                    println!("From: {:?}", from);
-                    if from == sock_addr.as_socket().unwrap()
-                        && unsafe {
-                            std::mem::transmute::<&[MaybeUninit<u8>], &[u8]>(&buffer[0..len])
-                        } == message
-                    {
-                        conn.connect(&sock_addr).unwrap();
-                        println!("First stage succesful!");
-                        break;
-                    }
+    loop {
+        if time.elapsed().unwrap() > timeout {
+            return Err(ConnectOnError::StageOneFailed);
+        }
+
+        if let Ok((len, from)) = conn.recv_from(&mut buffer) {
+            if let Some(from) = from.as_socket() {
+                println!("From: {:?}", from);
+                // IPv6 adresses can come back with a scope id, so only ip and port are compared
+                // a symmetric NAT can answer from a port that was not predicted
+                if from.ip() == addr.ip()
+                    && (from.port() == addr.port() || !predicted.is_empty())
+                    && unsafe {
+                        std::mem::transmute::<&[MaybeUninit<u8>], &[u8]>(&buffer[0..len])
+                    } == message
+                {
+                    conn.addr = SocketAddr::new(addr.ip(), from.port());
+                    conn.connect(&SockAddr::from(conn.addr)).unwrap();
+                    println!("First stage succesful!");
+                    break;
                 }
             }
+        }
 
-            if time_send.elapsed().unwrap() > resend {
-                time_send = SystemTime::now();
-                let _ = conn.send_to(&message, &sock_addr);
+        if time_send.elapsed().unwrap() > resend {
+            time_send = SystemTime::now();
+            let _ = conn.send_to(&message, &sock_addr);
+            for predicted in predicted.iter() {
+                let _ = conn.send_to(&message, predicted);
             }
         }
+    }
 
-        let message = [21, 20, 20, 21];
-        let time = SystemTime::now();
-        let mut time_send = time;
-        let _ = conn.send(&message);
-
-        loop {
-            if time.elapsed().unwrap() > timeout {
-                return Err(ConnectOnError::StageTwoFailed);
-            }
+    let message = [21, 20, 20, 21];
+    let time = SystemTime::now();
+    let mut time_send = time;
+    let _ = conn.send(&message);
 
-            if let Ok(len) = conn.recv(&mut buffer) {
-                let buffer =
-                    unsafe { std::mem::transmute::<&[MaybeUninit<u8>], &[u8]>(&buffer[0..len]) };
-                if buffer == message {
-                    break;
-                }
-            }
+    loop {
+        if time.elapsed().unwrap() > timeout {
+            return Err(ConnectOnError::StageTwoFailed);
+        }
 
-            if time_send.elapsed().unwrap() > resend {
-                time_send = SystemTime::now();
-                let _ = conn.send(&message);
+        if let Ok(len) = conn.recv(&mut buffer) {
+            let buffer =
+                unsafe { std::mem::transmute::<&[MaybeUninit<u8>], &[u8]>(&buffer[0..len]) };
+            if buffer == message {
+                break;
             }
         }
 
-        Ok(conn)
+        if time_send.elapsed().unwrap() > resend {
+            time_send = SystemTime::now();
+            let _ = conn.send(&message);
+        }
     }
+
+    Ok(conn)
 }
 
 pub struct SearchResponse {
     pub adresses: Vec<Adress>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use socket2::{Domain, Protocol, Type};
+
+    use super::*;
+
+    fn response(signal: Arc<Signal>) -> Response<(), ()> {
+        Response {
+            connection: (),
+            packets: Packets::Tick { session: 0 },
+            fn_has: |_, _| false,
+            fn_get: |_, _| None,
+            cancel: CancelHandle::new(signal),
+        }
+    }
+
+    #[test]
+    fn cancelled_get_is_an_error() {
+        let response = response(Arc::new(Signal::default()));
+        let cancel = response.cancel_handle();
+        let thread = std::thread::spawn(move || response.get());
+
+        std::thread::sleep(Duration::from_millis(20));
+        cancel.cancel();
+        assert_eq!(thread.join().unwrap(), Err(ResponseError::Cancelled));
+    }
+
+    #[test]
+    fn vanished_answer_is_an_error() {
+        let mut response = response(Arc::new(Signal::default()));
+        response.fn_has = |_, _| true;
+        assert_eq!(response.get(), Err(ResponseError::Vanished));
+    }
+
+    #[test]
+    fn signal_wakes_up_on_readable_socket() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        let adress: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        socket.bind(&adress.into()).unwrap();
+        let signal = Signal::with_socket(&socket).unwrap();
+        let to = socket.local_addr().unwrap();
+
+        let start = Instant::now();
+        signal.wait(signal.generation(), Duration::from_millis(50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let sender = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sender.send_to(&[1], &to).unwrap();
+
+        let start = Instant::now();
+        signal.wait(signal.generation(), Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
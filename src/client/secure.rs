@@ -0,0 +1,501 @@
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use socket2::Socket;
+
+use crate::common::{
+    adress::Adress,
+    identity::{dh_public, Identity},
+};
+
+use super::{
+    response::{Conn, ConnectOn, ConnectOnError},
+    stream::recv,
+};
+
+/// `psk0` is the SHA-256 of the secret
+pub const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+/// Same as `NOISE_PARAMS` but the static keys are the `Identity` of both peers
+pub const NOISE_IDENTITY_PARAMS: &str = "Noise_KKpsk0_25519_ChaChaPoly_BLAKE2s";
+/// How often a handshake message is sent again
+pub const HANDSHAKE_RESEND: Duration = Duration::from_millis(250);
+
+/// Biggest UDP payload
+const MAX_DATAGRAM: usize = 65507;
+/// Kind and nonce before every message
+const HEADER_LEN: usize = 9;
+const TAG_LEN: usize = 16;
+/// Biggest message that `SecureConn::send` accepts
+pub const MAX_PAYLOAD: usize = MAX_DATAGRAM - HEADER_LEN - TAG_LEN;
+
+const HANDSHAKE_INIT: u8 = 0;
+const HANDSHAKE_RESPONSE: u8 = 1;
+const DATA: u8 = 2;
+
+#[derive(Debug)]
+pub enum SecureError {
+    Connect(ConnectOnError),
+    Io(io::Error),
+    Noise(snow::Error),
+    Timeout,
+    TooLarge,
+    /// The local peer has no `Identity` or the remote adress is not one
+    NotIdentity,
+}
+
+impl From<io::Error> for SecureError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<snow::Error> for SecureError {
+    fn from(value: snow::Error) -> Self {
+        Self::Noise(value)
+    }
+}
+
+impl From<ConnectOnError> for SecureError {
+    fn from(value: ConnectOnError) -> Self {
+        Self::Connect(value)
+    }
+}
+
+/// Remembers the last 64 nonces so a message cannot be replayed
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: u64,
+    bitmap: u64,
+    any: bool,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, nonce: u64) -> bool {
+        if !self.any || nonce > self.highest {
+            return true;
+        }
+        let offset = self.highest - nonce;
+        offset < 64 && self.bitmap & (1 << offset) == 0
+    }
+
+    fn insert(&mut self, nonce: u64) {
+        if !self.any {
+            self.any = true;
+            self.highest = nonce;
+            self.bitmap = 1;
+        } else if nonce > self.highest {
+            let shift = nonce - self.highest;
+            self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.highest = nonce;
+        } else {
+            self.bitmap |= 1 << (self.highest - nonce);
+        }
+    }
+}
+
+/// Encrypted and authenticated datagrams over a punched `Conn`
+/// Messages can still be lost or reordered like on UDP, but not changed or replayed
+pub struct SecureConn {
+    pub conn: Conn,
+    transport: StatelessTransportState,
+    /// Nonce of the next sent message
+    nonce: u64,
+    replay: ReplayWindow,
+    /// The second handshake message, sent again if the initiator did not get it
+    response: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl std::fmt::Debug for SecureConn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureConn")
+            .field("conn", &self.conn)
+            .field("nonce", &self.nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectOn {
+    /// Same as `connect` followed by `SecureConn::connect`
+    /// `secret` has to be known by both peers, like the `secret` of the `Request`
+    pub fn connect_secure(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+        secret: &str,
+    ) -> Result<SecureConn, SecureError> {
+        let local = self.connection.adress();
+        let remote = self.adress.clone();
+        let conn = self.connect(timeout, resend, socket)?;
+        SecureConn::connect(conn, &local, &remote, secret, timeout)
+    }
+
+    /// Same as `connect` followed by `SecureConn::connect_with_identity`
+    /// with the `Identity` of the connection, the peer has to do the same
+    pub fn connect_secure_with_identity(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+        secret: &str,
+    ) -> Result<SecureConn, SecureError> {
+        let Some(identity) = self.connection.read().unwrap().info.identity.clone() else {
+            return Err(SecureError::NotIdentity);
+        };
+        let remote = self.adress.clone();
+        let conn = self.connect(timeout, resend, socket)?;
+        SecureConn::connect_with_identity(conn, &identity, &remote, secret, timeout)
+    }
+}
+
+impl SecureConn {
+    /// Runs the handshake keyed with `secret`, only works between `local` and `remote`
+    /// The peer with the smaller adress is the initiator
+    /// The relay also sees the `secret` of a `Request`, so a secret exchanged
+    /// in another way should be used if the relay is not trusted
+    /// The adresses are only in the prologue so they are not authenticated, anyone that knows
+    /// `secret` can be the peer, use `connect_with_identity` to prove them
+    pub fn connect(
+        conn: Conn,
+        local: &Adress,
+        remote: &Adress,
+        secret: &str,
+        timeout: Duration,
+    ) -> Result<Self, SecureError> {
+        let initiator = local < remote;
+        let prologue = prologue(b"relay-man secure v1", local, remote);
+        Self::handshake(conn, initiator, secret.as_bytes(), &prologue, timeout)
+    }
+
+    /// Same as `connect` but both peers prove that they hold the `Identity` of their adress,
+    /// the handshake fails if the peer is not `remote`
+    pub fn connect_with_identity(
+        conn: Conn,
+        identity: &Identity,
+        remote: &Adress,
+        secret: &str,
+        timeout: Duration,
+    ) -> Result<Self, SecureError> {
+        let Some(remote_key) = dh_public(remote) else {
+            return Err(SecureError::NotIdentity);
+        };
+        let local = identity.adress();
+        let initiator = &local < remote;
+        let prologue = prologue(b"relay-man secure identity v1", &local, remote);
+
+        let psk: [u8; 32] = Sha256::digest(secret.as_bytes()).into();
+        let secret_key = identity.dh_secret();
+        let builder = Builder::new(NOISE_IDENTITY_PARAMS.parse()?)
+            .psk(0, &psk)
+            .prologue(&prologue)
+            .local_private_key(&secret_key)
+            .remote_public_key(&remote_key);
+        let noise = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+
+        Self::run_handshake(conn, initiator, noise, timeout)
+    }
+
+    /// Runs the handshake, both peers need the same `secret` and `prologue`
+    /// and only one of them can be the `initiator`
+    pub fn handshake(
+        conn: Conn,
+        initiator: bool,
+        secret: &[u8],
+        prologue: &[u8],
+        timeout: Duration,
+    ) -> Result<Self, SecureError> {
+        let psk: [u8; 32] = Sha256::digest(secret).into();
+        let builder = Builder::new(NOISE_PARAMS.parse()?)
+            .psk(0, &psk)
+            .prologue(prologue);
+        let noise = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+
+        Self::run_handshake(conn, initiator, noise, timeout)
+    }
+
+    fn run_handshake(
+        conn: Conn,
+        initiator: bool,
+        mut noise: HandshakeState,
+        timeout: Duration,
+    ) -> Result<Self, SecureError> {
+        let start = Instant::now();
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let mut payload = vec![0; MAX_DATAGRAM];
+        let mut response = None;
+
+        let mut init = Vec::new();
+        let mut last_send: Option<Instant> = None;
+        if initiator {
+            init = handshake_message(&mut noise, HANDSHAKE_INIT)?;
+        }
+
+        loop {
+            if start.elapsed() > timeout {
+                return Err(SecureError::Timeout);
+            }
+
+            if initiator && last_send.is_none_or(|time| time.elapsed() > HANDSHAKE_RESEND) {
+                let _ = conn.send(&init);
+                last_send = Some(Instant::now());
+            }
+
+            let Some(len) = recv(&conn, &mut buffer)? else {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            };
+            if len < 1 {
+                continue;
+            }
+
+            // a failed read leaves the state as it was, so anyone that can send to the socket
+            // cannot break the handshake by sending garbage
+            match buffer[0] {
+                HANDSHAKE_RESPONSE
+                    if initiator && noise.read_message(&buffer[1..len], &mut payload).is_ok() =>
+                {
+                    break;
+                }
+                HANDSHAKE_INIT
+                    if !initiator && noise.read_message(&buffer[1..len], &mut payload).is_ok() =>
+                {
+                    let message = handshake_message(&mut noise, HANDSHAKE_RESPONSE)?;
+                    let _ = conn.send(&message);
+                    response = Some(message);
+                    break;
+                }
+                // left overs from the hole punching
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            conn,
+            transport: noise.into_stateless_transport_mode()?,
+            nonce: 0,
+            replay: ReplayWindow::default(),
+            response,
+            buffer,
+        })
+    }
+
+    /// Sends `data` as one datagram, at most `MAX_PAYLOAD`
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, SecureError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(SecureError::TooLarge);
+        }
+
+        let nonce = self.nonce;
+        self.nonce += 1;
+
+        let mut message = vec![0; HEADER_LEN + data.len() + TAG_LEN];
+        message[0] = DATA;
+        message[1..HEADER_LEN].copy_from_slice(&nonce.to_be_bytes());
+        let len = self
+            .transport
+            .write_message(nonce, data, &mut message[HEADER_LEN..])?;
+        self.conn.send(&message[0..HEADER_LEN + len])?;
+
+        Ok(data.len())
+    }
+
+    /// Receives one message into `buffer`
+    /// Returns `Ok(None)` if there is nothing, the socket is non blocking
+    /// Messages that cannot be authenticated are dropped
+    pub fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, SecureError> {
+        loop {
+            let Some(len) = recv(&self.conn, &mut self.buffer)? else {
+                return Ok(None);
+            };
+            if len < 1 {
+                continue;
+            }
+
+            match self.buffer[0] {
+                HANDSHAKE_INIT => {
+                    if let Some(response) = &self.response {
+                        let _ = self.conn.send(response);
+                    }
+                }
+                DATA if len >= HEADER_LEN + TAG_LEN => {
+                    if buffer.len() < len - HEADER_LEN - TAG_LEN {
+                        return Err(SecureError::TooLarge);
+                    }
+
+                    let mut nonce = [0; 8];
+                    nonce.copy_from_slice(&self.buffer[1..HEADER_LEN]);
+                    let nonce = u64::from_be_bytes(nonce);
+                    if !self.replay.is_new(nonce) {
+                        continue;
+                    }
+
+                    let Ok(len) =
+                        self.transport
+                            .read_message(nonce, &self.buffer[HEADER_LEN..len], buffer)
+                    else {
+                        continue;
+                    };
+
+                    self.replay.insert(nonce);
+                    // the initiator could only send this after it got the response
+                    self.response = None;
+                    return Ok(Some(len));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Blocks until a message was received or `timeout` elapsed
+    pub fn recv_timeout(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, SecureError> {
+        let start = Instant::now();
+        loop {
+            if let Some(len) = self.recv(buffer)? {
+                return Ok(len);
+            }
+            if start.elapsed() > timeout {
+                return Err(SecureError::Timeout);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn handshake_message(noise: &mut HandshakeState, kind: u8) -> Result<Vec<u8>, SecureError> {
+    let mut message = vec![0; MAX_DATAGRAM];
+    message[0] = kind;
+    let len = noise.write_message(&[], &mut message[1..])?;
+    message.truncate(1 + len);
+    Ok(message)
+}
+
+/// Both adresses, the one of the initiator first
+fn prologue(label: &[u8], local: &Adress, remote: &Adress) -> Vec<u8> {
+    let (first, second) = if local < remote {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+
+    let mut prologue = label.to_vec();
+    for adress in [first, second] {
+        prologue.extend((adress.len() as u64).to_be_bytes());
+        prologue.extend(adress);
+    }
+    prologue
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::client::testing::{conn, pair, socket};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn exchange(a: &mut SecureConn, b: &mut SecureConn) {
+        let mut buffer = [0; 16];
+        a.send(b"ping").unwrap();
+        let len = b.recv_timeout(&mut buffer, TIMEOUT).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+        b.send(b"pong").unwrap();
+        let len = a.recv_timeout(&mut buffer, TIMEOUT).unwrap();
+        assert_eq!(&buffer[..len], b"pong");
+    }
+
+    #[test]
+    fn garbage_response_does_not_restart_the_handshake() {
+        let initiator = socket();
+        let responder = socket();
+        let inject = initiator.try_clone().unwrap();
+        let path = responder.try_clone().unwrap();
+        let initiator = conn(initiator, &responder);
+        let responder = conn(responder, &initiator.socket);
+
+        let thread = thread::spawn(move || {
+            SecureConn::handshake(initiator, true, b"secret", b"test", TIMEOUT)
+        });
+
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let start = Instant::now();
+        let len = loop {
+            if let Some(len) = recv(&responder, &mut buffer).unwrap() {
+                break len;
+            }
+            assert!(start.elapsed() < TIMEOUT);
+            thread::sleep(Duration::from_millis(1));
+        };
+        let init = buffer[..len].to_vec();
+
+        // a off path attacker answers first, then the responder gets the first message
+        path.send(&[HANDSHAKE_RESPONSE; 49]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        while recv(&responder, &mut buffer).unwrap().is_some() {}
+        inject.send(&init).unwrap();
+
+        let mut responder =
+            SecureConn::handshake(responder, false, b"secret", b"test", TIMEOUT).unwrap();
+        let mut initiator = thread.join().unwrap().unwrap();
+        exchange(&mut initiator, &mut responder);
+    }
+
+    #[test]
+    fn identities_are_proven() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let (adress_a, adress_b) = (a.adress(), b.adress());
+        let (conn_a, conn_b) = pair();
+
+        let thread = thread::spawn(move || {
+            SecureConn::connect_with_identity(conn_b, &b, &adress_a, "secret", TIMEOUT)
+        });
+        let mut secure_a =
+            SecureConn::connect_with_identity(conn_a, &a, &adress_b, "secret", TIMEOUT).unwrap();
+        let mut secure_b = thread.join().unwrap().unwrap();
+        exchange(&mut secure_a, &mut secure_b);
+    }
+
+    #[test]
+    fn wrong_identity_fails() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        let other = Identity::generate().adress();
+        let adress_b = b.adress();
+        let (conn_a, conn_b) = pair();
+        let timeout = Duration::from_millis(500);
+
+        // `b` expects `other` but `a` is on the other side
+        let thread = thread::spawn(move || {
+            SecureConn::connect_with_identity(conn_b, &b, &other, "secret", timeout)
+        });
+        let secure_a = SecureConn::connect_with_identity(conn_a, &a, &adress_b, "secret", timeout);
+        assert!(matches!(secure_a, Err(SecureError::Timeout)));
+        assert!(matches!(thread.join().unwrap(), Err(SecureError::Timeout)));
+    }
+
+    #[test]
+    fn adress_has_to_be_a_identity() {
+        let (conn, _) = pair();
+        let secure =
+            SecureConn::connect_with_identity(conn, &Identity::generate(), &vec![1], "", TIMEOUT);
+        assert!(matches!(secure, Err(SecureError::NotIdentity)));
+    }
+}
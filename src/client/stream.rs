@@ -0,0 +1,701 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use socket2::Socket;
+
+use super::response::{Conn, ConnectOn, ConnectOnError};
+
+/// Biggest payload of a segment, small enough to not be fragmented
+pub const MSS: usize = 1200;
+/// How long `read`, `write` and `flush` wait without hearing from the peer
+pub const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes that can be buffered on each side
+pub const BUFFER_SIZE: usize = 1024 * 1024;
+
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+const DATA: u8 = 0x40;
+const ACK: u8 = 0x41;
+/// kind, seq and fin
+const DATA_HEADER: usize = 10;
+/// kind, ack and window
+const ACK_LEN: usize = 13;
+
+#[derive(Debug)]
+struct Segment {
+    seq: u64,
+    data: Vec<u8>,
+    fin: bool,
+    sent: Instant,
+    /// Retransmitted segments are not used to measure the rtt
+    retransmitted: bool,
+}
+
+/// The state of a reliable stream without the socket
+/// Datagrams that have to be sent are pushed to `out`
+/// so it can be used by `PeerStream` and by the channels of a `Mux`
+#[derive(Debug)]
+pub(crate) struct Reliable {
+    send_buffer: VecDeque<u8>,
+    /// Sent but not acked, ordered by `seq`
+    unacked: VecDeque<Segment>,
+    next_seq: u64,
+    fin_queued: bool,
+    fin_sent: bool,
+    /// In segments
+    cwnd: f64,
+    ssthresh: f64,
+    /// In segments, from the last ack of the peer
+    peer_window: u32,
+    dup_acks: u32,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+
+    readable: VecDeque<u8>,
+    out_of_order: BTreeMap<u64, (Vec<u8>, bool)>,
+    out_of_order_len: usize,
+    recv_next: u64,
+    fin_received: bool,
+    advertised: u32,
+}
+
+impl Default for Reliable {
+    fn default() -> Self {
+        Self {
+            send_buffer: VecDeque::new(),
+            unacked: VecDeque::new(),
+            next_seq: 0,
+            fin_queued: false,
+            fin_sent: false,
+            cwnd: 2.0,
+            ssthresh: f64::MAX,
+            peer_window: (BUFFER_SIZE / MSS) as u32,
+            dup_acks: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            readable: VecDeque::new(),
+            out_of_order: BTreeMap::new(),
+            out_of_order_len: 0,
+            recv_next: 0,
+            fin_received: false,
+            advertised: (BUFFER_SIZE / MSS) as u32,
+        }
+    }
+}
+
+impl Reliable {
+    /// Bytes that were written but not acked by the peer
+    pub fn pending(&self) -> usize {
+        self.send_buffer.len() + self.unacked.iter().map(|s| s.data.len()).sum::<usize>()
+    }
+
+    /// If everything that was written was acked
+    pub fn is_flushed(&self) -> bool {
+        self.send_buffer.is_empty()
+            && self.unacked.is_empty()
+            && (!self.fin_queued || self.fin_sent)
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.fin_queued
+    }
+
+    /// If the peer ended the stream and everything was read
+    pub fn is_finished(&self) -> bool {
+        self.fin_received && self.readable.is_empty()
+    }
+
+    pub fn shutdown(&mut self) {
+        self.fin_queued = true;
+    }
+
+    /// Buffers as much of `buf` as fits, 0 if the buffer is full
+    pub fn write(&mut self, buf: &[u8]) -> usize {
+        let space = BUFFER_SIZE.saturating_sub(self.pending());
+        let len = space.min(buf.len());
+        self.send_buffer.extend(&buf[0..len]);
+        len
+    }
+
+    /// Reads what was received in order, 0 if there is nothing
+    pub fn read(&mut self, buf: &mut [u8], out: &mut Vec<Vec<u8>>) -> usize {
+        let len = buf.len().min(self.readable.len());
+        if len == 0 {
+            return 0;
+        }
+        for (to, from) in buf.iter_mut().zip(self.readable.drain(0..len)) {
+            *to = from;
+        }
+
+        // the peer could wait for space
+        if self.advertised == 0 && self.window() > 0 {
+            self.send_ack(out);
+        }
+        len
+    }
+
+    /// Retransmits and sends what the windows allow
+    pub fn tick(&mut self, out: &mut Vec<Vec<u8>>) {
+        self.retransmit(out);
+        self.send_segments(out);
+    }
+
+    fn window(&self) -> u32 {
+        let used = self.readable.len() + self.out_of_order_len;
+        (BUFFER_SIZE.saturating_sub(used) / MSS) as u32
+    }
+
+    /// Returns false if it was not a datagram of the stream
+    pub fn on_datagram(&mut self, datagram: &[u8], out: &mut Vec<Vec<u8>>) -> bool {
+        match datagram.first() {
+            Some(&DATA) if datagram.len() >= DATA_HEADER => {
+                let seq = read_u64(&datagram[1..9]);
+                let fin = datagram[9] != 0;
+                self.on_data(seq, &datagram[DATA_HEADER..], fin);
+                self.send_ack(out);
+                true
+            }
+            Some(&ACK) if datagram.len() >= ACK_LEN => {
+                let ack = read_u64(&datagram[1..9]);
+                let mut window = [0; 4];
+                window.copy_from_slice(&datagram[9..13]);
+                self.on_ack(ack, u32::from_be_bytes(window), out);
+                true
+            }
+            // left overs from the hole punching
+            _ => false,
+        }
+    }
+
+    fn on_data(&mut self, seq: u64, data: &[u8], fin: bool) {
+        if seq < self.recv_next || self.fin_received {
+            return;
+        }
+
+        let used = self.readable.len() + self.out_of_order_len;
+        if used + data.len() > BUFFER_SIZE || self.out_of_order.contains_key(&seq) {
+            return;
+        }
+
+        if seq > self.recv_next {
+            self.out_of_order_len += data.len();
+            self.out_of_order.insert(seq, (data.to_vec(), fin));
+            return;
+        }
+
+        self.readable.extend(data);
+        self.recv_next += 1;
+        self.fin_received = fin;
+
+        while let Some((data, fin)) = self.out_of_order.remove(&self.recv_next) {
+            self.out_of_order_len -= data.len();
+            self.readable.extend(data);
+            self.recv_next += 1;
+            if fin {
+                self.fin_received = true;
+                break;
+            }
+        }
+    }
+
+    fn on_ack(&mut self, ack: u64, window: u32, out: &mut Vec<Vec<u8>>) {
+        self.peer_window = window;
+
+        let Some(base) = self.unacked.front().map(|segment| segment.seq) else {
+            return;
+        };
+
+        if ack > base {
+            let now = Instant::now();
+            while let Some(segment) = self.unacked.front() {
+                if segment.seq >= ack {
+                    break;
+                }
+                if !segment.retransmitted {
+                    self.on_rtt(now.duration_since(segment.sent));
+                }
+                self.unacked.pop_front();
+
+                // slow start, then congestion avoidance
+                if self.cwnd < self.ssthresh {
+                    self.cwnd += 1.0;
+                } else {
+                    self.cwnd += 1.0 / self.cwnd;
+                }
+            }
+            self.dup_acks = 0;
+        } else if ack == base {
+            self.dup_acks += 1;
+            // fast retransmit
+            if self.dup_acks == 3 {
+                self.ssthresh = (self.cwnd / 2.0).max(2.0);
+                self.cwnd = self.ssthresh;
+                self.resend_front(out);
+            }
+        }
+    }
+
+    /// RFC 6298
+    fn on_rtt(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(rtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn retransmit(&mut self, out: &mut Vec<Vec<u8>>) {
+        let Some(segment) = self.unacked.front() else {
+            return;
+        };
+        if segment.sent.elapsed() < self.rto {
+            return;
+        }
+
+        self.ssthresh = (self.unacked.len() as f64 / 2.0).max(2.0);
+        self.cwnd = 1.0;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.dup_acks = 0;
+        self.resend_front(out)
+    }
+
+    fn resend_front(&mut self, out: &mut Vec<Vec<u8>>) {
+        let Some(segment) = self.unacked.front_mut() else {
+            return;
+        };
+        segment.sent = Instant::now();
+        segment.retransmitted = true;
+        out.push(encode_data(segment));
+    }
+
+    fn send_segments(&mut self, out: &mut Vec<Vec<u8>>) {
+        // at least one segment can be in flight, it is the probe for a closed window
+        let window = (self.cwnd as usize).min(self.peer_window as usize).max(1);
+
+        while self.unacked.len() < window {
+            let fin = self.send_buffer.len() <= MSS && self.fin_queued;
+            if self.send_buffer.is_empty() && (!fin || self.fin_sent) {
+                break;
+            }
+
+            let len = self.send_buffer.len().min(MSS);
+            let segment = Segment {
+                seq: self.next_seq,
+                data: self.send_buffer.drain(0..len).collect(),
+                fin,
+                sent: Instant::now(),
+                retransmitted: false,
+            };
+            self.next_seq += 1;
+            self.fin_sent |= fin;
+
+            out.push(encode_data(&segment));
+            self.unacked.push_back(segment);
+        }
+    }
+
+    fn send_ack(&mut self, out: &mut Vec<Vec<u8>>) {
+        self.advertised = self.window();
+        let mut datagram = Vec::with_capacity(ACK_LEN);
+        datagram.push(ACK);
+        datagram.extend(self.recv_next.to_be_bytes());
+        datagram.extend(self.advertised.to_be_bytes());
+        out.push(datagram);
+    }
+}
+
+/// Reliable ordered byte stream over a punched `Conn`
+/// Segments are acked and retransmitted, the sender respects the window
+/// of the receiver and a Reno like congestion window
+/// Both peers have to use a `PeerStream` on the same `Conn`
+#[derive(Debug)]
+pub struct PeerStream {
+    pub conn: Conn,
+    pub timeout: Duration,
+
+    reliable: Reliable,
+    last_recv: Instant,
+    /// Datagrams that still have to be sent
+    out: Vec<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ConnectOn {
+    /// Same as `connect` but returns a `PeerStream`
+    pub fn connect_stream(
+        self,
+        timeout: Duration,
+        resend: Duration,
+        socket: Socket,
+    ) -> Result<PeerStream, ConnectOnError> {
+        self.connect(timeout, resend, socket).map(PeerStream::new)
+    }
+}
+
+impl PeerStream {
+    pub fn new(conn: Conn) -> Self {
+        let _ = conn.set_nonblocking(true);
+        Self {
+            conn,
+            timeout: STREAM_TIMEOUT,
+            reliable: Reliable::default(),
+            last_recv: Instant::now(),
+            out: Vec::new(),
+            buffer: vec![0; 65536],
+        }
+    }
+
+    /// Sends, receives and retransmits without blocking
+    /// Returns true if something was received
+    /// Needs to be called if the stream is not used with `Read` or `Write`
+    /// so the peer gets its acks
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let mut received = false;
+        while let Some(len) = recv(&self.conn, &mut self.buffer)? {
+            if self
+                .reliable
+                .on_datagram(&self.buffer[0..len], &mut self.out)
+            {
+                received = true;
+                self.last_recv = Instant::now();
+            }
+        }
+
+        self.reliable.tick(&mut self.out);
+        self.send_out()?;
+        Ok(received)
+    }
+
+    /// Sends everything that was written and then the end of the stream
+    /// `read` on the peer returns 0 after it got everything
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.reliable.shutdown();
+        self.flush()
+    }
+
+    /// Bytes that were written but not acked by the peer
+    pub fn pending(&self) -> usize {
+        self.reliable.pending()
+    }
+
+    fn send_out(&mut self) -> io::Result<()> {
+        for datagram in self.out.drain(..) {
+            send(&self.conn, &datagram)?;
+        }
+        Ok(())
+    }
+
+    /// Polls once and sleeps a bit if nothing happened
+    /// Fails if nothing was heard from the peer for `timeout` since `start`,
+    /// the start of the blocking call, so a idle stream does not time out
+    fn wait(&mut self, start: Instant) -> io::Result<()> {
+        let received = self.poll()?;
+        if self.last_recv.max(start).elapsed() > self.timeout {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if !received {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let start = Instant::now();
+        loop {
+            self.poll()?;
+
+            let len = self.reliable.read(buf, &mut self.out);
+            if len > 0 {
+                self.send_out()?;
+                return Ok(len);
+            }
+
+            if self.reliable.is_finished() {
+                return Ok(0);
+            }
+
+            self.wait(start)?;
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.reliable.is_shutdown() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let start = Instant::now();
+        loop {
+            let len = self.reliable.write(buf);
+            if len > 0 {
+                self.poll()?;
+                return Ok(len);
+            }
+
+            self.wait(start)?;
+        }
+    }
+
+    /// Blocks until the peer acked everything
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        self.poll()?;
+        while !self.reliable.is_flushed() {
+            self.wait(start)?;
+        }
+        Ok(())
+    }
+}
+
+/// `Ok(None)` if there is nothing to read
+pub(crate) fn recv(conn: &Conn, buffer: &mut [u8]) -> io::Result<Option<usize>> {
+    match (&conn.socket).read(buffer) {
+        Ok(len) => Ok(Some(len)),
+        Err(err)
+            if err.kind() == io::ErrorKind::WouldBlock
+                || err.kind() == io::ErrorKind::Interrupted
+                || err.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// A lost datagram is like a dropped one, it is sent again later
+pub(crate) fn send(conn: &Conn, datagram: &[u8]) -> io::Result<()> {
+    match conn.send(datagram) {
+        Ok(_) => Ok(()),
+        Err(err)
+            if err.kind() == io::ErrorKind::WouldBlock
+                || err.kind() == io::ErrorKind::Interrupted
+                || err.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn encode_data(segment: &Segment) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(DATA_HEADER + segment.data.len());
+    datagram.push(DATA);
+    datagram.extend(segment.seq.to_be_bytes());
+    datagram.push(segment.fin as u8);
+    datagram.extend(&segment.data);
+    datagram
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buffer = [0; 8];
+    buffer.copy_from_slice(bytes);
+    u64::from_be_bytes(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::client::testing::pair;
+
+    use super::*;
+
+    /// Gives every datagram in `datagrams` to `to`, its answers are in the returned `Vec`
+    fn deliver(datagrams: Vec<Vec<u8>>, to: &mut Reliable) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        for datagram in datagrams {
+            assert!(to.on_datagram(&datagram, &mut out));
+        }
+        out
+    }
+
+    fn read_all(reliable: &mut Reliable) -> Vec<u8> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let len = reliable.read(&mut buffer, &mut Vec::new());
+        buffer.truncate(len);
+        buffer
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn lost_segment_is_retransmitted() {
+        let (mut a, mut b) = (Reliable::default(), Reliable::default());
+        let mut out = Vec::new();
+        a.write(b"hello");
+        a.tick(&mut out);
+        assert_eq!(out.len(), 1);
+        out.clear();
+
+        // nothing is sent again before the rto
+        a.tick(&mut out);
+        assert!(out.is_empty());
+
+        a.rto = Duration::ZERO;
+        a.tick(&mut out);
+        assert_eq!(out.len(), 1);
+        let acks = deliver(out, &mut b);
+        deliver(acks, &mut a);
+
+        assert!(a.is_flushed());
+        assert_eq!(read_all(&mut b), b"hello");
+    }
+
+    #[test]
+    fn reordered_segments_are_read_in_order() {
+        let (mut a, mut b) = (Reliable::default(), Reliable::default());
+        let mut out = Vec::new();
+        let sent = data(MSS * 3);
+        a.cwnd = 10.0;
+        a.write(&sent);
+        a.tick(&mut out);
+        assert_eq!(out.len(), 3);
+
+        out.reverse();
+        let acks = deliver(out, &mut b);
+        // the first two acks are for the segment that is missing
+        assert_eq!(read_u64(&acks[0][1..9]), 0);
+        assert_eq!(read_u64(&acks[2][1..9]), 3);
+        deliver(acks, &mut a);
+
+        assert!(a.is_flushed());
+        assert_eq!(read_all(&mut b), sent);
+    }
+
+    #[test]
+    fn fin_ends_the_stream() {
+        let (mut a, mut b) = (Reliable::default(), Reliable::default());
+        let mut out = Vec::new();
+        a.write(b"bye");
+        a.shutdown();
+        assert!(!a.is_flushed());
+        a.tick(&mut out);
+        assert_eq!(out.len(), 1);
+
+        let acks = deliver(out, &mut b);
+        deliver(acks, &mut a);
+        assert!(a.is_flushed());
+
+        assert!(!b.is_finished());
+        assert_eq!(read_all(&mut b), b"bye");
+        assert!(b.is_finished());
+    }
+
+    #[test]
+    fn zero_window_only_sends_a_probe() {
+        let (mut a, mut b) = (Reliable::default(), Reliable::default());
+        let mut out = Vec::new();
+        a.cwnd = 10.0;
+
+        // the peer has no space
+        let mut ack = vec![ACK];
+        ack.extend(0u64.to_be_bytes());
+        ack.extend(0u32.to_be_bytes());
+        deliver(vec![ack], &mut a);
+
+        a.write(&data(MSS * 3));
+        a.tick(&mut out);
+        assert_eq!(out.len(), 1);
+
+        // the probe is acked with the real window, the rest can be sent
+        let acks = deliver(std::mem::take(&mut out), &mut b);
+        deliver(acks, &mut a);
+        a.tick(&mut out);
+        assert_eq!(out.len(), 2);
+    }
+
+    #[test]
+    fn full_receiver_opens_the_window_after_read() {
+        let mut b = Reliable::default();
+        let mut out = Vec::new();
+        let segment = |seq: u64| {
+            encode_data(&Segment {
+                seq,
+                data: vec![0; MSS],
+                fin: false,
+                sent: Instant::now(),
+                retransmitted: false,
+            })
+        };
+
+        let mut seq = 0;
+        while b.window() > 0 {
+            out.clear();
+            b.on_datagram(&segment(seq), &mut out);
+            seq += 1;
+        }
+        assert_eq!(out.last().unwrap()[9..13], 0u32.to_be_bytes());
+
+        // a segment that does not fit is dropped
+        b.on_datagram(&segment(seq), &mut out);
+        assert_eq!(read_u64(&out.last().unwrap()[1..9]), seq);
+
+        out.clear();
+        let mut buffer = vec![0; MSS];
+        b.read(&mut buffer, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0][9..13], 1u32.to_be_bytes());
+    }
+
+    #[test]
+    fn idle_stream_can_write() {
+        let (a, b) = pair();
+        let mut a = PeerStream::new(a);
+        let mut b = PeerStream::new(b);
+        a.timeout = Duration::from_millis(200);
+        b.timeout = Duration::from_secs(5);
+
+        // longer than the timeout without traffic
+        thread::sleep(Duration::from_millis(300));
+
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            b.read_to_end(&mut received).unwrap();
+            received
+        });
+        a.write_all(b"hello").unwrap();
+        a.flush().unwrap();
+        a.shutdown().unwrap();
+        assert_eq!(reader.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let (a, _b) = pair();
+        let mut a = PeerStream::new(a);
+        a.timeout = Duration::from_millis(100);
+
+        a.write_all(b"hello").unwrap();
+        let start = Instant::now();
+        let error = a.flush().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= a.timeout);
+    }
+}
@@ -0,0 +1,104 @@
+//! Punched connections between two local sockets and a local relay, for the tests
+
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::common::AsRawSock;
+
+use super::response::Conn;
+
+/// A UDP socket bound on localhost
+pub fn socket() -> Socket {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
+    socket
+}
+
+/// `socket` connected to `to` like after hole punching
+pub fn conn(socket: Socket, to: &Socket) -> Conn {
+    let addr = to.local_addr().unwrap().as_socket().unwrap();
+    socket.connect(&addr.into()).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    Conn {
+        port: socket.local_addr().unwrap().as_socket().unwrap().port(),
+        fd: socket.as_raw(),
+        socket,
+        addr,
+        relayed: false,
+    }
+}
+
+/// Two connections that send to each other
+pub fn pair() -> (Conn, Conn) {
+    let (a, b) = (socket(), socket());
+    let a_conn = conn(a.try_clone().unwrap(), &b);
+    (a_conn, conn(b, &a))
+}
+
+/// A `RelayServer` on localhost that is stepped on a thread until it is dropped
+#[cfg(feature = "server")]
+pub struct Relay {
+    pub adress: String,
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "server")]
+impl Relay {
+    pub fn start(mut server: crate::server::RelayServer) -> Self {
+        use std::{
+            sync::{atomic::Ordering, Arc},
+            time::{Duration, Instant},
+        };
+
+        let adress = server.local_addrs()[0].to_string();
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    server.step_with_deadline(Instant::now() + Duration::from_millis(10));
+                }
+            }
+        });
+        Self {
+            adress,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A relay with the default settings
+#[cfg(feature = "server")]
+pub fn relay() -> Relay {
+    Relay::start(crate::server::testing::server())
+}
+
+/// `ConnectionInfo` of a client that registers `public` as `name`
+#[cfg(feature = "server")]
+pub fn info(public: Vec<u8>, name: &str) -> super::ConnectionInfo {
+    super::ConnectionInfo {
+        client: String::from("test"),
+        name: String::from(name),
+        public,
+        other: vec![],
+        privacy: false,
+        auth: Default::default(),
+        identity: None,
+        tls: None,
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::{self, Read, Write};
+
+use bytes_kman::TBytes;
+
+use super::packets::Packets;
+
+/// Every frame on the TCP control channel starts with a big endian `u32` length
+pub const HEADER_LEN: usize = 4;
+/// Frames bigger then this are considered a protocol violation
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// A connection is closed if more bytes wait to be sent, so a peer that does not read
+/// cannot make the other side keep everything
+pub const MAX_PENDING: usize = 4 * MAX_FRAME_LEN;
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge(usize),
+    Closed,
+    Io(io::Error),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Serializes a packet and prefixes it with the frame header
+pub fn encode(packet: &Packets) -> Vec<u8> {
+    let mut bytes = packet.to_bytes();
+    bytes.reverse();
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + bytes.len());
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend(bytes);
+    frame
+}
+
+/// Writes the hole frame, `conn` should be a blocking socket
+/// Non blocking sockets should use `Tls::write_packet`, it queues what cannot be written
+pub fn write_packet(mut conn: impl Write, packet: &Packets) -> io::Result<()> {
+    conn.write_all(&encode(packet))
+}
+
+/// Reassembles frames from a byte stream
+#[derive(Debug)]
+pub struct FrameDecoder {
+    pub buffer: Vec<u8>,
+    pub max_frame: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_LEN)
+    }
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Makes one read from `conn`
+    /// Returns `Ok(0)` if the non blocking socket has nothing
+    pub fn fill(&mut self, mut conn: impl Read) -> Result<usize, FrameError> {
+        let mut buffer = [0; 4096];
+        match conn.read(&mut buffer) {
+            Ok(0) => Err(FrameError::Closed),
+            Ok(len) => {
+                self.push(&buffer[0..len]);
+                Ok(len)
+            }
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(0)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the next complete frame
+    /// A frame that cannot be parsed is skipped
+    pub fn next_packet(&mut self) -> Result<Option<Packets>, FrameError> {
+        loop {
+            if self.buffer.len() < HEADER_LEN {
+                return Ok(None);
+            }
+
+            let mut header = [0; HEADER_LEN];
+            header.copy_from_slice(&self.buffer[0..HEADER_LEN]);
+            let len = u32::from_be_bytes(header) as usize;
+
+            if len > self.max_frame {
+                return Err(FrameError::TooLarge(len));
+            }
+
+            if self.buffer.len() < HEADER_LEN + len {
+                return Ok(None);
+            }
+
+            let mut frame: Vec<u8> = self.buffer.drain(0..HEADER_LEN + len).collect();
+            frame.drain(0..HEADER_LEN);
+
+            if let Some(packet) = Packets::from_bytes(&mut frame) {
+                return Ok(Some(packet));
+            }
+            log::warn!("Invalid frame of {len} bytes was skipped");
+        }
+    }
+
+    /// Blocks until a hole packet was read, `conn` should be a blocking socket
+    pub fn recv_packet(&mut self, mut conn: impl Read) -> Result<Packets, FrameError> {
+        loop {
+            if let Some(packet) = self.next_packet()? {
+                return Ok(packet);
+            }
+            self.fill(&mut conn)?;
+        }
+    }
+}
+
+/// Writes the hole frame to an async stream
+#[cfg(feature = "tokio")]
+pub async fn write_packet_async(
+    conn: &mut (impl tokio::io::AsyncWrite + Unpin),
+    packet: &Packets,
+) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    conn.write_all(&encode(packet)).await?;
+    // a TLS stream can keep a part
+    conn.flush().await
+}
+
+/// Reads from an async stream until a hole packet is in `buffer`
+/// Is cancel safe, the read bytes are allways kept in `buffer`
+#[cfg(feature = "tokio")]
+pub async fn read_packet_async(
+    conn: &mut (impl tokio::io::AsyncRead + Unpin),
+    buffer: &mut FrameDecoder,
+) -> Result<Packets, FrameError> {
+    use tokio::io::AsyncReadExt;
+
+    loop {
+        if let Some(packet) = buffer.next_packet()? {
+            return Ok(packet);
+        }
+
+        let mut bytes = [0; 4096];
+        let len = conn.read(&mut bytes).await?;
+        if len == 0 {
+            return Err(FrameError::Closed);
+        }
+        buffer.push(&bytes[0..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(session: usize) -> Vec<u8> {
+        encode(&Packets::Tick { session })
+    }
+
+    fn session(packet: Option<Packets>) -> Option<usize> {
+        match packet {
+            Some(Packets::Tick { session }) => Some(session),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let frame = tick(7);
+        let mut decoder = FrameDecoder::default();
+
+        for byte in &frame[..frame.len() - 1] {
+            decoder.push(&[*byte]);
+            assert!(decoder.next_packet().unwrap().is_none());
+        }
+        decoder.push(&frame[frame.len() - 1..]);
+        assert_eq!(session(decoder.next_packet().unwrap()), Some(7));
+        assert!(decoder.buffer.is_empty());
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let mut bytes = tick(1);
+        bytes.extend(tick(2));
+        let mut decoder = FrameDecoder::default();
+        decoder.fill(&bytes[..]).unwrap();
+
+        assert_eq!(session(decoder.next_packet().unwrap()), Some(1));
+        assert_eq!(session(decoder.next_packet().unwrap()), Some(2));
+        assert!(decoder.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_length_prefix() {
+        let mut decoder = FrameDecoder::new(16);
+        decoder.push(&17u32.to_be_bytes());
+
+        assert!(matches!(
+            decoder.next_packet(),
+            Err(FrameError::TooLarge(17))
+        ));
+    }
+
+    #[test]
+    fn zero_length_frame_is_skipped() {
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&0u32.to_be_bytes());
+        decoder.push(&tick(3));
+
+        assert_eq!(session(decoder.next_packet().unwrap()), Some(3));
+        assert!(decoder.buffer.is_empty());
+    }
+
+    #[test]
+    fn closed_stream() {
+        let mut decoder = FrameDecoder::default();
+        assert!(matches!(decoder.fill(&[][..]), Err(FrameError::Closed)));
+        assert!(matches!(
+            decoder.recv_packet(&tick(4)[..2]),
+            Err(FrameError::Closed)
+        ));
+    }
+}
@@ -0,0 +1,94 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use super::adress::Adress;
+
+/// Length of a adress that is a Ed25519 public key
+pub const IDENTITY_LEN: usize = 32;
+/// Length of the nonce in `RegisterResponse::Challenge`
+pub const CHALLENGE_LEN: usize = 32;
+
+/// A Ed25519 key pair, the public key is used as the `Adress`
+/// so only the holder of the secret key can register it on a relay
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self::from_seed(rand::random())
+    }
+
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// The secret key, needed to recreate the same identity with `from_seed`
+    pub fn seed(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    pub fn adress(&self) -> Adress {
+        self.key.verifying_key().to_bytes().to_vec()
+    }
+
+    /// Signs the `nonce` of a `RegisterResponse::Challenge`
+    pub fn sign_challenge(&self, nonce: &[u8]) -> Vec<u8> {
+        let message = challenge_message(&self.adress(), nonce);
+        self.key.sign(&message).to_bytes().to_vec()
+    }
+
+    /// The X25519 secret key of the identity, used as the Noise static key of `SecureConn`
+    pub fn dh_secret(&self) -> [u8; 32] {
+        self.key.to_scalar_bytes()
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity")
+            .field("adress", &self.adress())
+            .finish_non_exhaustive()
+    }
+}
+
+/// If `adress` can be used as a identity
+pub fn is_identity(adress: &Adress) -> bool {
+    verifying_key(adress).is_some()
+}
+
+pub fn new_challenge() -> Vec<u8> {
+    rand::random::<[u8; CHALLENGE_LEN]>().to_vec()
+}
+
+/// Checks that `signature` was made by the key of `adress` for `nonce`
+pub fn verify_challenge(adress: &Adress, nonce: &[u8], signature: &[u8]) -> bool {
+    let Some(key) = verifying_key(adress) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+
+    key.verify_strict(&challenge_message(adress, nonce), &signature)
+        .is_ok()
+}
+
+/// The X25519 public key of the identity `adress`, the one of `Identity::dh_secret`
+pub fn dh_public(adress: &Adress) -> Option<[u8; 32]> {
+    Some(verifying_key(adress)?.to_montgomery().to_bytes())
+}
+
+fn verifying_key(adress: &Adress) -> Option<VerifyingKey> {
+    let bytes: [u8; IDENTITY_LEN] = adress.as_slice().try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+fn challenge_message(adress: &Adress, nonce: &[u8]) -> Vec<u8> {
+    let mut message = b"relay-man identity v1".to_vec();
+    message.extend_from_slice(adress);
+    message.extend_from_slice(nonce);
+    message
+}
@@ -1,5 +1,9 @@
 pub mod adress;
+pub mod frame;
+pub mod identity;
 pub mod packets;
+pub mod stun;
+pub mod tls;
 
 #[cfg(target_os = "windows")]
 pub type RawSock = std::os::windows::io::RawSocket;
@@ -14,6 +18,10 @@ pub trait IntoRawSock {
     fn into_raw(self) -> RawSock;
 }
 
+pub trait AsRawSock {
+    fn as_raw(&self) -> RawSock;
+}
+
 impl FromRawSock for socket2::Socket {
     fn from_raw(raw_sock: RawSock) -> Self {
         #[cfg(target_os = "windows")]
@@ -50,3 +58,18 @@ impl IntoRawSock for socket2::Socket {
         self.into_raw_fd()
     }
 }
+
+impl AsRawSock for socket2::Socket {
+    fn as_raw(&self) -> RawSock {
+        #[cfg(target_os = "windows")]
+        use std::os::windows::io::AsRawSocket;
+
+        #[cfg(target_os = "windows")]
+        return self.as_raw_socket();
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        use std::os::unix::io::AsRawFd;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        self.as_raw_fd()
+    }
+}
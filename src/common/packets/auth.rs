@@ -0,0 +1,187 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes_kman::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::common::adress::Adress;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authentication sent with `Register::Client`
+#[derive(Bytes, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Auth {
+    #[default]
+    None,
+    Token(AuthToken),
+}
+
+/// Claims signed with HMAC-SHA256 by the issuer, the relay needs the same key to verify them
+/// Should be minted by a backend with `AuthToken::mint`, never by the client
+#[derive(Bytes, Clone, Debug, PartialEq, Eq)]
+pub struct AuthToken {
+    /// The only adress that can be registered, empty for any
+    pub adress: Adress,
+    /// The only `client` that can register, empty for any
+    pub client: String,
+    /// Unix time in seconds after witch the token is invalid, 0 for never
+    pub expires: u64,
+    pub mac: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    InvalidSignature,
+    Expired,
+    WrongAdress,
+    WrongClient,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AuthError::Missing => "Missing auth token",
+            AuthError::InvalidSignature => "Invalid auth token",
+            AuthError::Expired => "Auth token expired",
+            AuthError::WrongAdress => "Auth token is not for this adress",
+            AuthError::WrongClient => "Auth token is not for this client",
+        })
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl AuthToken {
+    pub fn mint(key: &[u8], adress: Adress, client: impl Into<String>, expires: u64) -> Self {
+        let mut token = Self {
+            adress,
+            client: client.into(),
+            expires,
+            mac: Vec::new(),
+        };
+        token.mac = token.hmac(key).finalize().into_bytes().to_vec();
+        token
+    }
+
+    /// Same as `mint` but expires after `valid_for`
+    pub fn mint_for(
+        key: &[u8],
+        adress: Adress,
+        client: impl Into<String>,
+        valid_for: std::time::Duration,
+    ) -> Self {
+        let expires = unix_now().saturating_add(valid_for.as_secs()).max(1);
+        Self::mint(key, adress, client, expires)
+    }
+
+    /// Checks the signature and the claims for a registration of `adress` by `client`
+    pub fn verify(&self, key: &[u8], adress: &Adress, client: &str) -> Result<(), AuthError> {
+        if self.hmac(key).verify_slice(&self.mac).is_err() {
+            return Err(AuthError::InvalidSignature);
+        }
+
+        if self.expires != 0 && unix_now() > self.expires {
+            return Err(AuthError::Expired);
+        }
+
+        if !self.adress.is_empty() && &self.adress != adress {
+            return Err(AuthError::WrongAdress);
+        }
+
+        if !self.client.is_empty() && self.client != client {
+            return Err(AuthError::WrongClient);
+        }
+
+        Ok(())
+    }
+
+    fn hmac(&self, key: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+        // every claim is length prefixed so they cannot be moved between fields
+        mac.update(b"relay-man auth v1");
+        mac.update(&(self.adress.len() as u64).to_be_bytes());
+        mac.update(&self.adress);
+        mac.update(&(self.client.len() as u64).to_be_bytes());
+        mac.update(self.client.as_bytes());
+        mac.update(&self.expires.to_be_bytes());
+        mac
+    }
+}
+
+impl Auth {
+    pub fn verify(&self, key: &[u8], adress: &Adress, client: &str) -> Result<(), AuthError> {
+        match self {
+            Auth::None => Err(AuthError::Missing),
+            Auth::Token(token) => token.verify(key, adress, client),
+        }
+    }
+}
+
+impl From<AuthToken> for Auth {
+    fn from(token: AuthToken) -> Self {
+        Self::Token(token)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn verify_claims() {
+        let token = AuthToken::mint(b"key", vec![1], "client", 0);
+        assert_eq!(token.verify(b"key", &vec![1], "client"), Ok(()));
+        assert_eq!(
+            token.verify(b"other", &vec![1], "client"),
+            Err(AuthError::InvalidSignature)
+        );
+        assert_eq!(
+            token.verify(b"key", &vec![2], "client"),
+            Err(AuthError::WrongAdress)
+        );
+        assert_eq!(
+            token.verify(b"key", &vec![1], "other"),
+            Err(AuthError::WrongClient)
+        );
+
+        let any = AuthToken::mint(b"key", vec![], String::new(), 0);
+        assert_eq!(any.verify(b"key", &vec![3], "other"), Ok(()));
+    }
+
+    #[test]
+    fn changed_claims_are_invalid() {
+        let mut token = AuthToken::mint(b"key", vec![1], "client", 0);
+        token.adress = vec![2];
+        assert_eq!(
+            token.verify(b"key", &vec![2], "client"),
+            Err(AuthError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn expired_and_missing() {
+        let expired = AuthToken::mint(b"key", vec![1], "client", 1);
+        assert_eq!(
+            expired.verify(b"key", &vec![1], "client"),
+            Err(AuthError::Expired)
+        );
+
+        let valid = AuthToken::mint_for(b"key", vec![1], "client", Duration::from_secs(60));
+        assert_eq!(valid.verify(b"key", &vec![1], "client"), Ok(()));
+
+        assert_eq!(
+            Auth::None.verify(b"key", &vec![1], "client"),
+            Err(AuthError::Missing)
+        );
+    }
+}
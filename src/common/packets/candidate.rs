@@ -0,0 +1,55 @@
+use std::net::{IpAddr, SocketAddr};
+
+use bytes_kman::prelude::*;
+
+#[derive(Bytes, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// A adress of a interface of the peer
+    Host,
+    /// The adress that the relay or a STUN server sees
+    ServerReflexive,
+    /// A adress that was only seen while checking, like a unpredicted port of a symmetric NAT
+    PeerReflexive,
+    /// A port on a relay that forwards to the peer
+    Relayed,
+}
+
+impl CandidateKind {
+    /// The type preference of RFC 8445, direct paths are preferred
+    pub fn preference(&self) -> u32 {
+        match self {
+            Self::Host => 126,
+            Self::PeerReflexive => 110,
+            Self::ServerReflexive => 100,
+            Self::Relayed => 0,
+        }
+    }
+}
+
+/// A adress where a peer could be reached, exchanged with `ConnectOn`
+#[derive(Bytes, Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub ip: String,
+    pub port: u16,
+    /// Bigger is checked first
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// `local` is the preference between candidates of the same kind, bigger is better
+    pub fn new(kind: CandidateKind, adress: SocketAddr, local: u16) -> Self {
+        Self {
+            kind,
+            ip: adress.ip().to_string(),
+            port: adress.port(),
+            // there is only one component
+            priority: (kind.preference() << 24) | ((local as u32) << 8) | 255,
+        }
+    }
+
+    pub fn adress(&self) -> Option<SocketAddr> {
+        let ip = self.ip.parse::<IpAddr>().ok()?;
+        Some(SocketAddr::new(ip, self.port))
+    }
+}
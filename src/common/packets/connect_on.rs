@@ -2,11 +2,26 @@ use bytes_kman::prelude::*;
 
 use crate::common::adress::Adress;
 
+use super::{Candidate, NatType};
+
 #[derive(Bytes, Clone, Debug)]
 pub struct ConnectOn {
     pub session: usize,
+    /// The `id` of the `RequestFinal` that this is the answer to, 0 if none
+    pub id: usize,
     pub to: String,
     pub port: u16,
     pub adress: Adress,
     pub time: u128,
+    /// Token for a `RelayRequest` if hole punching fails, empty if the relay does not forward
+    pub relay: Vec<u8>,
+    /// The advertised `NatType` of the peer
+    pub nat: NatType,
+    /// False if the relay expects hole punching to fail because of the NATs of both peers
+    pub punch: bool,
+    /// Ports that the symmetric NAT of the peer will likely use instead of the port in `to`
+    /// empty if they cannot be predicted
+    pub predicted: Vec<u16>,
+    /// Every adress where the peer could be reached, empty if it sent none
+    pub candidates: Vec<Candidate>,
 }
@@ -0,0 +1,94 @@
+use bytes_kman::prelude::*;
+
+use crate::common::adress::Adress;
+
+/// Machine readable reason of an `Error`
+#[derive(Bytes, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    AdressTaken,
+    UnknownSession,
+    NotRegistered,
+    PeerOffline,
+    UnknownRequest,
+    PayloadTooLarge,
+    UnsupportedVersion,
+    /// Refused by the policy of the relay
+    Denied,
+    /// Missing or invalid `Auth`
+    Unauthorized,
+    /// Missing or wrong `Register::Proof`
+    InvalidProof,
+    /// Missing, wrong or expired port token in `Register::Port`
+    InvalidToken,
+    /// The relay does not forward data or has no capacity left
+    RelayUnavailable,
+}
+
+/// The kind of packet that an `Error` is the answer to
+#[derive(Bytes, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorRequest {
+    Hello,
+    Register,
+    RegisterPort,
+    Search,
+    Info,
+    Request,
+    RequestResponse,
+    RequestFinal,
+    Unknown,
+    Relay,
+}
+
+/// Sent by the relay when a packet could not be processed
+#[derive(Bytes, Clone, Debug)]
+pub struct Error {
+    pub session: usize,
+    pub code: ErrorCode,
+    pub request: ErrorRequest,
+    /// The `id` of the failed request, 0 if the packet has none
+    pub id: usize,
+    /// The adress the failed request was about, empty if none
+    pub adress: Adress,
+    /// Human readable reason, can be empty
+    pub reason: String,
+}
+
+impl Error {
+    pub fn new(session: usize, code: ErrorCode, request: ErrorRequest) -> Self {
+        Self {
+            session,
+            code,
+            request,
+            id: 0,
+            adress: Adress::new(),
+            reason: String::new(),
+        }
+    }
+
+    pub fn with_id(mut self, id: usize) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn with_adress(mut self, adress: Adress) -> Self {
+        self.adress = adress;
+        self
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = reason.into();
+        self
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} on {:?}", self.code, self.request)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,138 @@
+use bytes_kman::prelude::*;
+
+/// Version of the control protocol spoken by this crate, bumped when a packet layout changes
+pub const PROTOCOL_VERSION: u16 = 11;
+/// Oldest version that is still understood, raised when older peers cannot parse a change
+pub const MIN_PROTOCOL_VERSION: u16 = 11;
+
+/// Optional features that can be negotiated in `Hello`
+pub mod capability {
+    /// Adresses can be proven with a `Identity`
+    pub const IDENTITY: &str = "identity";
+
+    pub fn supported() -> Vec<String> {
+        vec![IDENTITY.into()]
+    }
+}
+
+/// First packet sent by the client before `Register::Client`
+#[derive(Bytes, Clone, Debug)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Bytes, Clone, Debug)]
+pub enum HelloResponse {
+    Accepted {
+        version: u16,
+        capabilities: Vec<String>,
+    },
+    Rejected {
+        min_version: u16,
+        max_version: u16,
+    },
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: capability::supported(),
+        }
+    }
+
+    /// Computes the answer of a peer that speaks `PROTOCOL_VERSION` and supports `capabilities`
+    pub fn negotiate(&self, capabilities: &[String]) -> HelloResponse {
+        let version = self.version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return HelloResponse::Rejected {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            };
+        }
+
+        HelloResponse::Accepted {
+            version,
+            capabilities: self
+                .capabilities
+                .iter()
+                .filter(|capability| capabilities.contains(capability))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(version: u16, capabilities: &[&str]) -> Hello {
+        Hello {
+            version,
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    fn accepted(response: HelloResponse) -> (u16, Vec<String>) {
+        match response {
+            HelloResponse::Accepted {
+                version,
+                capabilities,
+            } => (version, capabilities),
+            response => panic!("Rejected: {response:?}"),
+        }
+    }
+
+    #[test]
+    fn same_version_is_accepted() {
+        let response = Hello::new().negotiate(&capability::supported());
+        assert_eq!(
+            accepted(response),
+            (PROTOCOL_VERSION, capability::supported())
+        );
+    }
+
+    #[test]
+    fn newer_peer_gets_our_version() {
+        let response = hello(PROTOCOL_VERSION + 5, &[]).negotiate(&[]);
+        assert_eq!(accepted(response).0, PROTOCOL_VERSION);
+        let response = hello(u16::MAX, &[]).negotiate(&[]);
+        assert_eq!(accepted(response).0, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn older_peer_is_rejected() {
+        for version in [0, MIN_PROTOCOL_VERSION - 1] {
+            let response = hello(version, &[capability::IDENTITY]).negotiate(&[]);
+            assert!(
+                matches!(
+                    response,
+                    HelloResponse::Rejected {
+                        min_version: MIN_PROTOCOL_VERSION,
+                        max_version: PROTOCOL_VERSION,
+                    }
+                ),
+                "{response:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_common_capabilities_are_used() {
+        let ours = vec![String::from("identity"), String::from("relay")];
+        let response = hello(PROTOCOL_VERSION, &["relay", "unknown", "identity"]).negotiate(&ours);
+        // in the order of the `Hello`
+        assert_eq!(accepted(response).1, ["relay", "identity"]);
+
+        let response = hello(PROTOCOL_VERSION, &["unknown"]).negotiate(&ours);
+        assert!(accepted(response).1.is_empty());
+    }
+}
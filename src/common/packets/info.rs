@@ -4,6 +4,7 @@ use crate::common::adress::Adress;
 
 #[derive(Bytes, Clone, Debug)]
 pub struct Info {
+    pub id: usize,
     pub has: bool,
     pub name: String,
     pub client: String,
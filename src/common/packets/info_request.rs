@@ -5,4 +5,6 @@ use bytes_kman::prelude::*;
 pub struct InfoRequest {
     pub adress: Adress,
     pub session: usize,
+    /// Chosen by the client and echoed back in `Info`
+    pub id: usize,
 }
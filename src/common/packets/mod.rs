@@ -1,10 +1,16 @@
 use bytes_kman::prelude::*;
 
+mod auth;
+mod candidate;
 mod connect_on;
+mod error;
+mod hello;
 mod info;
 mod info_request;
+mod nat;
 mod register;
 mod register_response;
+mod relay;
 mod request;
 mod request_final;
 mod request_response;
@@ -13,8 +19,9 @@ mod search_response;
 mod unregister;
 
 pub use self::{
-    connect_on::*, info::*, info_request::*, register::*, register_response::*, request::*,
-    request_final::*, request_response::*, search::*, search_response::*, unregister::*,
+    auth::*, candidate::*, connect_on::*, error::*, hello::*, info::*, info_request::*, nat::*,
+    register::*, register_response::*, relay::*, request::*, request_final::*, request_response::*,
+    search::*, search_response::*, unregister::*,
 };
 
 #[derive(Bytes, Clone, Debug)]
@@ -34,4 +41,53 @@ pub enum Packets {
     NewRequestFinal(NewRequestFinal),
     ConnectOn(ConnectOn),
     Tick { session: usize },
+    Hello(Hello),
+    HelloResponse(HelloResponse),
+    Error(Error),
+    RelayRequest(RelayRequest),
+    RelayResponse(RelayResponse),
+}
+
+impl Packets {
+    /// Sets the session of the packets that are sent by a client
+    pub fn set_session(&mut self, session: usize) {
+        match self {
+            Packets::UnRegister(pak) => pak.session = session,
+            Packets::Search(pak) => pak.session = session,
+            Packets::InfoRequest(pak) => pak.session = session,
+            Packets::Request(pak) => pak.session = session,
+            Packets::RequestResponse(pak) => pak.session = session,
+            Packets::RequestFinal(pak) => pak.session = session,
+            Packets::Tick { session: pak } => *pak = session,
+            Packets::RelayRequest(pak) => pak.session = session,
+            _ => {}
+        }
+    }
+
+    /// The request id of the packets that will have a response
+    pub fn request_id_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Packets::Search(pak) => Some(&mut pak.id),
+            Packets::InfoRequest(pak) => Some(&mut pak.id),
+            Packets::Request(pak) => Some(&mut pak.id),
+            Packets::RequestResponse(pak) => Some(&mut pak.id),
+            Packets::RequestFinal(pak) => Some(&mut pak.id),
+            Packets::RelayRequest(pak) => Some(&mut pak.id),
+            _ => None,
+        }
+    }
+
+    /// The id of the request that this packet is the answer to
+    pub fn response_id(&self) -> Option<usize> {
+        match self {
+            Packets::SearchResponse(pak) => Some(pak.id),
+            Packets::Info(pak) => Some(pak.id),
+            Packets::NewRequestResponse(pak) => Some(pak.id),
+            Packets::NewRequestFinal(pak) => Some(pak.id),
+            Packets::ConnectOn(pak) => Some(pak.id),
+            Packets::Error(pak) => Some(pak.id),
+            Packets::RelayResponse(pak) => Some(pak.id),
+            _ => None,
+        }
+    }
 }
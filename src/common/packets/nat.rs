@@ -0,0 +1,36 @@
+use bytes_kman::prelude::*;
+
+/// How the NAT of a client maps and filters UDP, found with `Connection::detect_nat`
+#[derive(Bytes, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NatType {
+    /// Not detected
+    #[default]
+    Unknown,
+    /// The reflexive adress is the local adress
+    Open,
+    /// Every host can send to the mapped port
+    FullCone,
+    /// Only ips that the client sent to can send to the mapped port
+    RestrictedCone,
+    /// Only ips and ports that the client sent to can send to the mapped port
+    PortRestrictedCone,
+    /// Every destination gets a other mapped port
+    Symmetric,
+    /// UDP is blocked
+    Blocked,
+}
+
+impl NatType {
+    /// If a hole can be punched between a peer behind `self` and a peer behind `other`
+    /// `Unknown` is always tried
+    pub fn can_punch(&self, other: &NatType) -> bool {
+        use NatType::*;
+        !matches!(
+            (self, other),
+            (Blocked, _)
+                | (_, Blocked)
+                | (Symmetric, Symmetric | PortRestrictedCone)
+                | (PortRestrictedCone, Symmetric)
+        )
+    }
+}
@@ -2,6 +2,8 @@ use bytes_kman::prelude::*;
 
 use crate::common::adress::Adress;
 
+use super::{Auth, Candidate, NatType};
+
 #[derive(Bytes, Clone, Debug)]
 pub enum Register {
     Client {
@@ -11,8 +13,36 @@ pub enum Register {
         other: Vec<u8>,
         privacy: bool,
         private_adress: String,
+        /// Only needed if the relay has a auth key
+        auth: Auth,
+        /// `public` is a Ed25519 public key, the relay answers with `RegisterResponse::Challenge`
+        /// A `public` of `IDENTITY_LEN` bytes is refused without it
+        identity: bool,
     },
     Port {
         session: usize,
+        /// The `port_token` of the `NewRequestResponse` or `NewRequestFinal`
+        token: Vec<u8>,
+    },
+    /// Answer to `RegisterResponse::Challenge`, made with `Identity::sign_challenge`
+    Proof {
+        signature: Vec<u8>,
+    },
+    /// Sent after `Connection::detect_nat`, used by the relay to choose how peers connect
+    Nat {
+        nat: NatType,
+    },
+    /// Like `Port` but only shows the relay what port the NAT allocated
+    /// sent from new sockets before `Port` so the relay can predict a symmetric NAT
+    Probe {
+        session: usize,
+        token: Vec<u8>,
+    },
+    /// Same as `Port` but also sends the candidates of the socket
+    /// the peer gets them in `ConnectOn::candidates`
+    Candidates {
+        session: usize,
+        token: Vec<u8>,
+        candidates: Vec<Candidate>,
     },
 }
@@ -4,13 +4,16 @@ use bytes_kman::prelude::*;
 pub enum RegisterResponse {
     Client { accepted: bool, session: usize },
     Port { port: u16 },
+    /// The client has to prove that it owns the adress with `Register::Proof`
+    Challenge { nonce: Vec<u8> },
 }
 
 impl RegisterResponse {
     pub fn accepted(&self) -> bool {
         match self {
-            RegisterResponse::Client { accepted, session } => *accepted,
-            RegisterResponse::Port { port } => true,
+            RegisterResponse::Client { accepted, .. } => *accepted,
+            RegisterResponse::Port { .. } => true,
+            RegisterResponse::Challenge { .. } => false,
         }
     }
 }
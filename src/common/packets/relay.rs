@@ -0,0 +1,23 @@
+use bytes_kman::prelude::*;
+
+/// First byte of the datagram that binds a UDP socket to a relayed port
+/// followed by the `relay` token of the `ConnectOn`, the relay echoes it back
+pub const RELAY_BIND: u8 = 0xFE;
+
+/// Asks the relay to forward the datagrams to the peer after hole punching failed
+#[derive(Bytes, Clone, Debug)]
+pub struct RelayRequest {
+    pub session: usize,
+    pub id: usize,
+    /// The `relay` token of the `ConnectOn`
+    pub token: Vec<u8>,
+}
+
+/// The UDP port on the relay that forwards to the peer
+/// it is on the same ip as the control connection
+#[derive(Bytes, Clone, Debug)]
+pub struct RelayResponse {
+    pub session: usize,
+    pub id: usize,
+    pub port: u16,
+}
@@ -4,6 +4,8 @@ use bytes_kman::prelude::*;
 #[derive(Bytes, Clone, Debug)]
 pub struct Request {
     pub session: usize,
+    /// Chosen by the client and echoed back in `NewRequestResponse`
+    pub id: usize,
     pub to: Adress,
     pub secret: String,
 }
@@ -13,4 +15,6 @@ pub struct NewRequest {
     pub session: usize,
     pub from: Adress,
     pub secret: String,
+    /// `from` was proven with a `Identity`
+    pub verified: bool,
 }
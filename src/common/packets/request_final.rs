@@ -5,6 +5,8 @@ use crate::common::adress::Adress;
 #[derive(Bytes, Clone, Debug)]
 pub struct RequestFinal {
     pub session: usize,
+    /// Chosen by the client and echoed back in `ConnectOn`
+    pub id: usize,
     pub to: Adress,
     pub accepted: bool,
     pub time_offset: u128,
@@ -13,6 +15,10 @@ pub struct RequestFinal {
 #[derive(Bytes, Clone, Debug)]
 pub struct NewRequestFinal {
     pub session: usize,
+    /// The `id` of the `RequestResponse` that this is the answer to
+    pub id: usize,
     pub from: Adress,
     pub accepted: bool,
+    /// Needed for `Register::Port`, empty if not accepted
+    pub port_token: Vec<u8>,
 }
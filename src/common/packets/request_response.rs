@@ -4,6 +4,8 @@ use bytes_kman::prelude::*;
 #[derive(Bytes, Clone, Debug)]
 pub struct RequestResponse {
     pub session: usize,
+    /// Chosen by the client and echoed back in `NewRequestFinal`
+    pub id: usize,
     pub to: Adress,
     pub accepted: bool,
     pub secret: String,
@@ -12,7 +14,11 @@ pub struct RequestResponse {
 #[derive(Bytes, Clone, Debug)]
 pub struct NewRequestResponse {
     pub session: usize,
+    /// The `id` of the `Request` that this is the answer to
+    pub id: usize,
     pub from: Adress,
     pub accepted: bool,
     pub secret: String,
+    /// Needed for `Register::Port`, empty if not accepted
+    pub port_token: Vec<u8>,
 }
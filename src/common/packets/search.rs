@@ -11,6 +11,8 @@ pub enum SearchType<T> {
 #[derive(Bytes, Clone, Debug, Default)]
 pub struct Search {
     pub session: usize,
+    /// Chosen by the client and echoed back in `SearchResponse`
+    pub id: usize,
     pub client: SearchType<String>,
     pub name: SearchType<String>,
     pub other: SearchType<Vec<u8>>,
@@ -4,5 +4,6 @@ use bytes_kman::prelude::*;
 #[derive(Bytes, Clone, Debug)]
 pub struct SearchResponse {
     pub session: usize,
+    pub id: usize,
     pub adresses: Vec<Adress>,
 }
@@ -0,0 +1,367 @@
+//! The Binding method of STUN (RFC 5389), so standard clients can learn
+//! their reflexive adress from the UDP port of a relay
+//! and the behaviour discovery of RFC 5780 with `CHANGE-REQUEST`
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LEN: usize = 20;
+pub const TRANSACTION_LEN: usize = 12;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+pub const BINDING_ERROR: u16 = 0x0111;
+
+pub const MAPPED_ADDRESS: u16 = 0x0001;
+pub const CHANGE_REQUEST: u16 = 0x0003;
+pub const ERROR_CODE: u16 = 0x0009;
+pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const SOFTWARE: u16 = 0x8022;
+pub const FINGERPRINT: u16 = 0x8028;
+pub const RESPONSE_ORIGIN: u16 = 0x802B;
+pub const OTHER_ADDRESS: u16 = 0x802C;
+
+pub const CHANGE_IP: u8 = 0x04;
+pub const CHANGE_PORT: u8 = 0x02;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+pub type Transaction = [u8; TRANSACTION_LEN];
+
+/// A parsed STUN message, attributes are not decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub kind: u16,
+    pub transaction: Transaction,
+    pub attributes: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    /// Returns `None` if `datagram` is not a STUN message
+    pub fn parse(datagram: &'a [u8]) -> Option<Self> {
+        if datagram.len() < HEADER_LEN || datagram[0] & 0xC0 != 0 {
+            return None;
+        }
+        let kind = u16::from_be_bytes([datagram[0], datagram[1]]);
+        let len = u16::from_be_bytes([datagram[2], datagram[3]]) as usize;
+        let cookie = u32::from_be_bytes([datagram[4], datagram[5], datagram[6], datagram[7]]);
+        if cookie != MAGIC_COOKIE || !len.is_multiple_of(4) || HEADER_LEN + len != datagram.len() {
+            return None;
+        }
+
+        let mut transaction = [0; TRANSACTION_LEN];
+        transaction.copy_from_slice(&datagram[8..HEADER_LEN]);
+
+        let mut attributes = Vec::new();
+        let mut rest = &datagram[HEADER_LEN..];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return None;
+            }
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            // attributes are padded to 4 bytes
+            let padded = (len + 3) & !3;
+            if rest.len() < 4 + padded {
+                return None;
+            }
+            attributes.push((kind, &rest[4..4 + len]));
+            rest = &rest[4 + padded..];
+        }
+
+        Some(Self {
+            kind,
+            transaction,
+            attributes,
+        })
+    }
+
+    pub fn attribute(&self, kind: u16) -> Option<&'a [u8]> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, value)| *value)
+    }
+
+    /// The reflexive adress of a Binding success response
+    pub fn mapped_adress(&self) -> Option<SocketAddr> {
+        if let Some(value) = self.attribute(XOR_MAPPED_ADDRESS) {
+            return decode_adress(value, Some(&self.transaction));
+        }
+        decode_adress(self.attribute(MAPPED_ADDRESS)?, None)
+    }
+
+    /// Where the server answers with a changed ip and port
+    pub fn other_adress(&self) -> Option<SocketAddr> {
+        decode_adress(self.attribute(OTHER_ADDRESS)?, None)
+    }
+
+    /// The flags of `CHANGE-REQUEST`, `CHANGE_IP` and `CHANGE_PORT`
+    pub fn change_request(&self) -> u8 {
+        self.attribute(CHANGE_REQUEST)
+            .and_then(|value| value.get(3).copied())
+            .unwrap_or(0)
+    }
+}
+
+pub fn new_transaction() -> Transaction {
+    rand::random()
+}
+
+pub fn binding_request(transaction: &Transaction) -> Vec<u8> {
+    encode(BINDING_REQUEST, transaction, &[])
+}
+
+/// A Binding request that asks to be answered from another ip and/or port
+pub fn change_request(transaction: &Transaction, change: u8) -> Vec<u8> {
+    encode(
+        BINDING_REQUEST,
+        transaction,
+        &[(CHANGE_REQUEST, &[0, 0, 0, change])],
+    )
+}
+
+/// The error response for a request with comprehension required attributes
+/// that are not known, `None` if all are known
+pub fn check_attributes(request: &Message) -> Option<Vec<u8>> {
+    let unknown: Vec<u16> = request
+        .attributes
+        .iter()
+        .map(|(kind, _)| *kind)
+        .filter(|kind| *kind < 0x8000 && *kind != CHANGE_REQUEST)
+        .collect();
+    if unknown.is_empty() {
+        return None;
+    }
+
+    let unknown_attributes: Vec<u8> = unknown.iter().flat_map(|k| k.to_be_bytes()).collect();
+    Some(encode(
+        BINDING_ERROR,
+        &request.transaction,
+        &[
+            (ERROR_CODE, &error_code(420, "Unknown Attribute")),
+            (UNKNOWN_ATTRIBUTES, &unknown_attributes),
+        ],
+    ))
+}
+
+/// Answer to a request that cannot be handled, like changing the ip without a alternate ip
+pub fn binding_error(transaction: &Transaction, code: u16, reason: &str) -> Vec<u8> {
+    encode(
+        BINDING_ERROR,
+        transaction,
+        &[(ERROR_CODE, &error_code(code, reason))],
+    )
+}
+
+/// `mapped` is the adress that the request came from
+/// `origin` is the adress that sends the response and `other` the adress
+/// with the alternate ip and port, both are only sent if known
+pub fn binding_success(
+    transaction: &Transaction,
+    mapped: SocketAddr,
+    origin: Option<SocketAddr>,
+    other: Option<SocketAddr>,
+) -> Vec<u8> {
+    let xor = encode_adress(mapped, Some(transaction));
+    let origin = origin.map(|origin| encode_adress(origin, None));
+    let other = other.map(|other| encode_adress(other, None));
+
+    let mut attributes: Vec<(u16, &[u8])> = vec![(XOR_MAPPED_ADDRESS, &xor)];
+    if let Some(origin) = &origin {
+        attributes.push((RESPONSE_ORIGIN, origin));
+    }
+    if let Some(other) = &other {
+        attributes.push((OTHER_ADDRESS, other));
+    }
+    attributes.push((SOFTWARE, b"relay-man"));
+
+    encode(BINDING_SUCCESS, transaction, &attributes)
+}
+
+fn error_code(code: u16, reason: &str) -> Vec<u8> {
+    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+    value.extend(reason.as_bytes());
+    value
+}
+
+fn encode(kind: u16, transaction: &Transaction, attributes: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (kind, value) in attributes {
+        body.extend(kind.to_be_bytes());
+        body.extend((value.len() as u16).to_be_bytes());
+        body.extend(*value);
+        body.resize((body.len() + 3) & !3, 0);
+    }
+
+    let mut message = Vec::with_capacity(HEADER_LEN + body.len());
+    message.extend(kind.to_be_bytes());
+    message.extend((body.len() as u16).to_be_bytes());
+    message.extend(MAGIC_COOKIE.to_be_bytes());
+    message.extend(transaction);
+    message.extend(body);
+    message
+}
+
+/// `transaction` is only used for XOR-MAPPED-ADDRESS
+fn encode_adress(adress: SocketAddr, transaction: Option<&Transaction>) -> Vec<u8> {
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    if let Some(transaction) = transaction {
+        mask.extend(transaction);
+    }
+    let xor = |bytes: &[u8]| -> Vec<u8> {
+        match transaction {
+            Some(_) => bytes.iter().zip(&mask).map(|(b, m)| b ^ m).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+
+    let (family, ip) = match adress.ip() {
+        IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+    };
+
+    let mut value = vec![0, family];
+    value.extend(xor(&adress.port().to_be_bytes()));
+    value.extend(xor(&ip));
+    value
+}
+
+fn decode_adress(value: &[u8], transaction: Option<&Transaction>) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    if let Some(transaction) = transaction {
+        mask.extend(transaction);
+    }
+    let xor = |bytes: &[u8]| -> Vec<u8> {
+        match transaction {
+            Some(_) => bytes.iter().zip(&mask).map(|(b, m)| b ^ m).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+
+    let port = xor(&value[2..4]);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = xor(&value[4..]);
+    let ip = match (value[1], ip.len()) {
+        (FAMILY_IPV4, 4) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+        (FAMILY_IPV6, 16) => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&ip);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the sample values of RFC 5769
+    const TRANSACTION: Transaction = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+    const XOR_IPV4: [u8; 8] = [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+    const XOR_IPV6: [u8; 20] = [
+        0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4,
+        0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+    ];
+
+    fn ipv4() -> SocketAddr {
+        "192.0.2.1:32853".parse().unwrap()
+    }
+
+    fn ipv6() -> SocketAddr {
+        "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn xor_mapped_adress_ipv4() {
+        assert_eq!(encode_adress(ipv4(), Some(&TRANSACTION)), XOR_IPV4);
+        assert_eq!(decode_adress(&XOR_IPV4, Some(&TRANSACTION)), Some(ipv4()));
+    }
+
+    #[test]
+    fn xor_mapped_adress_ipv6() {
+        assert_eq!(encode_adress(ipv6(), Some(&TRANSACTION)), XOR_IPV6);
+        assert_eq!(decode_adress(&XOR_IPV6, Some(&TRANSACTION)), Some(ipv6()));
+    }
+
+    #[test]
+    fn binding_success_round_trip() {
+        for (mapped, other) in [(ipv4(), ipv6()), (ipv6(), ipv4())] {
+            let response = binding_success(&TRANSACTION, mapped, Some(other), Some(other));
+            let message = Message::parse(&response).unwrap();
+
+            assert_eq!(message.kind, BINDING_SUCCESS);
+            assert_eq!(message.transaction, TRANSACTION);
+            assert_eq!(message.mapped_adress(), Some(mapped));
+            assert_eq!(message.other_adress(), Some(other));
+            assert_eq!(message.attribute(SOFTWARE), Some(&b"relay-man"[..]));
+        }
+    }
+
+    #[test]
+    fn mapped_adress_without_xor() {
+        let value = encode_adress(ipv4(), None);
+        let response = encode(BINDING_SUCCESS, &TRANSACTION, &[(MAPPED_ADDRESS, &value)]);
+
+        assert_eq!(
+            Message::parse(&response).unwrap().mapped_adress(),
+            Some(ipv4())
+        );
+    }
+
+    #[test]
+    fn magic_cookie_is_checked() {
+        let mut request = binding_request(&TRANSACTION);
+        assert!(Message::parse(&request).is_some());
+
+        request[7] ^= 1;
+        assert!(Message::parse(&request).is_none());
+    }
+
+    #[test]
+    fn change_request_flags() {
+        let request = change_request(&TRANSACTION, CHANGE_IP | CHANGE_PORT);
+        let message = Message::parse(&request).unwrap();
+
+        assert_eq!(message.change_request(), CHANGE_IP | CHANGE_PORT);
+        assert_eq!(check_attributes(&message), None);
+    }
+
+    #[test]
+    fn truncated_input() {
+        let response = binding_success(&TRANSACTION, ipv6(), None, None);
+        for len in 0..response.len() {
+            assert!(Message::parse(&response[..len]).is_none(), "len {len}");
+        }
+
+        // the header says there is more then there is
+        let mut request = binding_request(&TRANSACTION);
+        request[3] = 4;
+        assert!(Message::parse(&request).is_none());
+
+        // a attribute that is longer then the message
+        let mut response = response;
+        response[HEADER_LEN + 3] = 0xff;
+        assert!(Message::parse(&response).is_none());
+
+        for len in 0..XOR_IPV4.len() {
+            assert_eq!(decode_adress(&XOR_IPV4[..len], Some(&TRANSACTION)), None);
+        }
+        for len in 0..XOR_IPV6.len() {
+            assert_eq!(decode_adress(&XOR_IPV6[..len], Some(&TRANSACTION)), None);
+        }
+    }
+}
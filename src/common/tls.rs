@@ -0,0 +1,515 @@
+#[cfg(feature = "tls")]
+use std::{io::Read, sync::Arc, time::SystemTime};
+use std::{
+    io::{self, Write},
+    net::Shutdown,
+    sync::Mutex,
+};
+
+#[cfg(feature = "tls")]
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, ServerName,
+};
+#[cfg(feature = "tls")]
+use sha2::{Digest, Sha256};
+use socket2::Socket;
+#[cfg(feature = "tokio")]
+use tokio::net::TcpStream;
+
+use super::{
+    frame::{encode, FrameDecoder, FrameError, MAX_PENDING},
+    packets::Packets,
+};
+
+/// The read half of a control connection on tokio, encrypted or not
+#[cfg(feature = "tokio")]
+pub type AsyncReader = Box<dyn tokio::io::AsyncRead + Send + Unpin>;
+/// The write half of a control connection on tokio, encrypted or not
+#[cfg(feature = "tokio")]
+pub type AsyncWriter = Box<dyn tokio::io::AsyncWrite + Send + Unpin>;
+
+/// How the client checks the certificate of the relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsPin {
+    /// Trusts certificates signed by one of this CA certificates (DER)
+    Roots(Vec<Vec<u8>>),
+    /// Trusts only certificates with one of this SHA-256 fingerprints of the DER
+    /// the name of the relay is not checked
+    Sha256(Vec<[u8; 32]>),
+}
+
+/// TLS settings of a client `Connection`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// The name that the certificate has to be valid for, the host of the relay if empty
+    pub server_name: String,
+    pub pin: TlsPin,
+}
+
+impl TlsOptions {
+    pub fn new(pin: TlsPin) -> Self {
+        Self {
+            server_name: String::new(),
+            pin,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    /// Compiled without the `tls` feature
+    Unsupported,
+    InvalidName,
+    InvalidCertificate,
+    /// The handshake of a async stream failed without a TLS error
+    Io(io::Error),
+    #[cfg(feature = "tls")]
+    Rustls(rustls::Error),
+    #[cfg(feature = "tls")]
+    Generate(rcgen::RcgenError),
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for TlsError {
+    fn from(value: rustls::Error) -> Self {
+        Self::Rustls(value)
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(value: io::Error) -> Self {
+        #[cfg(feature = "tls")]
+        if let Some(error) = value
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<rustls::Error>())
+        {
+            return Self::Rustls(error.clone());
+        }
+        Self::Io(value)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rcgen::RcgenError> for TlsError {
+    fn from(value: rcgen::RcgenError) -> Self {
+        Self::Generate(value)
+    }
+}
+
+/// The TLS session of a control connection and the bytes that the socket did not take yet
+/// Without a session the packets are sent in cleartext
+#[derive(Debug, Default)]
+pub struct Tls {
+    #[cfg(feature = "tls")]
+    session: Option<Mutex<rustls::Connection>>,
+    /// Allways locked before `session`, so the records are queued in order
+    pending: Mutex<Vec<u8>>,
+}
+
+impl Tls {
+    #[cfg(feature = "tls")]
+    pub fn new(session: impl Into<rustls::Connection>) -> Self {
+        let mut session = session.into();
+        // `pending` is limited by `MAX_PENDING`
+        session.set_buffer_limit(None);
+        Self {
+            session: Some(Mutex::new(session)),
+            pending: Mutex::default(),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn server(config: &Arc<ServerConfig>) -> Result<Self, TlsError> {
+        Ok(Self::new(ServerConnection::new(config.clone())?))
+    }
+
+    /// `host` is used as the server name if `options.server_name` is empty
+    #[cfg(feature = "tls")]
+    pub fn client(options: &TlsOptions, host: &str) -> Result<Self, TlsError> {
+        Ok(Self::new(ClientConnection::new(
+            client_config(options)?,
+            server_name(options, host)?,
+        )?))
+    }
+
+    /// A client session if `options` is set, else no TLS
+    pub fn connect(options: Option<&TlsOptions>, host: &str) -> Result<Self, TlsError> {
+        let Some(options) = options else {
+            return Ok(Self::default());
+        };
+
+        #[cfg(feature = "tls")]
+        return Self::client(options, host);
+
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = (options, host);
+            Err(TlsError::Unsupported)
+        }
+    }
+
+    /// Same as `connect` for a tokio stream, returns the halves of the stream
+    #[cfg(feature = "tokio")]
+    pub async fn connect_async(
+        options: Option<&TlsOptions>,
+        host: &str,
+        stream: TcpStream,
+    ) -> Result<(AsyncReader, AsyncWriter), TlsError> {
+        let Some(options) = options else {
+            let (reader, writer) = stream.into_split();
+            return Ok((Box::new(reader), Box::new(writer)));
+        };
+
+        #[cfg(feature = "tls")]
+        {
+            let connector = tokio_rustls::TlsConnector::from(client_config(options)?);
+            let stream = connector
+                .connect(server_name(options, host)?, stream)
+                .await?;
+            let (reader, writer) = tokio::io::split(stream);
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = (options, host);
+            Err(TlsError::Unsupported)
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.session.is_some();
+
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    /// Queues the hole frame, encrypted if there is a session, and writes what the socket takes
+    /// The rest is written by `flush`, if more then `MAX_PENDING` bytes would wait
+    /// the connection is shutdown, so no frame is ever written partially
+    pub fn write_packet(&self, conn: &Socket, packet: &Packets) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let frame = encode(packet);
+        if pending.len() + frame.len() > MAX_PENDING {
+            let _ = conn.shutdown(Shutdown::Both);
+            return Err(io::Error::other("The peer does not read what is sent"));
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(session) = &self.session {
+            let mut session = session.lock().unwrap();
+            session.writer().write_all(&frame)?;
+            take_tls(&mut session, &mut pending)?;
+            return write_pending(&mut pending, conn);
+        }
+
+        pending.extend(frame);
+        write_pending(&mut pending, conn)
+    }
+
+    /// Writes what the socket takes from the queued bytes
+    pub fn flush(&self, conn: &Socket) -> io::Result<()> {
+        write_pending(&mut self.pending.lock().unwrap(), conn)
+    }
+
+    /// There are bytes that wait for the socket to be writable
+    pub fn has_pending(&self) -> bool {
+        !self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Same as `FrameDecoder::fill` but decrypted if there is a session
+    /// Returns `Ok(0)` if there was nothing or only handshake data
+    pub fn fill(&self, conn: &Socket, buffer: &mut FrameDecoder) -> Result<usize, FrameError> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = &self.session {
+            let mut pending = self.pending.lock().unwrap();
+            let mut session = session.lock().unwrap();
+            match session.read_tls(&mut &*conn) {
+                Ok(0) => return Err(FrameError::Closed),
+                Ok(_) => {}
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::Interrupted =>
+                {
+                    return Ok(0)
+                }
+                Err(err) => return Err(err.into()),
+            }
+
+            let state = session
+                .process_new_packets()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            // answers to the handshake
+            take_tls(&mut session, &mut pending)?;
+            write_pending(&mut pending, conn)?;
+
+            let len = state.plaintext_bytes_to_read();
+            if len == 0 && state.peer_has_closed() {
+                return Err(FrameError::Closed);
+            }
+
+            let mut bytes = vec![0; len];
+            session.reader().read_exact(&mut bytes)?;
+            buffer.push(&bytes);
+            return Ok(len);
+        }
+
+        buffer.fill(conn)
+    }
+
+    /// Same as `FrameDecoder::recv_packet` but decrypted if there is a session
+    pub fn recv_packet(
+        &self,
+        conn: &Socket,
+        buffer: &mut FrameDecoder,
+    ) -> Result<Packets, FrameError> {
+        loop {
+            if let Some(packet) = buffer.next_packet()? {
+                return Ok(packet);
+            }
+            self.fill(conn, buffer)?;
+        }
+    }
+}
+
+/// Moves everything that the session has to send to `pending`
+#[cfg(feature = "tls")]
+fn take_tls(session: &mut rustls::Connection, pending: &mut Vec<u8>) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(pending)?;
+    }
+    Ok(())
+}
+
+/// Writes from the front of `pending` until the non blocking socket is full
+fn write_pending(pending: &mut Vec<u8>, mut conn: &Socket) -> io::Result<()> {
+    while !pending.is_empty() {
+        match conn.write(pending) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => {
+                pending.drain(0..len);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// `options.server_name` or `host` if it is empty
+#[cfg(feature = "tls")]
+fn server_name(options: &TlsOptions, host: &str) -> Result<ServerName, TlsError> {
+    let name = if options.server_name.is_empty() {
+        host
+    } else {
+        &options.server_name
+    };
+    ServerName::try_from(name).map_err(|_| TlsError::InvalidName)
+}
+
+/// Config for a relay with the certificate chain and private key in DER
+#[cfg(feature = "tls")]
+pub fn server_config(
+    cert_chain: Vec<Vec<u8>>,
+    key: Vec<u8>,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            cert_chain.into_iter().map(Certificate).collect(),
+            PrivateKey(key),
+        )?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(feature = "tls")]
+pub fn client_config(options: &TlsOptions) -> Result<Arc<ClientConfig>, TlsError> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match &options.pin {
+        TlsPin::Roots(certs) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certs {
+                roots
+                    .add(&Certificate(cert.clone()))
+                    .map_err(|_| TlsError::InvalidCertificate)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsPin::Sha256(fingerprints) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                fingerprints: fingerprints.clone(),
+            }))
+            .with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// SHA-256 of a certificate in DER, used by `TlsPin::Sha256`
+#[cfg(feature = "tls")]
+pub fn fingerprint(cert: &[u8]) -> [u8; 32] {
+    Sha256::digest(cert).into()
+}
+
+/// Accepts only the pinned certificates, the handshake signature is still verified
+#[cfg(feature = "tls")]
+struct PinnedVerifier {
+    fingerprints: Vec<[u8; 32]>,
+}
+
+#[cfg(feature = "tls")]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.fingerprints.contains(&fingerprint(&end_entity.0)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from(
+                "Certificate is not pinned",
+            )))
+        }
+    }
+}
+
+/// A test CA and a certificate signed by it, so TLS can be used without a real certificate
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct SelfSigned {
+    pub ca: Vec<u8>,
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+#[cfg(feature = "tls")]
+impl SelfSigned {
+    /// `names` are the DNS names or IPs that the certificate is valid for
+    pub fn generate(names: &[&str]) -> Result<Self, TlsError> {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+
+        let mut params = CertificateParams::new(Vec::<String>::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "relay-man test CA");
+        let ca = Certificate::from_params(params)?;
+
+        let mut params = CertificateParams::new(Vec::<String>::new());
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "relay-man relay");
+        params.subject_alt_names = names
+            .iter()
+            .map(|name| match name.parse() {
+                Ok(ip) => SanType::IpAddress(ip),
+                Err(_) => SanType::DnsName(name.to_string()),
+            })
+            .collect();
+        let cert = Certificate::from_params(params)?;
+
+        Ok(Self {
+            ca: ca.serialize_der()?,
+            cert: cert.serialize_der_with_signer(&ca)?,
+            key: cert.serialize_private_key_der(),
+        })
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        server_config(vec![self.cert.clone()], self.key.clone())
+    }
+
+    /// Trusts the test CA
+    pub fn pin(&self) -> TlsPin {
+        TlsPin::Roots(vec![self.ca.clone()])
+    }
+
+    /// Trusts only the certificate
+    pub fn pin_sha256(&self) -> TlsPin {
+        TlsPin::Sha256(vec![fingerprint(&self.cert)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::common::packets::RegisterResponse;
+
+    /// A non blocking socket and its blocking peer that does not read
+    fn pair() -> (Socket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let conn = Socket::from(conn);
+        conn.set_nonblocking(true).unwrap();
+        (conn, peer)
+    }
+
+    fn big() -> Packets {
+        Packets::RegisterResponse(RegisterResponse::Challenge {
+            nonce: vec![7; 64 * 1024],
+        })
+    }
+
+    #[test]
+    fn full_socket_queues_hole_frames() {
+        let (conn, mut peer) = pair();
+        let tls = Tls::default();
+
+        let mut sent = 0;
+        while !tls.has_pending() {
+            tls.write_packet(&conn, &big()).unwrap();
+            sent += 1;
+        }
+        tls.write_packet(&conn, &big()).unwrap();
+        sent += 1;
+
+        let reader = std::thread::spawn(move || {
+            let mut decoder = FrameDecoder::default();
+            for _ in 0..sent {
+                let packet = decoder.recv_packet(&mut peer).unwrap();
+                assert!(matches!(
+                    packet,
+                    Packets::RegisterResponse(RegisterResponse::Challenge { nonce })
+                        if nonce.len() == 64 * 1024
+                ));
+            }
+        });
+
+        while tls.has_pending() {
+            tls.flush(&conn).unwrap();
+            std::thread::yield_now();
+        }
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn peer_that_does_not_read_is_closed() {
+        let (conn, mut peer) = pair();
+        let tls = Tls::default();
+
+        let mut queued = 0;
+        while tls.write_packet(&conn, &big()).is_ok() {
+            queued += 1;
+            assert!(queued < 1000);
+        }
+
+        // the stream ends with at most the start of a frame, never with an other frame after it
+        let mut decoder = FrameDecoder::default();
+        while let Ok(packet) = decoder.recv_packet(&mut peer) {
+            assert!(matches!(packet, Packets::RegisterResponse(_)));
+        }
+        assert!(matches!(decoder.next_packet(), Ok(None)));
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use bytes_kman::TBytes;
+use polling::Poller;
+use socket2::{SockAddr, Socket};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::common::{
+    adress::Adress,
+    frame::{read_packet_async, FrameDecoder, FrameError},
+    packets::{ErrorCode, ErrorRequest},
+    tls::Tls,
+    AsRawSock,
+};
+
+use super::{Client, ClientStage, RelayServer, RelayServerError, LISTENER_KEY, RELAY_KEY};
+
+/// How many frames can wait for a client, it is disconnected if it does not read them
+pub const SEND_QUEUE: usize = 256;
+
+/// Runs a `RelayServer` on tokio, every control connection is a task
+/// The packets are processed by the same handlers as the `RelayServer`
+#[derive(Clone, Debug)]
+pub struct AsyncRelayServer {
+    pub server: Arc<Mutex<RelayServer>>,
+}
+
+impl AsyncRelayServer {
+    pub fn new(ip: impl Into<String>, client_timeout: Duration) -> Result<Self, RelayServerError> {
+        Ok(Self {
+            server: Arc::new(Mutex::new(RelayServer::new(ip, client_timeout)?)),
+        })
+    }
+
+    /// Same as `RelayServer::bind`
+    pub fn bind(
+        adresses: impl ToSocketAddrs,
+        client_timeout: Duration,
+    ) -> Result<Self, RelayServerError> {
+        Ok(Self {
+            server: Arc::new(Mutex::new(RelayServer::bind(adresses, client_timeout)?)),
+        })
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.server.lock().unwrap().local_addrs()
+    }
+
+    /// Accepts clients on every listener of the relay, never returns if it could start
+    /// needs to be called inside of a tokio runtime
+    /// The TLS config and the `RelayQuota` have to be set before
+    pub async fn run(&self) -> io::Result<()> {
+        // the relayed ports are in the poller of the relay, a thread waits for them
+        let _relay_thread = if self.server.lock().unwrap().relay_quota.is_some() {
+            Some(RelayThread::spawn(self.server.clone()))
+        } else {
+            None
+        };
+
+        let mut sockets = Vec::new();
+        for listener in self.server.lock().unwrap().listeners.iter() {
+            sockets.push((
+                listener.conn.try_clone()?,
+                listener.conn_udp.try_clone()?,
+                listener.conn_alt.try_clone()?,
+            ));
+        }
+
+        // the tasks are aborted when `run` is dropped
+        let mut tasks = JoinSet::new();
+        for (index, (listener, udp, alt)) in sockets.into_iter().enumerate() {
+            let listener = TcpListener::from_std(listener.into())?;
+            let udp = UdpSocket::from_std(udp.into())?;
+            let alt = UdpSocket::from_std(alt.into())?;
+
+            tasks.spawn(self.clone().accept_loop(listener));
+            tasks.spawn(self.clone().udp_loop(index, false, udp));
+            tasks.spawn(self.clone().udp_loop(index, true, alt));
+        }
+
+        let interval = self.server.lock().unwrap().timers.housekeeping_interval;
+        let mut housekeeping = tokio::time::interval(interval);
+        loop {
+            housekeeping.tick().await;
+            self.server.lock().unwrap().housekeeping();
+        }
+    }
+
+    async fn accept_loop(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, from)) => {
+                    if let Err(error) = self.accept(stream, from) {
+                        log::error!("Cannot accept: {from:?}, {error:?}");
+                    }
+                }
+                Err(error) => log::error!("Accept failed: {error:?}"),
+            }
+        }
+    }
+
+    /// STUN Binding responses are sent by `RelayServer::on_stun_packet`
+    /// `alternate` sockets only answer STUN
+    async fn udp_loop(self, listener: usize, alternate: bool, udp: UdpSocket) {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((len, from)) = udp.recv_from(&mut buffer).await else {
+                continue;
+            };
+            let from_addr = SockAddr::from(from);
+            let stun = self.server.lock().unwrap().on_stun_packet(
+                listener,
+                alternate,
+                &buffer[0..len],
+                &from_addr,
+            );
+            if stun || alternate {
+                continue;
+            }
+
+            let pak = {
+                let mut server = self.server.lock().unwrap();
+                let pak = server.on_udp_packet(buffer[0..len].to_vec(), &from_addr);
+                server.connect();
+                pak
+            };
+
+            log::trace!("UDP Sent: {from:?}, {pak:?}");
+            let mut bytes = pak.to_bytes();
+            bytes.reverse();
+            let _ = udp.send_to(&bytes, from).await;
+        }
+    }
+
+    fn accept(&self, stream: TcpStream, from: SocketAddr) -> io::Result<()> {
+        let conn = Socket::from(stream.into_std()?);
+        let stream = TcpStream::from_std(conn.try_clone()?.into())?;
+
+        #[cfg(feature = "tls")]
+        if let Some(config) = self.server.lock().unwrap().tls.clone() {
+            tokio::spawn(self.clone().accept_tls(config, conn, stream, from));
+            return Ok(());
+        }
+
+        let (reader, writer) = stream.into_split();
+        self.add_client(conn, from, reader, writer);
+        Ok(())
+    }
+
+    /// The client is only added after the handshake
+    #[cfg(feature = "tls")]
+    async fn accept_tls(
+        self,
+        config: Arc<rustls::ServerConfig>,
+        conn: Socket,
+        stream: TcpStream,
+        from: SocketAddr,
+    ) {
+        let timeout = self.server.lock().unwrap().timers.handshake_timeout;
+        let acceptor = tokio_rustls::TlsAcceptor::from(config);
+
+        match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => {
+                let (reader, writer) = tokio::io::split(stream);
+                self.add_client(conn, from, reader, writer);
+            }
+            Ok(Err(error)) => log::trace!("From: {from:?}, TLS handshake failed: {error:?}"),
+            Err(_) => log::trace!("From: {from:?}, TLS handshake timed out"),
+        }
+    }
+
+    fn add_client(
+        &self,
+        conn: Socket,
+        from: SocketAddr,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) {
+        let (queue, frames) = mpsc::channel(SEND_QUEUE);
+
+        let session = {
+            let mut server = self.server.lock().unwrap();
+            let session = server.create_session();
+
+            let client = Client {
+                session,
+                fd: conn.as_raw(),
+                conn,
+                from: SockAddr::from(from),
+                stage: ClientStage::NotRegistered,
+                last_message: SystemTime::now(),
+                connected: SystemTime::now(),
+                buffer: FrameDecoder::default(),
+                version: 0,
+                capabilities: Vec::new(),
+                // the stream is encrypted by the tasks
+                tls: Tls::default(),
+                writer: Some(queue),
+            };
+
+            log::trace!("Accept: {from:?}, Client: {client:?}");
+
+            server.clients.push(client);
+            session
+        };
+
+        tokio::spawn(process_client(self.server.clone(), session, reader));
+        tokio::spawn(write_client(writer, frames));
+    }
+}
+
+/// Waits on the poller of the relay and forwards the datagrams of the relayed ports
+/// Stops when dropped
+struct RelayThread {
+    stop: Arc<AtomicBool>,
+    poller: Arc<Poller>,
+}
+
+impl RelayThread {
+    fn spawn(server: Arc<Mutex<RelayServer>>) -> Self {
+        let poller = server.lock().unwrap().poller.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = Self {
+            stop: stop.clone(),
+            poller: poller.clone(),
+        };
+        std::thread::spawn(move || {
+            let mut events = Vec::new();
+            while !stop.load(Ordering::SeqCst) {
+                events.clear();
+                if poller.wait(&mut events, None).is_err() {
+                    break;
+                }
+
+                let mut server = server.lock().unwrap();
+                // the listeners are also in the poller but are handled by tokio
+                for event in events.iter() {
+                    if (RELAY_KEY..LISTENER_KEY).contains(&event.key) {
+                        server.on_relay_socket(event.key);
+                    }
+                }
+            }
+        });
+        thread
+    }
+}
+
+impl Drop for RelayThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.poller.notify();
+    }
+}
+
+/// Writes the frames of one client, ends when the client is removed
+async fn write_client(mut writer: impl AsyncWrite + Unpin, mut frames: mpsc::Receiver<Vec<u8>>) {
+    while let Some(frame) = frames.recv().await {
+        if writer.write_all(&frame).await.is_err() || writer.flush().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads the packets of one client until it is closed or removed
+async fn process_client(
+    server: Arc<Mutex<RelayServer>>,
+    session: usize,
+    mut reader: impl AsyncRead + Unpin,
+) {
+    let mut buffer = FrameDecoder::default();
+
+    loop {
+        let packet = read_packet_async(&mut reader, &mut buffer).await;
+
+        let mut relay = server.lock().unwrap();
+        let Some(index) = relay.clients.index_of(session) else {
+            break;
+        };
+
+        match packet {
+            Ok(packet) => {
+                let mut packets = vec![packet];
+                while let Ok(Some(packet)) = buffer.next_packet() {
+                    packets.push(packet);
+                }
+
+                relay.handle_packets(index, packets);
+                relay.connect();
+            }
+            Err(error) => {
+                let Some(mut client) = relay.clients.get_mut(index) else {
+                    break;
+                };
+                log::trace!("From: {:?}, closed: {error:?}", client.from);
+                if let FrameError::TooLarge(_) = error {
+                    client.send_error(
+                        ErrorCode::PayloadTooLarge,
+                        ErrorRequest::Unknown,
+                        0,
+                        Adress::new(),
+                    );
+                }
+                client.last_message = SystemTime::UNIX_EPOCH;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::common::packets::Packets;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Runs `server` until the returned task is aborted
+    fn start(server: &AsyncRelayServer) -> tokio::task::JoinHandle<io::Result<()>> {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    }
+
+    #[test]
+    fn full_send_queue_disconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, from) = listener.accept().unwrap();
+
+        let (queue, _frames) = mpsc::channel(SEND_QUEUE);
+        let conn = Socket::from(conn);
+        let client = Client {
+            session: 1,
+            fd: conn.as_raw(),
+            conn,
+            from: SockAddr::from(from),
+            stage: ClientStage::NotRegistered,
+            last_message: SystemTime::now(),
+            connected: SystemTime::now(),
+            buffer: FrameDecoder::default(),
+            version: 0,
+            capabilities: Vec::new(),
+            tls: Tls::default(),
+            writer: Some(queue),
+        };
+
+        let tick = Packets::Tick { session: 1 };
+        for _ in 0..SEND_QUEUE {
+            client.send(&tick).unwrap();
+        }
+        assert!(client.send(&tick).is_err());
+
+        // nothing was written and the connection was closed
+        let mut buffer = [0; 1];
+        assert_eq!(peer.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[cfg(all(feature = "tls", feature = "client"))]
+    #[test]
+    fn tls_pins() {
+        use crate::{
+            client::{async_client::AsyncConnection, ConnectionError, ConnectionInfo},
+            common::tls::{SelfSigned, TlsError, TlsOptions},
+        };
+
+        runtime().block_on(async {
+            let cert = SelfSigned::generate(&["127.0.0.1"]).unwrap();
+            let other = SelfSigned::generate(&["127.0.0.1"]).unwrap();
+
+            let server = AsyncRelayServer::bind("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+            server
+                .server
+                .lock()
+                .unwrap()
+                .set_tls(vec![cert.cert.clone()], cert.key.clone())
+                .unwrap();
+            let relay = server.local_addrs()[0].to_string();
+            let task = start(&server);
+
+            let pins = [
+                (cert.pin(), true),
+                (cert.pin_sha256(), true),
+                (other.pin(), false),
+                (other.pin_sha256(), false),
+            ];
+            for (index, (pin, trusted)) in pins.into_iter().enumerate() {
+                let info = ConnectionInfo {
+                    client: String::from("test"),
+                    name: String::from("test"),
+                    public: vec![index as u8],
+                    other: vec![],
+                    privacy: false,
+                    auth: Default::default(),
+                    identity: None,
+                    tls: Some(TlsOptions::new(pin)),
+                };
+
+                match AsyncConnection::new(relay.clone(), info).await {
+                    Ok(connection) => {
+                        assert!(trusted);
+                        assert_eq!(connection.adress(), vec![index as u8]);
+                    }
+                    Err(ConnectionError::Tls(TlsError::Rustls(_))) => assert!(!trusted),
+                    Err(error) => panic!("Unexpected: {error:?}"),
+                }
+            }
+            task.abort();
+        });
+    }
+
+    #[test]
+    fn relays_datagrams() {
+        use std::net::UdpSocket;
+
+        use crate::{
+            common::packets::{RelayRequest, RELAY_BIND},
+            server::{
+                testing::{recv, register},
+                RelayQuota,
+            },
+        };
+
+        runtime().block_on(async {
+            let server = AsyncRelayServer::bind("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+            server
+                .server
+                .lock()
+                .unwrap()
+                .set_relay_quota(RelayQuota::default());
+            let task = start(&server);
+            tokio::task::yield_now().await;
+
+            let mut sides = Vec::new();
+            {
+                let mut relay = server.server.lock().unwrap();
+                let (a, peer_a) = register(&mut relay, vec![1]);
+                let (b, peer_b) = register(&mut relay, vec![2]);
+                let sessions = [relay.clients[a].session, relay.clients[b].session];
+                let (token_a, token_b) = relay.relay_tokens(sessions[0], sessions[1]);
+
+                for (index, peer, token) in [(a, peer_a, token_a), (b, peer_b, token_b)] {
+                    let request = RelayRequest {
+                        session: sessions[sides.len()],
+                        id: 1,
+                        token: token.clone(),
+                    };
+                    relay.on_relay_request(index, request);
+                    sides.push((peer, token));
+                }
+            }
+
+            let mut sockets = Vec::new();
+            for (mut peer, token) in sides {
+                let Packets::RelayResponse(response) = recv(&mut peer) else {
+                    panic!("No relayed port");
+                };
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                socket.connect(("127.0.0.1", response.port)).unwrap();
+
+                let mut bind = vec![RELAY_BIND];
+                bind.extend(token);
+                socket.send(&bind).unwrap();
+                let mut buffer = [0; 64];
+                let len = socket.recv(&mut buffer).unwrap();
+                assert_eq!(&buffer[0..len], &bind[..]);
+                sockets.push(socket);
+            }
+
+            sockets[0].send(b"hello").unwrap();
+            let mut buffer = [0; 64];
+            let len = sockets[1].recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[0..len], b"hello");
+
+            task.abort();
+        });
+    }
+}
@@ -1,16 +1,91 @@
-use std::time::SystemTime;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::SystemTime,
+};
 
-use bytes_kman::TBytes;
+use crate::common::packets::{Candidate, CandidateKind, ConnectOn, NatType, Packets};
 
-use crate::common::packets::{ConnectOn, Packets};
+use super::{socket_addr, ClientStage, Connecting, RelayServer};
 
-use super::{ClientStage, Connecting, RelayServer};
+/// `Register::Probe` ports kept for every connection of a client, older ones are dropped
+pub const MAX_PROBES: usize = 16;
+/// How many ports after the registered port are predicted
+pub const PREDICTED_PORTS: i32 = 16;
+/// `Register::Candidates` kept for every client and candidates kept of every one
+pub const MAX_CANDIDATES: usize = 16;
+
+/// Formats `host` and `port` so that it can be parsed back, IPv6 as `[ip]:port`
+pub fn host_port(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{host}:{port}"),
+    }
+}
+
+/// The ports that NATs allocate from, they start again at the lowest after the highest
+const NAT_PORTS: std::ops::RangeInclusive<i32> = 1024..=u16::MAX as i32;
+
+/// Predicts the next ports of a symmetric NAT from the ports it allocated before,
+/// oldest first, the last one should be the port used to connect
+/// Returns nothing if the NAT keeps the same port, there are not enough ports
+/// or no step between them is seen twice, like when the NAT allocates random ports
+pub fn predict_ports(ports: &[u16]) -> Vec<u16> {
+    let range = NAT_PORTS.end() - NAT_PORTS.start() + 1;
+    // the step is the shortest way around the range, so a wrap does not change it
+    let deltas: Vec<i32> = ports
+        .windows(2)
+        .map(|w| (w[1] as i32 - w[0] as i32).rem_euclid(range))
+        .map(|delta| {
+            if delta > range / 2 {
+                delta - range
+            } else {
+                delta
+            }
+        })
+        .collect();
+
+    // the most common step, other traffic through the NAT can skip some ports
+    let delta = deltas
+        .iter()
+        .copied()
+        .map(|delta| (delta, deltas.iter().filter(|d| **d == delta).count()))
+        .max_by_key(|(_, count)| *count);
+
+    let (Some((delta, count)), Some(last)) = (delta, ports.last()) else {
+        return Vec::new();
+    };
+    if delta == 0 || count < 2 {
+        return Vec::new();
+    }
+
+    (1..=PREDICTED_PORTS)
+        .map(|i| *last as i32 + delta * i)
+        .map(|port| NAT_PORTS.start() + (port - NAT_PORTS.start()).rem_euclid(range))
+        .map(|port| port as u16)
+        .collect()
+}
+
+/// Adds the adress that the relay saw to `candidates` if the client sent some
+fn with_reflexive(mut candidates: Vec<Candidate>, adress: SocketAddr) -> Vec<Candidate> {
+    let reflexive = Candidate::new(CandidateKind::ServerReflexive, adress, u16::MAX);
+    let known = candidates
+        .iter()
+        .any(|candidate| candidate.adress() == Some(adress));
+    if !candidates.is_empty() && !known {
+        candidates.push(reflexive);
+    }
+    candidates
+}
 
 impl RelayServer {
-    pub(crate) fn connect(&mut self) {
+    /// Sends `ConnectOn` to both clients when both accepted and registered a port
+    pub fn connect(&mut self) {
         let mut connect = Vec::new();
 
-        for client in self.clients.iter() {
+        for session in self.clients.connecting.iter() {
+            let Some(client) = self.clients.by_session(*session) else {
+                continue;
+            };
             if let ClientStage::Registered(rclient) = &client.stage {
                 if !rclient.to_connect.is_empty() && !rclient.ports.is_empty() {
                     for to_conn in rclient.to_connect.iter() {
@@ -25,69 +100,46 @@ impl RelayServer {
         // in connect every connection will be double but when connecting will be consumed and
         // seccond time nothing will happend!
 
-        'm: for conn in connect {
-            let mut index1 = 0;
-            let mut index2 = 0;
-
-            let mut connecting_to = None;
-            for (i, client) in self.clients.iter().enumerate() {
-                if client.session == conn.0 {
-                    if let ClientStage::Registered(rclient) = &client.stage {
-                        for conn_to in rclient.to_connect.iter() {
-                            if conn_to.session() == conn.1 {
-                                connecting_to = Some(client.session);
-                                break;
-                            }
-                        }
-                    }
-                    index1 = i;
-                    // cache the first client
-                    // this is ok because the hole relay is single threded
-                    break;
-                }
-            }
+        for conn in connect {
+            let Some(index1) = self.clients.index_of(conn.0) else {
+                continue;
+            };
+            let Some(index2) = self.clients.index_of(conn.1) else {
+                continue;
+            };
 
-            if let Some(connecting_to) = connecting_to {
-                for (i, client) in self.clients.iter().enumerate() {
-                    if client.session == conn.1 {
-                        let mut finded = false;
-
-                        if let ClientStage::Registered(rclient) = &client.stage {
-                            for conn_to in rclient.to_connect.iter() {
-                                if conn_to.session() == connecting_to {
-                                    finded = true;
-                                    break;
-                                }
-                            }
-                        }
-                        if !finded {
-                            continue 'm;
-                        }
-                        index2 = i;
-                        // cache the the seccond client
-                        // this is ok because the hole relay is single threded
-                        break;
-                    }
+            let is_connecting = |index: usize, to: usize| {
+                if let ClientStage::Registered(rclient) = &self.clients[index].stage {
+                    rclient
+                        .to_connect
+                        .iter()
+                        .any(|conn_to| conn_to.session() == to)
+                } else {
+                    false
                 }
-            } else {
+            };
+
+            if !is_connecting(index1, conn.1) || !is_connecting(index2, conn.0) {
                 continue;
             }
 
             let port1;
             let port2;
             let adress1;
-            let adress2;
             let private_adress1;
             let private_adress2;
             let addr1;
             let addr2;
+            let nat1;
+            let nat2;
 
-            if let Some(client) = self.clients.get_mut(index1) {
+            if let Some(mut client) = self.clients.get_mut(index1) {
+                adress1 = client.from.clone();
                 if let ClientStage::Registered(rclient) = &mut client.stage {
-                    port1 = rclient.ports.pop();
-                    adress1 = client.from.clone();
+                    port1 = rclient.take_port(conn.1);
                     addr1 = rclient.adress.clone();
                     private_adress1 = rclient.private_adress.clone();
+                    nat1 = rclient.nat;
                 } else {
                     continue;
                 }
@@ -95,44 +147,45 @@ impl RelayServer {
                 continue;
             }
 
-            if let Some(client) = self.clients.get_mut(index2) {
-                if let ClientStage::Registered(rclient) = &mut client.stage {
-                    port2 = rclient.ports.pop();
-                    adress2 = client.from.clone();
-                    addr2 = rclient.adress.clone();
-                    private_adress2 = rclient.private_adress.clone();
-                } else {
-                    continue;
-                }
-            } else {
+            let Some(mut client) = self.clients.get_mut(index2) else {
                 if let Some(port1) = port1 {
-                    if let Some(client) = self.clients.get_mut(index1) {
+                    if let Some(mut client) = self.clients.get_mut(index1) {
                         if let ClientStage::Registered(rclient) = &mut client.stage {
-                            rclient.ports.push(port1)
+                            rclient.ports.push((conn.1, port1))
                         } else {
                             continue;
                         }
                     }
                 }
                 continue;
+            };
+            let adress2 = client.from.clone();
+            if let ClientStage::Registered(rclient) = &mut client.stage {
+                port2 = rclient.take_port(conn.0);
+                addr2 = rclient.adress.clone();
+                private_adress2 = rclient.private_adress.clone();
+                nat2 = rclient.nat;
+            } else {
+                continue;
             }
+            drop(client);
 
             let Some(port1) = port1 else {
                 if let Some(port2) = port2{
-                    if let Some(client) = self.clients.get_mut(index2) {
+                    if let Some(mut client) = self.clients.get_mut(index2) {
 
                         if let ClientStage::Registered(rclient) = &mut client.stage {
-                        rclient.ports.push(port2);
+                        rclient.ports.push((conn.0, port2));
                         }else{continue}
                     }
                 }
                 continue
             };
             let Some(port2) = port2 else {
-                if let Some(client) = self.clients.get_mut(index1){
+                if let Some(mut client) = self.clients.get_mut(index1){
 
                         if let ClientStage::Registered(rclient) = &mut client.stage {
-                   rclient.ports.push(port1);
+                   rclient.ports.push((conn.1, port1));
                     }else{
                         continue
                     }
@@ -140,26 +193,49 @@ impl RelayServer {
                 continue
             };
 
-            if let Some(client) = self.clients.get_mut(index1) {
+            let mut id1 = 0;
+            let mut id2 = 0;
+            let mut probes1 = Vec::new();
+            let mut probes2 = Vec::new();
+            let mut candidates1 = Vec::new();
+            let mut candidates2 = Vec::new();
+
+            if let Some(mut client) = self.clients.get_mut(index1) {
                 if let ClientStage::Registered(rclient) = &mut client.stage {
                     rclient
                         .to_connect
                         .retain(|to_conn| to_conn.session() != conn.1);
+                    id1 = rclient.request_id(conn.1);
+                    rclient.remove_request_id(conn.1);
+                    probes1 = rclient.take_probes(conn.1);
+                    candidates1 = rclient.take_candidates(port1);
                 }
             }
-            if let Some(client) = self.clients.get_mut(index2) {
+            if let Some(mut client) = self.clients.get_mut(index2) {
                 if let ClientStage::Registered(rclient) = &mut client.stage {
                     rclient
                         .to_connect
                         .retain(|to_conn| to_conn.session() != conn.0);
+                    id2 = rclient.request_id(conn.0);
+                    rclient.remove_request_id(conn.0);
+                    probes2 = rclient.take_probes(conn.0);
+                    candidates2 = rclient.take_candidates(port2);
                 }
             }
 
-            let adress1 = adress1.as_socket().unwrap().ip();
-            let adress2 = adress2.as_socket().unwrap().ip();
+            let adress1 = socket_addr(&adress1).unwrap().ip();
+            let adress2 = socket_addr(&adress2).unwrap().ip();
 
             let has_the_same_ip = adress2 == adress1;
 
+            let mut candidates1 = with_reflexive(candidates1, SocketAddr::new(adress1, port1));
+            let mut candidates2 = with_reflexive(candidates2, SocketAddr::new(adress2, port2));
+            // both peers have to run the checks, else one would hole punch
+            if candidates1.is_empty() || candidates2.is_empty() {
+                candidates1.clear();
+                candidates2.clear();
+            }
+
             let (adress1, adress2) = if has_the_same_ip {
                 (private_adress1, private_adress2)
             } else {
@@ -172,33 +248,148 @@ impl RelayServer {
                 .as_nanos()
                 + conn.2;
 
+            // on the same network the NATs are not between the peers
+            let predict = |nat: NatType, mut probes: Vec<u16>, port: u16| {
+                if has_the_same_ip || !matches!(nat, NatType::Symmetric | NatType::Unknown) {
+                    return Vec::new();
+                }
+                probes.push(port);
+                predict_ports(&probes)
+            };
+            let predicted1 = predict(nat1, probes1, port1);
+            let predicted2 = predict(nat2, probes2, port2);
+
+            // a symmetric NAT with predicted ports can be punched like a port restricted one
+            let predictable = |nat: NatType, predicted: &Vec<u16>| {
+                if nat == NatType::Symmetric && !predicted.is_empty() {
+                    NatType::PortRestrictedCone
+                } else {
+                    nat
+                }
+            };
+            let (relay1, relay2) = self.relay_tokens(conn.0, conn.1);
+            let punch = has_the_same_ip
+                || predictable(nat1, &predicted1).can_punch(&predictable(nat2, &predicted2));
+
             let pak = ConnectOn {
                 session: conn.0,
-                to: format!("{}:{}", adress2, port2),
+                id: id1,
+                to: host_port(&adress2, port2),
                 port: port1,
                 adress: addr2,
                 time,
+                relay: relay1,
+                nat: nat2,
+                punch,
+                predicted: predicted2,
+                candidates: candidates2,
             };
 
-            let mut bytes = Packets::ConnectOn(pak).to_bytes();
-            bytes.reverse();
-            if let Some(client) = self.clients.get_mut(index1) {
-                let _ = client.conn.send(&bytes);
+            if let Some(client) = self.clients.get(index1) {
+                self.handler.on_connect_on(client.session, &pak);
+                let _ = client.send(&Packets::ConnectOn(pak));
             }
 
             let pak = ConnectOn {
                 session: conn.1,
-                to: format!("{}:{}", adress1, port1),
+                id: id2,
+                to: host_port(&adress1, port1),
                 port: port2,
                 adress: addr1,
                 time,
+                relay: relay2,
+                nat: nat1,
+                punch,
+                predicted: predicted1,
+                candidates: candidates1,
             };
 
-            let mut bytes = Packets::ConnectOn(pak).to_bytes();
-            bytes.reverse();
-            if let Some(client) = self.clients.get_mut(index2) {
-                let _ = client.conn.send(&bytes);
+            if let Some(client) = self.clients.get(index2) {
+                self.handler.on_connect_on(client.session, &pak);
+                let _ = client.send(&Packets::ConnectOn(pak));
             }
         }
+
+        // only keep the sessions that are still waiting for a port or a peer
+        let mut connecting = std::mem::take(&mut self.clients.connecting);
+        connecting.retain(|session| {
+            let Some(client) = self.clients.by_session(*session) else {
+                return false;
+            };
+            if let ClientStage::Registered(rclient) = &client.stage {
+                rclient
+                    .to_connect
+                    .iter()
+                    .any(|to_conn| matches!(to_conn, Connecting::Finishing(..)))
+            } else {
+                false
+            }
+        });
+        self.clients.connecting = connecting;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_port_can_be_parsed_back() {
+        assert_eq!(host_port("1.2.3.4", 5), "1.2.3.4:5");
+        assert_eq!(host_port("::1", 5), "[::1]:5");
+        assert_eq!(host_port("fd00::1:2", 65535), "[fd00::1:2]:65535");
+        // a private adress can already have brackets
+        assert_eq!(host_port("[::1]", 5), "[::1]:5");
+        assert_eq!(host_port("example.com", 5), "example.com:5");
+
+        for host in ["1.2.3.4", "::1", "::ffff:1.2.3.4", "[::1]"] {
+            let adress = host_port(host, 5).parse::<SocketAddr>().unwrap();
+            assert_eq!(adress.port(), 5, "{host}");
+        }
+    }
+
+    #[test]
+    fn incrementing_ports() {
+        let predicted = predict_ports(&[40000, 40001, 40002]);
+        assert_eq!(
+            predicted,
+            (40003..40003 + PREDICTED_PORTS as u16).collect::<Vec<_>>()
+        );
+
+        // other traffic took some ports, the most common step wins
+        let predicted = predict_ports(&[40000, 40002, 40004, 40009, 40011]);
+        assert_eq!(predicted[..3], [40013, 40015, 40017]);
+
+        let predicted = predict_ports(&[40010, 40008, 40006]);
+        assert_eq!(predicted[..3], [40004, 40002, 40000]);
+    }
+
+    #[test]
+    fn not_enough_ports() {
+        assert!(predict_ports(&[]).is_empty());
+        assert!(predict_ports(&[40000]).is_empty());
+        assert!(predict_ports(&[40000, 40001]).is_empty());
+        // the NAT keeps the same port
+        assert!(predict_ports(&[40000, 40000, 40000]).is_empty());
+    }
+
+    #[test]
+    fn random_ports() {
+        assert!(predict_ports(&[40000, 12345, 61000, 3050, 27182, 50001]).is_empty());
+    }
+
+    #[test]
+    fn wrap_around() {
+        let predicted = predict_ports(&[65531, 65533, 65535]);
+        assert_eq!(predicted[..3], [1025, 1027, 1029]);
+        assert_eq!(predicted.len(), PREDICTED_PORTS as usize);
+
+        // the step over the wrap is the same as the others
+        let predicted = predict_ports(&[65534, 1024, 1026]);
+        assert_eq!(predicted[..2], [1028, 1030]);
+
+        let predicted = predict_ports(&[1028, 1026, 1024]);
+        assert_eq!(predicted[..2], [65534, 65532]);
+        assert!(predicted.iter().all(|port| *port >= 1024));
     }
 }
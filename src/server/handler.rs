@@ -0,0 +1,226 @@
+use crate::common::{adress::Adress, packets::ConnectOn};
+
+use super::RegisteredClient;
+
+/// Hooks called by `RelayServer` when something happens
+/// Every hook does nothing by default, so only the needed ones can be implemented
+#[allow(unused_variables)]
+pub trait RelayServerHandler: Send {
+    /// A client was registered with `client.adress`
+    fn on_register(&mut self, session: usize, client: &RegisteredClient) {}
+
+    /// A client was removed because it unregistered, closed the connection or misbehaved
+    /// `adress` is `None` if the client was not registered
+    fn on_unregister(&mut self, session: usize, adress: Option<&Adress>) {}
+
+    /// A client was removed because it did not send anything in `client_timeout`
+    /// or did not finish the handshake
+    fn on_timeout(&mut self, session: usize, adress: Option<&Adress>) {}
+
+    /// `from` sent a request to `to`
+    fn on_request(&mut self, from: &Adress, to: &Adress) {}
+
+    /// `from` answered the request of `to`
+    fn on_request_response(&mut self, from: &Adress, to: &Adress, accepted: bool) {}
+
+    /// `from` finished the request with `to`
+    fn on_request_final(&mut self, from: &Adress, to: &Adress, accepted: bool) {}
+
+    /// `connect_on` was sent to `session`
+    fn on_connect_on(&mut self, session: usize, connect_on: &ConnectOn) {}
+}
+
+/// Does nothing
+impl RelayServerHandler for () {}
+
+impl std::fmt::Debug for dyn RelayServerHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RelayServerHandler")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    use bytes_kman::TBytes;
+    use socket2::SockAddr;
+
+    use crate::{
+        common::packets::{
+            Auth, Packets, Register, RegisterResponse, Request, RequestFinal, RequestResponse,
+            UnRegister,
+        },
+        server::{testing, RelayServer},
+    };
+
+    use super::*;
+
+    /// Writes down every hook that was called
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl RelayServerHandler for Recorder {
+        fn on_register(&mut self, session: usize, client: &RegisteredClient) {
+            self.push(format!("register {session} {:?}", client.adress));
+        }
+
+        fn on_unregister(&mut self, session: usize, adress: Option<&Adress>) {
+            self.push(format!("unregister {session} {adress:?}"));
+        }
+
+        fn on_timeout(&mut self, session: usize, adress: Option<&Adress>) {
+            self.push(format!("timeout {session} {adress:?}"));
+        }
+
+        fn on_request(&mut self, from: &Adress, to: &Adress) {
+            self.push(format!("request {from:?} {to:?}"));
+        }
+
+        fn on_request_response(&mut self, from: &Adress, to: &Adress, accepted: bool) {
+            self.push(format!("request_response {from:?} {to:?} {accepted}"));
+        }
+
+        fn on_request_final(&mut self, from: &Adress, to: &Adress, accepted: bool) {
+            self.push(format!("request_final {from:?} {to:?} {accepted}"));
+        }
+
+        fn on_connect_on(&mut self, session: usize, connect_on: &ConnectOn) {
+            self.push(format!("connect_on {session} {:?}", connect_on.adress));
+        }
+    }
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    fn register_port(server: &mut RelayServer, session: usize, token: Vec<u8>, port: u16) {
+        let mut bytes = Packets::Register(Register::Port { session, token }).to_bytes();
+        bytes.reverse();
+        let from = SockAddr::from(SocketAddr::from(([127, 0, 0, 1], port)));
+        let response = server.on_udp_packet(bytes, &from);
+        assert!(
+            matches!(
+                response,
+                Packets::RegisterResponse(RegisterResponse::Port { .. })
+            ),
+            "{response:?}"
+        );
+    }
+
+    #[test]
+    fn hooks_are_called_in_order() {
+        let recorder = Recorder::default();
+        let mut server = testing::server();
+        server.set_handler(recorder.clone());
+
+        let (a, mut peer_a) = testing::connect(&mut server);
+        let (b, mut peer_b) = testing::connect(&mut server);
+        let (session_a, session_b) = (server.clients[a].session, server.clients[b].session);
+        for (index, adress) in [(a, 1), (b, 2)] {
+            let register = testing::register_packet(vec![adress], Auth::None, false);
+            server.handle_packets(index, vec![register]);
+        }
+        testing::recv(&mut peer_a);
+        testing::recv(&mut peer_b);
+        assert_eq!(
+            recorder.take(),
+            [
+                format!("register {session_a} [1]"),
+                format!("register {session_b} [2]"),
+            ]
+        );
+
+        let request = Request {
+            session: session_a,
+            id: 1,
+            to: vec![2],
+            secret: String::new(),
+        };
+        server.handle_packets(a, vec![Packets::Request(request)]);
+        testing::recv(&mut peer_b);
+
+        let response = RequestResponse {
+            session: session_b,
+            id: 2,
+            to: vec![1],
+            accepted: true,
+            secret: String::new(),
+        };
+        server.handle_packets(b, vec![Packets::RequestResponse(response)]);
+        let Packets::NewRequestResponse(response) = testing::recv(&mut peer_a) else {
+            panic!("No NewRequestResponse");
+        };
+
+        let request_final = RequestFinal {
+            session: session_a,
+            id: 3,
+            to: vec![2],
+            accepted: true,
+            time_offset: 0,
+        };
+        server.handle_packets(a, vec![Packets::RequestFinal(request_final)]);
+        let Packets::NewRequestFinal(request_final) = testing::recv(&mut peer_b) else {
+            panic!("No NewRequestFinal");
+        };
+        assert_eq!(
+            recorder.take(),
+            [
+                "request [1] [2]",
+                "request_response [2] [1] true",
+                "request_final [1] [2] true",
+            ]
+        );
+
+        // `ConnectOn` is only sent when both have a port
+        register_port(&mut server, session_a, response.port_token, 40001);
+        server.connect();
+        assert!(recorder.take().is_empty());
+        register_port(&mut server, session_b, request_final.port_token, 40002);
+        server.connect();
+        // the connecting clients are not ordered
+        let mut calls = recorder.take();
+        calls.sort();
+        let mut expected = [
+            format!("connect_on {session_a} [2]"),
+            format!("connect_on {session_b} [1]"),
+        ];
+        expected.sort();
+        assert_eq!(calls, expected);
+
+        let unregister = UnRegister { session: session_a };
+        server.handle_packets(a, vec![Packets::UnRegister(unregister)]);
+        server.housekeeping();
+        assert_eq!(
+            recorder.take(),
+            [format!("unregister {session_a} Some([1])")]
+        );
+
+        let b = server.clients.index_of(session_b).unwrap();
+        server.clients.get_mut(b).unwrap().last_message =
+            SystemTime::now() - server.client_timeout * 2;
+        let (c, _peer_c) = testing::connect(&mut server);
+        let session_c = server.clients[c].session;
+        server.clients.get_mut(c).unwrap().last_message = SystemTime::UNIX_EPOCH;
+        server.housekeeping();
+        let mut removed = recorder.take();
+        removed.sort();
+        assert_eq!(
+            removed,
+            [
+                format!("timeout {session_b} Some([2])"),
+                format!("unregister {session_c} None"),
+            ]
+        );
+    }
+}
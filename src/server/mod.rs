@@ -1,24 +1,55 @@
+#[cfg(feature = "tokio")]
+pub mod async_server;
 mod connect;
+mod handler;
 mod on_info;
 mod on_request;
 mod on_request_final;
 mod on_request_response;
 mod on_search;
+mod policy;
+mod port_token;
+mod registry;
+mod relay;
+#[cfg(test)]
+pub(crate) mod testing;
+mod timers;
 
 use bytes_kman::TBytes;
 use polling::{Event, Poller};
 use rand::random;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-// RelayServer allways should be on this port
+pub use handler::RelayServerHandler;
+pub use policy::*;
+pub use port_token::*;
+pub use registry::{ClientMut, ClientRegistry};
+pub use relay::*;
+pub use timers::*;
+
+// RelayServer::new will bind on this port
 pub const PORT: u16 = 2120;
-pub const UDP_KEY: usize = usize::MAX - 1;
+/// Poller keys from this are used by the listeners, sessions are never in this range
+/// The TCP listener `i` has the key `LISTENER_KEY + i * 3`, its UDP socket the next one
+/// and its alternate UDP socket the one after
+pub const LISTENER_KEY: usize = usize::MAX - 1024;
+/// Poller keys from this up to `LISTENER_KEY` are used by the relayed ports
+pub const RELAY_KEY: usize = LISTENER_KEY - (1 << 20);
 
-use crate::common::{adress::Adress, packets::*, FromRawSock, IntoRawSock, RawSock};
+use crate::common::{
+    adress::Adress,
+    frame::{FrameDecoder, FrameError},
+    identity::{is_identity, new_challenge, verify_challenge, IDENTITY_LEN},
+    tls::{Tls, TlsError},
+    packets::*,
+    stun, FromRawSock, IntoRawSock, RawSock,
+};
 use std::{
+    io,
     mem::MaybeUninit,
-    net::ToSocketAddrs,
-    time::{Duration, SystemTime},
+    net::{Ipv6Addr, Shutdown, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 #[derive(PartialEq, Clone, Debug)]
@@ -38,6 +69,11 @@ impl Connecting {
 #[derive(Debug, Clone, PartialEq)]
 pub enum ClientStage {
     NotRegistered,
+    /// Waiting for the `Register::Proof` of a identity
+    Challenged {
+        rclient: RegisteredClient,
+        nonce: Vec<u8>,
+    },
     Registered(RegisteredClient),
 }
 
@@ -47,10 +83,79 @@ pub struct RegisteredClient {
     pub client: String,
     pub other: Vec<u8>,
     pub adress: Adress,
-    pub ports: Vec<u16>,
+    /// Ports of `Register::Port` with the peer session of the connection they are for
+    pub ports: Vec<(usize, u16)>,
     pub to_connect: Vec<Connecting>,
     pub privacy: bool,
     pub private_adress: String,
+    /// The `id` of the last request sent to a peer session
+    /// used to answer with the right `id`
+    pub request_ids: Vec<(usize, usize)>,
+    /// Attached by the `RelayPolicy` on register
+    pub metadata: Vec<(String, String)>,
+    /// The adress was proven with a `Identity`
+    pub verified: bool,
+    /// Advertised with `Register::Nat`
+    pub nat: NatType,
+    /// Ports of `Register::Probe` in the order they were seen with the peer session
+    /// of the connection they are for, at most `MAX_PROBES` for every peer
+    pub probes: Vec<(usize, u16)>,
+    /// `Register::Candidates` with the port they were sent from, at most `MAX_CANDIDATES`
+    pub candidates: Vec<(u16, Vec<Candidate>)>,
+}
+
+impl RegisteredClient {
+    pub fn request_id(&self, session: usize) -> usize {
+        self.request_ids
+            .iter()
+            .find(|(s, _)| *s == session)
+            .map_or(0, |(_, id)| *id)
+    }
+
+    pub fn set_request_id(&mut self, session: usize, id: usize) {
+        self.remove_request_id(session);
+        self.request_ids.push((session, id));
+    }
+
+    pub fn remove_request_id(&mut self, session: usize) {
+        self.request_ids.retain(|(s, _)| *s != session);
+    }
+
+    /// Removes the candidates that were sent from `port`
+    pub fn take_candidates(&mut self, port: u16) -> Vec<Candidate> {
+        match self.candidates.iter().rposition(|(p, _)| *p == port) {
+            Some(index) => self.candidates.remove(index).1,
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes the port that was registered for the connection to `peer`
+    pub fn take_port(&mut self, peer: usize) -> Option<u16> {
+        let index = self.ports.iter().rposition(|(p, _)| *p == peer)?;
+        Some(self.ports.remove(index).1)
+    }
+
+    /// Adds a probe for the connection to `peer`, the oldest one of it is dropped after `MAX_PROBES`
+    pub fn add_probe(&mut self, peer: usize, port: u16) {
+        self.probes.push((peer, port));
+        if self.probes.iter().filter(|(p, _)| *p == peer).count() > connect::MAX_PROBES {
+            if let Some(index) = self.probes.iter().position(|(p, _)| *p == peer) {
+                self.probes.remove(index);
+            }
+        }
+    }
+
+    /// Removes the probes for the connection to `peer`, oldest first
+    pub fn take_probes(&mut self, peer: usize) -> Vec<u16> {
+        let mut probes = Vec::new();
+        self.probes.retain(|(p, port)| {
+            if *p == peer {
+                probes.push(*port);
+            }
+            *p != peer
+        });
+        probes
+    }
 }
 
 #[derive(Debug)]
@@ -61,7 +166,78 @@ pub struct Client {
     pub from: SockAddr,
     pub stage: ClientStage,
     pub last_message: SystemTime,
-    pub buffer: Vec<MaybeUninit<u8>>,
+    /// When the client was accepted, used to expire unfinished handshakes
+    pub connected: SystemTime,
+    pub buffer: FrameDecoder,
+    /// Negotiated protocol version, 0 until `Hello` was received
+    pub version: u16,
+    pub capabilities: Vec<String>,
+    pub tls: Tls,
+    /// Queue of the writer task of a `AsyncRelayServer`, `None` if the packets are written to `conn`
+    #[cfg(feature = "tokio")]
+    pub writer: Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
+}
+
+impl Client {
+    pub fn send(&self, packet: &Packets) -> io::Result<()> {
+        log::trace!("To: {:?}, packet: {packet:?}", self.from);
+
+        #[cfg(feature = "tokio")]
+        if let Some(writer) = &self.writer {
+            // a client that does not read is disconnected
+            let frame = crate::common::frame::encode(packet);
+            return writer.try_send(frame).map_err(|_| {
+                let _ = self.conn.shutdown(Shutdown::Both);
+                io::Error::other("The send queue is full")
+            });
+        }
+
+        self.tls.write_packet(&self.conn, packet)
+    }
+
+    pub fn send_error(&self, code: ErrorCode, request: ErrorRequest, id: usize, adress: Adress) {
+        let error = Error::new(self.session, code, request)
+            .with_id(id)
+            .with_adress(adress);
+        let _ = self.send(&Packets::Error(error));
+    }
+
+    /// Sends `ErrorCode::Denied` with the reason if `decision` is `Deny`
+    /// Returns true if the packet can be processed
+    pub fn allowed(
+        &self,
+        decision: Decision,
+        request: ErrorRequest,
+        id: usize,
+        adress: Adress,
+    ) -> bool {
+        let Decision::Deny(reason) = decision else {
+            return true;
+        };
+
+        let error = Error::new(self.session, ErrorCode::Denied, request)
+            .with_id(id)
+            .with_adress(adress)
+            .with_reason(reason);
+        let _ = self.send(&Packets::Error(error));
+        false
+    }
+
+    /// Checks that a packet with `session` can be processed for this client
+    /// if not the client is notified with an `Error`
+    pub fn validate(&self, session: usize, request: ErrorRequest, id: usize) -> bool {
+        if self.session != session {
+            self.send_error(ErrorCode::UnknownSession, request, id, Adress::new());
+            return false;
+        }
+
+        if !matches!(self.stage, ClientStage::Registered(_)) {
+            self.send_error(ErrorCode::NotRegistered, request, id, Adress::new());
+            return false;
+        }
+
+        true
+    }
 }
 
 impl PartialEq for Client {
@@ -70,166 +246,334 @@ impl PartialEq for Client {
     }
 }
 
+/// Returns the adress of `adress` with IPv4 mapped IPv6 adresses as IPv4
+/// so peers on a dual stack listener compare the same as on a IPv4 listener
+pub fn socket_addr(adress: &SockAddr) -> Option<SocketAddr> {
+    let adress = adress.as_socket()?;
+    if let SocketAddr::V6(v6) = adress {
+        if let Some(ipv4) = v6.ip().to_ipv4_mapped() {
+            return Some(SocketAddr::new(ipv4.into(), v6.port()));
+        }
+    }
+    Some(adress)
+}
+
+/// A TCP and UDP socket bound on the same adress
 #[derive(Debug)]
-pub struct RelayServer {
-    pub clients: Vec<Client>,
-    pub poller: Poller,
+pub struct Listener {
+    /// The bound adress, with the real port if it was bound on port 0
+    pub adress: SocketAddr,
     pub conn: Socket,
     pub fd: RawSock,
     pub conn_udp: Socket,
     pub fd_udp: RawSock,
+    /// Second UDP socket on the same ip, only answers STUN
+    /// used for the behaviour discovery of RFC 5780
+    pub conn_alt: Socket,
+    pub fd_alt: RawSock,
+    pub alt_port: u16,
+}
+
+impl Listener {
+    pub fn bind(adress: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(adress, None)
+    }
+
+    /// Binds on `[::]:port` and also accepts IPv4 clients as IPv4 mapped adresses
+    pub fn dual_stack(port: u16) -> io::Result<Self> {
+        Self::bind_with(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), Some(false))
+    }
+
+    /// Binds the TCP socket first so with port 0 the UDP socket gets the same port
+    /// `only_v6` is only used for IPv6 adresses, `None` keeps the os default
+    pub fn bind_with(adress: SocketAddr, only_v6: Option<bool>) -> io::Result<Self> {
+        let only_v6 = only_v6.filter(|_| adress.is_ipv6());
+
+        let conn = Socket::new(
+            Domain::for_address(adress),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        if let Some(only_v6) = only_v6 {
+            conn.set_only_v6(only_v6)?;
+        }
+        conn.set_nonblocking(true)?;
+        conn.bind(&SockAddr::from(adress))?;
+        conn.listen(128)?;
+
+        let Some(adress) = conn.local_addr()?.as_socket() else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+
+        let conn_udp = Socket::new(Domain::for_address(adress), Type::DGRAM, None)?;
+        if let Some(only_v6) = only_v6 {
+            conn_udp.set_only_v6(only_v6)?;
+        }
+        conn_udp.set_nonblocking(true)?;
+        conn_udp.bind(&SockAddr::from(adress))?;
+
+        // the next port if it is free, so it is easy to open in a firewall
+        let conn_alt = Socket::new(Domain::for_address(adress), Type::DGRAM, None)?;
+        if let Some(only_v6) = only_v6 {
+            conn_alt.set_only_v6(only_v6)?;
+        }
+        conn_alt.set_nonblocking(true)?;
+        let next = SocketAddr::new(adress.ip(), adress.port().wrapping_add(1));
+        if next.port() == 0 || conn_alt.bind(&SockAddr::from(next)).is_err() {
+            conn_alt.bind(&SockAddr::from(SocketAddr::new(adress.ip(), 0)))?;
+        }
+        let alt_port = conn_alt
+            .local_addr()?
+            .as_socket()
+            .map_or(0, |adress| adress.port());
+
+        let fd = conn.into_raw();
+        let fd_udp = conn_udp.into_raw();
+        let fd_alt = conn_alt.into_raw();
+
+        Ok(Self {
+            adress,
+            conn: Socket::from_raw(fd),
+            fd,
+            conn_udp: Socket::from_raw(fd_udp),
+            fd_udp,
+            conn_alt: Socket::from_raw(fd_alt),
+            fd_alt,
+            alt_port,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RelayServer {
+    pub clients: ClientRegistry,
+    /// Shared with the thread that waits for the relayed ports of a `AsyncRelayServer`
+    pub poller: Arc<Poller>,
+    pub listeners: Vec<Listener>,
     pub buffer: Vec<MaybeUninit<u8>>,
     pub client_timeout: Duration,
+    pub timers: Timers,
+    pub capabilities: Vec<String>,
+    pub handler: Box<dyn RelayServerHandler>,
+    pub policy: Box<dyn RelayPolicy>,
+    /// Random key that signs the port tokens, made when the relay is created
+    pub port_token_key: [u8; 32],
+    /// If set only clients with a `AuthToken` signed with this key can register
+    pub auth_key: Option<Vec<u8>>,
+    /// If true every adress has to be a `Identity` proven with `Register::Proof`
+    /// Adresses of `IDENTITY_LEN` bytes allways have to be proven
+    pub require_identity: bool,
+    /// If set every control connection has to use TLS
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    pub relays: RelayRegistry,
+    /// If set the relay forwards the datagrams of peers that cannot punch a hole
+    pub relay_quota: Option<RelayQuota>,
 }
 
 #[derive(Debug)]
 pub enum RelayServerError {
     CannotCreatePoller,
+    InvalidAdress,
+    CannotBind(SocketAddr, io::Error),
+    TooManyAdresses,
 }
 
 impl RelayServer {
+    /// Binds on `ip` with the default `PORT`, `ip` can also be a IPv6 adress or a host
     pub fn new(ip: impl Into<String>, client_timeout: Duration) -> Result<Self, RelayServerError> {
-        let adress = format!("{}:{}", ip.into(), PORT);
-        let adress = adress.to_socket_addrs().unwrap().next().unwrap();
-        let adress_sock = SockAddr::from(adress);
-        let conn = Socket::new(
-            Domain::for_address(adress),
-            Type::STREAM,
-            Some(Protocol::TCP),
-        )
-        .unwrap();
-        let conn_udp = Socket::new(Domain::for_address(adress), Type::DGRAM, None).unwrap();
+        let ip = ip.into();
+        Self::bind((ip.as_str(), PORT), client_timeout)
+    }
+
+    /// Binds one dual stack listener that accepts IPv4 and IPv6 clients on `port`
+    pub fn dual_stack(port: u16, client_timeout: Duration) -> Result<Self, RelayServerError> {
+        match Listener::dual_stack(port) {
+            Ok(listener) => Self::with_listeners(vec![listener], client_timeout),
+            Err(error) => Err(RelayServerError::CannotBind(
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+                error,
+            )),
+        }
+    }
 
-        conn.set_nonblocking(true).unwrap();
-        conn.bind(&adress_sock).unwrap();
-        conn.listen(128).unwrap();
+    /// Binds on every adress that `adresses` resolves to
+    /// The port can be 0, use `local_addrs` to get the bound ports
+    pub fn bind(
+        adresses: impl ToSocketAddrs,
+        client_timeout: Duration,
+    ) -> Result<Self, RelayServerError> {
+        let Ok(adresses) = adresses.to_socket_addrs() else {
+            return Err(RelayServerError::InvalidAdress);
+        };
+        let adresses: Vec<SocketAddr> = adresses.collect();
+        let mut listeners = Vec::new();
+        for adress in adresses {
+            match Listener::bind(adress) {
+                Ok(listener) => listeners.push(listener),
+                Err(error) => return Err(RelayServerError::CannotBind(adress, error)),
+            }
+        }
 
-        conn_udp.set_nonblocking(true).unwrap();
-        conn_udp.bind(&adress_sock).unwrap();
+        Self::with_listeners(listeners, client_timeout)
+    }
 
-        let Ok(poller) = Poller::new() else{
+    /// Creates the relay from already bound listeners
+    pub fn with_listeners(
+        listeners: Vec<Listener>,
+        client_timeout: Duration,
+    ) -> Result<Self, RelayServerError> {
+        if listeners.is_empty() {
+            return Err(RelayServerError::InvalidAdress);
+        }
+        if listeners.len() * 3 > usize::MAX - LISTENER_KEY {
+            return Err(RelayServerError::TooManyAdresses);
+        }
+
+        let Ok(poller) = Poller::new() else {
             println!("Cannot create poller!");
-            return Err(RelayServerError::CannotCreatePoller)
+            return Err(RelayServerError::CannotCreatePoller);
         };
 
-        let fd = conn.into_raw();
-        let fd_udp = conn_udp.into_raw();
-        poller.add(fd, Event::readable(0)).unwrap();
-        poller.add(fd_udp, Event::readable(UDP_KEY)).unwrap();
-
-        let conn = Socket::from_raw(fd);
-        let conn_udp = Socket::from_raw(fd_udp);
+        for (i, listener) in listeners.iter().enumerate() {
+            poller
+                .add(listener.fd, Event::readable(LISTENER_KEY + i * 3))
+                .unwrap();
+            poller
+                .add(listener.fd_udp, Event::readable(LISTENER_KEY + i * 3 + 1))
+                .unwrap();
+            poller
+                .add(listener.fd_alt, Event::readable(LISTENER_KEY + i * 3 + 2))
+                .unwrap();
+        }
 
         let mut buffer = Vec::new();
         buffer.resize(1024, MaybeUninit::new(0));
 
         Ok(Self {
-            clients: Vec::new(),
-            poller,
+            clients: ClientRegistry::default(),
+            poller: Arc::new(poller),
+            listeners,
             buffer,
-            fd,
             client_timeout,
-            conn,
-            conn_udp,
-            fd_udp,
+            timers: Timers::default(),
+            capabilities: capability::supported(),
+            handler: Box::new(()),
+            policy: Box::new(()),
+            port_token_key: random(),
+            auth_key: None,
+            require_identity: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            relays: RelayRegistry::default(),
+            relay_quota: None,
         })
     }
 
-    pub fn avalibile_adress(&self, adress: &Adress) -> bool {
-        for client in self.clients.iter() {
-            if let ClientStage::Registered(client) = &client.stage {
-                if client.adress == *adress {
-                    return false;
-                }
-            }
+    pub fn set_handler(&mut self, handler: impl RelayServerHandler + 'static) {
+        self.handler = Box::new(handler);
+    }
+
+    pub fn set_policy(&mut self, policy: impl RelayPolicy + 'static) {
+        self.policy = Box::new(policy);
+    }
+
+    /// Requires TLS with the certificate chain and private key in DER
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, cert_chain: Vec<Vec<u8>>, key: Vec<u8>) -> Result<(), TlsError> {
+        self.tls = Some(crate::common::tls::server_config(cert_chain, key)?);
+        Ok(())
+    }
+
+    /// A new TLS session for a accepted client, no TLS if the relay has none
+    pub fn tls_session(&self) -> Result<Tls, TlsError> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            return Tls::server(config);
         }
-        true
+
+        Ok(Tls::default())
+    }
+
+    /// Requires a `AuthToken` minted with `key` on registration
+    pub fn set_auth_key(&mut self, key: impl Into<Vec<u8>>) {
+        self.auth_key = Some(key.into());
+    }
+
+    /// The adresses that the relay is listening on
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.adress)
+            .collect()
+    }
+
+    pub fn avalibile_adress(&self, adress: &Adress) -> bool {
+        !self.clients.contains_adress(adress)
     }
 
     pub fn create_session(&self) -> usize {
         let mut session = random();
 
-        'l: loop {
-            if session == 0 {
-                session = random();
-                continue 'l;
-            }
-
-            for client in self.clients.iter() {
-                if client.session == session {
-                    session = random();
-                    continue 'l;
-                }
-            }
-            break;
+        while session == 0 || session >= RELAY_KEY || self.clients.contains_session(session) {
+            session = random();
         }
 
         session
     }
 
+    /// Blocks until there are events
     pub fn listen(&mut self) {
+        self.listen_timeout(None);
+    }
+
+    /// Waits for events at most `timeout`, `None` waits forever
+    pub fn listen_timeout(&mut self, timeout: Option<Duration>) {
         let mut events = Vec::new();
-        let Ok(_) = self.poller.wait(&mut events, None) else {return};
+        let Ok(_) = self.poller.wait(&mut events, timeout) else {
+            return;
+        };
 
-        'main: for event in events {
+        for event in events {
             match event.key {
-                0 => {
-                    self.accept_new();
-                    self.poller.modify(self.fd, Event::readable(0)).unwrap();
-                }
-                UDP_KEY => {
-                    self.poller
-                        .modify(self.fd_udp, Event::readable(event.key))
-                        .unwrap();
+                key if key >= LISTENER_KEY => {
+                    let index = (key - LISTENER_KEY) / 3;
+                    let Some(listener) = self.listeners.get(index) else {
+                        continue;
+                    };
 
-                    let mut buffer = [MaybeUninit::uninit(); 1024];
-                    if let Ok((len, from)) = self.conn_udp.recv_from(&mut buffer) {
-                        let buffer = buffer[0..len].to_vec();
-                        let mut buffer: Vec<u8> = unsafe { std::mem::transmute(buffer) };
-                        if let Some(Packets::Register(Register::Port { session })) =
-                            Packets::from_bytes(&mut buffer)
-                        {
-                            log::trace!("UDP From: {from:?}, Packets::Register(Register::Port{{ session: {session} }})");
-                            for client in self.clients.iter_mut() {
-                                if client.session == session {
-                                    let mut port = None;
-                                    if let Some(ipv4) = from.as_socket_ipv4() {
-                                        port = Some(ipv4.port());
-                                    }
+                    if (key - LISTENER_KEY).is_multiple_of(3) {
+                        let fd = listener.fd;
+                        self.accept_new(index);
+                        self.poller.modify(fd, Event::readable(key)).unwrap();
+                        continue;
+                    }
 
-                                    if let Some(ipv6) = from.as_socket_ipv6() {
-                                        port = Some(ipv6.port())
-                                    }
+                    let alternate = (key - LISTENER_KEY) % 3 == 2;
+                    let (conn, fd) = if alternate {
+                        (&listener.conn_alt, listener.fd_alt)
+                    } else {
+                        (&listener.conn_udp, listener.fd_udp)
+                    };
+                    self.poller.modify(fd, Event::readable(key)).unwrap();
 
-                                    if let Some(port) = port {
-                                        if let ClientStage::Registered(client) = &mut client.stage {
-                                            client.ports.push(port);
-                                            let pak =
-                                                Packets::RegisterResponse(RegisterResponse::Port {
-                                                    port,
-                                                });
-                                            log::trace!("UDP Sent: {from:?}, {pak:?}");
-                                            let mut bytes = pak.to_bytes();
-                                            bytes.reverse();
-                                            self.conn_udp.send_to(&mut bytes, &from);
-                                            continue 'main;
-                                        }
-                                    }
-                                }
-                            }
+                    // big enough for a `Register::Candidates`
+                    let mut buffer = [MaybeUninit::uninit(); 4096];
+                    if let Ok((len, from)) = conn.recv_from(&mut buffer) {
+                        let buffer = buffer[0..len].to_vec();
+                        let buffer: Vec<u8> = unsafe { std::mem::transmute(buffer) };
+                        if self.on_stun_packet(index, alternate, &buffer, &from) || alternate {
+                            continue;
                         }
-
-                        let pak = Packets::RegisterResponse(RegisterResponse::Client {
-                            accepted: false,
-                            session: 0,
-                        });
+                        let pak = self.on_udp_packet(buffer, &from);
 
                         log::trace!("UDP Sent: {from:?}, {pak:?}");
                         let mut bytes = pak.to_bytes();
                         bytes.reverse();
-                        self.conn_udp.send_to(&mut bytes, &from);
+                        let _ = self.listeners[index].conn_udp.send_to(&bytes, &from);
                     }
                 }
+                key if key >= RELAY_KEY => self.on_relay_socket(key),
                 _ => {
                     if let Some(fd) = self.process_client(event.key) {
                         self.poller.modify(fd, Event::readable(event.key)).unwrap();
@@ -239,15 +583,154 @@ impl RelayServer {
         }
     }
 
-    pub fn accept_new(&mut self) {
-        if let Ok((conn, from)) = self.conn.accept() {
+    /// Answers a STUN Binding request that was received on the listener at `listener`,
+    /// on its alternate UDP socket if `alternate`
+    /// Returns false if `buffer` is not a Binding request
+    pub fn on_stun_packet(
+        &self,
+        listener: usize,
+        alternate: bool,
+        buffer: &[u8],
+        from: &SockAddr,
+    ) -> bool {
+        let Some(request) = stun::Message::parse(buffer) else {
+            return false;
+        };
+        if request.kind != stun::BINDING_REQUEST {
+            return false;
+        }
+        let (Some(received), Some(mapped)) = (self.listeners.get(listener), socket_addr(from))
+        else {
+            return true;
+        };
+        log::trace!(
+            "STUN Binding: {from:?}, change: {}",
+            request.change_request()
+        );
+
+        let reply = |conn: &Socket, response: Vec<u8>| {
+            let _ = conn.send_to(&response, from);
+        };
+        let received_conn = if alternate {
+            &received.conn_alt
+        } else {
+            &received.conn_udp
+        };
+
+        if let Some(error) = stun::check_attributes(&request) {
+            reply(received_conn, error);
+            return true;
+        }
+
+        let change = request.change_request();
+        let other = self.alternate_listener(listener);
+        let target = if change & stun::CHANGE_IP != 0 {
+            let Some(other) = other else {
+                let error = stun::binding_error(&request.transaction, 420, "No alternate ip");
+                reply(received_conn, error);
+                return true;
+            };
+            other
+        } else {
+            received
+        };
+        let (conn, port) = if alternate ^ (change & stun::CHANGE_PORT != 0) {
+            (&target.conn_alt, target.alt_port)
+        } else {
+            (&target.conn_udp, target.adress.port())
+        };
+
+        // a unspecified ip is not known, clients use the ip they sent to
+        let origin = SocketAddr::new(target.adress.ip(), port);
+        let origin = (!origin.ip().is_unspecified()).then_some(origin);
+        let other = other.unwrap_or(received);
+        let other = SocketAddr::new(other.adress.ip(), other.alt_port);
+
+        let response = stun::binding_success(&request.transaction, mapped, origin, Some(other));
+        reply(conn, response);
+        true
+    }
+
+    /// A listener of the same family and port on another ip, used to answer with a changed ip
+    pub fn alternate_listener(&self, listener: usize) -> Option<&Listener> {
+        let adress = self.listeners.get(listener)?.adress;
+        self.listeners.iter().find(|other| {
+            other.adress.is_ipv4() == adress.is_ipv4()
+                && other.adress.port() == adress.port()
+                && !other.adress.ip().is_unspecified()
+                && !adress.ip().is_unspecified()
+                && other.adress.ip() != adress.ip()
+        })
+    }
+
+    /// Handles a `Register::Port`, `Register::Probe` or `Register::Candidates`
+    /// that was sent over UDP
+    /// Returns the packet that should be sent back to `from`
+    pub fn on_udp_packet(&mut self, mut buffer: Vec<u8>, from: &SockAddr) -> Packets {
+        let code = ErrorCode::UnknownSession;
+        let (session, token, probe, candidates) = match Packets::from_bytes(&mut buffer) {
+            Some(Packets::Register(Register::Port { session, token })) => {
+                (session, token, false, None)
+            }
+            Some(Packets::Register(Register::Probe { session, token })) => {
+                (session, token, true, None)
+            }
+            Some(Packets::Register(Register::Candidates {
+                session,
+                token,
+                candidates,
+            })) => (session, token, false, Some(candidates)),
+            _ => return Packets::Error(Error::new(0, code, ErrorRequest::RegisterPort)),
+        };
+        log::trace!("UDP From: {from:?}, session: {session}, probe: {probe}");
+
+        let peer = match self.check_port_token(session, &token) {
+            Ok(peer) => peer,
+            Err(error) => return Packets::Error(Error::new(0, error, ErrorRequest::RegisterPort)),
+        };
+        if let Some(mut client) = self.clients.by_session_mut(session) {
+            if let Some(port) = from.as_socket().map(|from| from.port()) {
+                if let ClientStage::Registered(client) = &mut client.stage {
+                    if probe {
+                        client.add_probe(peer, port);
+                    } else {
+                        client.ports.push((peer, port));
+                    }
+                    if let Some(mut candidates) = candidates {
+                        candidates.truncate(connect::MAX_CANDIDATES);
+                        client.candidates.push((port, candidates));
+                        let len = client.candidates.len();
+                        client
+                            .candidates
+                            .drain(..len.saturating_sub(connect::MAX_CANDIDATES));
+                    }
+                    return Packets::RegisterResponse(RegisterResponse::Port { port });
+                }
+            }
+        }
+
+        Packets::Error(Error::new(0, code, ErrorRequest::RegisterPort))
+    }
+
+    /// Accepts a client from the listener at `listener`
+    pub fn accept_new(&mut self, listener: usize) {
+        let Some(listener) = self.listeners.get(listener) else {
+            return;
+        };
+
+        if let Ok((conn, from)) = listener.conn.accept() {
+            let tls = match self.tls_session() {
+                Ok(tls) => tls,
+                Err(error) => {
+                    log::error!("Cannot create TLS session: {error:?}");
+                    return;
+                }
+            };
             let _ = conn.set_nonblocking(true);
             let fd = conn.into_raw();
             let session = self.create_session();
             self.poller.add(fd, Event::readable(session)).unwrap();
             let conn = Socket::from_raw(fd);
-            let _ = conn.set_recv_buffer_size(1024);
-            let _ = conn.set_send_buffer_size(1024);
 
             let client = Client {
                 session,
@@ -256,7 +739,13 @@ impl RelayServer {
                 from: from.clone(),
                 stage: ClientStage::NotRegistered,
                 last_message: SystemTime::now(),
-                buffer: vec![MaybeUninit::new(0); 1024],
+                connected: SystemTime::now(),
+                buffer: FrameDecoder::default(),
+                version: 0,
+                capabilities: Vec::new(),
+                tls,
+                #[cfg(feature = "tokio")]
+                writer: None,
             };
 
             log::trace!("Accept: {from:?}, Client: {client:?}");
@@ -266,176 +755,369 @@ impl RelayServer {
     }
 
     pub fn process_client(&mut self, session: usize) -> Option<RawSock> {
+        let index = self.clients.index_of(session)?;
+        let fd = Some(self.clients[index].fd);
+
+        let mut packets = Vec::new();
+
+        if let Some(mut client) = self.clients.get_mut(index) {
+            let client = &mut *client;
+            if let Err(error) = client.tls.fill(&client.conn, &mut client.buffer) {
+                log::trace!("From: {:?}, closed: {error:?}", client.from);
+                client.last_message = SystemTime::UNIX_EPOCH;
+                return None;
+            }
+
+            loop {
+                match client.buffer.next_packet() {
+                    Ok(Some(packet)) => packets.push(packet),
+                    Ok(None) => break,
+                    Err(error) => {
+                        log::trace!("From: {:?}, invalid frame: {error:?}", client.from);
+                        if let FrameError::TooLarge(_) = error {
+                            client.send_error(
+                                ErrorCode::PayloadTooLarge,
+                                ErrorRequest::Unknown,
+                                0,
+                                Adress::new(),
+                            );
+                        }
+                        client.last_message = SystemTime::UNIX_EPOCH;
+                        return None;
+                    }
+                }
+            }
+        } else {
+            return fd;
+        }
+
+        self.handle_packets(index, packets);
+
+        fd
+    }
+
+    /// Registers the client at `index` and sends the `RegisterResponse`
+    fn finish_register(&mut self, index: usize, rclient: RegisteredClient) {
+        self.clients.register(index, rclient);
+
+        let Some(mut client) = self.clients.get_mut(index) else {
+            return;
+        };
+        let pak = Packets::RegisterResponse(RegisterResponse::Client {
+            accepted: true,
+            session: client.session,
+        });
+
+        client.last_message = SystemTime::now();
+
+        let _ = client.send(&pak);
+
+        if let ClientStage::Registered(rclient) = &client.stage {
+            self.handler.on_register(client.session, rclient);
+        }
+    }
+
+    /// Processes the packets received from the client at `index`
+    pub fn handle_packets(&mut self, index: usize, packets: Vec<Packets>) {
         let mut to_search = Vec::new();
         let mut to_info = Vec::new();
         let mut to_request = Vec::new();
         let mut to_request_response = Vec::new();
         let mut to_request_final = Vec::new();
+        let mut to_relay = Vec::new();
 
-        let mut used_adresses = Vec::new();
-        let mut index = None;
-        let mut fd = None;
-        for (i, client) in self.clients.iter().enumerate() {
-            if let ClientStage::Registered(rclient) = &client.stage {
-                used_adresses.push(rclient.adress.clone())
-            }
+        for packet in packets {
+            let Some(mut client) = self.clients.get_mut(index) else {
+                continue;
+            };
+            log::trace!("From: {:?}, packet: {packet:?}", client.from);
+            match packet {
+                Packets::Hello(hello) => {
+                    let response = hello.negotiate(&self.capabilities);
+                    let _ = client.send(&Packets::HelloResponse(response.clone()));
 
-            if client.session == session {
-                index = Some(i);
-                fd = Some(client.fd)
-            }
-        }
+                    match response {
+                        HelloResponse::Accepted {
+                            version,
+                            capabilities,
+                        } => {
+                            client.version = version;
+                            client.capabilities = capabilities;
+                            client.last_message = SystemTime::now();
+                        }
+                        HelloResponse::Rejected { .. } => {
+                            client.last_message = SystemTime::UNIX_EPOCH;
+                            return;
+                        }
+                    }
+                }
+                Packets::Register(register) => match register {
+                    Register::Client {
+                        client: client_name,
+                        public,
+                        name,
+                        other,
+                        privacy,
+                        private_adress,
+                        auth,
+                        identity,
+                    } => {
+                        if client.version == 0 {
+                            client.send_error(
+                                ErrorCode::UnsupportedVersion,
+                                ErrorRequest::Register,
+                                0,
+                                public,
+                            );
+                            client.last_message = SystemTime::UNIX_EPOCH;
+                            return;
+                        }
 
-        let Some(index) = index else{return fd};
+                        if let Some(key) = &self.auth_key {
+                            if let Err(error) = auth.verify(key, &public, &client_name) {
+                                let error = Error::new(
+                                    client.session,
+                                    ErrorCode::Unauthorized,
+                                    ErrorRequest::Register,
+                                )
+                                .with_adress(public)
+                                .with_reason(error.to_string());
+                                let _ = client.send(&Packets::Error(error));
+                                return;
+                            }
+                        }
 
-        let mut buffer;
+                        let identity_error = if identity && !is_identity(&public) {
+                            Some("Adress is not a Ed25519 public key")
+                        } else if !identity && self.require_identity {
+                            Some("The relay requires a identity")
+                        } else if !identity && public.len() == IDENTITY_LEN {
+                            // else anyone could take the adress of a identity
+                            Some("A adress of 32 bytes has to be a proven identity")
+                        } else {
+                            None
+                        };
+                        if let Some(reason) = identity_error {
+                            let error = Error::new(
+                                client.session,
+                                ErrorCode::InvalidProof,
+                                ErrorRequest::Register,
+                            )
+                            .with_adress(public)
+                            .with_reason(reason);
+                            let _ = client.send(&Packets::Error(error));
+                            return;
+                        }
 
-        if let Some(client) = self.clients.get_mut(index) {
-            let Ok(len) = client.conn.recv(&mut client.buffer) else {
-                    client.last_message = SystemTime::UNIX_EPOCH;
-                    return None
-                };
-            if len == 0 {
-                client.last_message = SystemTime::UNIX_EPOCH;
-                return None;
-            }
+                        drop(client);
+                        if self.clients.contains_adress(&public) {
+                            self.clients[index].send_error(
+                                ErrorCode::AdressTaken,
+                                ErrorRequest::Register,
+                                0,
+                                public,
+                            );
+                            return;
+                        }
 
-            let tmp_buffer: &[u8] = unsafe { std::mem::transmute(&client.buffer[0..len]) };
-            buffer = tmp_buffer.to_owned();
-        } else {
-            return fd;
-        }
+                        // Adress is valid
 
-        loop {
-            if let Some(client) = self.clients.get_mut(index) {
-                if buffer.is_empty() {
-                    break;
-                };
-                let Some(packet) = Packets::from_bytes(&mut buffer)else{return fd};
-                log::trace!("From: {:?}, packet: {packet:?}", client.from);
-                match packet {
-                    Packets::Register(register) => match register {
-                        Register::Client {
-                            client: client_name,
-                            public,
+                        let mut rclient = RegisteredClient {
                             name,
+                            client: client_name,
                             other,
+                            adress: public,
+                            ports: vec![],
+                            to_connect: vec![],
                             privacy,
                             private_adress,
-                        } => {
-                            if used_adresses.contains(&public) {
-                                let pak = Packets::RegisterResponse(RegisterResponse::Client {
-                                    accepted: false,
-                                    session: 0,
-                                });
-                                let mut bytes = pak.to_bytes();
-                                bytes.reverse();
-
-                                let _ = client.conn.send(&bytes);
-                                return fd;
+                            request_ids: vec![],
+                            metadata: vec![],
+                            verified: false,
+                            nat: NatType::Unknown,
+                            probes: vec![],
+                            candidates: vec![],
+                        };
+
+                        let client = &self.clients[index];
+                        match self.policy.on_register(client, &rclient) {
+                            Decision::Accept => {}
+                            Decision::AcceptWith(metadata) => rclient.metadata = metadata,
+                            decision => {
+                                client.allowed(decision, ErrorRequest::Register, 0, rclient.adress);
+                                return;
+                            }
+                        }
+
+                        if identity {
+                            let nonce = new_challenge();
+                            let client = &self.clients[index];
+                            let _ = client.send(&Packets::RegisterResponse(
+                                RegisterResponse::Challenge {
+                                    nonce: nonce.clone(),
+                                },
+                            ));
+                            if let Some(mut client) = self.clients.get_mut(index) {
+                                client.last_message = SystemTime::now();
+                                client.stage = ClientStage::Challenged { rclient, nonce };
                             }
+                        } else {
+                            self.finish_register(index, rclient);
+                        }
+                    }
+                    Register::Proof { signature } => {
+                        let ClientStage::Challenged { mut rclient, nonce } = client.stage.clone()
+                        else {
+                            client.send_error(
+                                ErrorCode::InvalidProof,
+                                ErrorRequest::Register,
+                                0,
+                                Adress::new(),
+                            );
+                            continue;
+                        };
 
-                            // Adress is valid
-
-                            client.stage = ClientStage::Registered(RegisteredClient {
-                                name,
-                                client: client_name,
-                                other,
-                                adress: public,
-                                ports: vec![],
-                                to_connect: vec![],
-                                privacy,
-                                private_adress,
-                            });
-
-                            let pak = Packets::RegisterResponse(RegisterResponse::Client {
-                                accepted: true,
-                                session: client.session,
-                            });
-
-                            let mut bytes = pak.to_bytes();
-                            bytes.reverse();
-                            client.last_message = SystemTime::now();
+                        if !verify_challenge(&rclient.adress, &nonce, &signature) {
+                            client.send_error(
+                                ErrorCode::InvalidProof,
+                                ErrorRequest::Register,
+                                0,
+                                rclient.adress,
+                            );
+                            client.stage = ClientStage::NotRegistered;
+                            continue;
+                        }
+                        drop(client);
 
-                            let _ = client.conn.send(&bytes);
+                        // someone else could have registered it while waiting for the proof
+                        if self.clients.contains_adress(&rclient.adress) {
+                            self.clients[index].send_error(
+                                ErrorCode::AdressTaken,
+                                ErrorRequest::Register,
+                                0,
+                                rclient.adress,
+                            );
+                            self.clients.set_stage(index, ClientStage::NotRegistered);
+                            continue;
                         }
-                        Register::Port { session } => {
-                            let mut pak = Packets::RegisterResponse(RegisterResponse::Client {
-                                accepted: false,
-                                session,
-                            });
-                            let Ok(conn) = client.conn.try_clone() else {break};
-                            let from = client.from.clone();
-                            for parent in self.clients.iter_mut() {
-                                if parent.session == session {
-                                    if let Some(ipv4) = from.as_socket_ipv4() {
-                                        let Some(from_ipv4) = parent.from.as_socket_ipv4() else{break};
-                                        if ipv4.ip() != from_ipv4.ip() {
-                                            break;
-                                        };
-                                        if let ClientStage::Registered(registered) =
-                                            &mut parent.stage
-                                        {
-                                            registered.ports.push(ipv4.port());
+
+                        rclient.verified = true;
+                        self.finish_register(index, rclient);
+                    }
+                    Register::Port { session, token } => {
+                        let mut pak = Packets::Error(Error::new(
+                            client.session,
+                            ErrorCode::UnknownSession,
+                            ErrorRequest::RegisterPort,
+                        ));
+                        let from = client.from.clone();
+                        drop(client);
+                        let peer = self.check_port_token(session, &token);
+                        if let Some(mut parent) = self.clients.by_session_mut(session) {
+                            if let (Some(from), Some(parent_from)) =
+                                (socket_addr(&from), socket_addr(&parent.from))
+                            {
+                                if from.ip() == parent_from.ip() {
+                                    if let ClientStage::Registered(registered) = &mut parent.stage {
+                                        if let Ok(peer) = peer {
+                                            registered.ports.push((peer, from.port()));
                                             pak =
                                                 Packets::RegisterResponse(RegisterResponse::Port {
-                                                    port: ipv4.port(),
+                                                    port: from.port(),
                                                 });
+                                        } else {
+                                            pak = Packets::Error(Error::new(
+                                                session,
+                                                ErrorCode::InvalidToken,
+                                                ErrorRequest::RegisterPort,
+                                            ));
                                         }
                                     }
-                                    break;
                                 }
                             }
-
-                            let mut bytes = pak.to_bytes();
-                            bytes.reverse();
-
-                            let _ = conn.send(&bytes);
-                        }
-                    },
-                    Packets::UnRegister(session) => {
-                        if client.session == session.session {
-                            client.last_message = std::time::UNIX_EPOCH;
-                        }
-                    }
-                    Packets::Search(search) => {
-                        if search.session == client.session {
-                            to_search.push(search);
-                            client.last_message = SystemTime::now();
-                        }
-                    }
-                    Packets::InfoRequest(info) => {
-                        if info.session == client.session {
-                            to_info.push(info);
-                            client.last_message = SystemTime::now();
-                        }
-                    }
-                    Packets::Request(request) => {
-                        if request.session == client.session {
-                            to_request.push(request);
-                            client.last_message = SystemTime::now();
-                        }
-                    }
-                    Packets::RequestResponse(request_response) => {
-                        if request_response.session == client.session {
-                            to_request_response.push(request_response);
-                            client.last_message = SystemTime::now();
-                        }
-                    }
-                    Packets::RequestFinal(request_final) => {
-                        if request_final.session == client.session {
-                            to_request_final.push(request_final);
-                            client.last_message = SystemTime::now();
                         }
+
+                        let _ = self.clients[index].send(&pak);
                     }
-                    Packets::Tick { session } => {
-                        if client.session == session {
+                    // only the port seen over UDP shows the NAT
+                    Register::Probe { .. } | Register::Candidates { .. } => {}
+                    Register::Nat { nat } => {
+                        if let ClientStage::Registered(rclient) = &mut client.stage {
+                            rclient.nat = nat;
                             client.last_message = SystemTime::now();
                         }
                     }
-
-                    _ => {}
+                },
+                Packets::UnRegister(session) if client.session == session.session => {
+                    client.last_message = std::time::UNIX_EPOCH;
+                }
+                Packets::Search(search)
+                    if client.validate(search.session, ErrorRequest::Search, search.id)
+                        && client.allowed(
+                            self.policy.on_search(&client, &search),
+                            ErrorRequest::Search,
+                            search.id,
+                            Adress::new(),
+                        ) =>
+                {
+                    to_search.push(search);
+                    client.last_message = SystemTime::now();
+                }
+                Packets::InfoRequest(info)
+                    if client.validate(info.session, ErrorRequest::Info, info.id)
+                        && client.allowed(
+                            self.policy.on_info(&client, &info.adress),
+                            ErrorRequest::Info,
+                            info.id,
+                            info.adress.clone(),
+                        ) =>
+                {
+                    to_info.push(info);
+                    client.last_message = SystemTime::now();
+                }
+                Packets::Request(request)
+                    if client.validate(request.session, ErrorRequest::Request, request.id)
+                        && client.allowed(
+                            self.policy.on_request(&client, &request.to),
+                            ErrorRequest::Request,
+                            request.id,
+                            request.to.clone(),
+                        ) =>
+                {
+                    to_request.push(request);
+                    client.last_message = SystemTime::now();
+                }
+                Packets::RequestResponse(request_response)
+                    if client.validate(
+                        request_response.session,
+                        ErrorRequest::RequestResponse,
+                        request_response.id,
+                    ) =>
+                {
+                    to_request_response.push(request_response);
+                    client.last_message = SystemTime::now();
                 }
+                Packets::RequestFinal(request_final)
+                    if client.validate(
+                        request_final.session,
+                        ErrorRequest::RequestFinal,
+                        request_final.id,
+                    ) =>
+                {
+                    to_request_final.push(request_final);
+                    client.last_message = SystemTime::now();
+                }
+                Packets::RelayRequest(request)
+                    if client.validate(request.session, ErrorRequest::Relay, request.id) =>
+                {
+                    to_relay.push(request);
+                    client.last_message = SystemTime::now();
+                }
+                Packets::Tick { session } if client.session == session => {
+                    client.last_message = SystemTime::now();
+                }
+
+                _ => {}
             }
         }
 
@@ -459,20 +1141,286 @@ impl RelayServer {
             self.on_request_final(index, request_final)
         }
 
-        fd
+        for request in to_relay {
+            self.on_relay_request(index, request)
+        }
     }
 
+    /// Processes the events and the timers, wakes up on its own for the timers
     pub fn step(&mut self) {
-        self.listen();
+        self.step_with_deadline(Instant::now() + self.poll_timeout());
+    }
+
+    /// Same as `step` but returns at least at `deadline`
+    /// `Instant::now()` will not block
+    pub fn step_with_deadline(&mut self, deadline: Instant) {
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .min(self.poll_timeout());
+        self.listen_timeout(Some(timeout));
+        self.connect();
+        self.run_timers();
+        self.flush_clients();
+    }
+
+    /// Writes what was queued for the clients,
+    /// a client with bytes left wakes up the poller when it is writable
+    pub fn flush_clients(&mut self) {
+        self.clients.for_each_mut(|client| {
+            if !client.tls.has_pending() {
+                return;
+            }
+            if let Err(error) = client.tls.flush(&client.conn) {
+                log::trace!("To: {:?}, closed: {error:?}", client.from);
+                client.last_message = SystemTime::UNIX_EPOCH;
+                return;
+            }
+            if client.tls.has_pending() {
+                let _ = self.poller.modify(client.fd, Event::all(client.session));
+            }
+        });
+    }
+
+    /// Removes the clients that did not send anything in `client_timeout`
+    /// or did not register in `timers.handshake_timeout`
+    pub fn remove_timed_out(&mut self) {
         self.clients.retain(|client| {
-            if client.last_message.elapsed().unwrap() < self.client_timeout {
+            let handshake_expired = !matches!(client.stage, ClientStage::Registered(_))
+                && client.connected.elapsed().unwrap_or_default() > self.timers.handshake_timeout;
+
+            if client.last_message.elapsed().unwrap_or_default() < self.client_timeout
+                && !handshake_expired
+            {
                 true
             } else {
                 let _ = self.poller.delete(client.fd);
+                let _ = client.conn.shutdown(Shutdown::Both);
+
+                let adress = match &client.stage {
+                    ClientStage::Registered(rclient) => Some(&rclient.adress),
+                    ClientStage::NotRegistered | ClientStage::Challenged { .. } => None,
+                };
+                // closed, unregistered and kicked clients have `last_message` set to `UNIX_EPOCH`
+                if client.last_message == SystemTime::UNIX_EPOCH {
+                    self.handler.on_unregister(client.session, adress);
+                } else {
+                    self.handler.on_timeout(client.session, adress);
+                }
                 false
             }
         });
+    }
+}
 
-        self.connect();
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::testing::{connect, recv, register_packet, server};
+    use super::*;
+    use crate::common::identity::Identity;
+
+    #[test]
+    fn probes_are_kept_per_peer() {
+        let mut rclient = super::testing::registered(vec![1]);
+        for port in 0..connect::MAX_PROBES as u16 + 2 {
+            rclient.add_probe(2, 40000 + port);
+            rclient.add_probe(3, 50000 + port);
+        }
+
+        // the oldest probes of a peer are dropped
+        let probes = rclient.take_probes(2);
+        assert_eq!(probes.len(), connect::MAX_PROBES);
+        assert_eq!(probes[0], 40002);
+        assert!(probes.windows(2).all(|w| w[1] == w[0] + 1));
+
+        // the probes of a other connection are kept
+        assert!(rclient.take_probes(2).is_empty());
+        let probes = rclient.take_probes(3);
+        assert_eq!(probes.len(), connect::MAX_PROBES);
+        assert_eq!(probes[0], 50002);
+        assert!(rclient.probes.is_empty());
+    }
+
+    #[test]
+    fn register_needs_a_hello() {
+        let mut server = server();
+        let (index, mut peer) = connect(&mut server);
+        server.clients.get_mut(index).unwrap().version = 0;
+
+        server.handle_packets(index, vec![register_packet(vec![1], Auth::None, false)]);
+        let Packets::Error(error) = recv(&mut peer) else {
+            panic!("Registered without a hello");
+        };
+        assert_eq!(error.code, ErrorCode::UnsupportedVersion);
+        assert!(!server.clients.contains_adress(&vec![1]));
+    }
+
+    #[test]
+    fn old_hello_is_rejected() {
+        let mut server = server();
+        let (index, mut peer) = connect(&mut server);
+        server.clients.get_mut(index).unwrap().version = 0;
+        let session = server.clients[index].session;
+
+        let hello = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: vec![],
+        };
+        server.handle_packets(index, vec![Packets::Hello(hello)]);
+        assert!(matches!(
+            recv(&mut peer),
+            Packets::HelloResponse(HelloResponse::Rejected { .. })
+        ));
+        server.remove_timed_out();
+        assert!(!server.clients.contains_session(session));
+
+        let (index, mut peer) = connect(&mut server);
+        server.clients.get_mut(index).unwrap().version = 0;
+        server.handle_packets(index, vec![Packets::Hello(Hello::new())]);
+        assert!(matches!(
+            recv(&mut peer),
+            Packets::HelloResponse(HelloResponse::Accepted {
+                version: PROTOCOL_VERSION,
+                ..
+            })
+        ));
+        assert_eq!(server.clients[index].version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn register_checks_auth_token() {
+        let mut server = server();
+        server.set_auth_key(b"key".to_vec());
+
+        let cases: [(Auth, bool); 5] = [
+            (Auth::None, false),
+            (AuthToken::mint(b"other", vec![1], "test", 0).into(), false),
+            (AuthToken::mint(b"key", vec![2], "test", 0).into(), false),
+            // expired in 1970
+            (AuthToken::mint(b"key", vec![1], "test", 1).into(), false),
+            (AuthToken::mint(b"key", vec![1], "test", 0).into(), true),
+        ];
+
+        for (auth, accepted) in cases {
+            let (index, mut peer) = connect(&mut server);
+            server.handle_packets(index, vec![register_packet(vec![1], auth, false)]);
+
+            match recv(&mut peer) {
+                Packets::RegisterResponse(RegisterResponse::Client { accepted: true, .. }) => {
+                    assert!(accepted)
+                }
+                Packets::Error(error) => {
+                    assert!(!accepted);
+                    assert_eq!(error.code, ErrorCode::Unauthorized);
+                }
+                packet => panic!("Unexpected: {packet:?}"),
+            }
+            assert_eq!(server.clients.contains_adress(&vec![1]), accepted);
+        }
+    }
+
+    /// Sends `Register::Client` for `identity` and returns the nonce of the challenge
+    fn challenge(server: &mut RelayServer, identity: &Identity) -> (usize, TcpStream, Vec<u8>) {
+        let (index, mut peer) = connect(server);
+        let register = register_packet(identity.adress(), Auth::None, true);
+        server.handle_packets(index, vec![register]);
+
+        let Packets::RegisterResponse(RegisterResponse::Challenge { nonce }) = recv(&mut peer)
+        else {
+            panic!("No challenge");
+        };
+        (index, peer, nonce)
+    }
+
+    fn prove(
+        server: &mut RelayServer,
+        index: usize,
+        peer: &mut TcpStream,
+        signature: Vec<u8>,
+    ) -> bool {
+        let proof = Packets::Register(Register::Proof { signature });
+        server.handle_packets(index, vec![proof]);
+        match recv(peer) {
+            Packets::RegisterResponse(RegisterResponse::Client { accepted: true, .. }) => true,
+            Packets::Error(error) => {
+                assert_eq!(error.code, ErrorCode::InvalidProof);
+                false
+            }
+            packet => panic!("Unexpected: {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn identity_challenge_good_signature() {
+        let mut server = server();
+        let identity = Identity::generate();
+        let (index, mut peer, nonce) = challenge(&mut server, &identity);
+
+        let signature = identity.sign_challenge(&nonce);
+        assert!(prove(&mut server, index, &mut peer, signature));
+        let ClientStage::Registered(rclient) = &server.clients[index].stage else {
+            panic!("Not registered");
+        };
+        assert!(rclient.verified);
+    }
+
+    #[test]
+    fn identity_challenge_bad_signature() {
+        let mut server = server();
+        let identity = Identity::generate();
+        let (index, mut peer, nonce) = challenge(&mut server, &identity);
+
+        // signed by a other key
+        let signature = Identity::generate().sign_challenge(&nonce);
+        assert!(!prove(&mut server, index, &mut peer, signature));
+        assert!(!server.clients.contains_adress(&identity.adress()));
+        assert!(matches!(
+            server.clients[index].stage,
+            ClientStage::NotRegistered
+        ));
+    }
+
+    #[test]
+    fn identity_challenge_replayed_nonce() {
+        let mut server = server();
+        let identity = Identity::generate();
+        let (index, mut peer, nonce) = challenge(&mut server, &identity);
+        let signature = identity.sign_challenge(&nonce);
+        assert!(prove(&mut server, index, &mut peer, signature.clone()));
+
+        // the same proof again on the same connection
+        assert!(!prove(&mut server, index, &mut peer, signature.clone()));
+
+        // and on a new connection after the adress was free again
+        let index = server.clients.index_of_adress(&identity.adress()).unwrap();
+        server.clients.set_stage(index, ClientStage::NotRegistered);
+        let (index, mut peer, new_nonce) = challenge(&mut server, &identity);
+        assert_ne!(nonce, new_nonce);
+        assert!(!prove(&mut server, index, &mut peer, signature));
+        assert!(!server.clients.contains_adress(&identity.adress()));
+    }
+
+    #[test]
+    fn identity_adress_cannot_be_squatted() {
+        let mut server = server();
+        assert!(!server.require_identity);
+        let identity = Identity::generate();
+
+        for adress in [identity.adress(), vec![0xff; IDENTITY_LEN]] {
+            let (index, mut peer) = connect(&mut server);
+            let register = register_packet(adress.clone(), Auth::None, false);
+            server.handle_packets(index, vec![register]);
+            let Packets::Error(error) = recv(&mut peer) else {
+                panic!("Registered without a proof");
+            };
+            assert_eq!(error.code, ErrorCode::InvalidProof);
+            assert!(!server.clients.contains_adress(&adress));
+        }
+
+        // the holder of the key still can
+        let (index, mut peer, nonce) = challenge(&mut server, &identity);
+        let signature = identity.sign_challenge(&nonce);
+        assert!(prove(&mut server, index, &mut peer, signature));
     }
 }
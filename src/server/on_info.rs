@@ -1,6 +1,4 @@
-use bytes_kman::TBytes;
-
-use crate::common::packets::{Info, InfoRequest, Packets};
+use crate::common::packets::{ErrorCode, ErrorRequest, Info, InfoRequest, Packets};
 
 use super::{ClientStage, RelayServer};
 
@@ -15,6 +13,7 @@ impl RelayServer {
         }
 
         let mut pak = Info {
+            id: info.id,
             has: false,
             name: String::new(),
             client: String::new(),
@@ -22,25 +21,30 @@ impl RelayServer {
             adress: vec![],
         };
 
-        for client in self.clients.iter() {
+        if let Some(client) = self.clients.by_adress(&info.adress) {
             if let ClientStage::Registered(rclient) = &client.stage {
-                if rclient.adress == info.adress {
+                // a hidden peer looks offline
+                if self.policy.filter_search(&self.clients[index], rclient) {
                     pak.has = true;
                     pak.name = rclient.name.clone();
                     pak.client = rclient.client.clone();
                     pak.other = rclient.other.clone();
                     pak.adress = rclient.adress.clone();
-                    break;
                 }
             }
         }
 
-        if let Some(client) = self.clients.get_mut(index) {
-            let pak = Packets::Info(pak);
-            let mut bytes = pak.to_bytes();
-            bytes.reverse();
-
-            let _ = client.conn.send(&bytes);
+        if let Some(client) = self.clients.get(index) {
+            if pak.has {
+                let _ = client.send(&Packets::Info(pak));
+            } else {
+                client.send_error(
+                    ErrorCode::PeerOffline,
+                    ErrorRequest::Info,
+                    info.id,
+                    info.adress,
+                );
+            }
         }
     }
 }
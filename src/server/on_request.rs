@@ -1,6 +1,4 @@
-use bytes_kman::TBytes;
-
-use crate::common::packets::{NewRequest, NewRequestResponse, Packets, Request};
+use crate::common::packets::{ErrorCode, ErrorRequest, NewRequest, Packets, Request};
 
 use super::{ClientStage, Connecting, RelayServer};
 
@@ -9,48 +7,51 @@ impl RelayServer {
         let mut session = None;
 
         let mut from = None;
+        let mut verified = false;
         if let Some(client) = self.clients.get(index) {
             if let ClientStage::Registered(rclient) = &client.stage {
                 from = Some(rclient.adress.clone());
+                verified = rclient.verified;
             } else {
                 return;
             }
         }
         let Some(from) = from else{return};
 
-        for client in self.clients.iter_mut() {
-            if let ClientStage::Registered(rclient) = &mut client.stage {
-                if rclient.adress == request.to {
-                    let pak = Packets::NewRequest(NewRequest {
-                        session: client.session,
-                        from,
-                        secret: request.secret,
-                    });
-                    let mut bytes = pak.to_bytes();
-                    bytes.reverse();
-                    let _ = client.conn.send(&bytes);
-                    session = Some(client.session);
-                    break;
+        let to = self.clients.by_adress(&request.to).filter(|client| {
+            // a hidden peer looks offline
+            match &client.stage {
+                ClientStage::Registered(rclient) => {
+                    self.policy.filter_search(&self.clients[index], rclient)
                 }
+                _ => false,
             }
+        });
+        if let Some(client) = to {
+            self.handler.on_request(&from, &request.to);
+            let pak = Packets::NewRequest(NewRequest {
+                session: client.session,
+                from,
+                secret: request.secret,
+                verified,
+            });
+            let _ = client.send(&pak);
+            session = Some(client.session);
         }
 
-        if let Some(client) = self.clients.get_mut(index) {
+        if let Some(mut client) = self.clients.get_mut(index) {
             if let Some(session) = session {
                 if let ClientStage::Registered(rclient) = &mut client.stage {
-                    rclient.to_connect.push(Connecting::Start(session))
+                    rclient.to_connect.push(Connecting::Start(session));
+                    rclient.set_request_id(session, request.id);
                 }
             } else {
-                let pak = Packets::NewRequestResponse(NewRequestResponse {
-                    session: client.session,
-                    from: request.to,
-                    accepted: false,
-                    secret: String::new(),
-                });
-                let mut bytes = pak.to_bytes();
-                bytes.reverse();
-
-                let _ = client.conn.send(&bytes);
+                client.send_error(
+                    ErrorCode::PeerOffline,
+                    ErrorRequest::Request,
+                    request.id,
+                    request.to,
+                );
             }
         }
     }
@@ -1,25 +1,18 @@
-use bytes_kman::TBytes;
-
-use crate::common::packets::{NewRequestFinal, Packets, RequestFinal};
+use crate::common::packets::{ErrorCode, ErrorRequest, NewRequestFinal, Packets, RequestFinal};
 
 use super::{ClientStage, Connecting, RelayServer};
 
 impl RelayServer {
     pub(crate) fn on_request_final(&mut self, index: usize, request_final: RequestFinal) {
         let mut to = None;
-        for client in self.clients.iter() {
+        if let Some(client) = self.clients.by_adress(&request_final.to) {
             if let ClientStage::Registered(rclient) = &client.stage {
-                if rclient.adress == request_final.to {
-                    if rclient
-                        .to_connect
-                        .contains(&Connecting::Start(request_final.session))
-                    {
-                        to = Some(client.session)
-                    }
-                    break;
+                if rclient
+                    .to_connect
+                    .contains(&Connecting::Start(request_final.session))
+                {
+                    to = Some(client.session)
                 }
-            } else {
-                return;
             }
         }
 
@@ -33,45 +26,58 @@ impl RelayServer {
         }
 
         let Some(from) = from else{return};
-        let Some(_) = to else{return};
+        let Some(to) = to else {
+            self.clients[index].send_error(
+                ErrorCode::UnknownRequest,
+                ErrorRequest::RequestFinal,
+                request_final.id,
+                request_final.to,
+            );
+            return;
+        };
 
+        self.handler
+            .on_request_final(&from, &request_final.to, request_final.accepted);
+
+        let mut port_token = Vec::new();
+        if request_final.accepted {
+            port_token = self.port_token(to, request_final.session);
+        }
         let mut session = None;
-        for client in self.clients.iter_mut() {
+        if let Some(mut client) = self.clients.by_session_mut(to) {
+            let client = &mut *client;
             if let ClientStage::Registered(rclient) = &mut client.stage {
-                if rclient.adress == request_final.to {
-                    let pak = NewRequestFinal {
-                        session: client.session,
-                        from,
-                        accepted: request_final.accepted,
-                    };
-                    let mut bytes = Packets::NewRequestFinal(pak).to_bytes();
-                    bytes.reverse();
-                    let _ = client.conn.send(&bytes);
-                    session = Some(client.session);
-                    if request_final.accepted {
-                        for to_conn in rclient.to_connect.iter_mut() {
-                            if to_conn.session() == request_final.session {
-                                *to_conn = Connecting::Finishing(
-                                    to_conn.session(),
-                                    request_final.time_offset,
-                                );
-                                break;
-                            }
+                if request_final.accepted {
+                    for to_conn in rclient.to_connect.iter_mut() {
+                        if to_conn.session() == request_final.session {
+                            *to_conn =
+                                Connecting::Finishing(to_conn.session(), request_final.time_offset);
+                            break;
                         }
-                    } else {
-                        rclient
-                            .to_connect
-                            .retain(|to_conn| to_conn.session() != request_final.session);
                     }
-                    break;
+                } else {
+                    rclient
+                        .to_connect
+                        .retain(|to_conn| to_conn.session() != request_final.session);
                 }
-            } else {
-                return;
+                let pak = NewRequestFinal {
+                    session: client.session,
+                    id: rclient.request_id(request_final.session),
+                    from,
+                    accepted: request_final.accepted,
+                    port_token,
+                };
+                if !request_final.accepted {
+                    rclient.remove_request_id(request_final.session);
+                }
+                let _ = client.send(&Packets::NewRequestFinal(pak));
+                session = Some(client.session);
             }
         }
 
         let Some(session) = session else{return};
-        if let Some(client) = self.clients.get_mut(index) {
+        let mut connecting = false;
+        if let Some(mut client) = self.clients.get_mut(index) {
             if let ClientStage::Registered(rclient) = &mut client.stage {
                 if request_final.accepted {
                     for to_conn in rclient.to_connect.iter_mut() {
@@ -81,12 +87,19 @@ impl RelayServer {
                             break;
                         }
                     }
+                    rclient.set_request_id(session, request_final.id);
+                    connecting = true;
                 } else {
                     rclient
                         .to_connect
                         .retain(|to_conn| to_conn.session() != session);
+                    rclient.remove_request_id(session);
                 }
             }
         }
+        if connecting {
+            self.clients.connecting.insert(request_final.session);
+            self.clients.connecting.insert(session);
+        }
     }
 }
@@ -1,24 +1,20 @@
-use bytes_kman::TBytes;
-
-use crate::common::packets::{NewRequestResponse, Packets, RequestResponse};
+use crate::common::packets::{
+    ErrorCode, ErrorRequest, NewRequestResponse, Packets, RequestResponse,
+};
 
 use super::{ClientStage, Connecting, RelayServer};
 
 impl RelayServer {
     pub(crate) fn on_request_response(&mut self, index: usize, request_response: RequestResponse) {
         let mut to = None;
-        for client in self.clients.iter() {
+        if let Some(client) = self.clients.by_adress(&request_response.to) {
             if let ClientStage::Registered(rclient) = &client.stage {
-                if rclient.adress == request_response.to {
-                    for session in rclient.to_connect.iter() {
-                        if session.session() == request_response.session {
-                            to = Some(client.session);
-                            break;
-                        }
+                for session in rclient.to_connect.iter() {
+                    if session.session() == request_response.session {
+                        to = Some(client.session);
+                        break;
                     }
                 }
-            } else {
-                return;
             }
         }
 
@@ -34,41 +30,54 @@ impl RelayServer {
         }
 
         let Some(from) = from else {return};
-        let Some(to) = to else {return};
+        let Some(to) = to else {
+            self.clients[index].send_error(
+                ErrorCode::UnknownRequest,
+                ErrorRequest::RequestResponse,
+                request_response.id,
+                request_response.to,
+            );
+            return;
+        };
         let Some(uid) = uid else {return};
 
-        for client in self.clients.iter_mut() {
+        self.handler
+            .on_request_response(&from, &request_response.to, request_response.accepted);
+
+        let port_token = if request_response.accepted {
+            self.port_token(to, uid)
+        } else {
+            Vec::new()
+        };
+        if let Some(mut client) = self.clients.by_session_mut(to) {
+            let client = &mut *client;
             if let ClientStage::Registered(rclient) = &mut client.stage {
-                if rclient.adress == request_response.to {
-                    let pak = NewRequestResponse {
-                        session: client.session,
-                        from,
-                        accepted: request_response.accepted,
-                        secret: request_response.secret,
-                    };
-                    let mut bytes = Packets::NewRequestResponse(pak).to_bytes();
-                    bytes.reverse();
-                    let _ = client.conn.send(&bytes);
-                    break;
-                }
+                let pak = NewRequestResponse {
+                    session: client.session,
+                    id: rclient.request_id(uid),
+                    from,
+                    accepted: request_response.accepted,
+                    secret: request_response.secret,
+                    port_token,
+                };
+                let _ = client.send(&Packets::NewRequestResponse(pak));
             }
         }
 
         if request_response.accepted {
-            if let Some(client) = self.clients.get_mut(index) {
+            if let Some(mut client) = self.clients.get_mut(index) {
                 if let ClientStage::Registered(rclient) = &mut client.stage {
-                    rclient.to_connect.push(Connecting::Start(to))
+                    rclient.to_connect.push(Connecting::Start(to));
+                    rclient.set_request_id(to, request_response.id);
                 }
             }
         } else {
-            for client in self.clients.iter_mut() {
+            if let Some(mut client) = self.clients.by_session_mut(to) {
                 if let ClientStage::Registered(rclient) = &mut client.stage {
-                    if client.session == to {
-                        rclient
-                            .to_connect
-                            .retain(|to_conn| to_conn.session() != uid);
-                        break;
-                    }
+                    rclient
+                        .to_connect
+                        .retain(|to_conn| to_conn.session() != uid);
+                    rclient.remove_request_id(uid);
                 }
             }
         }
@@ -1,17 +1,16 @@
-use bytes_kman::TBytes;
-
-use crate::common::packets::{Packets, Search, SearchResponse, SearchType};
+use crate::common::packets::{ErrorRequest, Packets, Search, SearchResponse, SearchType};
 
 use super::{ClientStage, RelayServer};
 
 impl RelayServer {
     pub(crate) fn on_search(&mut self, index: usize, search: Search) {
-        let session;
-        if let Some(client) = self.clients.get(index) {
-            session = client.session;
-        } else {
+        let Some(client) = self.clients.get(index) else {
+            return;
+        };
+        if !client.validate(search.session, ErrorRequest::Search, search.id) {
             return;
         }
+        let session = client.session;
 
         let mut adresses = Vec::new();
 
@@ -80,16 +79,67 @@ impl RelayServer {
                     SearchType::None => {}
                 };
 
-                if valid {
+                if valid && self.policy.filter_search(&self.clients[index], rclient) {
                     adresses.push(rclient.adress.clone());
                 }
             }
         }
 
-        let pak = Packets::SearchResponse(SearchResponse { session, adresses });
-        let mut bytes = pak.to_bytes();
-        bytes.reverse();
+        let pak = Packets::SearchResponse(SearchResponse {
+            session,
+            id: search.id,
+            adresses,
+        });
+        let _ = self.clients[index].send(&pak);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common::packets::ErrorCode,
+        server::testing::{connect, recv, register, server},
+    };
+
+    use super::*;
+
+    fn search(session: usize, name: &str) -> Search {
+        Search {
+            session,
+            id: 5,
+            name: SearchType::Exact(String::from(name)),
+            ..Default::default()
+        }
+    }
+
+    fn error(packet: Packets) -> Option<ErrorCode> {
+        match packet {
+            Packets::Error(error) if error.request == ErrorRequest::Search && error.id == 5 => {
+                Some(error.code)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn search_errors_are_sent() {
+        let mut server = server();
+        let (index, mut peer) = connect(&mut server);
+        let session = server.clients[index].session;
+
+        server.on_search(index, search(session, "test"));
+        assert_eq!(error(recv(&mut peer)), Some(ErrorCode::NotRegistered));
+
+        let (index, mut peer) = register(&mut server, vec![1]);
+        let session = server.clients[index].session;
+        server.on_search(index, search(session + 1, "test"));
+        assert_eq!(error(recv(&mut peer)), Some(ErrorCode::UnknownSession));
 
-        let _ = self.clients.get_mut(index).unwrap().conn.send(&bytes);
+        server.on_search(index, search(session, "test"));
+        let Packets::SearchResponse(response) = recv(&mut peer) else {
+            panic!("No SearchResponse");
+        };
+        assert_eq!(response.id, 5);
+        assert_eq!(response.adresses, vec![vec![1]]);
     }
 }
@@ -0,0 +1,365 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::common::{adress::Adress, packets::Search};
+
+use super::{socket_addr, Client, RegisteredClient};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    /// Accepts and stores the metadata in `RegisteredClient::metadata`
+    /// only used by `RelayPolicy::on_register`
+    AcceptWith(Vec<(String, String)>),
+    /// The reason is sent to the client in the `Error`
+    Deny(String),
+}
+
+/// Decides what a client is allowed to do on the relay
+/// Every method accepts by default
+#[allow(unused_variables)]
+pub trait RelayPolicy: Send {
+    /// `client` is not registered yet, `registering` is how it would be registered
+    fn on_register(&mut self, client: &Client, registering: &RegisteredClient) -> Decision {
+        Decision::Accept
+    }
+
+    fn on_search(&mut self, client: &Client, search: &Search) -> Decision {
+        Decision::Accept
+    }
+
+    /// Returns false to hide `found` from `client`, it is not in the search results
+    /// and cannot be found with a `InfoRequest` or a `Request`
+    fn filter_search(&mut self, client: &Client, found: &RegisteredClient) -> bool {
+        true
+    }
+
+    fn on_info(&mut self, client: &Client, adress: &Adress) -> Decision {
+        Decision::Accept
+    }
+
+    fn on_request(&mut self, client: &Client, to: &Adress) -> Decision {
+        Decision::Accept
+    }
+}
+
+/// Accepts everything
+impl RelayPolicy for () {}
+
+impl std::fmt::Debug for dyn RelayPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RelayPolicy")
+    }
+}
+
+/// A IP network like `10.0.0.0/8` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    pub ip: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRange {
+    pub fn new(ip: IpAddr, prefix: u8) -> Self {
+        Self { ip, prefix }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let prefix = self.prefix.min(32) as u32;
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let prefix = self.prefix.min(128) as u32;
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(ip: IpAddr) -> Self {
+        let prefix = if ip.is_ipv4() { 32 } else { 128 };
+        Self { ip, prefix }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((ip, prefix)) = s.split_once('/') else {
+            return s.parse::<IpAddr>().map(Self::from).map_err(|_| ());
+        };
+
+        let ip = ip.parse::<IpAddr>().map_err(|_| ())?;
+        let prefix = prefix.parse::<u8>().map_err(|_| ())?;
+        if prefix > if ip.is_ipv4() { 32 } else { 128 } {
+            return Err(());
+        }
+
+        Ok(Self { ip, prefix })
+    }
+}
+
+/// Allowlist and denylist on the IP of the client, the `client` name and the adress
+/// A empty allowlist allows everything, the denylist wins over the allowlist
+#[derive(Debug, Clone, Default)]
+pub struct ListPolicy {
+    pub allow_ips: Vec<IpRange>,
+    pub deny_ips: Vec<IpRange>,
+    pub allow_clients: Vec<String>,
+    pub deny_clients: Vec<String>,
+    pub allow_adresses: Vec<Adress>,
+    pub deny_adresses: Vec<Adress>,
+}
+
+impl ListPolicy {
+    fn check_ip(&self, client: &Client) -> Decision {
+        let Some(from) = socket_addr(&client.from) else {
+            return Decision::Deny(String::from("Unknown ip"));
+        };
+        let ip = from.ip();
+
+        if self.deny_ips.iter().any(|range| range.contains(&ip))
+            || (!self.allow_ips.is_empty()
+                && !self.allow_ips.iter().any(|range| range.contains(&ip)))
+        {
+            return Decision::Deny(format!("Ip {ip} is not allowed"));
+        }
+
+        Decision::Accept
+    }
+
+    fn check_adress(&self, adress: &Adress) -> bool {
+        !self.deny_adresses.contains(adress)
+            && (self.allow_adresses.is_empty() || self.allow_adresses.contains(adress))
+    }
+}
+
+impl RelayPolicy for ListPolicy {
+    fn on_register(&mut self, client: &Client, registering: &RegisteredClient) -> Decision {
+        if let Decision::Deny(reason) = self.check_ip(client) {
+            return Decision::Deny(reason);
+        }
+
+        let name = &registering.client;
+        if self.deny_clients.contains(name)
+            || (!self.allow_clients.is_empty() && !self.allow_clients.contains(name))
+        {
+            return Decision::Deny(format!("Client {name} is not allowed"));
+        }
+
+        if !self.check_adress(&registering.adress) {
+            return Decision::Deny(String::from("Adress is not allowed"));
+        }
+
+        Decision::Accept
+    }
+
+    fn on_search(&mut self, client: &Client, _: &Search) -> Decision {
+        self.check_ip(client)
+    }
+
+    fn on_info(&mut self, client: &Client, _: &Adress) -> Decision {
+        self.check_ip(client)
+    }
+
+    fn on_request(&mut self, client: &Client, to: &Adress) -> Decision {
+        if let Decision::Deny(reason) = self.check_ip(client) {
+            return Decision::Deny(reason);
+        }
+
+        if !self.check_adress(to) {
+            return Decision::Deny(String::from("Adress is not allowed"));
+        }
+
+        Decision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::packets::{ErrorCode, InfoRequest, Packets, Request},
+        server::testing::{connect, recv, register, registered, server},
+    };
+
+    /// Hides every adress that starts with 0
+    struct Hide;
+
+    impl RelayPolicy for Hide {
+        fn filter_search(&mut self, _: &Client, found: &RegisteredClient) -> bool {
+            found.adress.first() != Some(&0)
+        }
+    }
+
+    fn offline(packet: Packets) -> bool {
+        matches!(packet, Packets::Error(error) if error.code == ErrorCode::PeerOffline)
+    }
+
+    #[test]
+    fn hidden_peer_cannot_be_looked_up() {
+        let mut server = server();
+        server.set_policy(Hide);
+        let (index, mut peer) = register(&mut server, vec![1]);
+        register(&mut server, vec![0]);
+        register(&mut server, vec![2]);
+
+        for adress in [vec![0], vec![2]] {
+            let session = server.clients[index].session;
+            server.on_info(
+                index,
+                InfoRequest {
+                    adress: adress.clone(),
+                    session,
+                    id: 1,
+                },
+            );
+            let info = recv(&mut peer);
+            assert_eq!(offline(info.clone()), adress == vec![0], "{info:?}");
+
+            server.on_request(
+                index,
+                Request {
+                    session,
+                    id: 2,
+                    to: adress.clone(),
+                    secret: String::new(),
+                },
+            );
+            if adress == vec![0] {
+                assert!(offline(recv(&mut peer)));
+            }
+        }
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ip_range_parses() {
+        assert_eq!(range("10.0.0.0/8"), IpRange::new(ip("10.0.0.0"), 8));
+        assert_eq!(range("fd00::/8"), IpRange::new(ip("fd00::"), 8));
+        assert_eq!(range("0.0.0.0/0"), IpRange::new(ip("0.0.0.0"), 0));
+        assert_eq!(range("::1/128"), IpRange::new(ip("::1"), 128));
+        // a ip without a prefix is only that ip
+        assert_eq!(range("1.2.3.4"), IpRange::new(ip("1.2.3.4"), 32));
+        assert_eq!(range("::1"), IpRange::new(ip("::1"), 128));
+
+        for bad in [
+            "",
+            "/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "localhost",
+            "[::1]/64",
+        ] {
+            assert_eq!(bad.parse::<IpRange>(), Err(()), "{bad}");
+        }
+    }
+
+    #[test]
+    fn ip_range_masks_the_prefix() {
+        let v4 = range("192.168.1.77/24");
+        assert!(v4.contains(&ip("192.168.1.1")));
+        assert!(v4.contains(&ip("192.168.1.255")));
+        assert!(!v4.contains(&ip("192.168.2.1")));
+        // a prefix that is not on a byte
+        let v4 = range("10.16.0.0/12");
+        assert!(v4.contains(&ip("10.31.255.255")));
+        assert!(!v4.contains(&ip("10.32.0.0")));
+        assert!(!v4.contains(&ip("10.15.255.255")));
+
+        let v6 = range("fd00::/8");
+        assert!(v6.contains(&ip("fdff::1")));
+        assert!(!v6.contains(&ip("fe80::1")));
+        let v6 = range("2001:db8::/33");
+        assert!(v6.contains(&ip("2001:db8:7fff::1")));
+        assert!(!v6.contains(&ip("2001:db8:8000::1")));
+
+        assert!(range("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(range("::/0").contains(&ip("ffff::1")));
+        assert!(range("1.2.3.4").contains(&ip("1.2.3.4")));
+        assert!(!range("1.2.3.4").contains(&ip("1.2.3.5")));
+
+        // v4 and v6 never match each other
+        assert!(!range("0.0.0.0/0").contains(&ip("::1")));
+        assert!(!range("::/0").contains(&ip("127.0.0.1")));
+        assert!(!range("::ffff:0:0/96").contains(&ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn list_policy_deny_wins() {
+        let mut server = server();
+        let (index, _peer) = connect(&mut server);
+        let client = &server.clients[index];
+        let search = Search::default();
+        let allowed = |policy: &mut ListPolicy, name: &str, adress: Adress| {
+            let mut registering = registered(adress.clone());
+            registering.client = String::from(name);
+            [
+                policy.on_register(client, &registering),
+                policy.on_search(client, &search),
+                policy.on_info(client, &adress),
+                policy.on_request(client, &adress),
+            ]
+            .map(|decision| decision == Decision::Accept)
+        };
+
+        // everything is allowed by default
+        let mut policy = ListPolicy::default();
+        assert_eq!(allowed(&mut policy, "test", vec![1]), [true; 4]);
+
+        // the client is on 127.0.0.1
+        policy.allow_ips = vec![range("10.0.0.0/8")];
+        assert_eq!(allowed(&mut policy, "test", vec![1]), [false; 4]);
+        policy.allow_ips.push(range("127.0.0.0/8"));
+        assert_eq!(allowed(&mut policy, "test", vec![1]), [true; 4]);
+        policy.deny_ips = vec![range("127.0.0.1")];
+        assert_eq!(allowed(&mut policy, "test", vec![1]), [false; 4]);
+        policy.deny_ips.clear();
+
+        // names are only checked on register
+        policy.allow_clients = vec![String::from("other")];
+        assert_eq!(
+            allowed(&mut policy, "test", vec![1]),
+            [false, true, true, true]
+        );
+        policy.allow_clients.push(String::from("test"));
+        policy.deny_clients = vec![String::from("test")];
+        assert_eq!(
+            allowed(&mut policy, "test", vec![1]),
+            [false, true, true, true]
+        );
+        assert_eq!(allowed(&mut policy, "other", vec![1]), [true; 4]);
+        policy.deny_clients.clear();
+
+        // adresses are checked on register and request
+        policy.allow_adresses = vec![vec![1], vec![2]];
+        assert_eq!(allowed(&mut policy, "test", vec![1]), [true; 4]);
+        assert_eq!(
+            allowed(&mut policy, "test", vec![3]),
+            [false, true, true, false]
+        );
+        policy.deny_adresses = vec![vec![2]];
+        assert_eq!(
+            allowed(&mut policy, "test", vec![2]),
+            [false, true, true, false]
+        );
+        assert_eq!(allowed(&mut policy, "test", vec![1]), [true; 4]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::common::packets::ErrorCode;
+
+use super::{ClientStage, RelayServer};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes of a port or relay token: the peer session, when it expires and the HMAC-SHA256
+pub const PORT_TOKEN_LEN: usize = 8 + 8 + 32;
+
+const PORT_LABEL: &[u8] = b"relay-man port v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortTokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+}
+
+/// A token that allows `session` to register ports for the connection to `peer`
+/// until `expires`, in unix milliseconds
+/// Only the relay with `key` can make or check it
+pub fn mint_port_token(key: &[u8], session: usize, peer: usize, expires: u64) -> Vec<u8> {
+    mint_token(PORT_LABEL, key, session, peer, expires)
+}
+
+/// Checks that `token` was made by `key` for `session` and is not expired at `now`
+/// Returns the peer session of the connection that it is for
+pub fn verify_port_token(
+    key: &[u8],
+    session: usize,
+    token: &[u8],
+    now: u64,
+) -> Result<usize, PortTokenError> {
+    verify_token(PORT_LABEL, key, session, token, now)
+}
+
+/// Same as `mint_port_token`, `label` keeps tokens of different uses apart
+pub(crate) fn mint_token(
+    label: &[u8],
+    key: &[u8],
+    session: usize,
+    peer: usize,
+    expires: u64,
+) -> Vec<u8> {
+    let mut token = Vec::with_capacity(PORT_TOKEN_LEN);
+    token.extend((peer as u64).to_be_bytes());
+    token.extend(expires.to_be_bytes());
+    token.extend(
+        hmac(label, key, session, peer as u64, expires)
+            .finalize()
+            .into_bytes(),
+    );
+    token
+}
+
+pub(crate) fn verify_token(
+    label: &[u8],
+    key: &[u8],
+    session: usize,
+    token: &[u8],
+    now: u64,
+) -> Result<usize, PortTokenError> {
+    if token.len() != PORT_TOKEN_LEN {
+        return Err(PortTokenError::Malformed);
+    }
+    let peer = u64::from_be_bytes(token[0..8].try_into().unwrap());
+    let expires = u64::from_be_bytes(token[8..16].try_into().unwrap());
+
+    if hmac(label, key, session, peer, expires)
+        .verify_slice(&token[16..])
+        .is_err()
+    {
+        return Err(PortTokenError::InvalidSignature);
+    }
+    if now > expires {
+        return Err(PortTokenError::Expired);
+    }
+    usize::try_from(peer).map_err(|_| PortTokenError::Malformed)
+}
+
+fn hmac(label: &[u8], key: &[u8], session: usize, peer: u64, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(label);
+    mac.update(&(session as u64).to_be_bytes());
+    mac.update(&peer.to_be_bytes());
+    mac.update(&expires.to_be_bytes());
+    mac
+}
+
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl RelayServer {
+    /// Creates a token for `Register::Port` that `session` can only use
+    /// for the pending connection to `peer`, valid for `port_token_timeout`
+    pub fn port_token(&self, session: usize, peer: usize) -> Vec<u8> {
+        let expires = unix_millis(SystemTime::now() + self.timers.port_token_timeout);
+        mint_port_token(&self.port_token_key, session, peer, expires)
+    }
+
+    /// Checks a token of `Register::Port`, `Register::Probe` or `Register::Candidates`
+    /// sent for `session`, the connection that it was made for has to be still pending
+    /// Returns the peer session of that connection
+    pub fn check_port_token(&self, session: usize, token: &[u8]) -> Result<usize, ErrorCode> {
+        let Some(client) = self.clients.by_session(session) else {
+            return Err(ErrorCode::UnknownSession);
+        };
+        let ClientStage::Registered(rclient) = &client.stage else {
+            return Err(ErrorCode::UnknownSession);
+        };
+
+        let now = unix_millis(SystemTime::now());
+        let Ok(peer) = verify_port_token(&self.port_token_key, session, token, now) else {
+            return Err(ErrorCode::InvalidToken);
+        };
+        if rclient
+            .to_connect
+            .iter()
+            .any(|to_conn| to_conn.session() == peer)
+        {
+            Ok(peer)
+        } else {
+            Err(ErrorCode::InvalidToken)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use socket2::SockAddr;
+
+    use bytes_kman::TBytes;
+
+    use crate::common::packets::{Error, ErrorRequest, Packets, Register, RegisterResponse};
+    use crate::server::{testing, Connecting};
+
+    use super::*;
+
+    #[test]
+    fn token_checks() {
+        let token = mint_port_token(b"key", 1, 2, 100);
+        assert_eq!(verify_port_token(b"key", 1, &token, 100), Ok(2));
+        assert_eq!(
+            verify_port_token(b"key", 1, &token, 101),
+            Err(PortTokenError::Expired)
+        );
+        assert_eq!(
+            verify_port_token(b"other", 1, &token, 100),
+            Err(PortTokenError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_port_token(b"key", 3, &token, 100),
+            Err(PortTokenError::InvalidSignature)
+        );
+
+        let mut moved = token.clone();
+        moved[7] = 3;
+        assert_eq!(
+            verify_port_token(b"key", 1, &moved, 100),
+            Err(PortTokenError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_port_token(b"key", 1, &token[1..], 100),
+            Err(PortTokenError::Malformed)
+        );
+    }
+
+    fn register_port(server: &mut RelayServer, session: usize, token: Vec<u8>) -> Packets {
+        let mut bytes = Packets::Register(Register::Port { session, token }).to_bytes();
+        bytes.reverse();
+        let from = SockAddr::from(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        server.on_udp_packet(bytes, &from)
+    }
+
+    fn is_invalid(pak: Packets) -> bool {
+        matches!(
+            pak,
+            Packets::Error(Error {
+                code: ErrorCode::InvalidToken,
+                request: ErrorRequest::RegisterPort,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn token_is_only_for_its_connection() {
+        let mut server = testing::server();
+        let (a, _peer_a) = testing::register(&mut server, vec![1]);
+        let (b, _peer_b) = testing::register(&mut server, vec![2]);
+        let (c, _peer_c) = testing::register(&mut server, vec![3]);
+        let (a, b, c) = (
+            server.clients[a].session,
+            server.clients[b].session,
+            server.clients[c].session,
+        );
+        if let ClientStage::Registered(rclient) =
+            &mut server.clients.by_session_mut(a).unwrap().stage
+        {
+            rclient.to_connect.push(Connecting::Start(b));
+        }
+
+        // a connection that is not pending
+        let token = server.port_token(a, c);
+        assert!(is_invalid(register_port(&mut server, a, token)));
+        // the token of a other connection
+        let token = server.port_token(a, b);
+        assert!(is_invalid(register_port(&mut server, c, token)));
+        // signed by a other relay
+        let token = mint_port_token(b"other", a, b, u64::MAX);
+        assert!(is_invalid(register_port(&mut server, a, token)));
+        // expired
+        let token = mint_port_token(&server.port_token_key, a, b, 1);
+        assert!(is_invalid(register_port(&mut server, a, token)));
+
+        let token = server.port_token(a, b);
+        assert!(matches!(
+            register_port(&mut server, a, token),
+            Packets::RegisterResponse(RegisterResponse::Port { port: 40000 })
+        ));
+        let ClientStage::Registered(rclient) = &mut server.clients.by_session_mut(a).unwrap().stage
+        else {
+            panic!("not registered");
+        };
+        assert_eq!(rclient.take_port(c), None);
+        assert_eq!(rclient.take_port(b), Some(40000));
+    }
+}
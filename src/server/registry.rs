@@ -0,0 +1,342 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut, Index},
+};
+
+use crate::common::adress::Adress;
+
+use super::{Client, ClientStage, RegisteredClient};
+
+/// Stores the clients of a relay indexed by session and by `Adress`
+/// A client can only be changed with a `ClientMut` so the indexes stay valid
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    clients: Vec<Client>,
+    /// session -> index in `clients`
+    sessions: HashMap<usize, usize>,
+    /// adress -> session
+    adresses: HashMap<Adress, usize>,
+    /// Sessions that have a `Connecting::Finishing` and are waiting for `connect`
+    pub connecting: HashSet<usize>,
+}
+
+impl ClientRegistry {
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Client> {
+        self.clients.iter()
+    }
+
+    /// Calls `f` with every client, the indexes are updated after every call
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(&mut Client)) {
+        for index in 0..self.clients.len() {
+            if let Some(mut client) = self.get_mut(index) {
+                f(&mut client);
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Client> {
+        self.clients.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<ClientMut<'_>> {
+        let client = self.clients.get_mut(index)?;
+        Some(ClientMut {
+            old_session: client.session,
+            old_adress: registered_adress(client).cloned(),
+            client,
+            index,
+            sessions: &mut self.sessions,
+            adresses: &mut self.adresses,
+        })
+    }
+
+    /// Index of the client with `session`
+    pub fn index_of(&self, session: usize) -> Option<usize> {
+        self.sessions.get(&session).copied()
+    }
+
+    /// Index of the registered client with `adress`
+    pub fn index_of_adress(&self, adress: &Adress) -> Option<usize> {
+        self.adresses
+            .get(adress)
+            .and_then(|session| self.index_of(*session))
+    }
+
+    pub fn contains_session(&self, session: usize) -> bool {
+        self.sessions.contains_key(&session)
+    }
+
+    pub fn contains_adress(&self, adress: &Adress) -> bool {
+        self.adresses.contains_key(adress)
+    }
+
+    pub fn by_session(&self, session: usize) -> Option<&Client> {
+        self.index_of(session).map(|index| &self.clients[index])
+    }
+
+    pub fn by_session_mut(&mut self, session: usize) -> Option<ClientMut<'_>> {
+        self.get_mut(self.index_of(session)?)
+    }
+
+    pub fn by_adress(&self, adress: &Adress) -> Option<&Client> {
+        self.index_of_adress(adress)
+            .map(|index| &self.clients[index])
+    }
+
+    pub fn by_adress_mut(&mut self, adress: &Adress) -> Option<ClientMut<'_>> {
+        self.get_mut(self.index_of_adress(adress)?)
+    }
+
+    pub fn push(&mut self, client: Client) {
+        self.sessions.insert(client.session, self.clients.len());
+        if let Some(adress) = registered_adress(&client) {
+            self.adresses.insert(adress.clone(), client.session);
+        }
+        self.clients.push(client);
+    }
+
+    /// Sets the client at `index` as registered with `rclient.adress`
+    pub fn register(&mut self, index: usize, rclient: RegisteredClient) {
+        self.set_stage(index, ClientStage::Registered(rclient));
+    }
+
+    /// Changes the stage of the client at `index`, the adress index is updated
+    pub fn set_stage(&mut self, index: usize, stage: ClientStage) {
+        if let Some(mut client) = self.get_mut(index) {
+            client.stage = stage;
+        }
+    }
+
+    /// Removes the client at `index`, the last client takes its index
+    pub fn remove(&mut self, index: usize) -> Client {
+        let client = self.clients.swap_remove(index);
+        self.forget(&client);
+
+        if let Some(moved) = self.clients.get(index) {
+            self.sessions.insert(moved.session, index);
+        }
+
+        client
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&Client) -> bool) {
+        let mut index = 0;
+        while index < self.clients.len() {
+            if f(&self.clients[index]) {
+                index += 1;
+            } else {
+                self.remove(index);
+            }
+        }
+    }
+
+    fn forget(&mut self, client: &Client) {
+        self.sessions.remove(&client.session);
+        self.connecting.remove(&client.session);
+        if let Some(adress) = registered_adress(client) {
+            if self.adresses.get(adress) == Some(&client.session) {
+                self.adresses.remove(adress);
+            }
+        }
+    }
+}
+
+fn registered_adress(client: &Client) -> Option<&Adress> {
+    match &client.stage {
+        ClientStage::Registered(rclient) => Some(&rclient.adress),
+        _ => None,
+    }
+}
+
+/// A mutable client of a `ClientRegistry`,
+/// updates the indexes when dropped if the session or the adress was changed
+pub struct ClientMut<'a> {
+    client: &'a mut Client,
+    index: usize,
+    old_session: usize,
+    old_adress: Option<Adress>,
+    sessions: &'a mut HashMap<usize, usize>,
+    adresses: &'a mut HashMap<Adress, usize>,
+}
+
+impl Deref for ClientMut<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        self.client
+    }
+}
+
+impl DerefMut for ClientMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client
+    }
+}
+
+impl Drop for ClientMut<'_> {
+    fn drop(&mut self) {
+        let session = self.client.session;
+        if session != self.old_session {
+            if self.sessions.get(&self.old_session) == Some(&self.index) {
+                self.sessions.remove(&self.old_session);
+            }
+            self.sessions.insert(session, self.index);
+        }
+
+        let adress = registered_adress(self.client);
+        if session != self.old_session || adress != self.old_adress.as_ref() {
+            if let Some(old) = &self.old_adress {
+                if self.adresses.get(old) == Some(&self.old_session) {
+                    self.adresses.remove(old);
+                }
+            }
+            if let Some(adress) = adress {
+                self.adresses.insert(adress.clone(), session);
+            }
+        }
+    }
+}
+
+impl Index<usize> for ClientRegistry {
+    type Output = Client;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.clients[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::testing::{connect, register, registered, server};
+
+    use super::*;
+
+    #[test]
+    fn remove_moves_the_last_client() {
+        let mut server = server();
+        let (a, _a) = register(&mut server, vec![1]);
+        let (b, _b) = register(&mut server, vec![2]);
+        let (c, _c) = register(&mut server, vec![3]);
+        let sessions: Vec<usize> = [a, b, c]
+            .iter()
+            .map(|index| server.clients[*index].session)
+            .collect();
+
+        let removed = server.clients.remove(a);
+        assert_eq!(removed.session, sessions[0]);
+        assert_eq!(server.clients.len(), 2);
+        assert!(!server.clients.contains_session(sessions[0]));
+        assert!(!server.clients.contains_adress(&vec![1]));
+
+        // the last client took the index of the removed one
+        assert_eq!(server.clients.index_of(sessions[2]), Some(a));
+        assert_eq!(server.clients.index_of_adress(&vec![3]), Some(a));
+        assert_eq!(server.clients.index_of(sessions[1]), Some(b));
+        assert_eq!(server.clients.index_of_adress(&vec![2]), Some(b));
+
+        // removing the last client moves nothing
+        let last = server.clients.len() - 1;
+        server.clients.remove(last);
+        assert_eq!(server.clients.index_of(sessions[2]), Some(a));
+        assert_eq!(server.clients.index_of(sessions[1]), None);
+        assert!(!server.clients.contains_adress(&vec![2]));
+    }
+
+    #[test]
+    fn retain_keeps_the_indexes() {
+        let mut server = server();
+        let mut peers = Vec::new();
+        for adress in 0..6 {
+            peers.push(register(&mut server, vec![adress]));
+        }
+
+        server.clients.retain(|client| match &client.stage {
+            ClientStage::Registered(rclient) => rclient.adress[0] % 2 == 0,
+            _ => false,
+        });
+
+        assert_eq!(server.clients.len(), 3);
+        for adress in 0..6 {
+            let index = server.clients.index_of_adress(&vec![adress]);
+            if adress % 2 == 1 {
+                assert_eq!(index, None);
+                continue;
+            }
+            let client = &server.clients[index.unwrap()];
+            assert_eq!(server.clients.index_of(client.session), index);
+            assert!(
+                matches!(&client.stage, ClientStage::Registered(rclient) if rclient.adress == vec![adress])
+            );
+        }
+    }
+
+    #[test]
+    fn set_stage_updates_the_adress() {
+        let mut server = server();
+        let (index, _peer) = register(&mut server, vec![1]);
+        let session = server.clients[index].session;
+
+        server.clients.set_stage(index, ClientStage::NotRegistered);
+        assert!(!server.clients.contains_adress(&vec![1]));
+        assert_eq!(server.clients.index_of(session), Some(index));
+
+        server.clients.register(index, registered(vec![2]));
+        assert_eq!(server.clients.index_of_adress(&vec![2]), Some(index));
+
+        // a new adress replaces the old one
+        server.clients.register(index, registered(vec![3]));
+        assert!(!server.clients.contains_adress(&vec![2]));
+        assert_eq!(server.clients.index_of_adress(&vec![3]), Some(index));
+    }
+
+    #[test]
+    fn reregistered_adress_belongs_to_the_new_client() {
+        let mut server = server();
+        let (a, _a) = register(&mut server, vec![1]);
+        let (b, _b) = connect(&mut server);
+
+        server.clients.register(b, registered(vec![1]));
+        assert_eq!(server.clients.index_of_adress(&vec![1]), Some(b));
+
+        // the old client does not remove the adress of the new one
+        server.clients.set_stage(a, ClientStage::NotRegistered);
+        assert_eq!(server.clients.index_of_adress(&vec![1]), Some(b));
+        server.clients.remove(a);
+        assert_eq!(server.clients.index_of_adress(&vec![1]), Some(a));
+    }
+
+    #[test]
+    fn client_mut_updates_the_indexes() {
+        let mut server = server();
+        let (index, _peer) = register(&mut server, vec![1]);
+
+        if let Some(mut client) = server.clients.get_mut(index) {
+            if let ClientStage::Registered(rclient) = &mut client.stage {
+                rclient.adress = vec![2];
+            }
+        }
+        assert!(!server.clients.contains_adress(&vec![1]));
+        assert_eq!(server.clients.index_of_adress(&vec![2]), Some(index));
+
+        server.clients.by_adress_mut(&vec![2]).unwrap().stage = ClientStage::NotRegistered;
+        assert!(!server.clients.contains_adress(&vec![2]));
+
+        let session = server.clients[index].session;
+        server.clients.by_session_mut(session).unwrap().session = 1000;
+        assert!(!server.clients.contains_session(session));
+        assert_eq!(server.clients.index_of(1000), Some(index));
+
+        server.clients.for_each_mut(|client| {
+            client.stage = ClientStage::Registered(registered(vec![3]));
+        });
+        assert_eq!(server.clients.index_of_adress(&vec![3]), Some(index));
+    }
+}
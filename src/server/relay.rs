@@ -0,0 +1,542 @@
+use std::{
+    collections::HashMap,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use polling::Event;
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::common::{
+    packets::{Error, ErrorCode, ErrorRequest, Packets, RelayRequest, RelayResponse, RELAY_BIND},
+    FromRawSock, IntoRawSock, RawSock,
+};
+
+use super::{
+    port_token::{mint_token, unix_millis, verify_token},
+    ClientStage, RelayServer, LISTENER_KEY, RELAY_KEY,
+};
+
+const RELAY_LABEL: &[u8] = b"relay-man relay v1";
+
+/// Limits of the relayed connections, used when hole punching fails
+#[derive(Debug, Clone)]
+pub struct RelayQuota {
+    /// Bytes per second that a session can send over all its relayed connections
+    pub bandwidth: u64,
+    /// How long a relayed connection can be used
+    pub duration: Duration,
+    /// Relayed connections that can exist at the same time
+    pub max_allocations: usize,
+}
+
+impl Default for RelayQuota {
+    fn default() -> Self {
+        Self {
+            bandwidth: 256 * 1024,
+            duration: Duration::from_secs(600),
+            max_allocations: 256,
+        }
+    }
+}
+
+/// The UDP socket of a `RelaySide`
+#[derive(Debug)]
+pub struct RelayPort {
+    pub conn: Socket,
+    pub fd: RawSock,
+    pub port: u16,
+}
+
+/// One peer of a `RelayAllocation`
+#[derive(Debug)]
+pub struct RelaySide {
+    pub session: usize,
+    /// The token of the `RelayRequest` of this peer, empty until then
+    /// the peer binds its adress by sending it to the port
+    pub token: Vec<u8>,
+    /// Bound on the `RelayRequest` of the peer
+    pub port: Option<RelayPort>,
+    /// The adress that sent the token, datagrams from other adresses are dropped
+    pub peer: Option<SocketAddr>,
+    /// Bytes forwarded from this peer
+    pub bytes: u64,
+}
+
+impl RelaySide {
+    fn new(session: usize) -> Self {
+        Self {
+            session,
+            token: Vec::new(),
+            port: None,
+            peer: None,
+            bytes: 0,
+        }
+    }
+}
+
+/// Two UDP ports that forward the datagrams of two peers to each other
+#[derive(Debug)]
+pub struct RelayAllocation {
+    /// Poller key of the first side, the second side has the next key
+    pub key: usize,
+    pub sides: [RelaySide; 2],
+    pub created: Instant,
+    pub last_used: Instant,
+}
+
+/// Token bucket of a session
+#[derive(Debug, Clone, Copy)]
+pub struct Bandwidth {
+    /// Bytes that can be sent now
+    pub allowance: f64,
+    pub updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RelayRegistry {
+    /// By the poller key of the first side
+    pub allocations: HashMap<usize, RelayAllocation>,
+    /// The sessions of both sides, the smaller one first -> the key of their allocation
+    pub by_sessions: HashMap<(usize, usize), usize>,
+    /// session -> what it can still send
+    pub bandwidth: HashMap<usize, Bandwidth>,
+    /// Where the search for a free key starts
+    pub next_key: usize,
+}
+
+impl Default for RelayRegistry {
+    fn default() -> Self {
+        Self {
+            allocations: HashMap::new(),
+            by_sessions: HashMap::new(),
+            bandwidth: HashMap::new(),
+            next_key: RELAY_KEY,
+        }
+    }
+}
+
+fn pair(session1: usize, session2: usize) -> (usize, usize) {
+    (session1.min(session2), session1.max(session2))
+}
+
+impl RelayRegistry {
+    pub fn len(&self) -> usize {
+        self.allocations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allocations.is_empty()
+    }
+
+    /// Creates a allocation for two sessions, the ports are bound on `RelayRequest`
+    /// Returns nothing if there are no free poller keys
+    pub fn allocate(&mut self, session1: usize, session2: usize) -> Option<&mut RelayAllocation> {
+        let key = self.free_key()?;
+        let allocation = RelayAllocation {
+            key,
+            sides: [RelaySide::new(session1), RelaySide::new(session2)],
+            created: Instant::now(),
+            last_used: Instant::now(),
+        };
+        self.by_sessions.insert(pair(session1, session2), key);
+        Some(self.allocations.entry(key).or_insert(allocation))
+    }
+
+    /// The allocation of the two sessions
+    pub fn by_sessions_mut(
+        &mut self,
+        session1: usize,
+        session2: usize,
+    ) -> Option<&mut RelayAllocation> {
+        let key = self.by_sessions.get(&pair(session1, session2))?;
+        self.allocations.get_mut(key)
+    }
+
+    /// The allocation and the side with the poller `key`
+    pub fn by_key_mut(&mut self, key: usize) -> Option<(&mut RelayAllocation, usize)> {
+        let side = key.checked_sub(RELAY_KEY)? % 2;
+        let allocation = self.allocations.get_mut(&(key - side))?;
+        Some((allocation, side))
+    }
+
+    /// Removes the allocations for witch `keep` returns false
+    pub fn retain(&mut self, mut keep: impl FnMut(&RelayAllocation) -> bool) {
+        self.allocations.retain(|_, allocation| keep(allocation));
+        let allocations = &self.allocations;
+        self.by_sessions
+            .retain(|_, key| allocations.contains_key(key));
+        self.bandwidth.retain(|session, _| {
+            allocations
+                .values()
+                .any(|allocation| allocation.sides.iter().any(|side| side.session == *session))
+        });
+    }
+
+    /// Takes `len` bytes from the bandwidth of `session`
+    /// Returns false if the session sent too much
+    pub fn consume(&mut self, session: usize, len: usize, bandwidth: u64) -> bool {
+        let now = Instant::now();
+        // one second of burst
+        let bucket = self.bandwidth.entry(session).or_insert(Bandwidth {
+            allowance: bandwidth as f64,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.allowance = (bucket.allowance + elapsed * bandwidth as f64).min(bandwidth as f64);
+        bucket.updated = now;
+
+        if bucket.allowance < len as f64 {
+            return false;
+        }
+        bucket.allowance -= len as f64;
+        true
+    }
+
+    /// Keys are handed out in order, so a free one is usually found right away
+    fn free_key(&mut self) -> Option<usize> {
+        let keys = (LISTENER_KEY - RELAY_KEY) / 2;
+        for _ in 0..keys {
+            let key = self.next_key;
+            self.next_key += 2;
+            if self.next_key + 1 >= LISTENER_KEY {
+                self.next_key = RELAY_KEY;
+            }
+            if !self.allocations.contains_key(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+impl RelayServer {
+    /// Forwards the datagrams of peers that cannot punch a hole, limited by `quota`
+    pub fn set_relay_quota(&mut self, quota: RelayQuota) {
+        self.relay_quota = Some(quota);
+    }
+
+    /// Tokens for the `ConnectOn` of two peers, empty if the relay does not forward
+    /// Nothing is allocated until a peer sends its token in a `RelayRequest`
+    pub(crate) fn relay_tokens(&self, session1: usize, session2: usize) -> (Vec<u8>, Vec<u8>) {
+        let Some(quota) = &self.relay_quota else {
+            return (Vec::new(), Vec::new());
+        };
+
+        let expires = unix_millis(SystemTime::now() + quota.duration);
+        let key = &self.port_token_key;
+        (
+            mint_token(RELAY_LABEL, key, session1, session2, expires),
+            mint_token(RELAY_LABEL, key, session2, session1, expires),
+        )
+    }
+
+    pub(crate) fn on_relay_request(&mut self, index: usize, request: RelayRequest) {
+        let client = &self.clients[index];
+        let error = |code: ErrorCode, reason: &str| {
+            let error = Error::new(client.session, code, ErrorRequest::Relay)
+                .with_id(request.id)
+                .with_reason(reason);
+            let _ = client.send(&Packets::Error(error));
+        };
+
+        let Some(quota) = &self.relay_quota else {
+            error(
+                ErrorCode::RelayUnavailable,
+                "The relay does not forward data",
+            );
+            return;
+        };
+
+        let now = unix_millis(SystemTime::now());
+        let Ok(peer) = verify_token(
+            RELAY_LABEL,
+            &self.port_token_key,
+            client.session,
+            &request.token,
+            now,
+        ) else {
+            error(ErrorCode::InvalidToken, "Unknown relay token");
+            return;
+        };
+        let registered = |session: usize| {
+            self.clients
+                .by_session(session)
+                .is_some_and(|client| matches!(client.stage, ClientStage::Registered(_)))
+        };
+        if !registered(peer) {
+            error(ErrorCode::PeerOffline, "The peer is not registered");
+            return;
+        }
+
+        let pair = pair(client.session, peer);
+        let allocation = if self.relays.by_sessions.contains_key(&pair) {
+            self.relays.by_sessions_mut(client.session, peer)
+        } else if self.relays.len() < quota.max_allocations {
+            self.relays.allocate(client.session, peer)
+        } else {
+            None
+        };
+        let Some(allocation) = allocation else {
+            error(ErrorCode::RelayUnavailable, "No relayed ports left");
+            return;
+        };
+        let Some(side) = allocation
+            .sides
+            .iter()
+            .position(|side| side.session == client.session)
+        else {
+            return;
+        };
+        allocation.sides[side].token = request.token.clone();
+
+        if allocation.sides[side].port.is_none() {
+            // on the ip that the client already reaches
+            let ip = match client.conn.local_addr().ok().and_then(|a| a.as_socket()) {
+                Some(adress) => adress.ip(),
+                None => {
+                    error(ErrorCode::RelayUnavailable, "Unknown local adress");
+                    return;
+                }
+            };
+            let Ok(port) = bind_relay_port(SocketAddr::new(ip, 0)) else {
+                error(ErrorCode::RelayUnavailable, "Cannot bind a port");
+                return;
+            };
+            if self
+                .poller
+                .add(port.fd, Event::readable(allocation.key + side))
+                .is_err()
+            {
+                error(ErrorCode::RelayUnavailable, "Cannot bind a port");
+                return;
+            }
+            allocation.sides[side].port = Some(port);
+        }
+
+        allocation.last_used = Instant::now();
+        let Some(port) = &allocation.sides[side].port else {
+            return;
+        };
+        let _ = client.send(&Packets::RelayResponse(RelayResponse {
+            session: client.session,
+            id: request.id,
+            port: port.port,
+        }));
+    }
+
+    /// Forwards what was received on the relayed port with `key`
+    pub fn on_relay_socket(&mut self, key: usize) {
+        let Some(bandwidth) = self.relay_quota.as_ref().map(|quota| quota.bandwidth) else {
+            return;
+        };
+        let Some((allocation, side)) = self.relays.by_key_mut(key) else {
+            return;
+        };
+
+        let mut forward = Vec::new();
+        let state = &mut allocation.sides[side];
+        let session = state.session;
+        let Some(port) = &state.port else {
+            return;
+        };
+
+        let mut buffer = [MaybeUninit::uninit(); 65536];
+        while let Ok((len, from)) = port.conn.recv_from(&mut buffer) {
+            let datagram: &[u8] = unsafe { std::mem::transmute(&buffer[0..len]) };
+
+            let Some(from) = from.as_socket() else {
+                continue;
+            };
+
+            if datagram.first() == Some(&RELAY_BIND)
+                && !state.token.is_empty()
+                && datagram[1..] == state.token[..]
+            {
+                // also later, the NAT of the peer could have changed the adress
+                let _ = port.conn.send_to(datagram, &SockAddr::from(from));
+                state.peer = Some(from);
+                continue;
+            }
+
+            if state.peer == Some(from) {
+                forward.push(datagram.to_vec());
+            }
+        }
+        let _ = self.poller.modify(port.fd, Event::readable(key));
+
+        if forward.is_empty() {
+            return;
+        }
+        allocation.last_used = Instant::now();
+
+        // datagrams are dropped until the other peer is bound
+        let other = &allocation.sides[1 - side];
+        let (Some(port), Some(peer)) = (&other.port, &other.peer) else {
+            return;
+        };
+        let (Ok(conn), peer) = (port.conn.try_clone(), SockAddr::from(*peer)) else {
+            return;
+        };
+
+        let mut sent = 0;
+        for datagram in forward {
+            if self.relays.consume(session, datagram.len(), bandwidth)
+                && conn.send_to(&datagram, &peer).is_ok()
+            {
+                sent += datagram.len() as u64;
+            }
+        }
+
+        if let Some((allocation, side)) = self.relays.by_key_mut(key) {
+            allocation.sides[side].bytes += sent;
+        }
+    }
+
+    /// Removes the relayed connections that used up `RelayQuota::duration`,
+    /// were idle for `timers.relay_idle_timeout` or have a peer that is not registered
+    pub fn remove_expired_relays(&mut self) {
+        let duration = self
+            .relay_quota
+            .as_ref()
+            .map_or(Duration::ZERO, |quota| quota.duration);
+        let idle_timeout = self.timers.relay_idle_timeout;
+
+        let clients = &self.clients;
+        let poller = &self.poller;
+        self.relays.retain(|allocation| {
+            let registered = allocation.sides.iter().all(|side| {
+                clients
+                    .by_session(side.session)
+                    .is_some_and(|client| matches!(client.stage, ClientStage::Registered(_)))
+            });
+            if registered
+                && allocation.created.elapsed() < duration
+                && allocation.last_used.elapsed() < idle_timeout
+            {
+                return true;
+            }
+
+            for side in allocation.sides.iter() {
+                if let Some(port) = &side.port {
+                    let _ = poller.delete(port.fd);
+                }
+            }
+            false
+        });
+    }
+}
+
+fn bind_relay_port(adress: SocketAddr) -> std::io::Result<RelayPort> {
+    let conn = Socket::new(Domain::for_address(adress), Type::DGRAM, None)?;
+    conn.set_nonblocking(true)?;
+    conn.bind(&SockAddr::from(adress))?;
+    let port = conn
+        .local_addr()?
+        .as_socket()
+        .map_or(0, |adress| adress.port());
+
+    let fd = conn.into_raw();
+    Ok(RelayPort {
+        conn: Socket::from_raw(fd),
+        fd,
+        port,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use crate::server::testing::{recv, register, server};
+
+    use super::*;
+
+    fn request(server: &mut RelayServer, index: usize, token: &[u8]) {
+        let request = RelayRequest {
+            session: server.clients[index].session,
+            id: 1,
+            token: token.to_vec(),
+        };
+        server.on_relay_request(index, request);
+    }
+
+    fn error_code(peer: &mut TcpStream) -> ErrorCode {
+        match recv(peer) {
+            Packets::Error(error) => error.code,
+            packet => panic!("Not a error: {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn ports_are_allocated_on_request() {
+        let mut server = server();
+        server.set_relay_quota(RelayQuota {
+            max_allocations: 1,
+            ..Default::default()
+        });
+        let (a, mut peer_a) = register(&mut server, vec![1]);
+        let (b, mut peer_b) = register(&mut server, vec![2]);
+        let (c, _peer_c) = register(&mut server, vec![3]);
+        let [a_session, b_session, c_session] =
+            [a, b, c].map(|index| server.clients[index].session);
+
+        // tokens for many connections take nothing
+        let (token_ab, token_ba) = server.relay_tokens(a_session, b_session);
+        let (token_ac, _) = server.relay_tokens(a_session, c_session);
+        for _ in 0..4 {
+            server.relay_tokens(a_session, c_session);
+        }
+        assert!(server.relays.is_empty());
+
+        request(&mut server, a, &token_ab);
+        assert!(matches!(recv(&mut peer_a), Packets::RelayResponse(_)));
+        request(&mut server, b, &token_ba);
+        assert!(matches!(recv(&mut peer_b), Packets::RelayResponse(_)));
+        assert_eq!(server.relays.len(), 1);
+        let allocation = server.relays.by_sessions_mut(b_session, a_session).unwrap();
+        assert!(allocation.sides.iter().all(|side| side.port.is_some()));
+
+        // the quota is used by the connection that asked first
+        request(&mut server, a, &token_ac);
+        assert_eq!(error_code(&mut peer_a), ErrorCode::RelayUnavailable);
+        assert_eq!(server.relays.len(), 1);
+    }
+
+    #[test]
+    fn relay_token_is_only_for_its_session() {
+        let mut server = server();
+        server.set_relay_quota(RelayQuota::default());
+        let (a, _peer_a) = register(&mut server, vec![1]);
+        let (b, mut peer_b) = register(&mut server, vec![2]);
+        let [a_session, b_session] = [a, b].map(|index| server.clients[index].session);
+
+        let (token_ab, _) = server.relay_tokens(a_session, b_session);
+        request(&mut server, b, &token_ab);
+        assert_eq!(error_code(&mut peer_b), ErrorCode::InvalidToken);
+        request(&mut server, b, &[1; 16]);
+        assert_eq!(error_code(&mut peer_b), ErrorCode::InvalidToken);
+        assert!(server.relays.is_empty());
+    }
+
+    #[test]
+    fn allocations_are_found_by_key() {
+        let mut relays = RelayRegistry::default();
+        let first = relays.allocate(1, 2).unwrap().key;
+        let second = relays.allocate(3, 4).unwrap().key;
+        assert_ne!(first, second);
+
+        assert_eq!(relays.by_key_mut(first).unwrap().1, 0);
+        let (allocation, side) = relays.by_key_mut(second + 1).unwrap();
+        assert_eq!((allocation.key, side), (second, 1));
+        assert_eq!(allocation.sides[side].session, 4);
+        assert!(relays.by_key_mut(RELAY_KEY - 1).is_none());
+
+        relays.retain(|allocation| allocation.key != first);
+        assert!(relays.by_key_mut(first).is_none());
+        assert!(relays.by_sessions_mut(2, 1).is_none());
+        assert_eq!(relays.by_sessions_mut(4, 3).unwrap().key, second);
+    }
+}
@@ -0,0 +1,105 @@
+//! Clients that are connected to a `RelayServer` without its listeners, for the tests
+
+use std::{
+    io::Read,
+    net::{TcpListener, TcpStream},
+    time::{Duration, SystemTime},
+};
+
+use socket2::Socket;
+
+use crate::common::{
+    adress::Adress,
+    frame::{FrameDecoder, HEADER_LEN},
+    packets::{Auth, NatType, Packets, Register, PROTOCOL_VERSION},
+    tls::Tls,
+    AsRawSock,
+};
+
+use super::{Client, ClientStage, RegisteredClient, RelayServer};
+
+pub fn server() -> RelayServer {
+    RelayServer::bind("127.0.0.1:0", Duration::from_secs(60)).unwrap()
+}
+
+/// Adds a client that did send `Hello`, the packets that it gets are read from the stream
+/// Returns the index of the client
+pub fn connect(server: &mut RelayServer) -> (usize, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let (conn, from) = listener.accept().unwrap();
+    let conn = Socket::from(conn);
+    conn.set_nonblocking(true).unwrap();
+
+    server.clients.push(Client {
+        session: server.create_session(),
+        fd: conn.as_raw(),
+        conn,
+        from: from.into(),
+        stage: ClientStage::NotRegistered,
+        last_message: SystemTime::now(),
+        connected: SystemTime::now(),
+        buffer: FrameDecoder::default(),
+        version: PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+        tls: Tls::default(),
+        #[cfg(feature = "tokio")]
+        writer: None,
+    });
+    (server.clients.len() - 1, peer)
+}
+
+/// Same as `connect` but registered with `adress`
+pub fn register(server: &mut RelayServer, adress: Adress) -> (usize, TcpStream) {
+    let (index, peer) = connect(server);
+    server.clients.register(index, registered(adress));
+    (index, peer)
+}
+
+/// A `RegisteredClient` with `adress`
+pub fn registered(adress: Adress) -> RegisteredClient {
+    RegisteredClient {
+        name: String::from("test"),
+        client: String::from("test"),
+        other: vec![],
+        adress,
+        ports: vec![],
+        to_connect: vec![],
+        privacy: false,
+        private_adress: String::new(),
+        request_ids: vec![],
+        metadata: vec![],
+        verified: false,
+        nat: NatType::Unknown,
+        probes: vec![],
+        candidates: vec![],
+    }
+}
+
+/// The next packet that the client got, reads only that frame
+pub fn recv(peer: &mut TcpStream) -> Packets {
+    let mut header = [0; HEADER_LEN];
+    peer.read_exact(&mut header).unwrap();
+    let mut frame = vec![0; u32::from_be_bytes(header) as usize];
+    peer.read_exact(&mut frame).unwrap();
+
+    let mut decoder = FrameDecoder::default();
+    decoder.push(&header);
+    decoder.push(&frame);
+    decoder.next_packet().unwrap().unwrap()
+}
+
+/// `Register::Client` for `public`
+pub fn register_packet(public: Adress, auth: Auth, identity: bool) -> Packets {
+    Packets::Register(Register::Client {
+        client: String::from("test"),
+        public,
+        name: String::from("test"),
+        other: vec![],
+        privacy: false,
+        private_adress: String::new(),
+        auth,
+        identity,
+    })
+}
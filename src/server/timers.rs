@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+use super::RelayServer;
+
+/// How often the clients are checked for timeouts
+pub const HOUSEKEEPING_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a client can be connected without finishing `Hello` and `Register::Client`
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a port token can be used for `Register::Port`
+pub const PORT_TOKEN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a relayed connection is kept without forwarding anything
+pub const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Timers {
+    pub housekeeping_interval: Duration,
+    pub handshake_timeout: Duration,
+    pub port_token_timeout: Duration,
+    pub relay_idle_timeout: Duration,
+    pub next_housekeeping: Instant,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            housekeeping_interval: HOUSEKEEPING_INTERVAL,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            port_token_timeout: PORT_TOKEN_TIMEOUT,
+            relay_idle_timeout: RELAY_IDLE_TIMEOUT,
+            next_housekeeping: Instant::now() + HOUSEKEEPING_INTERVAL,
+        }
+    }
+}
+
+impl RelayServer {
+    /// How long the server can wait for packets before a timer needs to run
+    /// Useful when the server is driven from an external event loop
+    pub fn poll_timeout(&self) -> Duration {
+        self.timers
+            .next_housekeeping
+            .saturating_duration_since(Instant::now())
+    }
+
+    /// Runs the timers that are due
+    pub fn run_timers(&mut self) {
+        let now = Instant::now();
+        if now < self.timers.next_housekeeping {
+            return;
+        }
+
+        self.housekeeping();
+        self.timers.next_housekeeping = now + self.timers.housekeeping_interval;
+    }
+
+    /// Removes the timed out clients, the unfinished handshakes and the expired relayed connections
+    pub fn housekeeping(&mut self) {
+        self.remove_timed_out();
+        self.remove_expired_relays();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::common::packets::ErrorCode;
+    use crate::server::{testing, ClientStage, Connecting};
+
+    use super::*;
+
+    fn ago(seconds: u64) -> SystemTime {
+        SystemTime::now() - Duration::from_secs(seconds)
+    }
+
+    fn set_times(server: &mut RelayServer, index: usize, last_message: u64, connected: u64) {
+        let mut client = server.clients.get_mut(index).unwrap();
+        client.last_message = ago(last_message);
+        client.connected = ago(connected);
+    }
+
+    fn has(server: &RelayServer, session: usize) -> bool {
+        server.clients.contains_session(session)
+    }
+
+    #[test]
+    fn idle_clients_are_removed() {
+        let mut server = testing::server();
+        server.client_timeout = Duration::from_secs(60);
+        let (idle, _idle) = testing::register(&mut server, vec![1]);
+        let (active, _active) = testing::register(&mut server, vec![2]);
+        set_times(&mut server, idle, 61, 61);
+        set_times(&mut server, active, 59, 61);
+        let (idle, active) = (server.clients[idle].session, server.clients[active].session);
+
+        server.housekeeping();
+        assert!(!has(&server, idle));
+        assert!(!server.clients.contains_adress(&vec![1]));
+        assert!(has(&server, active));
+    }
+
+    #[test]
+    fn unfinished_handshakes_expire() {
+        let mut server = testing::server();
+        server.timers.handshake_timeout = Duration::from_secs(10);
+        let (expired, _expired) = testing::connect(&mut server);
+        let (fresh, _fresh) = testing::connect(&mut server);
+        let (registered, _registered) = testing::register(&mut server, vec![1]);
+        let sessions: Vec<usize> = [expired, fresh, registered]
+            .iter()
+            .map(|index| server.clients[*index].session)
+            .collect();
+        // all of them are sending ticks
+        set_times(&mut server, expired, 0, 11);
+        set_times(&mut server, fresh, 0, 9);
+        set_times(&mut server, registered, 0, 11);
+
+        server.housekeeping();
+        assert!(!has(&server, sessions[0]));
+        assert!(has(&server, sessions[1]));
+        assert!(has(&server, sessions[2]));
+    }
+
+    #[test]
+    fn timers_run_when_due() {
+        let mut server = testing::server();
+        let (index, _peer) = testing::connect(&mut server);
+        let session = server.clients[index].session;
+        server.clients.get_mut(index).unwrap().last_message = SystemTime::UNIX_EPOCH;
+
+        server.timers.next_housekeeping = Instant::now() + Duration::from_secs(60);
+        server.run_timers();
+        assert!(has(&server, session));
+        assert!(server.poll_timeout() > Duration::from_secs(59));
+
+        server.timers.next_housekeeping = Instant::now();
+        server.run_timers();
+        assert!(!has(&server, session));
+        assert!(server.poll_timeout() <= server.timers.housekeeping_interval);
+        assert!(server.poll_timeout() > Duration::ZERO);
+    }
+
+    #[test]
+    fn port_tokens_expire() {
+        let mut server = testing::server();
+        server.timers.port_token_timeout = Duration::from_millis(50);
+        let (a, _a) = testing::register(&mut server, vec![1]);
+        let (b, _b) = testing::register(&mut server, vec![2]);
+        let (a, b) = (server.clients[a].session, server.clients[b].session);
+        if let Some(mut client) = server.clients.by_session_mut(a) {
+            if let ClientStage::Registered(rclient) = &mut client.stage {
+                rclient.to_connect.push(Connecting::Start(b));
+            }
+        }
+
+        let token = server.port_token(a, b);
+        assert_eq!(server.check_port_token(a, &token), Ok(b));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            server.check_port_token(a, &token),
+            Err(ErrorCode::InvalidToken)
+        );
+    }
+}